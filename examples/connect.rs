@@ -1,3 +1,4 @@
+use std::borrow::Cow;
 use std::time::Duration;
 
 use futures::future::ok;
@@ -30,7 +31,7 @@ async fn main() -> Result<(), failure::Error> {
             ToServer::Send {
                 destination: "rusty".into(),
                 transaction: None,
-                body: Some(b"Hello there rustaceans!".to_vec()),
+                body: Some(Cow::Borrowed(b"Hello there rustaceans!")),
             }
             .into(),
         )
@@ -46,8 +47,7 @@ async fn main() -> Result<(), failure::Error> {
         tokio::time::delay_for(Duration::from_millis(200)).await;
 
         tokio::time::delay_for(Duration::from_secs(1)).await;
-        sink.send(ToServer::Disconnect { receipt: None }.into())
-            .await?;
+        sink.send(client::disconnect()).await?;
         println!("Disconnect sent");
 
         Ok(())
@@ -0,0 +1,43 @@
+use std::process::Stdio;
+
+use futures::prelude::*;
+use tokio::process::Command;
+use tokio_stomp::client::{self, ConnectOptions};
+
+// This example connects to a STOMP server which is only reachable through an SSH gateway,
+// by running `ssh -W` to forward the child process's stdin and stdout to the broker.
+// It subscribes to a queue, sends a message to it and prints what it receives.
+//
+// Run it with `cargo run --example ssh_tunnel --features process -- user@gateway broker:61613`
+
+#[tokio::main]
+async fn main() -> Result<(), failure::Error> {
+    let mut args = std::env::args().skip(1);
+    let gateway = args.next().unwrap_or_else(|| "localhost".into());
+    let broker = args.next().unwrap_or_else(|| "127.0.0.1:61613".into());
+
+    let mut ssh = Command::new("ssh")
+        .arg("-W")
+        .arg(&broker)
+        .arg(&gateway)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let stdin = ssh.stdin.take().unwrap();
+    let stdout = ssh.stdout.take().unwrap();
+
+    let host = broker.split(':').next().unwrap();
+    let mut conn = client::connect_stdio(stdin, stdout, host, ConnectOptions::new()).await?;
+    conn.send(client::subscribe("/queue/tunnel", "myid"))
+        .await?;
+    conn.send(client::send_text(
+        "/queue/tunnel",
+        "Hello through the tunnel",
+    ))
+    .await?;
+    let msg = conn.next().await.transpose()?;
+    println!("{:?}", msg);
+    conn.send(client::disconnect()).await?;
+    Ok(())
+}
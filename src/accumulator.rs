@@ -0,0 +1,120 @@
+use bytes::BytesMut;
+use tokio_util::codec::Decoder;
+
+use crate::client::{ClientCodec, ConnectOptions};
+use crate::{FromServer, Message, Result};
+
+/// Decodes frames from bytes pushed into it, for reading STOMP from a source other than
+/// an `AsyncRead`, e.g. a message queue carrying raw frames.
+///
+/// Bytes are decoded exactly as `StompTransport` decodes them, so heart-beats are skipped
+/// and the options' frame size limit, `content-length` handling and codec layer all apply.
+/// Bytes may be pushed split at any point. Once `next_frame` has failed the input can't
+/// be resynchronized, and the accumulator should be discarded.
+#[derive(Debug)]
+pub struct FrameAccumulator {
+    codec: ClientCodec,
+    buffer: BytesMut,
+}
+
+impl FrameAccumulator {
+    /// Decode frames as a connection made with `options` would
+    pub fn new(options: &ConnectOptions) -> Self {
+        FrameAccumulator {
+            codec: options.codec(),
+            buffer: BytesMut::new(),
+        }
+    }
+
+    /// Append received bytes
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Remove the next complete frame, if one has been received
+    pub fn next_frame(&mut self) -> Result<Option<Message<FromServer>>> {
+        self.codec.decode(&mut self.buffer)
+    }
+
+    /// The number of bytes pushed which have not yet been decoded as part of a frame
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+}
+
+impl Default for FrameAccumulator {
+    fn default() -> Self {
+        FrameAccumulator::new(&ConnectOptions::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StompError;
+
+    const INPUT: &[u8] = b"\n\r\nCONNECTED\nversion:1.2\n\n\x00\n\
+        MESSAGE\ndestination:a\nmessage-id:1\nsubscription:1\ncontent-length:5\n\na\x00b\x00c\x00\n\
+        RECEIPT\nreceipt-id:r\n\n\x00\n\n\
+        MESSAGE\ndestination:b\nmessage-id:2\nsubscription:1\n\nhello\x00";
+
+    fn drain(accumulator: &mut FrameAccumulator, frames: &mut Vec<String>) {
+        while let Some(msg) = accumulator.next_frame().unwrap() {
+            frames.push(format!("{:?}", msg));
+        }
+    }
+
+    fn decode_in_chunks(chunks: &[&[u8]]) -> Vec<String> {
+        let mut accumulator = FrameAccumulator::default();
+        let mut frames = vec![];
+        for chunk in chunks {
+            accumulator.push(chunk);
+            drain(&mut accumulator, &mut frames);
+        }
+        assert_eq!(accumulator.buffered(), 0);
+        frames
+    }
+
+    #[test]
+    fn any_split_decodes_the_same() {
+        let whole = decode_in_chunks(&[INPUT]);
+        assert_eq!(whole.len(), 4);
+        let mut accumulator = FrameAccumulator::default();
+        accumulator.push(INPUT);
+        accumulator.next_frame().unwrap();
+        let msg = accumulator.next_frame().unwrap().unwrap();
+        assert_eq!(msg.body_as_str(), Some("a\0b\0c"));
+
+        let bytes: Vec<&[u8]> = INPUT.chunks(1).collect();
+        assert_eq!(decode_in_chunks(&bytes), whole);
+        for first in 0..INPUT.len() {
+            for second in first..INPUT.len() {
+                let chunks = [&INPUT[..first], &INPUT[first..second], &INPUT[second..]];
+                assert_eq!(
+                    decode_in_chunks(&chunks),
+                    whole,
+                    "split at {}, {}",
+                    first,
+                    second
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn options_apply() {
+        let options = ConnectOptions::new().max_frame_size(16);
+        let mut accumulator = FrameAccumulator::new(&options);
+        accumulator.push(b"RECEIPT\nreceipt-id:r\n\n\x00");
+        let err = accumulator.next_frame().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StompError>(),
+            Some(StompError::FrameTooLarge { limit: 16 })
+        ));
+
+        let mut accumulator = FrameAccumulator::default();
+        accumulator.push(b"\n\nRECEIPT\nreceipt-id:r\n");
+        assert!(accumulator.next_frame().unwrap().is_none());
+        assert_eq!(accumulator.buffered(), b"RECEIPT\nreceipt-id:r\n".len());
+    }
+}
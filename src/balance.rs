@@ -0,0 +1,262 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::prelude::*;
+
+use crate::{Message, Result, StompError, ToServer};
+
+/// A sink which distributes outgoing messages over several connections in round-robin order.
+///
+/// A connection which returns an error is removed from the rotation.
+/// If frames were sent on it since it was last flushed, they may have been lost, so its
+/// error is returned from the next `poll_flush` or `poll_close`, once the remaining
+/// connections have been polled.
+/// Once no connections remain, every operation fails with `StompError::NoConnections`.
+pub struct LoadBalancedSink<S> {
+    connections: Vec<Member<S>>,
+    next: usize,
+    /// The error of a connection removed with frames yet to be flushed
+    failed: Option<failure::Error>,
+}
+
+struct Member<S> {
+    sink: S,
+    /// Whether frames have been sent since the last flush
+    unflushed: bool,
+}
+
+impl<S> LoadBalancedSink<S>
+where
+    S: Sink<Message<ToServer>, Error = failure::Error> + Unpin,
+{
+    pub fn new(connections: Vec<S>) -> Self {
+        LoadBalancedSink {
+            connections: connections
+                .into_iter()
+                .map(|sink| Member {
+                    sink,
+                    unflushed: false,
+                })
+                .collect(),
+            next: 0,
+            failed: None,
+        }
+    }
+
+    /// The number of connections still in rotation
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+
+    /// Remove a connection, returning whether frames sent on it were yet to be flushed
+    fn remove(&mut self, index: usize, err: &failure::Error) -> bool {
+        log::warn!("removing connection from rotation: {}", err);
+        let member = self.connections.remove(index);
+        if self.next >= self.connections.len() {
+            self.next = 0;
+        }
+        member.unflushed
+    }
+
+    /// Remove a failed connection, keeping its error for the next flush if frames were lost
+    fn fail(&mut self, index: usize, err: failure::Error) {
+        if self.remove(index, &err) && self.failed.is_none() {
+            self.failed = Some(err);
+        }
+    }
+
+    /// Poll every connection with `f`, removing those which fail.
+    /// Fails if frames were lost along with a connection since the last flush.
+    fn poll_all(
+        &mut self,
+        cx: &mut Context<'_>,
+        f: impl Fn(Pin<&mut S>, &mut Context<'_>) -> Poll<Result<()>>,
+    ) -> Poll<Result<()>> {
+        let mut pending = false;
+        let mut index = 0;
+        while index < self.connections.len() {
+            let member = &mut self.connections[index];
+            match f(Pin::new(&mut member.sink), cx) {
+                Poll::Ready(Ok(())) => {
+                    member.unflushed = false;
+                    index += 1;
+                }
+                Poll::Ready(Err(err)) => self.fail(index, err),
+                Poll::Pending => {
+                    pending = true;
+                    index += 1;
+                }
+            }
+        }
+        if pending {
+            Poll::Pending
+        } else if let Some(err) = self.failed.take() {
+            Poll::Ready(Err(err))
+        } else {
+            Poll::Ready(Ok(()))
+        }
+    }
+}
+
+impl<S> Sink<Message<ToServer>> for LoadBalancedSink<S>
+where
+    S: Sink<Message<ToServer>, Error = failure::Error> + Unpin,
+{
+    type Error = failure::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        while !this.connections.is_empty() {
+            let next = this.next;
+            match Pin::new(&mut this.connections[next].sink).poll_ready(cx) {
+                Poll::Ready(Err(err)) => this.fail(next, err),
+                other => return other,
+            }
+        }
+        Poll::Ready(Err(StompError::NoConnections.into()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message<ToServer>) -> Result<()> {
+        let this = self.get_mut();
+        if this.connections.is_empty() {
+            return Err(StompError::NoConnections.into());
+        }
+        let next = this.next;
+        let member = &mut this.connections[next];
+        match Pin::new(&mut member.sink).start_send(item) {
+            Ok(()) => {
+                member.unflushed = true;
+                this.next = (next + 1) % this.connections.len();
+                Ok(())
+            }
+            Err(err) => {
+                this.remove(next, &err);
+                Err(err)
+            }
+        }
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        futures::ready!(this.poll_all(cx, |conn, cx| conn.poll_flush(cx)))?;
+        if this.connections.is_empty() {
+            Poll::Ready(Err(StompError::NoConnections.into()))
+        } else {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().poll_all(cx, |conn, cx| conn.poll_close(cx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::mpsc;
+    use futures::executor::block_on;
+
+    type TestSink = sink::SinkMapErr<
+        mpsc::UnboundedSender<Message<ToServer>>,
+        fn(mpsc::SendError) -> failure::Error,
+    >;
+
+    fn channels(
+        n: usize,
+    ) -> (
+        LoadBalancedSink<TestSink>,
+        Vec<mpsc::UnboundedReceiver<Message<ToServer>>>,
+    ) {
+        let (senders, receivers) = (0..n)
+            .map(|_| {
+                let (tx, rx) = mpsc::unbounded();
+                let to_failure: fn(mpsc::SendError) -> failure::Error = failure::Error::from;
+                (tx.sink_map_err(to_failure), rx)
+            })
+            .unzip();
+        (LoadBalancedSink::new(senders), receivers)
+    }
+
+    fn message() -> Message<ToServer> {
+        ToServer::Unsubscribe { id: "1".into() }.into()
+    }
+
+    fn received(rx: &mut mpsc::UnboundedReceiver<Message<ToServer>>) -> usize {
+        std::iter::from_fn(|| rx.next().now_or_never().flatten()).count()
+    }
+
+    #[test]
+    fn round_robin() {
+        let (mut sink, mut receivers) = channels(3);
+        for _ in 0..6 {
+            block_on(sink.send(message())).unwrap();
+        }
+        for rx in &mut receivers {
+            assert_eq!(received(rx), 2);
+        }
+    }
+
+    #[test]
+    fn failed_connections_are_removed() {
+        let (mut sink, mut receivers) = channels(3);
+        receivers.remove(1);
+        for _ in 0..4 {
+            block_on(sink.send(message())).unwrap();
+        }
+        assert_eq!(sink.len(), 2);
+        for rx in &mut receivers {
+            assert_eq!(received(rx), 2);
+        }
+
+        receivers.clear();
+        let err = block_on(sink.send(message())).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StompError>(),
+            Some(StompError::NoConnections)
+        ));
+        assert!(sink.is_empty());
+    }
+
+    /// Accepts frames, but fails to flush them
+    struct Broken;
+
+    impl Sink<Message<ToServer>> for Broken {
+        type Error = failure::Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(self: Pin<&mut Self>, _: Message<ToServer>) -> Result<()> {
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<()>> {
+            Poll::Ready(Err(failure::err_msg("connection reset")))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+            self.poll_flush(cx)
+        }
+    }
+
+    #[test]
+    fn lost_frames_fail_the_flush() {
+        let (tx, mut rx) = mpsc::unbounded();
+        let tx = tx.sink_map_err(failure::Error::from).left_sink();
+        let mut sink = LoadBalancedSink::new(vec![tx, Broken.right_sink()]);
+        block_on(sink.feed(message())).unwrap();
+        block_on(sink.feed(message())).unwrap();
+        let err = block_on(sink.flush()).unwrap_err();
+        assert_eq!(err.to_string(), "connection reset");
+        assert_eq!(sink.len(), 1);
+        assert_eq!(received(&mut rx), 1);
+        // the failure is only reported once
+        block_on(sink.send(message())).unwrap();
+        assert_eq!(received(&mut rx), 1);
+    }
+}
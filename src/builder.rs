@@ -0,0 +1,374 @@
+use std::borrow::Cow;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::dialect::BrokerDialect;
+use crate::id::SubscriptionIdGenerator;
+use crate::{AckMode, Message, ToServer};
+
+/// Routing semantics of an ActiveMQ Artemis address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoutingType {
+    /// Point-to-point, each message is consumed by a single subscriber
+    Anycast,
+    /// Publish-subscribe, each message is delivered to every subscriber
+    Multicast,
+}
+
+/// ActiveMQ Artemis specific subscription settings
+#[derive(Debug, Clone)]
+pub struct ArtemisOptions {
+    /// Name of a durable subscription. Artemis only keeps it across reconnects if the
+    /// connection also set a `client-id` (see `ConnectOptions::connect_header`).
+    pub durable_name: Option<String>,
+    pub routing_type: RoutingType,
+    /// Selector deciding which messages the subscription receives
+    pub filter: Option<String>,
+}
+
+/// Builder for Subscribe messages
+#[derive(Debug, Clone)]
+pub struct SubscriptionBuilder {
+    destination: String,
+    id: String,
+    ack: Option<AckMode>,
+    headers: Vec<(String, String)>,
+}
+
+impl SubscriptionBuilder {
+    pub fn new(destination: impl Into<String>, id: impl Into<String>) -> Self {
+        SubscriptionBuilder {
+            destination: destination.into(),
+            id: id.into(),
+            ack: None,
+            headers: vec![],
+        }
+    }
+
+    /// Start building a subscription whose id is taken from `generator`
+    pub fn with_generator(
+        destination: impl Into<String>,
+        generator: &mut impl SubscriptionIdGenerator,
+    ) -> Self {
+        Self::new(destination, generator.next())
+    }
+
+    /// Set the acknowledgment mode of the subscription
+    pub fn ack(mut self, ack: AckMode) -> Self {
+        self.ack = Some(ack);
+        self
+    }
+
+    /// Add an extra header to the Subscribe frame
+    pub fn header(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((key.into(), value.into()));
+        self
+    }
+
+    /// Ask the broker not to deliver messages sent over this same connection, by adding
+    /// the `no-local:true` header. This isn't part of STOMP, and brokers which don't support
+    /// it ignore it, so check the broker's documentation. ActiveMQ Classic expects
+    /// `activemq.noLocal:true` instead, which can be added with `header`.
+    pub fn no_local(self) -> Self {
+        self.header("no-local", "true")
+    }
+
+    /// Limit the number of unacknowledged messages the broker sends at once,
+    /// using the header understood by `dialect` (if any)
+    pub fn prefetch(self, dialect: &dyn BrokerDialect, count: u32) -> Self {
+        match dialect.prefetch(count) {
+            Some((key, value)) => self.header(key, value),
+            None => self,
+        }
+    }
+
+    /// Add the headers ActiveMQ Artemis uses to configure the subscription:
+    /// `subscription-type`, `selector` and `durable-subscription-name`
+    pub fn artemis(self, options: ArtemisOptions) -> Self {
+        let routing_type = match options.routing_type {
+            RoutingType::Anycast => "ANYCAST",
+            RoutingType::Multicast => "MULTICAST",
+        };
+        let mut builder = self.header("subscription-type", routing_type);
+        if let Some(filter) = options.filter {
+            builder = builder.header("selector", filter);
+        }
+        match options.durable_name {
+            Some(name) => builder.header("durable-subscription-name", name),
+            None => builder,
+        }
+    }
+
+    pub fn build(self) -> Message<ToServer> {
+        Message::with_headers(
+            ToServer::Subscribe {
+                destination: self.destination,
+                id: self.id,
+                ack: self.ack,
+            },
+            self.headers,
+        )
+    }
+}
+
+impl From<SubscriptionBuilder> for Message<ToServer> {
+    fn from(builder: SubscriptionBuilder) -> Message<ToServer> {
+        builder.build()
+    }
+}
+
+/// A subscription exported by `StompTransport::export_subscriptions`, e.g. to be handed to
+/// another process which restores it with `ConnectOptions::restore_subscriptions`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde_crate::Serialize, serde_crate::Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct SubscriptionSpec {
+    pub destination: String,
+    pub id: String,
+    pub ack: AckMode,
+    /// The SUBSCRIBE frame's extra headers, other than `receipt`
+    pub headers: Vec<(String, String)>,
+}
+
+impl From<SubscriptionSpec> for SubscriptionBuilder {
+    fn from(spec: SubscriptionSpec) -> SubscriptionBuilder {
+        SubscriptionBuilder {
+            destination: spec.destination,
+            id: spec.id,
+            ack: Some(spec.ack),
+            headers: spec.headers,
+        }
+    }
+}
+
+impl From<SubscriptionSpec> for Message<ToServer> {
+    fn from(spec: SubscriptionSpec) -> Message<ToServer> {
+        SubscriptionBuilder::from(spec).build()
+    }
+}
+
+/// Builder for Send messages
+#[derive(Debug, Clone)]
+pub struct SendBuilder {
+    destination: String,
+    transaction: Option<String>,
+    body: Option<Vec<u8>>,
+    content_type: Option<String>,
+    auto_content_type: bool,
+    headers: Vec<(String, String)>,
+}
+
+impl SendBuilder {
+    pub fn new(destination: impl Into<String>) -> Self {
+        SendBuilder {
+            destination: destination.into(),
+            transaction: None,
+            body: None,
+            content_type: None,
+            auto_content_type: true,
+            headers: vec![],
+        }
+    }
+
+    /// Send the message as part of a transaction
+    pub fn transaction(mut self, transaction: impl Into<String>) -> Self {
+        self.transaction = Some(transaction.into());
+        self
+    }
+
+    /// Set the body of the message.
+    /// Unless a content type is set explicitly, it will be `text/plain;charset=UTF-8`
+    /// if the body is valid UTF-8, and `application/octet-stream` otherwise.
+    pub fn body_bytes(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = Some(body.into());
+        self
+    }
+
+    /// Set the `content-type` header
+    pub fn content_type(mut self, content_type: impl Into<String>) -> Self {
+        self.content_type = Some(content_type.into());
+        self
+    }
+
+    /// Don't infer a `content-type` header from the body
+    pub fn no_auto_content_type(mut self) -> Self {
+        self.auto_content_type = false;
+        self
+    }
+
+    /// Add an extra header to the Send frame
+    pub fn header(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((key.into(), value.into()));
+        self
+    }
+
+    /// Have the message expire `ttl` from now, see `deadline`
+    pub fn ttl(self, ttl: Duration) -> Self {
+        self.deadline(SystemTime::now() + ttl)
+    }
+
+    /// Have the message expire at `deadline`, by setting the `expires` header. Brokers discard
+    /// messages which haven't been delivered by then, and `StompTransport` drops them rather
+    /// than sending them late, failing with `StompError::MessageExpired`.
+    pub fn deadline(self, deadline: SystemTime) -> Self {
+        let millis = deadline
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis());
+        // an `expires` of 0 means the message never expires
+        self.header("expires", millis.max(1).to_string())
+    }
+
+    pub fn build(self) -> Message<ToServer> {
+        let content_type = match (self.content_type, &self.body) {
+            (Some(content_type), _) => Some(content_type),
+            (None, Some(body)) if self.auto_content_type => Some(match std::str::from_utf8(body) {
+                Ok(_) => "text/plain;charset=UTF-8".to_string(),
+                Err(_) => "application/octet-stream".to_string(),
+            }),
+            (None, _) => None,
+        };
+        let mut headers = self.headers;
+        if let Some(content_type) = content_type {
+            headers.insert(0, ("content-type".into(), content_type));
+        }
+        Message::with_headers(
+            ToServer::Send {
+                destination: self.destination,
+                transaction: self.transaction,
+                body: self.body.map(Cow::Owned),
+            },
+            headers,
+        )
+    }
+}
+
+impl From<SendBuilder> for Message<ToServer> {
+    fn from(builder: SendBuilder) -> Message<ToServer> {
+        builder.build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+
+    fn serialize(msg: Message<ToServer>) -> String {
+        let mut buffer = BytesMut::new();
+        msg.to_frame().serialize(&mut buffer);
+        String::from_utf8_lossy(&buffer).into_owned()
+    }
+
+    #[test]
+    fn expiry_headers() {
+        let msg = SendBuilder::new("a")
+            .deadline(UNIX_EPOCH + Duration::from_millis(1500))
+            .build();
+        assert!(serialize(msg).contains("\nexpires:1500\n"));
+        let msg = SendBuilder::new("a").deadline(UNIX_EPOCH).build();
+        assert!(serialize(msg).contains("\nexpires:1\n"));
+
+        let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let msg = SendBuilder::new("a").ttl(Duration::from_secs(1)).build();
+        let expires: u128 = String::from_utf8_lossy(&msg.extra_headers[0].1)
+            .parse()
+            .unwrap();
+        assert!(expires >= (before + Duration::from_secs(1)).as_millis());
+    }
+
+    #[test]
+    fn generated_ids() {
+        let mut generator = crate::id::AtomicCounter::default();
+        let first = SubscriptionBuilder::with_generator("a", &mut generator).build();
+        let second = SubscriptionBuilder::with_generator("b", &mut generator).build();
+        assert!(serialize(first).contains("\nid:sub-0\n"));
+        assert!(serialize(second).contains("\nid:sub-1\n"));
+    }
+
+    #[test]
+    fn no_local_header() {
+        let msg = SubscriptionBuilder::new("a", "1").no_local().build();
+        assert_eq!(
+            serialize(msg),
+            "SUBSCRIBE\ndestination:a\nid:1\nno-local:true\n\n\x00"
+        );
+    }
+
+    #[test]
+    fn prefetch_uses_dialect() {
+        use crate::dialect::BrokerFlavor;
+
+        let msg = SubscriptionBuilder::new("a", "1")
+            .prefetch(&BrokerFlavor::RabbitMQ, 5)
+            .build();
+        assert!(serialize(msg).contains("\nprefetch-count:5\n"));
+
+        let msg = SubscriptionBuilder::new("a", "1")
+            .prefetch(&BrokerFlavor::Generic, 5)
+            .build();
+        assert_eq!(serialize(msg), "SUBSCRIBE\ndestination:a\nid:1\n\n\x00");
+    }
+
+    #[test]
+    fn artemis_headers() {
+        let msg = SubscriptionBuilder::new("orders", "sub-1")
+            .artemis(ArtemisOptions {
+                durable_name: Some("eu-orders".into()),
+                routing_type: RoutingType::Multicast,
+                filter: Some("region = 'EU'".into()),
+            })
+            .build();
+        assert_eq!(
+            serialize(msg),
+            "SUBSCRIBE\ndestination:orders\nid:sub-1\nsubscription-type:MULTICAST\n\
+             selector:region = 'EU'\ndurable-subscription-name:eu-orders\n\n\x00"
+        );
+
+        let msg = SubscriptionBuilder::new("orders", "sub-2")
+            .ack(AckMode::Client)
+            .artemis(ArtemisOptions {
+                durable_name: None,
+                routing_type: RoutingType::Anycast,
+                filter: None,
+            })
+            .build();
+        assert_eq!(
+            serialize(msg),
+            "SUBSCRIBE\ndestination:orders\nid:sub-2\nack:client\n\
+             subscription-type:ANYCAST\n\n\x00"
+        );
+    }
+
+    #[test]
+    fn send_content_type() {
+        let content_type = |builder: SendBuilder| {
+            let frame = serialize(builder.build());
+            frame
+                .lines()
+                .find(|line| line.starts_with("content-type:"))
+                .map(|line| line["content-type:".len()..].to_string())
+        };
+
+        let text = SendBuilder::new("a").body_bytes("héllo");
+        assert_eq!(
+            content_type(text).as_deref(),
+            Some("text/plain;charset=UTF-8")
+        );
+        let binary = SendBuilder::new("a").body_bytes(vec![0xff, 0xfe, 0x00]);
+        assert_eq!(
+            content_type(binary).as_deref(),
+            Some("application/octet-stream")
+        );
+        let explicit = SendBuilder::new("a")
+            .body_bytes(vec![0xff])
+            .content_type("image/png");
+        assert_eq!(content_type(explicit).as_deref(), Some("image/png"));
+        let disabled = SendBuilder::new("a")
+            .body_bytes(vec![0xff])
+            .no_auto_content_type();
+        assert_eq!(content_type(disabled), None);
+        assert_eq!(content_type(SendBuilder::new("a")), None);
+    }
+}
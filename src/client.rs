@@ -1,17 +1,474 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::io;
 use std::net::ToSocketAddrs;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use bytes::{Buf, BytesMut};
+use bytes::{Buf, Bytes, BytesMut};
 use futures::prelude::*;
 use futures::sink::SinkExt;
-use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 
 use tokio::net::TcpStream;
-use tokio_util::codec::{Decoder, Encoder, Framed};
+use tokio::sync::watch;
+use tokio::time::{self, Instant};
+use tokio_util::codec::{Decoder, Encoder, Framed, FramedParts};
 
-type ClientTransport<S> = Framed<S, ClientCodec>;
+type ClientTransport<S> = Framed<WriteProgress<S>, ClientCodec>;
 
 use crate::frame;
-use crate::{FromServer, Message, Result, ToServer};
+use crate::id::{uuid_v4, IdGenerator};
+use crate::layer::LayerFactory;
+use crate::pretty;
+use crate::progress::WriteProgress;
+use crate::queue::OutgoingQueue;
+use crate::redact::FrameLogger;
+use crate::{AckMode, FromServer, Message, Result, StompError, ToServer};
+
+pub use crate::accumulator::FrameAccumulator;
+pub use crate::balance::LoadBalancedSink;
+pub use crate::builder::{
+    ArtemisOptions, RoutingType, SendBuilder, SubscriptionBuilder, SubscriptionSpec,
+};
+pub use crate::credentials::{CredentialEncoding, CredentialsProvider, StaticCredentials};
+pub use crate::dialect::{BrokerDialect, BrokerFlavor, ServerCapabilities};
+pub use crate::filter::{
+    AndFilter, DestinationFilter, FilteredStream, MessageFilter, MessageFilterBuilder, NotFilter,
+    OrFilter, StompFilter, SubscriptionFilter,
+};
+pub use crate::frame::{
+    decode_all, encode_to_string, parse_heartbeat, write_frame, write_send_streaming, BytesMutSink,
+    FrameCommand, HeaderCase, HeaderOrder, LineEnding, SerializeSink,
+};
+pub use crate::id::{AtomicCounter, IdKind, SubscriptionIdGenerator, UuidGenerator};
+pub use crate::layer::CodecLayer;
+pub use crate::merge::{FairMerge, FAIR_MERGE_CAPACITY};
+#[cfg(feature = "metrics")]
+pub use crate::monitor::{FrameStats, HistogramSnapshot, StompMonitor, DEFAULT_SIZE_BUCKETS};
+pub use crate::partition::{PartitionReceiver, Partitioner, PARTITION_CHANNEL_CAPACITY};
+pub use crate::pool::{Pool, PoolConfig, PooledConnection};
+pub use crate::pretty::{pretty_frame, PRETTY_BODY_LIMIT};
+pub use crate::queue::{QueueDepth, QueueLevel};
+pub use crate::redact::{FrameDirection, DEFAULT_REDACTED_HEADERS};
+pub use crate::replay::SelectiveReplay;
+pub use crate::shared::ArcTransport;
+#[doc(hidden)] // blocks the thread, so it is only for migrating synchronous code or FFI
+pub use crate::writer::SyncStompWriter;
+
+pub mod consumer;
+pub mod failover;
+#[cfg(feature = "quic")]
+pub mod quic;
+pub mod stdio;
+#[cfg(feature = "tls")]
+pub mod tls;
+
+#[cfg(feature = "process")]
+pub use stdio::connect_stdio;
+
+/// Options used when establishing a connection
+#[derive(Debug, Clone, Default)]
+pub struct ConnectOptions {
+    credentials: StaticCredentials,
+    credentials_provider: Option<Arc<dyn CredentialsProvider>>,
+    credential_encoding: CredentialEncoding,
+    buffer: BufferConfig,
+    allow_unknown_transactions: bool,
+    allow_inapplicable_acks: bool,
+    allow_resubscribe: bool,
+    lenient_content_length: bool,
+    header_case: HeaderCase,
+    header_continuations: bool,
+    max_frame_size: Option<usize>,
+    line_ending: LineEnding,
+    header_order: HeaderOrder,
+    dialect: Option<Arc<dyn BrokerDialect>>,
+    send_timeout: Option<Duration>,
+    recv_timeout: Option<Duration>,
+    write_stall_timeout: Option<Duration>,
+    heartbeat: Option<Duration>,
+    liveness_fallback: Option<Duration>,
+    ping_strategy: PingStrategy,
+    max_pending_receipts: Option<usize>,
+    receipt_limit_policy: ReceiptLimitPolicy,
+    codec_layer: Option<LayerFactory>,
+    frame_logger: Option<FrameLogger>,
+    redacted_headers: Option<Vec<String>>,
+    orphan_policy: OrphanPolicy,
+    echo_header: Option<String>,
+    host_header: HostHeader,
+    connect_headers: Vec<(String, String)>,
+    restored_subscriptions: Vec<SubscriptionSpec>,
+    #[cfg(feature = "metrics")]
+    size_buckets: Option<Vec<usize>>,
+    #[cfg(feature = "tls")]
+    tls: tls::TlsConfig,
+}
+
+impl ConnectOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The login to authenticate with
+    pub fn login(mut self, login: impl Into<String>) -> Self {
+        self.credentials.login = Some(login.into());
+        self
+    }
+
+    /// The passcode to authenticate with
+    pub fn passcode(mut self, passcode: impl Into<String>) -> Self {
+        self.credentials.passcode = Some(passcode.into());
+        self
+    }
+
+    /// Ask `provider` for the login and passcode immediately before each CONNECT,
+    /// instead of using the fixed ones given to `login` and `passcode`. Every connection
+    /// made with these options asks again, including those made by `failover` and `Pool`
+    /// to replace failed ones, so short-lived tokens are renewed on reconnecting.
+    pub fn credentials_provider(mut self, provider: Arc<dyn CredentialsProvider>) -> Self {
+        self.credentials_provider = Some(provider);
+        self
+    }
+
+    /// How the login and passcode are written into the CONNECT frame,
+    /// `CredentialEncoding::Plain` by default
+    pub fn credential_encoding(mut self, encoding: CredentialEncoding) -> Self {
+        self.credential_encoding = encoding;
+        self
+    }
+
+    /// Tune the buffer incoming frames are decoded from
+    pub fn buffer(mut self, buffer: BufferConfig) -> Self {
+        self.buffer = buffer;
+        self
+    }
+
+    /// The `host` header to send when connecting.
+    /// By default it is derived from the address connected to.
+    pub fn host_header(mut self, host_header: HostHeader) -> Self {
+        self.host_header = host_header;
+        self
+    }
+
+    /// Name the virtual host to connect to, rather than deriving it from the address
+    pub fn virtual_host(self, host: impl Into<String>) -> Self {
+        self.host_header(HostHeader::Explicit(host.into()))
+    }
+
+    /// Add a header to the CONNECT frame, after the standard ones. This is how to pass
+    /// broker-specific login options, e.g. `client-id` for a durable session or
+    /// IBM MQ's and Solace's extensions. Headers are sent in the order they are added.
+    pub fn connect_header(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.connect_headers.push((key.into(), value.into()));
+        self
+    }
+
+    /// Don't reject frames which reference a transaction that is not in progress.
+    /// Normally such frames fail locally, rather than provoking an ERROR from the server.
+    pub fn allow_unknown_transactions(mut self, allow: bool) -> Self {
+        self.allow_unknown_transactions = allow;
+        self
+    }
+
+    /// Let `StompTransport::ack_message` and `nack_message` build frames the server
+    /// is bound to reject, e.g. to test a broker's behavior
+    pub fn allow_inapplicable_acks(mut self, allow: bool) -> Self {
+        self.allow_inapplicable_acks = allow;
+        self
+    }
+
+    /// Let a SUBSCRIBE reuse the id of an open subscription, e.g. to change its destination,
+    /// rather than failing with `StompError::SubscriptionIdInUse`
+    pub fn allow_resubscribe(mut self, allow: bool) -> Self {
+        self.allow_resubscribe = allow;
+        self
+    }
+
+    /// When a received frame's content-length disagrees with its NUL terminator, log a warning
+    /// and take the body to end at the first NUL, instead of failing with
+    /// `StompError::ContentLengthMismatch`
+    pub fn lenient_content_length(mut self, lenient: bool) -> Self {
+        self.lenient_content_length = lenient;
+        self
+    }
+
+    /// How the names of received headers are matched, `HeaderCase::Sensitive` by default.
+    /// This applies to the headers parsed into the fields of `FromServer`, to the
+    /// `content-length` header which determines where a frame's body ends, and to the
+    /// standard headers read from `extra_headers`, such as `ack`.
+    pub fn header_case(mut self, case: HeaderCase) -> Self {
+        self.header_case = case;
+        self
+    }
+
+    /// Accept header values continued onto lines starting with whitespace, as sent by some
+    /// STOMP 1.0 brokers and JMS bridges. The continuation, stripped of its leading
+    /// whitespace, is appended to the value. STOMP 1.2 has no such thing, so by default
+    /// these frames fail to parse.
+    pub fn allow_header_continuations(mut self, allow: bool) -> Self {
+        self.header_continuations = allow;
+        self
+    }
+
+    /// Fail with `StompError::FrameTooLarge` once a frame being received exceeds this many bytes,
+    /// rather than buffering it indefinitely
+    pub fn max_frame_size(mut self, bytes: usize) -> Self {
+        self.max_frame_size = Some(bytes);
+        self
+    }
+
+    /// The line ending of frames sent to the server, `LineEnding::Lf` by default
+    pub fn line_ending(mut self, line_ending: LineEnding) -> Self {
+        self.line_ending = line_ending;
+        self
+    }
+
+    /// Make `subscriptions`, e.g. exported from another connection by
+    /// `StompTransport::export_subscriptions`, straight after connecting. They are tracked
+    /// like any other subscription, and generated ids will not clash with theirs.
+    pub fn restore_subscriptions(mut self, subscriptions: Vec<SubscriptionSpec>) -> Self {
+        self.restored_subscriptions = subscriptions;
+        self
+    }
+
+    /// The order of the headers of frames sent to the server, `HeaderOrder::Declared` by default
+    pub fn header_order(mut self, order: HeaderOrder) -> Self {
+        self.header_order = order;
+        self
+    }
+
+    /// The broker being connected to, which determines how vendor specific options are expressed
+    pub fn flavor(self, flavor: BrokerFlavor) -> Self {
+        self.dialect(flavor)
+    }
+
+    /// Use a custom mapping of vendor specific options, for brokers not covered by `BrokerFlavor`
+    pub fn dialect(mut self, dialect: impl BrokerDialect + 'static) -> Self {
+        self.dialect = Some(Arc::new(dialect));
+        self
+    }
+
+    /// The timeout applied by `StompTransport::timed_send`
+    pub fn send_timeout(mut self, timeout: Duration) -> Self {
+        self.send_timeout = Some(timeout);
+        self
+    }
+
+    /// The timeout applied by `StompTransport::timed_recv`
+    pub fn recv_timeout(mut self, timeout: Duration) -> Self {
+        self.recv_timeout = Some(timeout);
+        self
+    }
+
+    /// Fail the connection with `CloseReason::WriteStalled` when frames handed to it
+    /// are waiting to be flushed, and nothing has been written for this long, e.g. because
+    /// the broker stopped reading and the socket's buffer is full. Without this, a stalled
+    /// writer is only noticed once TCP gives up, which can take many minutes. Sending then
+    /// fails with `StompError::WriteStalled`, and the stream ends.
+    /// Only frames sent via the `Sink` are covered.
+    pub fn write_stall_timeout(mut self, timeout: Duration) -> Self {
+        self.write_stall_timeout = Some(timeout);
+        self
+    }
+
+    /// Ask the server to send heart-beats at least this often. No heart-beats are offered
+    /// in return. The server may decline, or send them less often, which is logged as a
+    /// warning and reported by `StompTransport::heartbeat_negotiation`. An interval under
+    /// a millisecond requests none, and one too long for the header is capped.
+    pub fn heartbeat(mut self, incoming: Duration) -> Self {
+        self.heartbeat = Some(incoming);
+        self
+    }
+
+    /// If the server declines the heart-beats requested with `heartbeat`, or sends them far
+    /// less often than requested, fail the stream with `StompError::ServerSilent` once
+    /// nothing has been received for `max_silence`. The connection is then shut down and
+    /// the stream ends, with `CloseReason::HeartbeatTimeout`. This suits connections which
+    /// receive frames regularly; otherwise `max_silence` should allow for the quiet periods.
+    pub fn liveness_fallback(mut self, max_silence: Duration) -> Self {
+        self.liveness_fallback = Some(max_silence);
+        self
+    }
+
+    /// The frames `StompTransport::ping` sends, `PingStrategy::Transaction` by default
+    pub fn ping_strategy(mut self, strategy: PingStrategy) -> Self {
+        self.ping_strategy = strategy;
+        self
+    }
+
+    /// Limit the number of receipts requested but not yet received. Once `limit` is reached,
+    /// `StompTransport::send_with_receipt` follows the `receipt_limit_policy`. Receipts
+    /// requested by any frame count towards the limit until they arrive, including those
+    /// a `send_with_receipt` stopped waiting for. There is no limit by default.
+    pub fn max_pending_receipts(mut self, limit: usize) -> Self {
+        self.max_pending_receipts = Some(limit);
+        self
+    }
+
+    /// What happens once `max_pending_receipts` is reached, `ReceiptLimitPolicy::Wait`
+    /// by default
+    pub fn receipt_limit_policy(mut self, policy: ReceiptLimitPolicy) -> Self {
+        self.receipt_limit_policy = policy;
+        self
+    }
+
+    /// What to do with MESSAGE frames for subscriptions which are not open
+    pub fn orphan_policy(mut self, policy: OrphanPolicy) -> Self {
+        self.orphan_policy = policy;
+        self
+    }
+
+    /// Drop MESSAGE frames which this connection sent itself, e.g. when publishing to
+    /// and subscribing from the same topic. Every SEND is stamped with a `header`
+    /// (e.g. `x-sender`) naming the connection, and MESSAGEs carrying the same value
+    /// are not yielded. The broker must pass the header on to subscribers, as most do.
+    ///
+    /// Dropped messages are ACKed if their subscription uses `client-individual`
+    /// acknowledgment. Subscriptions using cumulative `client` acknowledgment are
+    /// never filtered, as an ACK would acknowledge every message before it too.
+    /// See `StompTransport::allow_echoes` and `echoes_dropped`.
+    /// Frames written with `send_raw` are not stamped.
+    pub fn suppress_echoes(mut self, header: impl Into<String>) -> Self {
+        self.echo_header = Some(header.into());
+        self
+    }
+
+    /// Pass every frame sent and received through a `CodecLayer`, e.g. to encrypt them.
+    /// `layer` is called to create a fresh layer for each connection.
+    pub fn codec_layer<L: CodecLayer + 'static>(
+        mut self,
+        layer: impl Fn() -> L + Send + Sync + 'static,
+    ) -> Self {
+        self.codec_layer = Some(LayerFactory::new(layer));
+        self
+    }
+
+    /// Pass a description of every frame sent and received to `logger`, e.g. to trace
+    /// traffic with `log::trace!`. Frames are rendered as by `pretty_frame`, with the values
+    /// of credential headers masked (see `redact_headers`) and bodies cut off after
+    /// `PRETTY_BODY_LIMIT` bytes.
+    ///
+    /// Independently of this, whenever `log` is enabled at the trace level every frame is
+    /// logged, rendered the same way.
+    pub fn log_frames(
+        mut self,
+        logger: impl Fn(FrameDirection, &str) + Send + Sync + 'static,
+    ) -> Self {
+        self.frame_logger = Some(FrameLogger::new(logger));
+        self
+    }
+
+    /// The headers whose values `log_frames` and the frames logged at trace level mask,
+    /// compared case-insensitively, replacing `DEFAULT_REDACTED_HEADERS`
+    pub fn redact_headers<H: Into<String>>(mut self, headers: impl IntoIterator<Item = H>) -> Self {
+        self.redacted_headers = Some(headers.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Upper bounds (in bytes) of the buckets frame sizes are counted in,
+    /// instead of `DEFAULT_SIZE_BUCKETS`
+    #[cfg(feature = "metrics")]
+    pub fn frame_size_buckets(mut self, bounds: Vec<usize>) -> Self {
+        self.size_buckets = Some(bounds);
+        self
+    }
+
+    /// How `tls::connect` sets up TLS, `TlsConfig::Default` by default
+    #[cfg(feature = "tls")]
+    pub fn tls_config(mut self, config: tls::TlsConfig) -> Self {
+        self.tls = config;
+        self
+    }
+
+    /// Set up TLS with `config`, e.g. to trust a private CA or present a client certificate
+    #[cfg(feature = "tls")]
+    pub fn tls(self, config: Arc<rustls::ClientConfig>) -> Self {
+        self.tls_config(tls::TlsConfig::Custom(config))
+    }
+
+    /// Verify the server's certificate with `verifier` instead of against the root
+    /// certificates, e.g. to pin a certificate or override hostname verification
+    #[cfg(feature = "tls")]
+    pub fn tls_with_verifier(self, verifier: Arc<dyn rustls::ServerCertVerifier>) -> Self {
+        self.tls_config(tls::TlsConfig::with_verifier(verifier))
+    }
+
+    fn framed<S>(&self, stream: S) -> ClientTransport<S>
+    where
+        S: AsyncRead + AsyncWrite,
+    {
+        let mut parts = FramedParts::new(WriteProgress::new(stream), self.codec());
+        parts.read_buf = BytesMut::with_capacity(self.buffer.initial_capacity);
+        Framed::from_parts(parts)
+    }
+
+    pub(crate) fn codec(&self) -> ClientCodec {
+        let mut codec = ClientCodec::with_buffer_config(self.buffer);
+        codec.lenient_content_length = self.lenient_content_length;
+        codec.header_case = self.header_case;
+        codec.header_continuations = self.header_continuations;
+        codec.max_frame_size = self.max_frame_size;
+        codec.line_ending = self.line_ending;
+        codec.header_order = self.header_order;
+        codec.layer = self.codec_layer.as_ref().map(LayerFactory::create);
+        codec.redacted_headers = self.redacted_headers.clone();
+        codec.frame_logger = self.frame_logger.clone().map(|mut logger| {
+            if let Some(headers) = &self.redacted_headers {
+                logger.redact(headers.clone());
+            }
+            logger
+        });
+        #[cfg(feature = "metrics")]
+        {
+            if let Some(bounds) = &self.size_buckets {
+                codec.stats = FrameStats::with_size_buckets(bounds.clone());
+            }
+        }
+        codec
+    }
+}
+
+/// The `host` header of the CONNECT frame, which names the virtual host to connect to
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum HostHeader {
+    /// The host connected to, without any `:port` suffix
+    #[default]
+    Derived,
+    /// The given virtual host
+    Explicit(String),
+    /// No `host` header at all. It is optional in STOMP 1.0, and many brokers don't require it.
+    Omitted,
+}
+
+impl HostHeader {
+    fn value(&self, address: String) -> Option<String> {
+        match self {
+            HostHeader::Derived => Some(strip_port(&address).to_string()),
+            HostHeader::Explicit(host) => Some(host.clone()),
+            HostHeader::Omitted => None,
+        }
+    }
+}
+
+/// Remove the `:port` suffix of an address, if it has one.
+/// Bare IPv6 addresses are left as they are, but bracketed ones lose their port.
+fn strip_port(address: &str) -> &str {
+    match address.rfind(':') {
+        Some(i) if address[i + 1..].parse::<u16>().is_ok() => {
+            let host = &address[..i];
+            if !host.contains(':') || host.ends_with(']') {
+                host
+            } else {
+                address
+            }
+        }
+        _ => address,
+    }
+}
 
 /// Connect to a STOMP server via TCP, including the connection handshake.
 /// If successful, returns a tuple of a message stream and a sender,
@@ -20,15 +477,40 @@ pub async fn connect(
     address: impl Into<String>,
     login: Option<String>,
     passcode: Option<String>,
-) -> Result<
-    impl Stream<Item = Result<Message<FromServer>>> + Sink<Message<ToServer>, Error = failure::Error>,
-> {
+) -> Result<StompTransport<TcpStream>> {
+    let options = ConnectOptions {
+        credentials: StaticCredentials { login, passcode },
+        ..Default::default()
+    };
+    connect_with_options(address, options).await
+}
+
+/// Connect to a STOMP server via TCP using the given options,
+/// including the connection handshake.
+pub async fn connect_with_options(
+    address: impl Into<String>,
+    options: ConnectOptions,
+) -> Result<StompTransport<TcpStream>> {
     let address = address.into();
     let addr = address.as_str().to_socket_addrs().unwrap().next().unwrap();
     let tcp = TcpStream::connect(&addr).await?;
-    let mut transport = ClientCodec.framed(tcp);
-    client_handshake(&mut transport, address, login, passcode).await?;
-    Ok(transport)
+    connect_stream_with_options(tcp, address, options).await
+}
+
+/// The receiving half of a connection split by `connect_split`
+pub type StompStream = stream::SplitStream<StompTransport<TcpStream>>;
+
+/// The sending half of a connection split by `connect_split`
+pub type StompSink = stream::SplitSink<StompTransport<TcpStream>, Message<ToServer>>;
+
+/// Connect to a STOMP server via TCP using the given options, and split the connection
+/// into halves which can be moved to separate tasks to receive and send messages
+pub async fn connect_split(
+    address: impl Into<String>,
+    options: ConnectOptions,
+) -> Result<(StompStream, StompSink)> {
+    let (sink, stream) = connect_with_options(address, options).await?.split();
+    Ok((stream, sink))
 }
 
 /// Connect to a STOMP server via TCP, including the connection handshake.
@@ -39,80 +521,4987 @@ pub async fn connect_stream<S>(
     host: String,
     login: Option<String>,
     passcode: Option<String>,
-) -> Result<
-    impl Stream<Item = Result<Message<FromServer>>> + Sink<Message<ToServer>, Error = failure::Error>,
-> where S: AsyncRead + AsyncWrite + Sized + Unpin {
-    let mut transport = ClientCodec.framed(stream);
-    client_handshake(&mut transport, host, login, passcode).await?;
+) -> Result<StompTransport<S>>
+where
+    S: AsyncRead + AsyncWrite + Sized + Unpin,
+{
+    let options = ConnectOptions {
+        credentials: StaticCredentials { login, passcode },
+        ..Default::default()
+    };
+    connect_stream_with_options(stream, host, options).await
+}
+
+/// Perform the connection handshake over an existing stream using the given options.
+/// Unless `ConnectOptions::host_header` says otherwise, the `host` header is derived from `host`.
+pub async fn connect_stream_with_options<S>(
+    stream: S,
+    host: String,
+    options: ConnectOptions,
+) -> Result<StompTransport<S>>
+where
+    S: AsyncRead + AsyncWrite + Sized + Unpin,
+{
+    let mut transport = options.framed(stream);
+    let details = client_handshake(&mut transport, host, &options).await?;
+    let mut transport = StompTransport::new(transport, &options);
+    transport.connected(details);
+    if !options.restored_subscriptions.is_empty() {
+        for subscription in &options.restored_subscriptions {
+            transport.ids.skip_past(&subscription.id);
+            transport.feed(subscription.clone().into()).await?;
+        }
+        transport.flush().await?;
+    }
     Ok(transport)
 }
 
 async fn client_handshake<S>(
     transport: &mut ClientTransport<S>,
     host: String,
-    login: Option<String>,
-    passcode: Option<String>,
-) -> Result<()>
+    options: &ConnectOptions,
+) -> Result<ConnectionDetails>
 where
     S: AsyncRead + AsyncWrite + Sized + Unpin,
 {
+    let provider: &dyn CredentialsProvider = match &options.credentials_provider {
+        Some(provider) => &**provider,
+        None => &options.credentials,
+    };
+    let (login, passcode) = provider.credentials().await?;
+    let encode = |value: String| options.credential_encoding.encode(&value);
     let connect = Message {
         content: ToServer::Connect {
             accept_version: "1.2".into(),
-            host,
-            login,
-            passcode,
-            heartbeat: None,
+            host: options.host_header.value(host),
+            login: login.map(encode),
+            passcode: passcode.map(encode),
+            heartbeat: options
+                .heartbeat
+                .map(|incoming| u32::try_from(incoming.as_millis()).unwrap_or(u32::MAX))
+                .filter(|&incoming| incoming > 0)
+                .map(|incoming| (0, incoming)),
         },
-        extra_headers: vec![],
+        extra_headers: options
+            .connect_headers
+            .iter()
+            .map(|(key, value)| (key.clone().into_bytes(), value.clone().into_bytes()))
+            .collect(),
     };
     // Send the message
     transport.send(connect).await?;
     // Receive reply
     let msg = transport.next().await.transpose()?;
-    if let Some(FromServer::Connected { .. }) = msg.as_ref().map(|m| &m.content) {
-        Ok(())
-    } else {
-        Err(failure::format_err!("unexpected reply: {:?}", msg))
+    match msg {
+        Some(Message {
+            content:
+                FromServer::Connected {
+                    version,
+                    session,
+                    server,
+                    heartbeat,
+                },
+            extra_headers,
+        }) => Ok(ConnectionDetails {
+            version,
+            session,
+            server,
+            heartbeat,
+            extra_headers,
+        }),
+        // describe the reply without its headers or body, in case it echoes the credentials
+        Some(Message {
+            content: FromServer::Error { message, .. },
+            ..
+        }) => Err(failure::format_err!(
+            "connection rejected: {}",
+            message.unwrap_or_default()
+        )),
+        Some(Message { content, .. }) => {
+            let command = match content {
+                FromServer::Connected { .. } => "CONNECTED",
+                FromServer::Message { .. } => "MESSAGE",
+                FromServer::Receipt { .. } => "RECEIPT",
+                FromServer::Error { .. } => "ERROR",
+            };
+            Err(failure::format_err!(
+                "unexpected reply to CONNECT: {}",
+                command
+            ))
+        }
+        None => Err(failure::format_err!("connection closed during handshake")),
     }
 }
 
-/// Convenience function to build a Subscribe message
-pub fn subscribe(dest: impl Into<String>, id: impl Into<String>) -> Message<ToServer> {
-    ToServer::Subscribe {
-        destination: dest.into(),
-        id: id.into(),
-        ack: None,
+/// What the server reported about itself in its CONNECTED frame
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionDetails {
+    pub version: String,
+    pub session: Option<String>,
+    pub server: Option<String>,
+    pub heartbeat: Option<String>,
+    /// Any other headers of the CONNECTED frame, e.g. vendor specific ones
+    pub extra_headers: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl ConnectionDetails {
+    /// What the broker advertised about itself: its name and version from the `server` header,
+    /// and the CONNECTED frame's extra headers
+    pub fn capabilities(&self) -> ServerCapabilities {
+        let server = self.server.as_deref().unwrap_or_default();
+        ServerCapabilities {
+            extras: self.extra_headers.clone(),
+            ..ServerCapabilities::from_server_header(server)
+        }
+    }
+
+    /// Guess which broker this is from its advertised name or the `session` header,
+    /// returning `BrokerFlavor::Generic` if unsure.
+    /// When no flavor or dialect is set in `ConnectOptions`, the connection uses this guess.
+    pub fn detect_flavor(&self) -> BrokerFlavor {
+        let capabilities = self.capabilities();
+        let name = capabilities.name.as_deref().unwrap_or_default();
+        let session = self.session.as_deref().unwrap_or_default();
+        if name.starts_with("ActiveMQ-Artemis") {
+            BrokerFlavor::Artemis
+        } else if name.starts_with("ActiveMQ") {
+            BrokerFlavor::ActiveMQ
+        } else if name.starts_with("RabbitMQ") {
+            BrokerFlavor::RabbitMQ
+        } else if !name.is_empty() {
+            BrokerFlavor::Generic
+        // the server header may be hidden, but session ids have recognizable formats
+        } else if session.starts_with("session-") {
+            BrokerFlavor::RabbitMQ
+        } else if session.starts_with("ID:") {
+            BrokerFlavor::ActiveMQ
+        } else {
+            BrokerFlavor::Generic
+        }
     }
-    .into()
 }
 
-struct ClientCodec;
+/// How many finished transactions are remembered, to reject a BEGIN which reuses one's id
+const RECENT_TRANSACTIONS: usize = 256;
 
-impl Decoder for ClientCodec {
-    type Item = Message<FromServer>;
-    type Error = failure::Error;
+/// How many closed subscriptions are remembered, to tell whether their orphaned messages
+/// can be NACKed
+const RECENT_SUBSCRIPTIONS: usize = 256;
 
-    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
-        let (item, offset) = match frame::parse_frame(&src) {
-            Ok((remain, frame)) => (
-                Message::<FromServer>::from_frame(frame),
-                remain.as_ptr() as usize - src.as_ptr() as usize,
-            ),
-            Err(nom::Err::Incomplete(_)) => return Ok(None),
-            Err(e) => failure::bail!("Parse failed: {:?}", e),
+type BrokerErrorCallback = Box<dyn Fn(Option<String>, Option<Vec<u8>>) + Send>;
+type ExpiredCallback = Box<dyn Fn(&Message<ToServer>) + Send>;
+type DisconnectCallback = Box<dyn FnOnce(DisconnectReason) + Send>;
+type Headers = Vec<(Vec<u8>, Vec<u8>)>;
+type AckDecorator = Box<dyn Fn(&Message<FromServer>) -> Vec<(String, String)> + Send>;
+
+/// How an outgoing frame changes the state a `StompTransport` keeps track of
+struct Outgoing {
+    change: StateChange,
+    /// The receipt the frame requests, which becomes pending
+    receipt: Option<String>,
+}
+
+enum StateChange {
+    Subscribe(SubscriptionSpec),
+    Unsubscribe(String),
+    Disconnect,
+    Begin(String),
+    /// A COMMIT or ABORT
+    Finish(String),
+    None,
+}
+
+/// Why a connection's stream of messages ended
+#[derive(Debug)]
+pub enum DisconnectReason {
+    /// The server closed the connection
+    Graceful,
+    /// The server closed the connection after sending an ERROR frame with this message
+    BrokerError(String),
+    IoError(io::Error),
+    /// Reading from the connection timed out, or writing to it stalled
+    Timeout,
+    /// Data was received which could not be decoded as a STOMP frame
+    ProtocolError(String),
+}
+
+impl Clone for DisconnectReason {
+    fn clone(&self) -> Self {
+        match self {
+            DisconnectReason::Graceful => DisconnectReason::Graceful,
+            DisconnectReason::BrokerError(message) => {
+                DisconnectReason::BrokerError(message.clone())
+            }
+            DisconnectReason::IoError(err) => {
+                DisconnectReason::IoError(io::Error::new(err.kind(), err.to_string()))
+            }
+            DisconnectReason::Timeout => DisconnectReason::Timeout,
+            DisconnectReason::ProtocolError(message) => {
+                DisconnectReason::ProtocolError(message.clone())
+            }
+        }
+    }
+}
+
+/// Why a connection ended. Each way the stream of messages can end maps to exactly one reason:
+/// an ERROR frame from the server takes precedence, then a DISCONNECT sent by the client.
+#[derive(Debug, Clone)]
+pub enum CloseReason {
+    /// The client sent a DISCONNECT frame
+    LocalDisconnect,
+    /// The server sent this ERROR frame before the connection ended
+    RemoteError(FromServer),
+    /// Reading from the connection timed out, e.g. because the server stopped sending heart-beats
+    HeartbeatTimeout,
+    /// Reading from the connection failed
+    IoError(io::ErrorKind),
+    /// The server closed the connection
+    RemoteEof,
+    /// Data was received which could not be decoded as a STOMP frame
+    ProtocolError(String),
+    /// No frames could be written for longer than `ConnectOptions::write_stall_timeout`
+    WriteStalled,
+}
+
+/// How far the server may stretch the requested heart-beat interval before it is reported
+/// as `HeartbeatNegotiation::Inflated`
+const HEARTBEAT_INFLATION_LIMIT: u32 = 2;
+
+/// How the server answered the heart-beats requested with `ConnectOptions::heartbeat`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeartbeatNegotiation {
+    /// No heart-beats were requested
+    NotRequested,
+    /// The server sends heart-beats at this interval, close to the one requested
+    Agreed(Duration),
+    /// The server sends no heart-beats, e.g. because it replied with `heart-beat:0,0`
+    Declined,
+    /// The server sends heart-beats, but more than twice as far apart as requested
+    Inflated {
+        requested: Duration,
+        agreed: Duration,
+    },
+}
+
+impl HeartbeatNegotiation {
+    /// Work out the interval of the server's heart-beats from the CONNECTED frame's
+    /// `heart-beat` header, as the larger of the two sides' intervals
+    fn negotiate(requested: Option<Duration>, header: Option<&str>) -> Self {
+        let requested = match requested {
+            Some(requested) if requested.as_millis() > 0 => requested,
+            _ => return HeartbeatNegotiation::NotRequested,
         };
-        src.advance(offset);
-        item.map(Some)
+        // a missing or malformed header is the same as `0,0`
+        let offered = header
+            .and_then(|header| parse_heartbeat(header).ok())
+            .map_or(0, |(outgoing, _)| outgoing);
+        if offered == 0 {
+            return HeartbeatNegotiation::Declined;
+        }
+        let agreed = requested.max(Duration::from_millis(offered.into()));
+        if agreed > requested * HEARTBEAT_INFLATION_LIMIT {
+            HeartbeatNegotiation::Inflated { requested, agreed }
+        } else {
+            HeartbeatNegotiation::Agreed(agreed)
+        }
+    }
+
+    /// Whether heart-beats were requested, but won't reveal a dead connection in time
+    pub fn is_degraded(&self) -> bool {
+        matches!(
+            self,
+            HeartbeatNegotiation::Declined | HeartbeatNegotiation::Inflated { .. }
+        )
     }
 }
 
-impl Encoder for ClientCodec {
-    type Item = Message<ToServer>;
-    type Error = failure::Error;
+/// How `StompTransport::ping` checks that the broker is responsive.
+/// Brokers differ in which of these is cheapest.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum PingStrategy {
+    /// Begin a transaction and abort it straight away, requesting a receipt for the ABORT
+    #[default]
+    Transaction,
+    /// Send an empty message to `destination`, requesting a receipt
+    Send { destination: String },
+}
 
-    fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<()> {
-        item.to_frame().serialize(dst);
-        Ok(())
+/// What `StompTransport::send_with_receipt` does while `ConnectOptions::max_pending_receipts`
+/// receipts are outstanding
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReceiptLimitPolicy {
+    /// Receive frames until a receipt arrives, holding the others to be yielded from
+    /// the stream, so that a producer is held back while the broker is slow to answer
+    #[default]
+    Wait,
+    /// Fail with `StompError::TooManyPendingReceipts` straight away
+    Fail,
+}
+
+/// How `StompTransport` handles a MESSAGE frame for a subscription which is not open,
+/// e.g. one the server sent before it processed an UNSUBSCRIBE
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OrphanPolicy {
+    /// Log a warning and yield the message like any other
+    #[default]
+    Deliver,
+    /// Send a NACK for the message, so the broker can redeliver it elsewhere, and skip it.
+    /// Messages for subscriptions which acknowledged them automatically, or which this
+    /// connection doesn't remember opening, can't be NACKed and are only skipped.
+    Nack,
+    /// Yield `StompError::UnknownSubscription` in place of the message
+    Error,
+}
+
+/// An established STOMP connection.
+/// Implements `Stream` to receive messages from the server
+/// and `Sink` to send messages to it.
+///
+/// Each frame is encoded whole by `start_send`, which requires exclusive access
+/// to the transport, so frames can never be interleaved on the wire.
+/// To send from several tasks, funnel their messages through a channel
+/// into the single task which owns the transport (or its `split` sink half).
+/// If a frame is only partly written, because a `send_ref` or `send_raw` future was
+/// dropped, sending anything more fails with `StompError::TornFrame`.
+///
+/// There is no background task reading from the connection. Methods which wait for a
+/// receipt (`send_with_receipt`, `ping`, `subscribe_confirmed`, `migrate`) read from it
+/// themselves until the receipt arrives, holding other frames for the stream, so they
+/// complete even if nothing else polls the stream.
+///
+/// Closing the sink (`SinkExt::close`) flushes the queued frames and shuts down the
+/// writing half of the connection. From then on every frame sent is rejected with
+/// `StompError::Closing`, but the stream keeps yielding the frames the server sends,
+/// including any already buffered, until the server closes its side of the connection.
+///
+/// The transport is `Send` when `S` is, so it can be moved into a spawned task,
+/// but it is not `Sync`: the callbacks it stores are only required to be `Send`.
+pub struct StompTransport<S> {
+    inner: ClientTransport<S>,
+    ids: IdGenerator,
+    transactions: HashSet<String>,
+    /// The ids of recently committed or aborted transactions, oldest first
+    finished_transactions: VecDeque<String>,
+    allow_unknown_transactions: bool,
+    /// The open subscriptions, by id
+    subscriptions: HashMap<String, SubscriptionSpec>,
+    /// The acknowledgment modes of subscriptions closed by `drain_and_disconnect`, whose
+    /// messages may still arrive until the broker confirms the UNSUBSCRIBE
+    draining: HashMap<String, AckMode>,
+    /// The ids and acknowledgment modes of recently closed subscriptions, oldest first
+    closed_subscriptions: VecDeque<(String, AckMode)>,
+    ack_decorators: HashMap<String, AckDecorator>,
+    allow_inapplicable_acks: bool,
+    allow_resubscribe: bool,
+    dialect: Arc<dyn BrokerDialect>,
+    error_callbacks: Vec<BrokerErrorCallback>,
+    expired_callbacks: Vec<ExpiredCallback>,
+    expired_messages: u64,
+    disconnect_callbacks: Vec<DisconnectCallback>,
+    /// The last ERROR frame received
+    broker_error: Option<FromServer>,
+    disconnect_sent: bool,
+    /// Whether the sink has been closed, after which nothing more may be sent
+    closing: bool,
+    close_reason: Option<CloseReason>,
+    send_timeout: Option<Duration>,
+    recv_timeout: Option<Duration>,
+    write_stall_timeout: Option<Duration>,
+    /// Fires when the frames waiting to be flushed have waited for `write_stall_timeout`
+    stall_timer: Option<time::Delay>,
+    ping_strategy: PingStrategy,
+    /// Frames received while waiting for a ping's receipt, yet to be yielded from the stream
+    held: VecDeque<Message<FromServer>>,
+    /// Reused by `send_ref` to serialize borrowed messages
+    write_buf: BytesMut,
+    /// Set while `write_buf` is being written straight to the connection, and left set if
+    /// that was abandoned part way, in which case no other frame may follow
+    partial_write: bool,
+    queue: OutgoingQueue,
+    details: ConnectionDetails,
+    connected_at: Instant,
+    dialect_is_explicit: bool,
+    orphan_policy: OrphanPolicy,
+    orphaned_messages: u64,
+    /// The header SENDs are stamped with, and this connection's value for it
+    echo_stamp: Option<Headers>,
+    /// Subscriptions which receive this connection's own messages regardless
+    echo_exempt: HashSet<String>,
+    echoes_dropped: u64,
+    /// NACKs for orphaned or throttled messages, and ACKs for dropped echoes,
+    /// which are yet to be handed to the writer
+    pending_nacks: VecDeque<Message<ToServer>>,
+    flushing_nacks: bool,
+    /// The ids of the receipts requested but not yet received
+    pending_receipts: HashSet<String>,
+    receipts_high_water: usize,
+    max_pending_receipts: Option<usize>,
+    receipt_limit_policy: ReceiptLimitPolicy,
+    requested_heartbeat: Option<Duration>,
+    heartbeat_negotiation: HeartbeatNegotiation,
+    liveness_fallback: Option<Duration>,
+    /// Fires when nothing has been received for `liveness_fallback`
+    liveness_timer: Option<time::Delay>,
+}
+
+impl<S> StompTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn new(inner: ClientTransport<S>, options: &ConnectOptions) -> Self {
+        let mut transport = StompTransport {
+            inner,
+            ids: IdGenerator::new(),
+            transactions: HashSet::new(),
+            finished_transactions: VecDeque::new(),
+            allow_unknown_transactions: options.allow_unknown_transactions,
+            subscriptions: HashMap::new(),
+            draining: HashMap::new(),
+            closed_subscriptions: VecDeque::new(),
+            ack_decorators: HashMap::new(),
+            allow_inapplicable_acks: options.allow_inapplicable_acks,
+            allow_resubscribe: options.allow_resubscribe,
+            dialect: options
+                .dialect
+                .clone()
+                .unwrap_or_else(|| Arc::new(BrokerFlavor::Generic)),
+            error_callbacks: vec![],
+            expired_callbacks: vec![],
+            expired_messages: 0,
+            disconnect_callbacks: vec![],
+            broker_error: None,
+            disconnect_sent: false,
+            closing: false,
+            close_reason: None,
+            send_timeout: options.send_timeout,
+            recv_timeout: options.recv_timeout,
+            write_stall_timeout: options.write_stall_timeout,
+            stall_timer: None,
+            ping_strategy: options.ping_strategy.clone(),
+            held: VecDeque::new(),
+            write_buf: BytesMut::new(),
+            partial_write: false,
+            queue: OutgoingQueue::default(),
+            details: ConnectionDetails::default(),
+            connected_at: Instant::now(),
+            dialect_is_explicit: options.dialect.is_some(),
+            orphan_policy: options.orphan_policy,
+            orphaned_messages: 0,
+            echo_stamp: options
+                .echo_header
+                .as_ref()
+                .map(|header| vec![(header.clone().into_bytes(), uuid_v4().into_bytes())]),
+            echo_exempt: HashSet::new(),
+            echoes_dropped: 0,
+            pending_nacks: VecDeque::new(),
+            flushing_nacks: false,
+            pending_receipts: HashSet::new(),
+            receipts_high_water: 0,
+            max_pending_receipts: options.max_pending_receipts,
+            receipt_limit_policy: options.receipt_limit_policy,
+            requested_heartbeat: options.heartbeat,
+            heartbeat_negotiation: HeartbeatNegotiation::NotRequested,
+            liveness_fallback: options.liveness_fallback,
+            liveness_timer: None,
+        };
+        transport.receipts_changed();
+        transport
+    }
+
+    /// Record the outcome of the handshake, detecting the broker's dialect unless one was given
+    fn connected(&mut self, details: ConnectionDetails) {
+        if !self.dialect_is_explicit {
+            self.dialect = Arc::new(details.detect_flavor());
+        }
+        self.heartbeat_negotiation =
+            HeartbeatNegotiation::negotiate(self.requested_heartbeat, details.heartbeat.as_deref());
+        if self.heartbeat_negotiation.is_degraded() {
+            log::warn!(
+                "server didn't agree to the requested heart-beats: {:?}",
+                self.heartbeat_negotiation
+            );
+        }
+        self.details = details;
+        self.connected_at = Instant::now();
+    }
+
+    /// Work out how an outgoing frame changes the state this transport keeps track of,
+    /// rejecting frames which are invalid in that state. The change is only applied, with
+    /// `record_outgoing`, once the frame has been handed to the connection.
+    fn check_outgoing(&self, msg: &Message<ToServer>) -> Result<Outgoing> {
+        if self.closing {
+            return Err(StompError::Closing.into());
+        }
+        let change = match &msg.content {
+            ToServer::Subscribe {
+                destination,
+                id,
+                ack,
+            } => {
+                if !self.allow_resubscribe && self.subscriptions.contains_key(id) {
+                    return Err(StompError::SubscriptionIdInUse(id.clone()).into());
+                }
+                if let Some(other) = self.subscription_to(destination, id) {
+                    log::warn!(
+                        "subscriptions '{}' and '{}' are both to '{}'",
+                        other,
+                        id,
+                        destination
+                    );
+                }
+                let headers = msg
+                    .extra_headers
+                    .iter()
+                    .filter(|(key, _)| key != b"receipt")
+                    .map(|(key, value)| {
+                        (
+                            String::from_utf8_lossy(key).into_owned(),
+                            String::from_utf8_lossy(value).into_owned(),
+                        )
+                    })
+                    .collect();
+                StateChange::Subscribe(SubscriptionSpec {
+                    destination: destination.clone(),
+                    id: id.clone(),
+                    ack: ack.unwrap_or(AckMode::Auto),
+                    headers,
+                })
+            }
+            ToServer::Unsubscribe { id } => StateChange::Unsubscribe(id.clone()),
+            ToServer::Disconnect { .. } => StateChange::Disconnect,
+            ToServer::Begin { transaction } => {
+                if self.transactions.contains(transaction)
+                    || self.finished_transactions.contains(transaction)
+                {
+                    return Err(StompError::TransactionIdInUse(transaction.clone()).into());
+                }
+                StateChange::Begin(transaction.clone())
+            }
+            ToServer::Commit { transaction } | ToServer::Abort { transaction } => {
+                StateChange::Finish(transaction.clone())
+            }
+            ToServer::Send {
+                transaction: Some(transaction),
+                ..
+            }
+            | ToServer::Ack {
+                transaction: Some(transaction),
+                ..
+            }
+            | ToServer::Nack {
+                transaction: Some(transaction),
+                ..
+            } => {
+                if !self.allow_unknown_transactions && !self.transactions.contains(transaction) {
+                    return Err(StompError::UnknownTransaction(transaction.clone()).into());
+                }
+                StateChange::None
+            }
+            _ => StateChange::None,
+        };
+        let receipt = match &msg.content {
+            ToServer::Disconnect {
+                receipt: Some(receipt),
+                ..
+            } => Some(receipt.clone()),
+            _ => msg
+                .extra_headers
+                .iter()
+                .find(|(key, _)| key == b"receipt")
+                .map(|(_, value)| String::from_utf8_lossy(value).into_owned()),
+        };
+        Ok(Outgoing { change, receipt })
+    }
+
+    /// Apply the changes worked out by `check_outgoing`, now that the frame has been sent
+    fn record_outgoing(&mut self, outgoing: Outgoing) {
+        match outgoing.change {
+            StateChange::Subscribe(spec) => {
+                self.subscriptions.insert(spec.id.clone(), spec);
+            }
+            StateChange::Unsubscribe(id) => {
+                if let Some(spec) = self.subscriptions.remove(&id) {
+                    if self.closed_subscriptions.len() == RECENT_SUBSCRIPTIONS {
+                        self.closed_subscriptions.pop_front();
+                    }
+                    self.closed_subscriptions.push_back((id.clone(), spec.ack));
+                }
+                self.ack_decorators.remove(&id);
+            }
+            StateChange::Disconnect => self.disconnect_sent = true,
+            StateChange::Begin(transaction) => {
+                self.transactions.insert(transaction);
+            }
+            StateChange::Finish(transaction) => {
+                if self.transactions.remove(&transaction) {
+                    if self.finished_transactions.len() == RECENT_TRANSACTIONS {
+                        self.finished_transactions.pop_front();
+                    }
+                    self.finished_transactions.push_back(transaction);
+                }
+            }
+            StateChange::None => {}
+        }
+        // count the receipt requested by the frame as pending
+        if let Some(receipt) = outgoing.receipt {
+            self.pending_receipts.insert(receipt);
+            self.receipts_changed();
+        }
+    }
+
+    /// Update the high-water mark and, with the `metrics` feature, the stats, after
+    /// `pending_receipts` changed
+    fn receipts_changed(&mut self) {
+        let pending = self.pending_receipts.len();
+        self.receipts_high_water = self.receipts_high_water.max(pending);
+        #[cfg(feature = "metrics")]
+        self.inner.codec_mut().stats.record_receipts(
+            pending,
+            self.receipts_high_water,
+            self.max_pending_receipts,
+        );
+    }
+
+    /// Stop counting `receipt` as pending, once nothing is waiting for it any more
+    fn forget_receipt(&mut self, receipt: &str) {
+        if self.pending_receipts.remove(receipt) {
+            self.receipts_changed();
+        }
+    }
+
+    /// The number of receipts requested but not yet received
+    pub fn pending_receipts(&self) -> usize {
+        self.pending_receipts.len()
+    }
+
+    /// The id of an open subscription to `destination`, other than `id`
+    fn subscription_to(&self, destination: &str, id: &str) -> Option<&str> {
+        self.subscriptions
+            .values()
+            .find(|other| other.id != id && other.destination == destination)
+            .map(|other| &other.id[..])
+    }
+
+    /// The acknowledgment mode of the open subscription `id`
+    fn ack_mode(&self, id: &str) -> Option<AckMode> {
+        self.subscriptions
+            .get(id)
+            .map(|subscription| subscription.ack)
+            .or_else(|| self.draining.get(id).copied())
+    }
+
+    /// The open subscriptions, in no particular order. A subscription is open from when its
+    /// SUBSCRIBE frame is sent until its UNSUBSCRIBE is, whether or not the server has
+    /// confirmed either; its headers are those of the SUBSCRIBE frame, other than `receipt`.
+    pub fn iter_subscriptions(&self) -> impl Iterator<Item = &SubscriptionSpec> {
+        self.subscriptions.values()
+    }
+
+    /// The open subscriptions, ordered by id, so that another connection (perhaps in another
+    /// process) can take them over with `ConnectOptions::restore_subscriptions`
+    pub fn export_subscriptions(&self) -> Vec<SubscriptionSpec> {
+        let mut subscriptions: Vec<_> = self.iter_subscriptions().cloned().collect();
+        subscriptions.sort_by(|a, b| a.id.cmp(&b.id));
+        subscriptions
+    }
+
+    /// The ids of the transactions which have begun but not been committed or aborted,
+    /// in no particular order
+    pub fn open_transactions(&self) -> impl Iterator<Item = &str> {
+        self.transactions.iter().map(String::as_str)
+    }
+
+    /// Build the frame acknowledging `msg`, a MESSAGE received on this connection.
+    /// Fails with `StompError::AckNotApplicable` if its subscription uses `AckMode::Auto`.
+    pub fn ack_message(&self, msg: &Message<FromServer>) -> Result<Message<ToServer>> {
+        let (id, subscription) = self.ack_target(msg)?;
+        let ack = Message::new(ToServer::Ack {
+            id,
+            transaction: None,
+        });
+        Ok(self.decorate_ack(ack.headers(subscription), msg))
+    }
+
+    /// Build the frame rejecting `msg`, a MESSAGE received on this connection.
+    /// Fails with `StompError::AckNotApplicable` if its subscription uses `AckMode::Auto`,
+    /// or if the server only speaks STOMP 1.0, which has no NACK.
+    pub fn nack_message(&self, msg: &Message<FromServer>) -> Result<Message<ToServer>> {
+        if !self.allow_inapplicable_acks && self.details.version == "1.0" {
+            let reason = "STOMP 1.0 has no NACK".to_string();
+            return Err(StompError::AckNotApplicable(reason).into());
+        }
+        let (id, subscription) = self.ack_target(msg)?;
+        let nack = Message::new(ToServer::Nack {
+            id,
+            transaction: None,
+        });
+        Ok(self.decorate_ack(nack.headers(subscription), msg))
+    }
+
+    /// Add headers to every ACK and NACK built by `ack_message` and `nack_message` for
+    /// messages from `subscription`, e.g. correlation headers for auditing.
+    /// `decorator` is called with the message being acknowledged, and is dropped
+    /// when the subscription is unsubscribed.
+    pub fn decorate_acks(
+        &mut self,
+        subscription: impl Into<String>,
+        decorator: impl Fn(&Message<FromServer>) -> Vec<(String, String)> + Send + 'static,
+    ) -> &mut Self {
+        self.ack_decorators
+            .insert(subscription.into(), Box::new(decorator));
+        self
+    }
+
+    fn decorate_ack(&self, ack: Message<ToServer>, msg: &Message<FromServer>) -> Message<ToServer> {
+        let decorator = match &msg.content {
+            FromServer::Message { subscription, .. } => self.ack_decorators.get(subscription),
+            _ => None,
+        };
+        match decorator {
+            Some(decorator) => ack.headers(decorator(msg)),
+            None => ack,
+        }
+    }
+
+    /// The id to acknowledge `msg` by, and the `subscription` header needed
+    /// when the server did not provide an `ack` header (before STOMP 1.2)
+    fn ack_target(
+        &self,
+        msg: &Message<FromServer>,
+    ) -> Result<(String, Option<(&'static str, String)>)> {
+        let (message_id, subscription) = match &msg.content {
+            FromServer::Message {
+                message_id,
+                subscription,
+                ..
+            } => (message_id, subscription),
+            other => failure::bail!("only MESSAGE frames can be acknowledged, not {:?}", other),
+        };
+        if !self.allow_inapplicable_acks {
+            if let Some(AckMode::Auto) = self.ack_mode(subscription) {
+                let reason = format!("subscription '{}' uses auto acknowledgment", subscription);
+                return Err(StompError::AckNotApplicable(reason).into());
+            }
+        }
+        let ack = msg
+            .extra_headers
+            .iter()
+            .find(|(key, _)| key == b"ack")
+            .map(|(_, value)| String::from_utf8_lossy(value).into_owned());
+        Ok(match ack {
+            Some(ack) => (ack, None),
+            None => (
+                message_id.clone(),
+                Some(("subscription", subscription.clone())),
+            ),
+        })
+    }
+
+    /// The dialect of the broker this connection is to
+    pub fn dialect(&self) -> &dyn BrokerDialect {
+        &*self.dialect
+    }
+
+    /// Totals of the traffic sent and received on this connection
+    #[cfg(feature = "metrics")]
+    pub fn stats(&self) -> &FrameStats {
+        self.inner.codec().stats()
+    }
+
+    /// Clear the frame size histograms and command counts of this connection
+    #[cfg(feature = "metrics")]
+    pub fn reset_stats(&mut self) {
+        self.inner.codec_mut().stats.reset();
+    }
+
+    /// Yield at most `max_per_second` messages per second, see `ThrottledTransport`
+    ///
+    /// # Panics
+    ///
+    /// If `max_per_second` is not positive and finite
+    pub fn throttle(self, max_per_second: f64) -> ThrottledTransport<S> {
+        assert!(
+            max_per_second > 0.0 && max_per_second.is_finite(),
+            "invalid message rate: {}",
+            max_per_second
+        );
+        ThrottledTransport {
+            transport: self,
+            interval: Duration::from_secs_f64(1.0 / max_per_second),
+            next_slot: Instant::now(),
+            delayed: None,
+            nacked: 0,
+        }
+    }
+
+    /// The number of MESSAGE frames received for subscriptions which were not open
+    pub fn orphaned_messages(&self) -> u64 {
+        self.orphaned_messages
+    }
+
+    /// Apply the orphan policy to a received message.
+    /// Returns `None` if the message was handled, and should not be yielded.
+    fn handle_orphan(&mut self, msg: Message<FromServer>) -> Option<Result<Message<FromServer>>> {
+        let subscription = match &msg.content {
+            FromServer::Message { subscription, .. }
+                if !self.subscriptions.contains_key(subscription)
+                    && !self.draining.contains_key(subscription) =>
+            {
+                subscription.clone()
+            }
+            _ => return Some(Ok(msg)),
+        };
+        self.orphaned_messages += 1;
+        match self.orphan_policy {
+            OrphanPolicy::Deliver => {
+                log::warn!(
+                    "received message for unknown subscription '{}'",
+                    subscription
+                );
+                Some(Ok(msg))
+            }
+            OrphanPolicy::Nack => {
+                let closed = self
+                    .closed_subscriptions
+                    .iter()
+                    .rev()
+                    .find(|(id, _)| *id == subscription);
+                match closed {
+                    Some((_, AckMode::Client)) | Some((_, AckMode::ClientIndividual)) => {
+                        match self.nack_message(&msg) {
+                            Ok(nack) => {
+                                self.pending_nacks.push_back(nack);
+                                None
+                            }
+                            Err(e) => Some(Err(e)),
+                        }
+                    }
+                    // the message was acknowledged automatically, or may have been,
+                    // so a NACK could be invalid
+                    _ => {
+                        log::warn!(
+                            "skipping message for unknown subscription '{}'",
+                            subscription
+                        );
+                        None
+                    }
+                }
+            }
+            OrphanPolicy::Error => Some(Err(StompError::UnknownSubscription(subscription).into())),
+        }
+    }
+
+    /// Receive this connection's own messages on `subscription`,
+    /// though echoes are otherwise suppressed (see `ConnectOptions::suppress_echoes`)
+    pub fn allow_echoes(&mut self, subscription: impl Into<String>) -> &mut Self {
+        self.echo_exempt.insert(subscription.into());
+        self
+    }
+
+    /// The number of MESSAGE frames dropped because this connection sent them
+    pub fn echoes_dropped(&self) -> u64 {
+        self.echoes_dropped
+    }
+
+    /// Whether `msg` is an echo of a SEND from this connection which should be dropped
+    fn is_echo(&self, msg: &Message<FromServer>) -> bool {
+        let stamp = match &self.echo_stamp {
+            Some(stamp) => &stamp[0],
+            None => return false,
+        };
+        let subscription = match &msg.content {
+            FromServer::Message { subscription, .. } => subscription,
+            _ => return false,
+        };
+        let filtered = match self.ack_mode(subscription) {
+            Some(AckMode::Client) | None => false,
+            Some(_) => !self.echo_exempt.contains(subscription),
+        };
+        filtered && msg.extra_headers.iter().any(|header| header == stamp)
+    }
+
+    /// Drop `msg` if it is an echo, ACKing it if need be.
+    /// Returns `None` if the message was dropped.
+    fn suppress_echo(&mut self, msg: Message<FromServer>) -> Option<Message<FromServer>> {
+        if !self.is_echo(&msg) {
+            return Some(msg);
+        }
+        self.echoes_dropped += 1;
+        if let Ok(ack) = self.ack_message(&msg) {
+            self.pending_nacks.push_back(ack);
+        }
+        None
+    }
+
+    /// Hand queued NACKs and ACKs for messages which were not yielded to the writer,
+    /// and start flushing them
+    fn poll_pending_nacks(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        if self.closing {
+            // the writing half is shut down, and the broker will redeliver these anyway
+            self.pending_nacks.clear();
+            return Poll::Ready(Ok(()));
+        }
+        while let Some(nack) = self.pending_nacks.pop_front() {
+            if Pin::new(&mut self.inner).poll_ready(cx)?.is_pending() {
+                self.pending_nacks.push_front(nack);
+                return Poll::Pending;
+            }
+            Pin::new(&mut self.inner).start_send(nack)?;
+            self.queue.push();
+            self.flushing_nacks = true;
+        }
+        if self.flushing_nacks {
+            futures::ready!(Pin::new(&mut self.inner).poll_flush(cx))?;
+            self.queue.flushed();
+            self.flushing_nacks = false;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// The number of frames handed to this transport via the `Sink` which are yet to be
+    /// flushed to the connection. Frames are counted until a flush completes, even if the
+    /// writer has already written some of them.
+    pub fn queue_depth(&self) -> usize {
+        self.queue.depth().get()
+    }
+
+    /// A handle to `queue_depth` which can be read from other tasks
+    pub fn queue_depth_handle(&self) -> QueueDepth {
+        self.queue.depth().clone()
+    }
+
+    /// How long the oldest frame counted by `queue_depth` has been waiting to be flushed
+    pub fn oldest_queued(&self) -> Option<Duration> {
+        self.queue.oldest_age()
+    }
+
+    /// Watch for the outgoing queue crossing its watermarks: the level becomes `HighWater`
+    /// once `queue_depth` reaches `high`, and `LowWater` again once it drains to `low`.
+    /// Only the most recent call's receivers are notified, and nothing is tracked once
+    /// they have all been dropped.
+    ///
+    /// # Panics
+    ///
+    /// If `low` is not less than `high`.
+    pub fn watch_queue(&mut self, low: usize, high: usize) -> watch::Receiver<QueueLevel> {
+        self.queue.watch(low, high)
+    }
+
+    /// What the server reported about itself when the connection was established
+    pub fn connection_details(&self) -> &ConnectionDetails {
+        &self.details
+    }
+
+    /// When the server accepted the connection, or when `migrate` moved to it
+    pub fn connected_at(&self) -> Instant {
+        self.connected_at
+    }
+
+    /// When a frame or heart-beat was last received, or when the connection was made if
+    /// nothing has been received since
+    pub fn last_received(&self) -> Instant {
+        match self.inner.codec().last_received() {
+            Some(received) if received > self.connected_at => received,
+            _ => self.connected_at,
+        }
+    }
+
+    /// How the server answered the heart-beats requested with `ConnectOptions::heartbeat`
+    pub fn heartbeat_negotiation(&self) -> HeartbeatNegotiation {
+        self.heartbeat_negotiation
+    }
+
+    /// How long ago the server accepted the connection, e.g. to replace connections before
+    /// a broker drops them for their age
+    pub fn connection_age(&self) -> Duration {
+        self.connected_at.elapsed()
+    }
+
+    /// Generate an identifier for a subscription, transaction or receipt which is
+    /// unique for this connection, e.g. `sub-3f2a9c01-17`
+    pub fn next_id(&mut self, kind: IdKind) -> String {
+        self.ids.next_id(kind)
+    }
+
+    /// Send a heart-beat (a lone EOL) to the server
+    pub(crate) async fn send_heartbeat(&mut self) -> Result<()> {
+        self.check_frame_boundary()?;
+        self.inner.flush().await?;
+        self.queue.flushed();
+        self.write_buf.clear();
+        self.write_buf.extend_from_slice(b"\n");
+        self.write_direct().await
+    }
+
+    /// Write `write_buf`, which holds whole frames, straight to the connection rather than
+    /// through the codec, whose buffer must have been flushed
+    async fn write_direct(&mut self) -> Result<()> {
+        self.check_frame_boundary()?;
+        self.partial_write = true;
+        let (io, buf) = (self.inner.get_mut(), &self.write_buf);
+        let written = async {
+            io.write_all(buf).await?;
+            io.flush().await
+        };
+        let result = written.await;
+        // a failed write leaves the connection broken anyway
+        self.partial_write = false;
+        Ok(result?)
+    }
+
+    /// Frames must reach the connection whole, one after another. Once a direct write
+    /// was abandoned part way, e.g. by dropping a `send_ref` future, anything written
+    /// would follow the remains of that frame, so the connection fails with
+    /// `StompError::TornFrame` instead.
+    fn check_frame_boundary(&self) -> Result<()> {
+        if self.partial_write {
+            return Err(StompError::TornFrame.into());
+        }
+        Ok(())
+    }
+
+    /// Register a callback to be invoked with the message and body of every
+    /// ERROR frame received from the server, before the frame is yielded from the stream.
+    /// Multiple callbacks may be registered, and are invoked in registration order.
+    pub fn on_broker_error(
+        &mut self,
+        callback: impl Fn(Option<String>, Option<Vec<u8>>) + Send + 'static,
+    ) -> &mut Self {
+        self.error_callbacks.push(Box::new(callback));
+        self
+    }
+
+    /// Register a callback to be invoked with every SEND which is dropped because it expired
+    /// before it was sent, see `SendBuilder::deadline`.
+    /// Multiple callbacks may be registered, and are invoked in registration order.
+    pub fn on_expired(
+        &mut self,
+        callback: impl Fn(&Message<ToServer>) + Send + 'static,
+    ) -> &mut Self {
+        self.expired_callbacks.push(Box::new(callback));
+        self
+    }
+
+    /// The number of SEND frames which were dropped because they had expired
+    pub fn expired_messages(&self) -> u64 {
+        self.expired_messages
+    }
+
+    /// Fail with `StompError::MessageExpired` if `msg` is a SEND whose `expires` header
+    /// has passed, in which case it should be dropped rather than sent
+    fn drop_if_expired(&mut self, msg: &Message<ToServer>) -> Result<()> {
+        if !matches!(msg.content, ToServer::Send { .. }) {
+            return Ok(());
+        }
+        let expires = msg
+            .extra_headers
+            .iter()
+            .find(|(key, _)| key == b"expires")
+            .and_then(|(_, value)| std::str::from_utf8(value).ok()?.parse::<u64>().ok());
+        match expires {
+            // an `expires` of 0 means the message never expires
+            Some(millis) if millis > 0 => {
+                if UNIX_EPOCH + Duration::from_millis(millis) > SystemTime::now() {
+                    return Ok(());
+                }
+            }
+            _ => return Ok(()),
+        }
+        log::debug!("dropping expired message: {:?}", msg.content);
+        self.expired_messages += 1;
+        for callback in &self.expired_callbacks {
+            callback(msg);
+        }
+        Err(StompError::MessageExpired.into())
+    }
+
+    /// Register a callback to be invoked once, when the stream of messages ends, fails with
+    /// an IO error or receives data which can't be decoded, e.g. to clean up after the connection.
+    /// Multiple callbacks may be registered, and are invoked in registration order.
+    pub fn on_disconnect(
+        &mut self,
+        callback: impl FnOnce(DisconnectReason) + Send + 'static,
+    ) -> &mut Self {
+        self.disconnect_callbacks.push(Box::new(callback));
+        self
+    }
+
+    /// Why the connection ended, or `None` if the stream of messages has not ended yet
+    pub fn closed(&self) -> Option<&CloseReason> {
+        self.close_reason.as_ref()
+    }
+
+    /// Whether nothing more can be done with the connection: it has ended, or has been
+    /// closed or sent a DISCONNECT
+    pub(crate) fn is_finished(&self) -> bool {
+        self.close_reason.is_some() || self.disconnect_sent || self.closing
+    }
+
+    /// Record why the connection ended, passing `disconnect_reason` to the disconnect
+    /// callbacks, unless an ERROR frame explains the ending better
+    fn disconnected(&mut self, reason: CloseReason, disconnect_reason: DisconnectReason) {
+        if self.close_reason.is_some() {
+            return;
+        }
+        let reason = match self.broker_error.take() {
+            Some(error) => CloseReason::RemoteError(error),
+            None if self.disconnect_sent => CloseReason::LocalDisconnect,
+            None => reason,
+        };
+        let disconnect_reason = match &reason {
+            CloseReason::RemoteError(FromServer::Error { message, .. }) => {
+                DisconnectReason::BrokerError(message.clone().unwrap_or_default())
+            }
+            _ => disconnect_reason,
+        };
+        for callback in self.disconnect_callbacks.drain(..) {
+            callback(disconnect_reason.clone());
+        }
+        self.close_reason = Some(reason);
+    }
+
+    /// Poll for the next message from the server, as `Stream::poll_next` but without pinning.
+    /// Returns `Ready(None)` once the connection has been closed.
+    /// For driving the connection from a hand-written `Future` or poll loop.
+    pub fn poll_next_message(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Message<FromServer>>>> {
+        self.poll_next_unpin(cx)
+    }
+
+    /// Poll until the connection can accept a message via `start_send`,
+    /// as `Sink::poll_ready`. This may flush previously sent messages.
+    pub fn poll_send_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.poll_ready_unpin(cx)
+    }
+
+    /// Queue a message for sending, as `Sink::start_send`.
+    /// `poll_send_ready` must have returned `Ready(Ok(()))` first,
+    /// and the message is only guaranteed to be written once `poll_flush` has completed.
+    pub fn start_send(&mut self, msg: Message<ToServer>) -> Result<()> {
+        self.start_send_unpin(msg)
+    }
+
+    /// Poll until every queued message has been written to the connection, as `Sink::poll_flush`
+    pub fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.poll_flush_unpin(cx)
+    }
+
+    /// Send a message without consuming it, serializing the frame straight from the
+    /// borrowed message. Use this to publish one body to several destinations
+    /// without cloning it, updating only the destination between sends.
+    ///
+    /// Any frames queued via the `Sink` are flushed first. If this future is dropped
+    /// before it completes, a partial frame may have been written to the connection,
+    /// after which sending fails with `StompError::TornFrame`.
+    pub async fn send_ref(&mut self, msg: &Message<ToServer>) -> Result<()> {
+        self.check_frame_boundary()?;
+        self.drop_if_expired(msg)?;
+        let outgoing = self.check_outgoing(msg)?;
+        self.inner.flush().await?;
+        self.queue.flushed();
+        self.write_buf.clear();
+        let codec = self.inner.codec();
+        let (line_ending, header_order) = (codec.line_ending, codec.header_order);
+        let mut frame = msg.to_frame();
+        if let (ToServer::Send { .. }, Some(stamp)) = (&msg.content, &self.echo_stamp) {
+            frame.extend_headers(stamp);
+        }
+        frame.serialize_with(&mut self.write_buf, line_ending, header_order);
+        let codec = self.inner.codec_mut();
+        codec.log_frame(FrameDirection::Sent, &self.write_buf);
+        codec.encode_layer(&mut self.write_buf)?;
+        #[cfg(feature = "metrics")]
+        {
+            let size = self.write_buf.len();
+            self.inner.codec_mut().stats.record_sent(&msg.content, size);
+        }
+        self.write_direct().await?;
+        self.record_outgoing(outgoing);
+        Ok(())
+    }
+
+    /// Send a frame which was serialized earlier, e.g. by `Message::serialize_to`,
+    /// writing it to the connection byte for byte (through any `CodecLayer`).
+    ///
+    /// Frames which fail validation are rejected with `StompError::InvalidFrame` before anything
+    /// is written. In debug builds the frame must parse as exactly one valid client frame; in
+    /// release builds it need only start with a known command and end with a NUL. The frame
+    /// is not otherwise inspected, so it doesn't update the subscriptions and transactions
+    /// this transport keeps track of.
+    pub async fn send_raw(&mut self, frame: Bytes) -> Result<()> {
+        if self.closing {
+            return Err(StompError::Closing.into());
+        }
+        frame::check_raw_frame(&frame)?;
+        self.check_frame_boundary()?;
+        self.inner.flush().await?;
+        self.queue.flushed();
+        self.write_buf.clear();
+        self.write_buf.extend_from_slice(&frame);
+        let codec = self.inner.codec_mut();
+        codec.log_frame(FrameDirection::Sent, &frame);
+        codec.encode_layer(&mut self.write_buf)?;
+        self.write_direct().await
+    }
+
+    /// Send a message and flush it to the server, failing with `StompError::SendElapsed`
+    /// if that takes longer than `timeout`
+    pub async fn send_timeout(&mut self, msg: Message<ToServer>, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let ready = future::poll_fn(|cx| self.poll_ready_unpin(cx));
+        match time::timeout_at(deadline, ready).await {
+            Ok(ready) => ready?,
+            Err(_) => {
+                let handed_to_writer = false;
+                return Err(StompError::SendElapsed { handed_to_writer }.into());
+            }
+        }
+        self.start_send_unpin(msg)?;
+        let flush = future::poll_fn(|cx| self.poll_flush_unpin(cx));
+        match time::timeout_at(deadline, flush).await {
+            Ok(flushed) => flushed,
+            Err(_) => {
+                let handed_to_writer = true;
+                Err(StompError::SendElapsed { handed_to_writer }.into())
+            }
+        }
+    }
+
+    /// Receive the next message, failing with `StompError::RecvElapsed`
+    /// if none arrives within `timeout`.
+    /// Returns `None` once the connection has been closed.
+    pub async fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<Message<FromServer>>> {
+        match time::timeout(timeout, self.next()).await {
+            Ok(msg) => msg.transpose(),
+            Err(_) => Err(StompError::RecvElapsed.into()),
+        }
+    }
+
+    /// Like `send_timeout`, using the timeout set in `ConnectOptions` (if any)
+    pub async fn timed_send(&mut self, msg: Message<ToServer>) -> Result<()> {
+        match self.send_timeout {
+            Some(timeout) => self.send_timeout(msg, timeout).await,
+            None => self.send(msg).await,
+        }
+    }
+
+    /// Like `recv_timeout`, using the timeout set in `ConnectOptions` (if any)
+    pub async fn timed_recv(&mut self) -> Result<Option<Message<FromServer>>> {
+        match self.recv_timeout {
+            Some(timeout) => self.recv_timeout(timeout).await,
+            None => self.next().await.transpose(),
+        }
+    }
+
+    /// Check that the broker is responsive end-to-end by sending frames which request a receipt
+    /// but have no other effect (see `PingStrategy`), returning the round-trip time.
+    /// Fails with `StompError::RecvElapsed` if the receipt doesn't arrive within `timeout`.
+    /// Frames received in the meantime are yielded from the stream afterwards.
+    pub async fn ping(&mut self, timeout: Duration) -> Result<Duration> {
+        let start = Instant::now();
+        let receipt = self.next_id(IdKind::Receipt);
+        let result = match time::timeout_at(start + timeout, self.ping_round_trip(&receipt)).await {
+            Ok(result) => result.map(|()| start.elapsed()),
+            Err(_) => Err(StompError::RecvElapsed.into()),
+        };
+        if result.is_err() {
+            self.forget_receipt(&receipt);
+        }
+        result
+    }
+
+    async fn ping_round_trip(&mut self, receipt: &str) -> Result<()> {
+        match self.ping_strategy.clone() {
+            PingStrategy::Transaction => {
+                let transaction = self.next_id(IdKind::Transaction);
+                let begin = ToServer::Begin {
+                    transaction: transaction.clone(),
+                };
+                self.feed(begin.into()).await?;
+                let abort = Message::new(ToServer::Abort { transaction });
+                self.send(abort.receipt(receipt)).await?;
+            }
+            PingStrategy::Send { destination } => {
+                let send = Message::new(ToServer::Send {
+                    destination,
+                    transaction: None,
+                    body: None,
+                });
+                self.send(send.receipt(receipt)).await?;
+            }
+        }
+        self.await_receipt(receipt, "ping").await
+    }
+
+    /// Send a message, waiting until the broker confirms it with a receipt.
+    /// Any `receipt` header on `msg` is replaced. Fails with `StompError::RecvElapsed`
+    /// if the receipt doesn't arrive within `timeout`, in which case the broker may yet
+    /// process the message. Frames received in the meantime are yielded from the stream
+    /// afterwards.
+    ///
+    /// If `ConnectOptions::max_pending_receipts` receipts are outstanding, the message is
+    /// only sent once one arrives, within the same `timeout`, or not at all with
+    /// `ReceiptLimitPolicy::Fail`. A receipt which doesn't arrive in time, or whose message
+    /// couldn't be sent, stops counting towards the limit.
+    pub async fn send_with_receipt(
+        &mut self,
+        msg: Message<ToServer>,
+        timeout: Duration,
+    ) -> Result<()> {
+        let receipt = self.next_id(IdKind::Receipt);
+        let confirm = async {
+            self.reserve_receipt().await?;
+            self.send(msg.receipt(receipt.as_str())).await?;
+            self.await_receipt(&receipt, "send").await
+        };
+        let result = match time::timeout(timeout, confirm).await {
+            Ok(result) => result,
+            Err(_) => Err(StompError::RecvElapsed.into()),
+        };
+        if result.is_err() {
+            self.forget_receipt(&receipt);
+        }
+        result
+    }
+
+    /// Subscribe, waiting until the broker confirms the subscription with a receipt,
+    /// and return the subscription's id. Any `receipt` header on `subscription` is replaced.
+    /// Fails with `StompError::SubscribeConfirmTimeout` if the receipt doesn't arrive within
+    /// `timeout`. As the broker may yet make the subscription, an UNSUBSCRIBE is then sent,
+    /// waiting up to `timeout` again to send it, and the subscription is forgotten either
+    /// way, so that it can be tried again with the same id.
+    /// Frames received in the meantime are yielded from the stream afterwards.
+    pub async fn subscribe_confirmed(
+        &mut self,
+        subscription: impl Into<Message<ToServer>>,
+        timeout: Duration,
+    ) -> Result<String> {
+        let msg = subscription.into();
+        let id = match &msg.content {
+            ToServer::Subscribe { id, .. } => id.clone(),
+            _ => failure::bail!("subscribe_confirmed requires a SUBSCRIBE frame"),
+        };
+        let already_open = self.subscriptions.contains_key(&id);
+        let receipt = self.next_id(IdKind::Receipt);
+        let confirm = async {
+            self.send(msg.receipt(receipt.as_str())).await?;
+            self.await_receipt(&receipt, "subscription").await
+        };
+        let result = match time::timeout(timeout, confirm).await {
+            Ok(result) => result.map(|()| id.clone()),
+            Err(_) => Err(StompError::SubscribeConfirmTimeout(id.clone()).into()),
+        };
+        if result.is_err() {
+            self.forget_receipt(&receipt);
+            // only undo a subscription which this call made
+            if !already_open && self.subscriptions.contains_key(&id) {
+                let unsubscribe = ToServer::Unsubscribe { id: id.clone() }.into();
+                let _ = time::timeout(timeout, self.send(unsubscribe)).await;
+                self.subscriptions.remove(&id);
+                self.ack_decorators.remove(&id);
+            }
+        }
+        result
+    }
+
+    /// Wait until fewer than `max_pending_receipts` receipts are outstanding, holding the
+    /// frames received meanwhile, or fail if the policy is `ReceiptLimitPolicy::Fail`
+    async fn reserve_receipt(&mut self) -> Result<()> {
+        let limit = match self.max_pending_receipts {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        while self.pending_receipts.len() >= limit {
+            if self.receipt_limit_policy == ReceiptLimitPolicy::Fail {
+                return Err(StompError::TooManyPendingReceipts { limit }.into());
+            }
+            match future::poll_fn(|cx| self.poll_frame(cx)).await {
+                Some(msg) => self.held.push_back(msg?),
+                None => failure::bail!("connection closed while waiting for a pending receipt"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Receive frames until the receipt with id `receipt` arrives, holding the others
+    /// to be yielded from the stream. Fails if the connection closes or the broker sends
+    /// an ERROR first; `purpose` describes the receipt in those errors.
+    ///
+    /// The connection is read by this future itself, rather than by whoever polls the
+    /// stream, so a task which does nothing but await a receipt still receives it.
+    /// Nothing else can read in the meantime, as the transport is borrowed mutably.
+    async fn await_receipt(&mut self, receipt: &str, purpose: &str) -> Result<()> {
+        loop {
+            let msg = match future::poll_fn(|cx| self.poll_frame(cx)).await {
+                Some(msg) => msg?,
+                None => failure::bail!("connection closed while waiting for {} receipt", purpose),
+            };
+            match &msg.content {
+                FromServer::Receipt { receipt_id } if receipt_id == receipt => return Ok(()),
+                FromServer::Error { message, .. } => {
+                    let message = message.clone().unwrap_or_default();
+                    self.held.push_back(msg);
+                    failure::bail!(
+                        "broker error while waiting for {} receipt: {}",
+                        purpose,
+                        message
+                    );
+                }
+                _ => self.held.push_back(msg),
+            }
+        }
+    }
+
+    /// Move to another broker, e.g. ahead of maintenance, given a connection to it made with
+    /// the same options. Every open subscription is first made again, with the same id,
+    /// acknowledgment mode and headers, on the new connection. The current broker is then
+    /// sent a DISCONNECT, and frames are received from it until its receipt arrives or
+    /// `timeout` elapses; they are yielded from the stream afterwards. Finally the new
+    /// connection replaces the current one.
+    ///
+    /// If the subscriptions can't be written to the new connection, that error is returned
+    /// before the current broker is disconnected, and this transport is left as it was.
+    /// Failures of the current connection are logged, not returned, so that a broker which
+    /// has already gone away can be left.
+    ///
+    /// Messages received from the old broker can no longer be acknowledged, and the old
+    /// broker aborts any open transactions.
+    pub async fn migrate(&mut self, new: StompTransport<S>, timeout: Duration) -> Result<()> {
+        let mut inner = new.inner;
+        for subscription in self.export_subscriptions() {
+            inner.feed(subscription.into()).await?;
+        }
+        inner.flush().await?;
+        match time::timeout(timeout, self.disconnect_round_trip()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => log::warn!("failed to disconnect before migrating: {}", e),
+            Err(_) => log::warn!("timed out waiting for disconnect receipt before migrating"),
+        }
+        self.inner = inner;
+        self.ids.rotate();
+        if !self.dialect_is_explicit {
+            self.dialect = new.dialect;
+        }
+        self.details = new.details;
+        self.connected_at = new.connected_at;
+        self.heartbeat_negotiation = new.heartbeat_negotiation;
+        self.liveness_timer = None;
+        self.transactions.clear();
+        self.closed_subscriptions.clear();
+        self.pending_nacks.clear();
+        self.flushing_nacks = false;
+        // the old broker won't answer, and the new codec's stats start afresh
+        self.pending_receipts.clear();
+        self.receipts_changed();
+        self.queue.flushed();
+        self.broker_error = None;
+        self.disconnect_sent = false;
+        self.closing = false;
+        self.close_reason = None;
+        self.partial_write = false;
+        Ok(())
+    }
+
+    async fn disconnect_round_trip(&mut self) -> Result<()> {
+        let receipt = self.next_id(IdKind::Receipt);
+        let disconnect = ToServer::Disconnect {
+            receipt: Some(receipt.clone()),
+        };
+        self.send(disconnect.into()).await?;
+        self.await_receipt(&receipt, "disconnect").await
+    }
+
+    /// Shut down gracefully: send an UNSUBSCRIBE requesting a receipt for every open
+    /// subscription, then keep receiving until the receipts arrive, passing every MESSAGE,
+    /// including any held from before, to `handler`. Each message the handler succeeds with
+    /// is acknowledged, and each it fails with is rejected, unless its subscription uses
+    /// `AckMode::Auto`. Then send a DISCONNECT requesting a receipt, and wait for that.
+    /// Finally the connection is closed, whether or not draining succeeded.
+    ///
+    /// Fails with `StompError::RecvElapsed` if the receipts don't all arrive within
+    /// `timeout`, in which case unacknowledged messages are redelivered by the broker, or if
+    /// the broker sends an ERROR first.
+    pub async fn drain_and_disconnect<F, Fut>(
+        &mut self,
+        timeout: Duration,
+        mut handler: F,
+    ) -> Result<()>
+    where
+        F: FnMut(Message<FromServer>) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let drained = match time::timeout(timeout, self.drain_round_trip(&mut handler)).await {
+            Ok(result) => result,
+            Err(_) => Err(StompError::RecvElapsed.into()),
+        };
+        self.draining.clear();
+        let closed = self.close().await;
+        drained.and(closed)
+    }
+
+    async fn drain_round_trip<F, Fut>(&mut self, handler: &mut F) -> Result<()>
+    where
+        F: FnMut(Message<FromServer>) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        self.draining = self
+            .iter_subscriptions()
+            .map(|subscription| (subscription.id.clone(), subscription.ack))
+            .collect();
+        let mut receipts = HashSet::new();
+        for id in self.draining.keys().cloned().collect::<Vec<_>>() {
+            let receipt = self.next_id(IdKind::Receipt);
+            let unsubscribe = Message::new(ToServer::Unsubscribe { id }).receipt(receipt.clone());
+            self.feed(unsubscribe).await?;
+            receipts.insert(receipt);
+        }
+        self.flush().await?;
+        while !receipts.is_empty() {
+            let msg = match self.next().await {
+                Some(msg) => msg?,
+                None => failure::bail!("connection closed while waiting for unsubscribe receipts"),
+            };
+            match &msg.content {
+                FromServer::Receipt { receipt_id } => {
+                    receipts.remove(receipt_id);
+                }
+                FromServer::Message { .. } => {
+                    // built up front, as the handler takes the message
+                    let ack = self.ack_message(&msg);
+                    let nack = self.nack_message(&msg);
+                    let reply = match handler(msg).await {
+                        Ok(()) => ack,
+                        Err(e) => {
+                            log::warn!("failed to handle message while draining: {}", e);
+                            nack
+                        }
+                    };
+                    // there is nothing to send for messages which need no acknowledgment
+                    if let Ok(reply) = reply {
+                        self.send(reply).await?;
+                    }
+                }
+                FromServer::Error { message, .. } => {
+                    let message = message.clone().unwrap_or_default();
+                    failure::bail!("broker error while draining: {}", message);
+                }
+                _ => {}
+            }
+        }
+        self.disconnect_round_trip().await
+    }
+
+    /// Poll an operation on the writer, failing the connection if it can't make progress
+    /// while frames have been waiting to be flushed for `write_stall_timeout`
+    fn poll_write(
+        &mut self,
+        cx: &mut Context<'_>,
+        op: impl FnOnce(Pin<&mut ClientTransport<S>>, &mut Context<'_>) -> Poll<Result<()>>,
+    ) -> Poll<Result<()>> {
+        self.check_frame_boundary()?;
+        if let Poll::Ready(result) = op(Pin::new(&mut self.inner), cx) {
+            self.stall_timer = None;
+            return Poll::Ready(result);
+        }
+        let (timeout, queued) = match (self.write_stall_timeout, self.queue.oldest()) {
+            (Some(timeout), Some(queued)) => (timeout, queued),
+            _ => return Poll::Pending,
+        };
+        // the writer is stalled once it has neither had frames to write nor written any
+        let since = match self.inner.get_ref().last_write() {
+            Some(written) => written.max(queued),
+            None => queued,
+        };
+        let deadline = since + timeout;
+        let timer = self
+            .stall_timer
+            .get_or_insert_with(|| time::delay_until(deadline));
+        if timer.deadline() != deadline {
+            timer.reset(deadline);
+        }
+        futures::ready!(Pin::new(timer).poll(cx));
+        self.stall_timer = None;
+        log::warn!("no frames could be written for {:?}", timeout);
+        self.disconnected(CloseReason::WriteStalled, DisconnectReason::Timeout);
+        Poll::Ready(Err(StompError::WriteStalled(timeout).into()))
+    }
+
+    /// Fail the stream if the server declined heart-beats, and has been silent for longer
+    /// than `liveness_fallback`
+    fn poll_liveness(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<Message<FromServer>>>> {
+        let max_silence = match self.liveness_fallback {
+            Some(max_silence)
+                if self.heartbeat_negotiation.is_degraded() && self.close_reason.is_none() =>
+            {
+                max_silence
+            }
+            _ => return Poll::Pending,
+        };
+        let deadline = self.last_received() + max_silence;
+        let timer = self
+            .liveness_timer
+            .get_or_insert_with(|| time::delay_until(deadline));
+        if timer.deadline() != deadline {
+            timer.reset(deadline);
+        }
+        futures::ready!(Pin::new(timer).poll(cx));
+        self.liveness_timer = None;
+        log::warn!("nothing received for {:?}", max_silence);
+        // tell the server, if it is still listening, that the connection is over
+        let _ = Pin::new(self.inner.get_mut()).poll_shutdown(cx);
+        self.disconnected(CloseReason::HeartbeatTimeout, DisconnectReason::Timeout);
+        Poll::Ready(Some(Err(StompError::ServerSilent(max_silence).into())))
+    }
+
+    /// Receive the next frame from the connection, bypassing any held frames
+    fn poll_frame(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<Message<FromServer>>>> {
+        let item = loop {
+            if let Poll::Ready(Err(e)) = self.poll_pending_nacks(cx) {
+                return Poll::Ready(Some(Err(e)));
+            }
+            let polled = match Pin::new(&mut self.inner).poll_next(cx) {
+                Poll::Ready(polled) => polled,
+                Poll::Pending => return self.poll_liveness(cx),
+            };
+            match polled {
+                Some(Ok(msg)) => {
+                    if let FromServer::Receipt { receipt_id } = &msg.content {
+                        if self.pending_receipts.remove(receipt_id) {
+                            self.receipts_changed();
+                        }
+                    }
+                    let msg = match self.suppress_echo(msg) {
+                        Some(msg) => msg,
+                        None => continue,
+                    };
+                    if let Some(item) = self.handle_orphan(msg) {
+                        break item;
+                    }
+                }
+                Some(Err(err)) => {
+                    let (reason, disconnect_reason) = match err.downcast_ref::<io::Error>() {
+                        Some(err) if err.kind() == io::ErrorKind::TimedOut => {
+                            (CloseReason::HeartbeatTimeout, DisconnectReason::Timeout)
+                        }
+                        Some(err) => (
+                            CloseReason::IoError(err.kind()),
+                            DisconnectReason::IoError(io::Error::new(err.kind(), err.to_string())),
+                        ),
+                        // the data can't be decoded, so nothing more can be read
+                        None if self.inner.codec().is_corrupted() => (
+                            CloseReason::ProtocolError(err.to_string()),
+                            DisconnectReason::ProtocolError(err.to_string()),
+                        ),
+                        // a frame which was decoded but not understood, which has been skipped
+                        None => return Poll::Ready(Some(Err(err))),
+                    };
+                    self.disconnected(reason, disconnect_reason);
+                    return Poll::Ready(Some(Err(err)));
+                }
+                None => {
+                    self.disconnected(CloseReason::RemoteEof, DisconnectReason::Graceful);
+                    return Poll::Ready(None);
+                }
+            }
+        };
+        if let Ok(Message { content, .. }) = &item {
+            if let FromServer::Error { message, body } = content {
+                for callback in &self.error_callbacks {
+                    callback(message.clone(), body.clone());
+                }
+                self.broker_error = Some(content.clone());
+            }
+        }
+        Poll::Ready(Some(item))
+    }
+}
+
+impl<S> Stream for StompTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    type Item = Result<Message<FromServer>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        match this.close_reason {
+            // the connection has been abandoned
+            Some(CloseReason::WriteStalled) | Some(CloseReason::HeartbeatTimeout) => {
+                return Poll::Ready(None)
+            }
+            // decoding would only fail again on the same data
+            Some(CloseReason::ProtocolError(_)) => return Poll::Ready(None),
+            _ => {}
+        }
+        match this.held.pop_front() {
+            Some(msg) => Poll::Ready(Some(Ok(msg))),
+            None => this.poll_frame(cx),
+        }
+    }
+}
+
+impl<S> Sink<Message<ToServer>> for StompTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    type Error = failure::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        if this.closing {
+            return Poll::Ready(Err(StompError::Closing.into()));
+        }
+        this.poll_write(cx, |inner, cx| inner.poll_ready(cx))
+    }
+
+    fn start_send(self: Pin<&mut Self>, mut item: Message<ToServer>) -> Result<()> {
+        let this = self.get_mut();
+        this.drop_if_expired(&item)?;
+        this.check_frame_boundary()?;
+        let outgoing = this.check_outgoing(&item)?;
+        if let (ToServer::Send { .. }, Some(stamp)) = (&item.content, &this.echo_stamp) {
+            item.extra_headers.extend(stamp.iter().cloned());
+        }
+        Pin::new(&mut this.inner).start_send(item)?;
+        this.record_outgoing(outgoing);
+        this.queue.push();
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        futures::ready!(this.poll_write(cx, |inner, cx| inner.poll_flush(cx)))?;
+        this.queue.flushed();
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        this.closing = true;
+        futures::ready!(this.poll_write(cx, |inner, cx| inner.poll_close(cx)))?;
+        this.queue.flushed();
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// A `StompTransport` which yields at most a given number of MESSAGE frames per second,
+/// created with `StompTransport::throttle`.
+///
+/// Messages which arrive faster than that are NACKed if their subscription uses
+/// `client-individual` acknowledgment, so that the broker can redeliver them later or
+/// to another consumer. Otherwise they are delayed until their turn, which holds back
+/// every frame received after them. Other frames, and sending, are not throttled.
+pub struct ThrottledTransport<S> {
+    transport: StompTransport<S>,
+    interval: Duration,
+    /// The earliest time the next message may be yielded
+    next_slot: Instant,
+    /// A message held back until its turn
+    delayed: Option<(time::Delay, Message<FromServer>)>,
+    nacked: u64,
+}
+
+impl<S> ThrottledTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// The number of messages which were NACKed for exceeding the rate
+    pub fn nacked_messages(&self) -> u64 {
+        self.nacked
+    }
+
+    pub fn get_ref(&self) -> &StompTransport<S> {
+        &self.transport
+    }
+
+    pub fn get_mut(&mut self) -> &mut StompTransport<S> {
+        &mut self.transport
+    }
+
+    /// Stop throttling. A message which was being delayed is lost, and will be
+    /// redelivered by the broker if it was not acknowledged.
+    pub fn into_inner(self) -> StompTransport<S> {
+        self.transport
+    }
+
+    /// Whether a message received now is over the rate, and should be NACKed rather than delayed
+    fn should_nack(&self, msg: &Message<FromServer>) -> bool {
+        match &msg.content {
+            FromServer::Message { subscription, .. } => matches!(
+                self.transport.ack_mode(subscription),
+                Some(AckMode::ClientIndividual)
+            ),
+            _ => false,
+        }
+    }
+}
+
+impl<S> Stream for ThrottledTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    type Item = Result<Message<FromServer>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some((delay, _)) = &mut this.delayed {
+                futures::ready!(Pin::new(delay).poll(cx));
+                let (_, msg) = this.delayed.take().unwrap();
+                this.next_slot = Instant::now() + this.interval;
+                return Poll::Ready(Some(Ok(msg)));
+            }
+            let msg = match futures::ready!(this.transport.poll_next_unpin(cx)) {
+                Some(Ok(msg)) => msg,
+                item => return Poll::Ready(item),
+            };
+            if !matches!(msg.content, FromServer::Message { .. }) {
+                return Poll::Ready(Some(Ok(msg)));
+            }
+            let now = Instant::now();
+            if now >= this.next_slot {
+                this.next_slot = now + this.interval;
+                return Poll::Ready(Some(Ok(msg)));
+            }
+            if this.should_nack(&msg) {
+                match this.transport.nack_message(&msg) {
+                    Ok(nack) => this.transport.pending_nacks.push_back(nack),
+                    Err(e) => return Poll::Ready(Some(Err(e))),
+                }
+                this.nacked += 1;
+            } else {
+                this.delayed = Some((time::delay_until(this.next_slot), msg));
+            }
+        }
+    }
+}
+
+impl<S> Sink<Message<ToServer>> for ThrottledTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    type Error = failure::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().transport.poll_ready_unpin(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message<ToServer>) -> Result<()> {
+        self.get_mut().transport.start_send_unpin(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().transport.poll_flush_unpin(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().transport.poll_close_unpin(cx)
+    }
+}
+
+/// Send several messages, flushing once after the last of them, so that they are written
+/// together where possible instead of one write per message. If a message is rejected,
+/// the ones before it have been queued and may be written when the sink is next flushed.
+pub async fn send_batch<S>(
+    sink: &mut S,
+    messages: impl IntoIterator<Item = Message<ToServer>>,
+) -> Result<()>
+where
+    S: Sink<Message<ToServer>, Error = failure::Error> + Unpin,
+{
+    for msg in messages {
+        sink.feed(msg).await?;
+    }
+    sink.flush().await
+}
+
+/// Convenience function to build a Subscribe message
+pub fn subscribe(dest: impl Into<String>, id: impl Into<String>) -> Message<ToServer> {
+    ToServer::Subscribe {
+        destination: dest.into(),
+        id: id.into(),
+        ack: None,
+    }
+    .into()
+}
+
+/// Convenience function to build a Subscribe message with the given acknowledgment mode.
+/// Further headers can be added with `Message::header`.
+///
+/// ```no_run
+/// # use futures::prelude::*;
+/// # use tokio_stomp::{client, AckMode, FromServer};
+/// # async fn example() -> Result<(), failure::Error> {
+/// let mut conn = client::connect("127.0.0.1:61613", None, None).await?;
+/// let subscribe = client::subscribe_with_ack("orders", "sub-1", AckMode::ClientIndividual)
+///     .header("activemq.prefetchSize", "10");
+/// conn.send(subscribe).await?;
+/// while let Some(msg) = conn.next().await.transpose()? {
+///     if let FromServer::Message { message_id, .. } = &msg.content {
+///         // STOMP 1.2 servers name the message to acknowledge in an `ack` header
+///         let id = msg
+///             .extra_headers
+///             .iter()
+///             .find(|(key, _)| key == b"ack")
+///             .map(|(_, value)| String::from_utf8_lossy(value).into_owned())
+///             .unwrap_or_else(|| message_id.clone());
+///         conn.send(client::ack(id)).await?;
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub fn subscribe_with_ack(
+    dest: impl Into<String>,
+    id: impl Into<String>,
+    ack: AckMode,
+) -> Message<ToServer> {
+    ToServer::Subscribe {
+        destination: dest.into(),
+        id: id.into(),
+        ack: Some(ack),
+    }
+    .into()
+}
+
+/// Convenience function to build an Ack message, outside of any transaction
+pub fn ack(id: impl Into<String>) -> Message<ToServer> {
+    ToServer::Ack {
+        id: id.into(),
+        transaction: None,
+    }
+    .into()
+}
+
+/// Convenience function to build a Send message with a text body
+pub fn send_text(dest: impl Into<String>, text: impl Into<String>) -> Message<ToServer> {
+    SendBuilder::new(dest)
+        .body_bytes(text.into())
+        .content_type("text/plain;charset=UTF-8")
+        .build()
+}
+
+/// Convenience function to build a Send message with a binary body
+pub fn send_bytes(dest: impl Into<String>, bytes: impl Into<Vec<u8>>) -> Message<ToServer> {
+    SendBuilder::new(dest)
+        .body_bytes(bytes)
+        .content_type("application/octet-stream")
+        .build()
+}
+
+/// Convenience function to build a Send message with `value` serialized as its JSON body.
+/// Fails with `StompError::InvalidBody` if `value` can't be serialized.
+#[cfg(feature = "serde")]
+pub fn send_json<T: serde_crate::Serialize + ?Sized>(
+    dest: impl Into<String>,
+    value: &T,
+) -> Result<Message<ToServer>> {
+    let body = serde_json::to_vec(value).map_err(|e| StompError::InvalidBody(e.to_string()))?;
+    Ok(SendBuilder::new(dest)
+        .body_bytes(body)
+        .content_type("application/json")
+        .build())
+}
+
+/// Convenience function to build a Disconnect message which requests a receipt
+/// with a generated id, so the server confirms everything before it was processed
+pub fn disconnect() -> Message<ToServer> {
+    Message::new(ToServer::Disconnect { receipt: None }).auto_receipt()
+}
+
+/// Sizing policy for the buffer incoming frames are decoded from
+#[derive(Debug, Clone, Copy)]
+pub struct BufferConfig {
+    /// The capacity the buffer is allocated with.
+    /// Note that `tokio_util` will always allocate at least 8 KiB for a new connection;
+    /// set `shrink_threshold` to release the excess once the first frame has been received.
+    pub initial_capacity: usize,
+    /// The minimum number of bytes reserved whenever the buffer is full
+    pub growth_increment: usize,
+    /// When the buffer is empty and its capacity exceeds this many bytes
+    /// (e.g. after receiving a very large frame), shrink it back to `initial_capacity`
+    pub shrink_threshold: Option<usize>,
+}
+
+impl Default for BufferConfig {
+    fn default() -> Self {
+        BufferConfig {
+            initial_capacity: 8 * 1024,
+            growth_increment: 8 * 1024,
+            shrink_threshold: None,
+        }
+    }
+}
+
+/// The codec used to encode and decode STOMP frames on the client side
+#[derive(Debug, Default)]
+pub struct ClientCodec {
+    buffer: BufferConfig,
+    buffer_len: usize,
+    buffer_capacity: usize,
+    lenient_content_length: bool,
+    header_case: HeaderCase,
+    header_continuations: bool,
+    max_frame_size: Option<usize>,
+    line_ending: LineEnding,
+    header_order: HeaderOrder,
+    frames_encoded: u64,
+    frames_decoded: u64,
+    last_received: Option<Instant>,
+    /// How much of the frame at the start of the buffer is known not to contain its terminating NUL
+    scanned: usize,
+    /// Set once data has been received which can't be decoded, and which decoding can't skip
+    corrupted: bool,
+    layer: Option<Box<dyn CodecLayer>>,
+    /// Data recovered by `layer` which has yet to be decoded
+    layered_buf: BytesMut,
+    frame_logger: Option<FrameLogger>,
+    /// The headers masked in frames logged at trace level, if not `DEFAULT_REDACTED_HEADERS`
+    redacted_headers: Option<Vec<String>>,
+    #[cfg(feature = "metrics")]
+    stats: FrameStats,
+}
+
+impl ClientCodec {
+    pub fn with_buffer_config(buffer: BufferConfig) -> Self {
+        ClientCodec {
+            buffer,
+            ..Default::default()
+        }
+    }
+
+    /// End the lines of encoded frames with `line_ending`
+    pub fn with_line_ending(mut self, line_ending: LineEnding) -> Self {
+        self.line_ending = line_ending;
+        self
+    }
+
+    /// Write the headers of encoded frames in `order`
+    pub fn with_header_order(mut self, order: HeaderOrder) -> Self {
+        self.header_order = order;
+        self
+    }
+
+    /// Pass every frame encoded and decoded through `layer`
+    pub fn with_layer(mut self, layer: impl CodecLayer + 'static) -> Self {
+        self.layer = Some(Box::new(layer));
+        self
+    }
+
+    /// The number of bytes left in the decode buffer after the last call to `decode`,
+    /// i.e. data which has been received but does not yet form a complete frame
+    pub fn buffer_len(&self) -> usize {
+        self.buffer_len
+    }
+
+    /// The capacity of the decode buffer after the last call to `decode`
+    pub fn buffer_capacity(&self) -> usize {
+        self.buffer_capacity
+    }
+
+    /// The number of frames encoded so far. Frames sent with `StompTransport::send_ref`
+    /// or `send_raw` are written without being encoded, and aren't counted.
+    pub fn frames_encoded(&self) -> u64 {
+        self.frames_encoded
+    }
+
+    /// The number of frames decoded so far, not counting heart-beats
+    pub fn frames_decoded(&self) -> u64 {
+        self.frames_decoded
+    }
+
+    /// When a frame or heart-beat was last decoded, if any has been
+    pub fn last_received(&self) -> Option<Instant> {
+        self.last_received
+    }
+
+    /// Totals of the traffic encoded and decoded so far
+    #[cfg(feature = "metrics")]
+    pub fn stats(&self) -> &FrameStats {
+        &self.stats
+    }
+
+    fn manage_buffer(&mut self, src: &mut BytesMut) {
+        match self.buffer.shrink_threshold {
+            Some(threshold) if src.is_empty() && src.capacity() > threshold => {
+                *src = BytesMut::with_capacity(self.buffer.initial_capacity);
+            }
+            _ => {
+                if src.capacity() == src.len() {
+                    src.reserve(self.buffer.growth_increment);
+                }
+            }
+        }
+        self.buffer_len = src.len();
+        self.buffer_capacity = src.capacity();
+    }
+
+    fn log_frame(&self, direction: FrameDirection, frame: &[u8]) {
+        if let Some(logger) = &self.frame_logger {
+            logger.log(direction, frame);
+        }
+        if log::log_enabled!(log::Level::Trace) {
+            let pretty = match &self.redacted_headers {
+                Some(headers) => pretty::pretty_frame_redacting(frame, headers),
+                None => pretty::pretty_frame(frame),
+            };
+            log::trace!("{:?} frame:\n{}", direction, pretty);
+        }
+    }
+
+    fn encode_layer(&mut self, frame: &mut BytesMut) -> Result<()> {
+        match &mut self.layer {
+            Some(layer) => layer.encode(frame),
+            None => Ok(()),
+        }
+    }
+
+    fn check_frame_size(&self, size: usize) -> Result<()> {
+        match self.max_frame_size {
+            Some(limit) if size > limit => Err(StompError::FrameTooLarge { limit }.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether data has been received which can't be decoded as STOMP frames
+    pub fn is_corrupted(&self) -> bool {
+        self.corrupted
+    }
+
+    /// Decode the next frame, which may then fail to convert to a `FromServer`.
+    /// Errors leave the frame in `src`, so decoding can't continue past them.
+    fn decode_frame(&mut self, src: &mut BytesMut) -> Result<Option<Result<Message<FromServer>>>> {
+        // skip heart-beats, so they don't pile up while waiting for the next frame
+        let eols = src
+            .iter()
+            .take_while(|&&b| b == b'\n' || b == b'\r')
+            .count();
+        if eols > 0 {
+            src.advance(eols);
+            self.scanned = 0;
+            self.last_received = Some(Instant::now());
+        }
+        // a frame can't be complete until its NUL has arrived, so only parse once there is
+        // a NUL which hasn't been looked at yet, instead of reparsing on every call
+        match src[self.scanned..].iter().position(|&b| b == 0) {
+            Some(nul) => self.scanned += nul + 1,
+            None => {
+                self.scanned = src.len();
+                self.check_frame_size(src.len())?;
+                self.manage_buffer(src);
+                return Ok(None);
+            }
+        }
+        if self.header_continuations && frame::unfold_headers(src, self.scanned) > 0 {
+            // the NUL found may have moved, so search again if the frame isn't complete
+            self.scanned = 0;
+        }
+        let parsed = match frame::parse_frame_with(&src, self.header_case) {
+            Err(e @ nom::Err::Error(_)) | Err(e @ nom::Err::Failure(_)) => {
+                match frame::content_length_mismatch(src, self.header_case) {
+                    Some(StompError::ContentLengthMismatch {
+                        declared,
+                        found_nul_at: Some(nul),
+                    }) if self.lenient_content_length => {
+                        log::warn!(
+                            "content-length {} disagrees with NUL terminator at {}, using the latter",
+                            declared,
+                            nul
+                        );
+                        frame::parse_frame_nul_terminated(src)
+                    }
+                    Some(mismatch) => return Err(mismatch.into()),
+                    None => Err(e),
+                }
+            }
+            parsed => parsed,
+        };
+        let (item, offset) = match parsed {
+            Ok((remain, mut frame)) => (
+                {
+                    frame.normalize_header_names(self.header_case);
+                    Message::<FromServer>::from_frame(frame)
+                },
+                remain.as_ptr() as usize - src.as_ptr() as usize,
+            ),
+            Err(nom::Err::Incomplete(_)) => {
+                // the NUL was part of the body
+                self.check_frame_size(src.len())?;
+                self.manage_buffer(src);
+                return Ok(None);
+            }
+            Err(e) => failure::bail!("Parse failed: {}", frame::describe_parse_error(e)),
+        };
+        self.check_frame_size(offset)?;
+        self.log_frame(FrameDirection::Received, &src[..offset]);
+        src.advance(offset);
+        self.scanned = 0;
+        self.last_received = Some(Instant::now());
+        if item.is_ok() {
+            self.frames_decoded += 1;
+        }
+        #[cfg(feature = "metrics")]
+        {
+            if let Ok(msg) = &item {
+                self.stats.record_received(&msg.content, offset);
+            }
+        }
+        self.manage_buffer(src);
+        Ok(Some(item))
+    }
+}
+
+impl Decoder for ClientCodec {
+    type Item = Message<FromServer>;
+    type Error = failure::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        let decoded = match &mut self.layer {
+            Some(layer) => layer.decode(src, &mut self.layered_buf).and_then(|()| {
+                let mut buf = std::mem::take(&mut self.layered_buf);
+                let decoded = self.decode_frame(&mut buf);
+                self.layered_buf = buf;
+                decoded
+            }),
+            None => self.decode_frame(src),
+        };
+        match decoded {
+            Ok(item) => item.transpose(),
+            Err(err) => {
+                self.corrupted = true;
+                Err(err)
+            }
+        }
+    }
+}
+
+impl Encoder for ClientCodec {
+    type Item = Message<ToServer>;
+    type Error = failure::Error;
+
+    fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<()> {
+        let len = dst.len();
+        item.to_frame()
+            .serialize_with(dst, self.line_ending, self.header_order);
+        self.log_frame(FrameDirection::Sent, &dst[len..]);
+        if self.layer.is_some() {
+            let mut frame = dst.split_off(len);
+            self.encode_layer(&mut frame)?;
+            dst.unsplit(frame);
+        }
+        self.frames_encoded += 1;
+        #[cfg(feature = "metrics")]
+        {
+            self.stats.record_sent(&item.content, dst.len() - len);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    /// An in-memory IO object which yields `input` to reads and records writes,
+    /// or fails them if `fail_writes` is set
+    #[derive(Default)]
+    struct MockIo {
+        input: io::Cursor<Vec<u8>>,
+        written: Arc<Mutex<Vec<u8>>>,
+        writes: Arc<AtomicUsize>,
+        fail_writes: bool,
+    }
+
+    impl MockIo {
+        fn new(input: &[u8]) -> Self {
+            MockIo {
+                input: io::Cursor::new(input.to_vec()),
+                ..Default::default()
+            }
+        }
+    }
+
+    impl AsyncRead for MockIo {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(io::Read::read(&mut self.get_mut().input, buf))
+        }
+    }
+
+    impl AsyncWrite for MockIo {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            if self.fail_writes {
+                return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+            }
+            self.written.lock().unwrap().extend_from_slice(buf);
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn mock_transport(input: &[u8]) -> StompTransport<MockIo> {
+        mock_transport_with_options(input, ConnectOptions::default())
+    }
+
+    fn mock_transport_with_options(
+        input: &[u8],
+        options: ConnectOptions,
+    ) -> StompTransport<MockIo> {
+        StompTransport::new(options.framed(MockIo::new(input)), &options)
+    }
+
+    fn ack_in(transaction: &str) -> Message<ToServer> {
+        ToServer::Ack {
+            id: "1".into(),
+            transaction: Some(transaction.into()),
+        }
+        .into()
+    }
+
+    fn is_unknown_transaction(err: &failure::Error) -> bool {
+        matches!(
+            err.downcast_ref::<StompError>(),
+            Some(StompError::UnknownTransaction(_))
+        )
+    }
+
+    #[test]
+    fn broker_error_callbacks() {
+        let mut transport = mock_transport(b"ERROR\nmessage:oops\n\nbad things\x00");
+        let calls = Arc::new(AtomicUsize::new(0));
+        let (c1, c2) = (calls.clone(), calls.clone());
+        transport
+            .on_broker_error(move |message, body| {
+                assert_eq!(message.as_deref(), Some("oops"));
+                assert_eq!(body.as_deref(), Some(&b"bad things"[..]));
+                c1.fetch_add(1, Ordering::SeqCst);
+            })
+            .on_broker_error(move |_, _| {
+                c2.fetch_add(1, Ordering::SeqCst);
+            });
+
+        let msg = futures::executor::block_on(transport.next());
+        assert!(matches!(
+            msg,
+            Some(Ok(Message {
+                content: FromServer::Error { .. },
+                ..
+            }))
+        ));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert!(futures::executor::block_on(transport.next()).is_none());
+    }
+
+    /// An IO object whose reads fail
+    struct BrokenIo(io::ErrorKind);
+
+    impl AsyncRead for BrokenIo {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(Err(self.0.into()))
+        }
+    }
+
+    impl AsyncWrite for BrokenIo {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Poll `transport` until its stream ends or fails, returning the reasons
+    /// passed to the disconnect callbacks
+    fn disconnect_reasons<S>(transport: StompTransport<S>) -> Vec<String>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        disconnect_and_close_reasons(transport).0
+    }
+
+    /// Like `disconnect_reasons`, also returning the reason reported by `closed()`
+    fn disconnect_and_close_reasons<S>(mut transport: StompTransport<S>) -> (Vec<String>, String)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let reasons = Arc::new(Mutex::new(vec![]));
+        let (r1, r2) = (reasons.clone(), reasons.clone());
+        transport
+            .on_disconnect(move |reason| r1.lock().unwrap().push(format!("{:?}", reason)))
+            .on_disconnect(move |reason| r2.lock().unwrap().push(format!("{:?}", reason)));
+        assert!(transport.closed().is_none());
+        while let Some(Ok(_)) = futures::executor::block_on(transport.next()) {}
+        // callbacks fire only once
+        let _ = futures::executor::block_on(transport.next());
+        let reasons = reasons.lock().unwrap().clone();
+        (reasons, format!("{:?}", transport.closed().unwrap()))
+    }
+
+    #[test]
+    fn disconnect_callbacks() {
+        let receipt = b"RECEIPT\nreceipt-id:r\n\n\x00";
+        assert_eq!(disconnect_reasons(mock_transport(receipt)), ["Graceful"; 2]);
+
+        let error = b"ERROR\nmessage:oops\n\n\x00";
+        assert_eq!(
+            disconnect_reasons(mock_transport(error)),
+            [r#"BrokerError("oops")"#; 2]
+        );
+
+        let options = ConnectOptions::new();
+        let broken = |kind| StompTransport::new(options.framed(BrokenIo(kind)), &options);
+        let reasons = disconnect_reasons(broken(io::ErrorKind::ConnectionReset));
+        assert_eq!(reasons.len(), 2);
+        assert!(reasons[0].starts_with("IoError("));
+        let reasons = disconnect_reasons(broken(io::ErrorKind::TimedOut));
+        assert_eq!(reasons, ["Timeout"; 2]);
+    }
+
+    #[test]
+    fn close_reason_for_each_path() {
+        use futures::executor::block_on;
+
+        let receipt = b"RECEIPT\nreceipt-id:r\n\n\x00";
+        let (_, closed) = disconnect_and_close_reasons(mock_transport(receipt));
+        assert_eq!(closed, "RemoteEof");
+
+        let mut transport = mock_transport(receipt);
+        block_on(transport.send(disconnect())).unwrap();
+        let (reasons, closed) = disconnect_and_close_reasons(transport);
+        assert_eq!(reasons, ["Graceful"; 2]);
+        assert_eq!(closed, "LocalDisconnect");
+
+        // an ERROR frame explains a DISCONNECT better than the DISCONNECT itself
+        let mut transport = mock_transport(b"ERROR\nmessage:oops\n\n\x00");
+        block_on(transport.send(disconnect())).unwrap();
+        let (_, closed) = disconnect_and_close_reasons(transport);
+        assert_eq!(
+            closed,
+            r#"RemoteError(Error { message: Some("oops"), body: None })"#
+        );
+
+        let options = ConnectOptions::new();
+        let broken = |kind| StompTransport::new(options.framed(BrokenIo(kind)), &options);
+        let (_, closed) = disconnect_and_close_reasons(broken(io::ErrorKind::ConnectionReset));
+        assert_eq!(closed, "IoError(ConnectionReset)");
+        let (_, closed) = disconnect_and_close_reasons(broken(io::ErrorKind::TimedOut));
+        assert_eq!(closed, "HeartbeatTimeout");
+
+        // a frame which isn't understood is skipped, and doesn't end the connection
+        let mut transport = mock_transport(b"NONSENSE\n\n\x00RECEIPT\nreceipt-id:r\n\n\x00");
+        assert!(block_on(transport.next()).unwrap().is_err());
+        assert!(transport.closed().is_none());
+        let (reasons, closed) = disconnect_and_close_reasons(transport);
+        assert_eq!(reasons, ["Graceful"; 2]);
+        assert_eq!(closed, "RemoteEof");
+
+        // data which can't be decoded does, and the stream ends after its error
+        let options = ConnectOptions::new().max_frame_size(16);
+        let mut transport = mock_transport_with_options(&[b'x'; 64], options.clone());
+        assert!(block_on(transport.next()).unwrap().is_err());
+        assert!(block_on(transport.next()).is_none());
+        let transport = mock_transport_with_options(&[b'x'; 64], options);
+        let (reasons, closed) = disconnect_and_close_reasons(transport);
+        assert_eq!(reasons.len(), 2);
+        assert!(reasons[0].starts_with("ProtocolError("), "{}", reasons[0]);
+        assert!(closed.starts_with("ProtocolError("), "{}", closed);
+    }
+
+    #[test]
+    fn reject_duplicate_subscription_ids() {
+        use futures::executor::block_on;
+
+        let mut transport = mock_transport(b"");
+        block_on(transport.send(subscribe("/queue/a", "1"))).unwrap();
+        let err = block_on(transport.send(subscribe("/queue/b", "1"))).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StompError>(),
+            Some(StompError::SubscriptionIdInUse(id)) if id == "1"
+        ));
+        assert_eq!(transport.subscriptions["1"].destination, "/queue/a");
+
+        block_on(transport.send(ToServer::Unsubscribe { id: "1".into() }.into())).unwrap();
+        block_on(transport.send(subscribe("/queue/b", "1"))).unwrap();
+
+        let options = ConnectOptions::new().allow_resubscribe(true);
+        let mut transport = mock_transport_with_options(b"", options);
+        block_on(transport.send(subscribe("/queue/a", "1"))).unwrap();
+        block_on(transport.send(subscribe_with_ack("/queue/b", "1", AckMode::Client))).unwrap();
+        assert_eq!(transport.subscriptions["1"].destination, "/queue/b");
+        assert!(matches!(transport.subscriptions["1"].ack, AckMode::Client));
+    }
+
+    #[test]
+    fn detect_shared_destinations() {
+        use futures::executor::block_on;
+
+        let mut transport = mock_transport(b"");
+        block_on(transport.send(subscribe("/queue/a", "1"))).unwrap();
+        assert_eq!(transport.subscription_to("/queue/a", "1"), None);
+        assert_eq!(transport.subscription_to("/queue/a", "2"), Some("1"));
+        assert_eq!(transport.subscription_to("/queue/b", "2"), None);
+        block_on(transport.send(subscribe("/queue/a", "2"))).unwrap();
+        block_on(transport.send(ToServer::Unsubscribe { id: "1".into() }.into())).unwrap();
+        assert_eq!(transport.subscription_to("/queue/a", "3"), Some("2"));
+    }
+
+    #[test]
+    fn send_raw_writes_frames_verbatim() {
+        use futures::executor::block_on;
+
+        // as stored in an outbox, with headers in an order `Message` wouldn't produce
+        let stored = Bytes::from_static(
+            b"SEND\ncontent-type:text/plain\ndestination:/queue/a\ncontent-length:2\n\nhi\x00",
+        );
+        let io = MockIo::new(b"");
+        let written = io.written.clone();
+        let options = ConnectOptions::new();
+        let mut transport = StompTransport::new(options.framed(io), &options);
+        block_on(transport.send(subscribe("/queue/b", "1"))).unwrap();
+        written.lock().unwrap().clear();
+
+        block_on(transport.send_raw(stored.clone())).unwrap();
+        let written = written.lock().unwrap().clone();
+        assert_eq!(written, stored);
+        let (_, frame) = frame::parse_frame(&written).unwrap();
+        match frame.to_client_msg().unwrap().content {
+            ToServer::Send {
+                destination, body, ..
+            } => {
+                assert_eq!(destination, "/queue/a");
+                assert_eq!(body.as_deref(), Some(&b"hi"[..]));
+            }
+            other => panic!("expected a SEND, got {:?}", other),
+        }
+
+        let err = block_on(transport.send_raw(Bytes::from_static(b"garbage"))).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StompError>(),
+            Some(StompError::InvalidFrame { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn expired_messages_are_dropped() {
+        use crate::test_utils::StompRouter;
+
+        let router = StompRouter::start().await.unwrap();
+        let mut transport = router.connect().await.unwrap();
+        let dropped = Arc::new(Mutex::new(vec![]));
+        let events = dropped.clone();
+        transport.on_expired(move |msg| events.lock().unwrap().push(encode_to_string(msg)));
+        let is_expired = |err: failure::Error| {
+            matches!(
+                err.downcast_ref::<StompError>(),
+                Some(StompError::MessageExpired)
+            )
+        };
+
+        // e.g. buffered with a 1s TTL while the broker was unreachable for 2s
+        let expired = SendBuilder::new("/queue/a")
+            .deadline(SystemTime::now() - Duration::from_secs(1))
+            .build();
+        assert!(is_expired(transport.send_ref(&expired).await.unwrap_err()));
+        assert!(is_expired(transport.send(expired).await.unwrap_err()));
+        assert_eq!(transport.expired_messages(), 2);
+        assert_eq!(dropped.lock().unwrap().len(), 2);
+        assert!(dropped.lock().unwrap()[0].contains("expires:"));
+
+        let live = SendBuilder::new("/queue/a").ttl(Duration::from_secs(60));
+        transport.send(live.build()).await.unwrap();
+        transport
+            .send(SendBuilder::new("/queue/a").build())
+            .await
+            .unwrap();
+        let never = SendBuilder::new("/queue/a").header("expires", "0");
+        transport
+            .send_with_receipt(never.build(), Duration::from_secs(5))
+            .await
+            .unwrap();
+        let sent = router.received(0);
+        assert_eq!(sent.len(), 4);
+        assert!(sent[1..]
+            .iter()
+            .all(|msg| matches!(msg.content, ToServer::Send { .. })));
+        assert_eq!(transport.expired_messages(), 2);
+    }
+
+    #[test]
+    fn reject_unknown_transactions() {
+        use futures::executor::block_on;
+
+        let mut transport = mock_transport(b"");
+        let err = block_on(transport.send(ack_in("tx-1"))).unwrap_err();
+        assert!(is_unknown_transaction(&err));
+
+        let begin = ToServer::Begin {
+            transaction: "tx-1".into(),
+        };
+        block_on(transport.send(begin.into())).unwrap();
+        block_on(transport.send(ack_in("tx-1"))).unwrap();
+
+        let commit = ToServer::Commit {
+            transaction: "tx-1".into(),
+        };
+        block_on(transport.send(commit.into())).unwrap();
+        let send = ToServer::Send {
+            destination: "a".into(),
+            transaction: Some("tx-1".into()),
+            body: None,
+        };
+        let err = block_on(transport.send(send.into())).unwrap_err();
+        assert!(is_unknown_transaction(&err));
+    }
+
+    #[test]
+    fn reject_reused_transaction_ids() {
+        use futures::executor::block_on;
+
+        let mut transport = mock_transport(b"");
+        let begin = |transaction: &str| {
+            Message::new(ToServer::Begin {
+                transaction: transaction.into(),
+            })
+        };
+        let is_in_use = |err: failure::Error| {
+            matches!(
+                err.downcast_ref::<StompError>(),
+                Some(StompError::TransactionIdInUse(id)) if id == "tx-1"
+            )
+        };
+        block_on(transport.send(begin("tx-1"))).unwrap();
+        assert!(is_in_use(
+            block_on(transport.send(begin("tx-1"))).unwrap_err()
+        ));
+        assert_eq!(transport.open_transactions().collect::<Vec<_>>(), ["tx-1"]);
+
+        let abort = ToServer::Abort {
+            transaction: "tx-1".into(),
+        };
+        block_on(transport.send(abort.into())).unwrap();
+        assert_eq!(transport.open_transactions().count(), 0);
+        assert!(is_in_use(
+            block_on(transport.send(begin("tx-1"))).unwrap_err()
+        ));
+
+        // only the most recent transactions are remembered
+        for n in 0..RECENT_TRANSACTIONS {
+            let transaction = format!("other-{}", n);
+            block_on(transport.send(begin(&transaction))).unwrap();
+            block_on(transport.send(ToServer::Commit { transaction }.into())).unwrap();
+        }
+        block_on(transport.send(begin("tx-1"))).unwrap();
+    }
+
+    #[test]
+    fn generated_transaction_ids_are_never_reused() {
+        use futures::executor::block_on;
+
+        let mut transport = mock_transport(b"");
+        let mut seen = HashSet::new();
+        for n in 0..2 * RECENT_TRANSACTIONS {
+            let transaction = transport.next_id(IdKind::Transaction);
+            assert!(seen.insert(transaction.clone()));
+            let begin = ToServer::Begin {
+                transaction: transaction.clone(),
+            };
+            block_on(transport.send(begin.into())).unwrap();
+            let end = if n % 2 == 0 {
+                ToServer::Commit { transaction }
+            } else {
+                ToServer::Abort { transaction }
+            };
+            block_on(transport.send(end.into())).unwrap();
+        }
+        assert_eq!(transport.open_transactions().count(), 0);
+    }
+
+    #[test]
+    fn failed_sends_are_not_tracked() {
+        use futures::executor::block_on;
+
+        let options = ConnectOptions::default();
+        let io = MockIo {
+            fail_writes: true,
+            ..MockIo::new(b"")
+        };
+        let mut transport = StompTransport::new(options.framed(io), &options);
+        let begin = Message::new(ToServer::Begin {
+            transaction: "tx-1".into(),
+        });
+        assert!(block_on(transport.send_ref(&begin)).is_err());
+        let subscription = subscribe("/queue/a", "1").receipt("r");
+        assert!(block_on(transport.send_ref(&subscription)).is_err());
+        assert_eq!(transport.open_transactions().count(), 0);
+        assert_eq!(transport.iter_subscriptions().count(), 0);
+        assert_eq!(transport.pending_receipts(), 0);
+    }
+
+    #[test]
+    fn failed_migration_keeps_current_broker() {
+        use futures::executor::block_on;
+
+        let options = ConnectOptions::default();
+        let io = MockIo::new(b"");
+        let written = io.written.clone();
+        let mut transport = StompTransport::new(options.framed(io), &options);
+        block_on(transport.send(subscribe("/queue/a", "1"))).unwrap();
+        let io = MockIo {
+            fail_writes: true,
+            ..MockIo::new(b"")
+        };
+        let new = StompTransport::new(options.framed(io), &options);
+        assert!(block_on(transport.migrate(new, Duration::from_secs(5))).is_err());
+        // the current broker was not told to disconnect
+        let written = String::from_utf8(written.lock().unwrap().clone()).unwrap();
+        assert!(written.starts_with("SUBSCRIBE\n"));
+        assert!(!written.contains("DISCONNECT"));
+        assert!(transport.closed().is_none());
+        assert_eq!(transport.iter_subscriptions().count(), 1);
+        block_on(transport.send(send_text("/queue/a", "still here"))).unwrap();
+    }
+
+    #[test]
+    fn allow_unknown_transactions() {
+        let options = ConnectOptions::new().allow_unknown_transactions(true);
+        let mut transport = mock_transport_with_options(b"", options);
+        futures::executor::block_on(transport.send(ack_in("tx-1"))).unwrap();
+    }
+
+    #[tokio::test]
+    async fn concurrent_producers_never_interleave_frames() {
+        use futures::channel::mpsc;
+        use tokio::io::AsyncReadExt;
+        use tokio::net::TcpListener;
+
+        const PER_PRODUCER: usize = 2000;
+
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            socket.set_recv_buffer_size(64).unwrap();
+            let mut received = BytesMut::new();
+            let mut buf = [0; 64];
+            let mut next_seq = [0; 2];
+            loop {
+                let n = socket.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    return next_seq;
+                }
+                received.extend_from_slice(&buf[..n]);
+                loop {
+                    let (offset, body) = match frame::parse_frame(&received) {
+                        Ok((remain, frame)) => match frame.to_client_msg().unwrap().content {
+                            ToServer::Send {
+                                body: Some(body), ..
+                            } => (remain.as_ptr() as usize - received.as_ptr() as usize, body),
+                            other => panic!("unexpected frame: {:?}", other),
+                        },
+                        Err(nom::Err::Incomplete(_)) => break,
+                        Err(e) => panic!("malformed frame: {:?}", e),
+                    };
+                    received.advance(offset);
+                    let body = String::from_utf8(body.into_owned()).unwrap();
+                    let mut parts = body.split('-').map(|p| p.parse::<usize>().unwrap());
+                    let (producer, seq) = (parts.next().unwrap(), parts.next().unwrap());
+                    assert_eq!(next_seq[producer], seq);
+                    next_seq[producer] += 1;
+                }
+            }
+        });
+
+        let tcp = TcpStream::connect(address).await.unwrap();
+        tcp.set_send_buffer_size(64).unwrap();
+        let options = ConnectOptions::default();
+        let transport = StompTransport::new(options.framed(tcp), &options);
+
+        let (tx, rx) = mpsc::channel(16);
+        for producer in 0..2 {
+            let mut tx = tx.clone();
+            tokio::spawn(async move {
+                for seq in 0..PER_PRODUCER {
+                    let msg = ToServer::Send {
+                        destination: "stress".into(),
+                        transaction: None,
+                        body: Some(format!("{}-{}", producer, seq).into_bytes().into()),
+                    };
+                    tx.send(msg.into()).await.unwrap();
+                }
+            });
+        }
+        drop(tx);
+        rx.map(Ok).forward(transport).await.unwrap();
+
+        assert_eq!(server.await.unwrap(), [PER_PRODUCER; 2]);
+    }
+
+    #[test]
+    fn content_length_mismatch() {
+        use futures::executor::block_on;
+
+        let input = b"MESSAGE\ndestination:a\nmessage-id:1\nsubscription:1\ncontent-length:4\n\n\
+                      12345678\x00RECEIPT\nreceipt-id:r\n\n\x00";
+
+        let mut transport = mock_transport(input);
+        let err = block_on(transport.next()).unwrap().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StompError>(),
+            Some(StompError::ContentLengthMismatch {
+                declared: 4,
+                found_nul_at: Some(8)
+            })
+        ));
+
+        let options = ConnectOptions::new().lenient_content_length(true);
+        let mut transport = mock_transport_with_options(input, options);
+        let msg = block_on(transport.next()).unwrap().unwrap();
+        match msg.content {
+            FromServer::Message { body, .. } => assert_eq!(body.unwrap(), b"12345678"),
+            other => panic!("unexpected message: {:?}", other),
+        }
+        let msg = block_on(transport.next()).unwrap().unwrap();
+        assert!(matches!(msg.content, FromServer::Receipt { .. }));
+    }
+
+    #[test]
+    fn header_case_policies() {
+        use futures::executor::block_on;
+
+        let input = b"MESSAGE\nDestination:/queue/a\nMessage-Id:7\nsubscription:1\n\
+            X-Trace:abc\nCONTENT-LENGTH:5\n\nab\x00cd\x00";
+
+        let mut transport = mock_transport(input);
+        let err = block_on(transport.next()).unwrap().unwrap_err();
+        assert!(format!("{:#}", err).contains("'destination' header: missing"));
+
+        let options = ConnectOptions::new().header_case(HeaderCase::Insensitive);
+        let mut transport = mock_transport_with_options(input, options);
+        let msg = block_on(transport.next()).unwrap().unwrap();
+        assert_eq!(
+            msg.body_delimitation(),
+            Some(crate::BodyDelimitation::ContentLength)
+        );
+        match msg.content {
+            FromServer::Message {
+                destination,
+                message_id,
+                body,
+                ..
+            } => {
+                assert_eq!(destination, "/queue/a");
+                assert_eq!(message_id, "7");
+                assert_eq!(body.unwrap(), b"ab\x00cd");
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+        let extra_headers: Vec<(&[u8], &[u8])> = msg
+            .extra_headers
+            .iter()
+            .map(|(k, v)| (&k[..], &v[..]))
+            .collect();
+        // the standard headers read from the extra headers are normalized as well
+        assert_eq!(
+            extra_headers,
+            [(&b"X-Trace"[..], &b"abc"[..]), (b"content-length", b"5")]
+        );
+
+        let input = b"MESSAGE\ndestination:/queue/a\nmessage-id:7\nsubscription:1\nAck:a-7\n\
+            Content-Type:text/plain\nRedelivered:true\nTimestamp:1000\n\n\x00";
+        let options = ConnectOptions::new().header_case(HeaderCase::Insensitive);
+        let mut transport = mock_transport_with_options(input, options);
+        let msg = block_on(transport.next()).unwrap().unwrap();
+        assert_eq!(msg.content_type(), Some(&b"text/plain"[..]));
+        let audit = msg.audit_record().unwrap();
+        assert_eq!(audit.ack_id.as_deref(), Some("a-7"));
+        assert!(audit.redelivered);
+        assert_eq!(audit.timestamp.as_deref(), Some("1000"));
+        let ack = transport.ack_message(&msg).unwrap();
+        assert!(matches!(ack.content, ToServer::Ack { ref id, .. } if id == "a-7"));
+        assert!(ack.extra_headers.is_empty());
+
+        // the content-length header is only honored under the insensitive policy
+        let input = b"MESSAGE\ndestination:/queue/a\nmessage-id:7\nsubscription:1\n\
+            CONTENT-LENGTH:5\n\nab\x00cd\x00";
+        let mut transport = mock_transport(input);
+        let msg = block_on(transport.next()).unwrap().unwrap();
+        assert_eq!(msg.body_as_str(), Some("ab"));
+    }
+
+    #[test]
+    fn header_continuations() {
+        use futures::executor::block_on;
+
+        let input = b"MESSAGE\ndestination:a\nmessage-id:1\nsubscription:1\n\
+                      x-route:bridge-1,\n  bridge-2\n\nhi\x00RECEIPT\nreceipt-id:r\n\n\x00";
+
+        let mut transport = mock_transport(input);
+        assert!(block_on(transport.next()).unwrap().is_err());
+
+        let options = ConnectOptions::new().allow_header_continuations(true);
+        let mut transport = mock_transport_with_options(input, options);
+        let msg = block_on(transport.next()).unwrap().unwrap();
+        assert_eq!(msg.body_as_str(), Some("hi"));
+        assert_eq!(
+            msg.extra_headers,
+            [(b"x-route".to_vec(), b"bridge-1,bridge-2".to_vec())]
+        );
+        let msg = block_on(transport.next()).unwrap().unwrap();
+        assert!(matches!(msg.content, FromServer::Receipt { .. }));
+
+        // a NUL inside the head ends the search for continuations
+        let mut codec = ClientCodec {
+            header_continuations: true,
+            ..Default::default()
+        };
+        let mut buffer = BytesMut::from(&b"\x00MESSAGE\nx:1\n      a\n\n"[..]);
+        assert!(codec.decode(&mut buffer).is_err());
+    }
+
+    #[test]
+    fn drive_through_poll_methods() {
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let io = MockIo::new(b"RECEIPT\nreceipt-id:r-1\n\n\x00");
+        let written = io.written.clone();
+        let options = ConnectOptions::new();
+        let mut transport = StompTransport::new(options.framed(io), &options);
+
+        assert!(transport.poll_send_ready(&mut cx).is_ready());
+        transport
+            .start_send(subscribe("/queue/a", "1").receipt("r-1"))
+            .unwrap();
+        assert!(written.lock().unwrap().is_empty());
+        match transport.poll_flush(&mut cx) {
+            Poll::Ready(Ok(())) => {}
+            other => panic!("flush did not complete: {:?}", other),
+        }
+        assert!(written.lock().unwrap().starts_with(b"SUBSCRIBE\n"));
+
+        match transport.poll_next_message(&mut cx) {
+            Poll::Ready(Some(Ok(msg))) => {
+                assert!(matches!(msg.content, FromServer::Receipt { .. }))
+            }
+            other => panic!("unexpected poll result: {:?}", other),
+        }
+        assert!(matches!(
+            transport.poll_next_message(&mut cx),
+            Poll::Ready(None)
+        ));
+    }
+
+    /// The CONNECT frame sent when connecting to `address` with `options`
+    fn connect_frame(address: &str, options: ConnectOptions) -> String {
+        use futures::executor::block_on;
+
+        let io = MockIo::new(b"CONNECTED\nversion:1.2\n\n\x00");
+        let written = io.written.clone();
+        block_on(connect_stream_with_options(io, address.into(), options)).unwrap();
+        let written = written.lock().unwrap();
+        String::from_utf8(written.clone()).unwrap()
+    }
+
+    #[test]
+    fn host_header_modes() {
+        let derived = connect_frame("10.0.0.1:61613", ConnectOptions::new());
+        assert_eq!(
+            derived,
+            "CONNECT\naccept-version:1.2\nhost:10.0.0.1\n\n\x00"
+        );
+
+        let options = ConnectOptions::new().virtual_host("/vhost");
+        let explicit = connect_frame("10.0.0.1:61613", options);
+        assert_eq!(explicit, "CONNECT\naccept-version:1.2\nhost:/vhost\n\n\x00");
+
+        let options = ConnectOptions::new().host_header(HostHeader::Omitted);
+        let omitted = connect_frame("10.0.0.1:61613", options);
+        assert_eq!(omitted, "CONNECT\naccept-version:1.2\n\n\x00");
+    }
+
+    #[test]
+    fn credentials_provider_per_connection() {
+        use futures::executor::block_on;
+        use futures::future::BoxFuture;
+
+        /// Mints a new token for every connection, failing once `fail` is set
+        #[derive(Debug, Default)]
+        struct Tokens {
+            minted: AtomicUsize,
+            fail: std::sync::atomic::AtomicBool,
+        }
+
+        impl CredentialsProvider for Tokens {
+            fn credentials(&self) -> BoxFuture<'_, Result<(Option<String>, Option<String>)>> {
+                Box::pin(async move {
+                    if self.fail.load(Ordering::SeqCst) {
+                        return Err(failure::err_msg("token service unavailable"));
+                    }
+                    let n = self.minted.fetch_add(1, Ordering::SeqCst) + 1;
+                    Ok((Some("svc".into()), Some(format!("token-{}", n))))
+                })
+            }
+        }
+
+        let tokens = Arc::new(Tokens::default());
+        let options = ConnectOptions::new()
+            .login("ignored")
+            .credentials_provider(tokens.clone());
+        for n in 1..=3 {
+            let frame = connect_frame("10.0.0.1:61613", options.clone());
+            let expected = format!(
+                "CONNECT\naccept-version:1.2\nhost:10.0.0.1\nlogin:svc\npasscode:token-{}\n\n\x00",
+                n
+            );
+            assert_eq!(frame, expected);
+        }
+
+        tokens.fail.store(true, Ordering::SeqCst);
+        let io = MockIo::new(b"CONNECTED\nversion:1.2\n\n\x00");
+        let written = io.written.clone();
+        let result = block_on(connect_stream_with_options(io, "a".into(), options));
+        let err = result.err().expect("the connection should fail");
+        assert_eq!(err.to_string(), "token service unavailable");
+        assert!(written.lock().unwrap().is_empty());
+
+        let options = ConnectOptions::new().login("user").passcode("secret");
+        assert_eq!(
+            connect_frame("10.0.0.1:61613", options),
+            "CONNECT\naccept-version:1.2\nhost:10.0.0.1\nlogin:user\npasscode:secret\n\n\x00"
+        );
+    }
+
+    #[test]
+    fn connect_debug_redacts_passcode() {
+        let connect = ToServer::Connect {
+            accept_version: "1.2".into(),
+            host: None,
+            login: Some("user".into()),
+            passcode: Some("secret".into()),
+            heartbeat: None,
+        };
+        let debug = format!("{:?}", connect);
+        assert!(debug.contains("passcode: Some(\"***\")"), "{}", debug);
+        assert!(!debug.contains("secret"));
+    }
+
+    #[test]
+    fn connect_options_debug_redacts_passcode() {
+        let options = ConnectOptions::new().login("user").passcode("secret");
+        let debug = format!("{:?}", options);
+        assert!(debug.contains("passcode: Some(\"***\")"), "{}", debug);
+        assert!(!debug.contains("secret"), "{}", debug);
+    }
+
+    #[test]
+    fn credentials_are_redacted() {
+        use futures::executor::block_on;
+
+        // the reply may echo the CONNECT frame
+        let replies: &[(&[u8], &str)] = &[
+            (
+                b"ERROR\nmessage:bad login\n\nCONNECT\npasscode:secret\n\x00",
+                "connection rejected: bad login",
+            ),
+            (
+                b"RECEIPT\nreceipt-id:passcode:secret\n\n\x00",
+                "unexpected reply to CONNECT: RECEIPT",
+            ),
+        ];
+        for (reply, expected) in replies {
+            let options = ConnectOptions::new().passcode("secret");
+            let err = block_on(connect_stream_with_options(
+                MockIo::new(reply),
+                "a".into(),
+                options,
+            ))
+            .err()
+            .unwrap();
+            assert_eq!(err.to_string(), *expected);
+        }
+
+        let logged = Arc::new(Mutex::new(vec![]));
+        let options = ConnectOptions::new()
+            .login("user")
+            .passcode("secret")
+            .connect_header("Authorization", "Bearer secret")
+            .log_frames({
+                let logged = logged.clone();
+                move |direction, frame| logged.lock().unwrap().push((direction, frame.to_string()))
+            });
+        let io = MockIo::new(b"CONNECTED\nversion:1.2\n\n\x00");
+        block_on(connect_stream_with_options(io, "a".into(), options.clone())).unwrap();
+        assert_eq!(
+            *logged.lock().unwrap(),
+            [
+                (
+                    FrameDirection::Sent,
+                    "CONNECT\n  accept-version : 1.2\n  host           : a\n  \
+                     login          : ***\n  passcode       : ***\n  Authorization  : ***"
+                        .to_string()
+                ),
+                (
+                    FrameDirection::Received,
+                    "CONNECTED\n  version : 1.2".to_string()
+                ),
+            ]
+        );
+
+        logged.lock().unwrap().clear();
+        let options = options.redact_headers(vec!["passcode"]);
+        let io = MockIo::new(b"CONNECTED\nversion:1.2\n\n\x00");
+        block_on(connect_stream_with_options(io, "a".into(), options)).unwrap();
+        let (_, connect) = &logged.lock().unwrap()[0];
+        assert!(connect.contains(
+            "login          : user\n  passcode       : ***\n  Authorization  : Bearer secret"
+        ));
+    }
+
+    #[tokio::test]
+    async fn receipts_arrive_without_polling_the_stream() {
+        use crate::test_utils::StompRouter;
+
+        let router = StompRouter::start().await.unwrap();
+        let mut transport = router.connect().await.unwrap();
+        let mut publisher = router.connect().await.unwrap();
+        // the task awaits receipts and nothing else, while messages arrive ahead of them
+        let waiting = tokio::spawn(async move {
+            let timeout = Duration::from_secs(5);
+            let id = transport
+                .subscribe_confirmed(subscribe("/queue/a", "1"), timeout)
+                .await
+                .unwrap();
+            publisher
+                .send(send_text("/queue/a", "first").receipt("sent"))
+                .await
+                .unwrap();
+            publisher.next().await.unwrap().unwrap();
+            let msg = send_text("/queue/a", "second").receipt("replaced");
+            transport.send_with_receipt(msg, timeout).await.unwrap();
+            transport.ping(timeout).await.unwrap();
+            (id, transport)
+        });
+        let (id, mut transport) = time::timeout(Duration::from_secs(10), waiting)
+            .await
+            .expect("a receipt was never received")
+            .unwrap();
+        assert_eq!(id, "1");
+        for expected in &["first", "second"] {
+            let msg = transport.next().await.unwrap().unwrap();
+            assert_eq!(msg.body_as_str(), Some(*expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn connection_age() {
+        let before = Instant::now();
+        let io = MockIo::new(b"CONNECTED\nversion:1.2\n\n\x00");
+        let transport = connect_stream_with_options(io, "a".into(), ConnectOptions::new())
+            .await
+            .unwrap();
+        let connected_at = transport.connected_at();
+        assert!(connected_at >= before && connected_at <= Instant::now());
+
+        time::delay_for(Duration::from_millis(20)).await;
+        assert!(transport.connection_age() >= Duration::from_millis(20));
+        assert!(transport.connection_age() <= before.elapsed());
+        assert_eq!(transport.connected_at(), connected_at);
+    }
+
+    #[test]
+    fn extra_connect_headers() {
+        let options = ConnectOptions::new()
+            .login("guest")
+            .connect_header("client-id", "service-1")
+            .connect_header("activate-session", "true");
+        let frame = connect_frame("10.0.0.1:61613", options);
+        assert_eq!(
+            frame,
+            "CONNECT\naccept-version:1.2\nhost:10.0.0.1\nlogin:guest\n\
+             client-id:service-1\nactivate-session:true\n\n\x00"
+        );
+    }
+
+    #[test]
+    fn percent_encoded_credentials() {
+        let options = ConnectOptions::new()
+            .login("user@example.com")
+            .passcode("p:ss w%rd")
+            .credential_encoding(CredentialEncoding::PercentEncoded);
+        let frame = connect_frame("10.0.0.1:61613", options);
+        assert_eq!(
+            frame,
+            "CONNECT\naccept-version:1.2\nhost:10.0.0.1\nlogin:user%40example.com\n\
+             passcode:p%3Ass%20w%25rd\n\n\x00"
+        );
+
+        let (_, parsed) = frame::parse_frame(frame.as_bytes()).unwrap();
+        match parsed.to_client_msg().unwrap().content {
+            ToServer::Connect {
+                login: Some(login),
+                passcode: Some(passcode),
+                ..
+            } => {
+                let encoding = CredentialEncoding::PercentEncoded;
+                assert_eq!(encoding.decode(&login).unwrap(), "user@example.com");
+                assert_eq!(encoding.decode(&passcode).unwrap(), "p:ss w%rd");
+            }
+            other => panic!("unexpected frame {:?}", other),
+        }
+    }
+
+    #[test]
+    fn strip_port_from_address() {
+        assert_eq!(strip_port("broker.example.com:61613"), "broker.example.com");
+        assert_eq!(strip_port("broker.example.com"), "broker.example.com");
+        assert_eq!(strip_port("[::1]:61613"), "[::1]");
+        assert_eq!(strip_port("::1"), "::1");
+        assert_eq!(strip_port("fe80::1:61613"), "fe80::1:61613");
+        assert_eq!(strip_port("localhost:http"), "localhost:http");
+    }
+
+    /// Connect to a server speaking `version`, subscribe with `mode` and receive a message
+    fn received_message(
+        version: &str,
+        mode: Option<AckMode>,
+        options: ConnectOptions,
+    ) -> (StompTransport<MockIo>, Message<FromServer>) {
+        use futures::executor::block_on;
+
+        let ack_header = if version == "1.2" { "ack:a-1\n" } else { "" };
+        let input = format!(
+            "CONNECTED\nversion:{}\n\n\x00\
+             MESSAGE\ndestination:/queue/a\nmessage-id:m-1\nsubscription:1\n{}\n\x00",
+            version, ack_header
+        );
+        let mut transport = block_on(connect_stream_with_options(
+            MockIo::new(input.as_bytes()),
+            "localhost".into(),
+            options,
+        ))
+        .unwrap();
+        let subscribe = match mode {
+            Some(mode) => subscribe_with_ack("/queue/a", "1", mode),
+            None => subscribe("/queue/a", "1"),
+        };
+        block_on(transport.send(subscribe)).unwrap();
+        let msg = block_on(transport.next()).unwrap().unwrap();
+        (transport, msg)
+    }
+
+    fn is_ack_not_applicable(err: &failure::Error) -> bool {
+        matches!(
+            err.downcast_ref::<StompError>(),
+            Some(StompError::AckNotApplicable(_))
+        )
+    }
+
+    #[test]
+    fn ack_checks_subscription_mode() {
+        let modes = [
+            (None, false),
+            (Some(AckMode::Auto), false),
+            (Some(AckMode::Client), true),
+            (Some(AckMode::ClientIndividual), true),
+        ];
+        for &(mode, applicable) in &modes {
+            let (transport, msg) = received_message("1.2", mode, ConnectOptions::new());
+            let ack = transport.ack_message(&msg);
+            let nack = transport.nack_message(&msg);
+            if applicable {
+                let ack = encode_to_string(&ack.unwrap());
+                assert_eq!(ack, "ACK\\nid:a-1\\n\\n\\x00");
+                let nack = encode_to_string(&nack.unwrap());
+                assert_eq!(nack, "NACK\\nid:a-1\\n\\n\\x00");
+            } else {
+                assert!(is_ack_not_applicable(&ack.unwrap_err()));
+                assert!(is_ack_not_applicable(&nack.unwrap_err()));
+            }
+        }
+
+        let options = ConnectOptions::new().allow_inapplicable_acks(true);
+        let (transport, msg) = received_message("1.2", Some(AckMode::Auto), options);
+        transport.ack_message(&msg).unwrap();
+    }
+
+    /// A transport which has just unsubscribed from subscription 1,
+    /// and then receives a MESSAGE for it followed by a RECEIPT
+    fn unsubscribe_race(
+        policy: OrphanPolicy,
+        ack: AckMode,
+    ) -> (StompTransport<MockIo>, Arc<Mutex<Vec<u8>>>) {
+        use futures::executor::block_on;
+
+        let io = MockIo::new(
+            b"MESSAGE\ndestination:/queue/a\nmessage-id:m-1\nsubscription:1\nack:a-1\n\n\x00\
+              RECEIPT\nreceipt-id:r-1\n\n\x00",
+        );
+        let written = io.written.clone();
+        let options = ConnectOptions::new().orphan_policy(policy);
+        let mut transport = StompTransport::new(options.framed(io), &options);
+        block_on(transport.send(subscribe_with_ack("/queue/a", "1", ack))).unwrap();
+        block_on(transport.send(ToServer::Unsubscribe { id: "1".into() }.into())).unwrap();
+        written.lock().unwrap().clear();
+        (transport, written)
+    }
+
+    #[test]
+    fn orphaned_messages_follow_policy() {
+        use futures::executor::block_on;
+
+        let is_receipt = |item: Option<Result<Message<FromServer>>>| {
+            matches!(
+                item,
+                Some(Ok(Message {
+                    content: FromServer::Receipt { .. },
+                    ..
+                }))
+            )
+        };
+
+        let (mut transport, written) = unsubscribe_race(OrphanPolicy::Deliver, AckMode::Client);
+        let msg = block_on(transport.next()).unwrap().unwrap();
+        assert!(matches!(msg.content, FromServer::Message { .. }));
+        assert_eq!(transport.orphaned_messages(), 1);
+        assert!(is_receipt(block_on(transport.next())));
+        assert!(written.lock().unwrap().is_empty());
+
+        let (mut transport, written) = unsubscribe_race(OrphanPolicy::Nack, AckMode::Client);
+        assert!(is_receipt(block_on(transport.next())));
+        assert_eq!(transport.orphaned_messages(), 1);
+        assert_eq!(&written.lock().unwrap()[..], &b"NACK\nid:a-1\n\n\x00"[..]);
+
+        // messages which were acknowledged automatically are skipped without a NACK
+        let (mut transport, written) = unsubscribe_race(OrphanPolicy::Nack, AckMode::Auto);
+        assert!(is_receipt(block_on(transport.next())));
+        assert_eq!(transport.orphaned_messages(), 1);
+        assert!(written.lock().unwrap().is_empty());
+
+        let (mut transport, written) = unsubscribe_race(OrphanPolicy::Error, AckMode::Client);
+        let err = block_on(transport.next()).unwrap().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StompError>(),
+            Some(StompError::UnknownSubscription(id)) if id == "1"
+        ));
+        assert_eq!(transport.orphaned_messages(), 1);
+        assert!(is_receipt(block_on(transport.next())));
+        assert!(written.lock().unwrap().is_empty());
+    }
+
+    fn throttled_transport(ack: AckMode) -> (ThrottledTransport<MockIo>, Arc<Mutex<Vec<u8>>>) {
+        use futures::executor::block_on;
+
+        let io = MockIo::new(
+            b"MESSAGE\ndestination:/queue/a\nmessage-id:m-1\nsubscription:1\nack:a-1\n\n\x00\
+              MESSAGE\ndestination:/queue/a\nmessage-id:m-2\nsubscription:1\nack:a-2\n\n\x00\
+              RECEIPT\nreceipt-id:r-1\n\n\x00",
+        );
+        let written = io.written.clone();
+        let options = ConnectOptions::new();
+        let mut transport = StompTransport::new(options.framed(io), &options);
+        block_on(transport.send(subscribe_with_ack("/queue/a", "1", ack))).unwrap();
+        written.lock().unwrap().clear();
+        (transport.throttle(10.0), written)
+    }
+
+    fn message_id_of(msg: &Message<FromServer>) -> &str {
+        match &msg.content {
+            FromServer::Message { message_id, .. } => message_id,
+            _ => panic!("expected a MESSAGE, got {:?}", msg),
+        }
+    }
+
+    #[tokio::test]
+    async fn throttle_nacks_messages_over_rate() {
+        let (mut transport, written) = throttled_transport(AckMode::ClientIndividual);
+        let first = transport.next().await.unwrap().unwrap();
+        assert_eq!(message_id_of(&first), "m-1");
+        let receipt = transport.next().await.unwrap().unwrap();
+        assert!(matches!(receipt.content, FromServer::Receipt { .. }));
+        assert_eq!(transport.nacked_messages(), 1);
+        assert_eq!(&written.lock().unwrap()[..], &b"NACK\nid:a-2\n\n\x00"[..]);
+    }
+
+    #[tokio::test]
+    async fn throttle_delays_messages_over_rate() {
+        let (mut transport, written) = throttled_transport(AckMode::Client);
+        let start = Instant::now();
+        let first = transport.next().await.unwrap().unwrap();
+        assert_eq!(message_id_of(&first), "m-1");
+        let second = transport.next().await.unwrap().unwrap();
+        assert_eq!(message_id_of(&second), "m-2");
+        assert!(start.elapsed() >= Duration::from_millis(90));
+        assert_eq!(transport.nacked_messages(), 0);
+        assert!(written.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn messages_for_open_subscriptions_are_not_orphans() {
+        use futures::executor::block_on;
+
+        let (transport, msg) =
+            received_message("1.2", Some(AckMode::Client), ConnectOptions::new());
+        assert!(matches!(msg.content, FromServer::Message { .. }));
+        assert_eq!(transport.orphaned_messages(), 0);
+
+        let options = ConnectOptions::new().orphan_policy(OrphanPolicy::Error);
+        let mut transport = mock_transport_with_options(
+            b"MESSAGE\ndestination:/queue/a\nmessage-id:m-1\nsubscription:2\n\n\x00",
+            options,
+        );
+        block_on(transport.send(subscribe("/queue/a", "2"))).unwrap();
+        block_on(transport.next()).unwrap().unwrap();
+        assert_eq!(transport.orphaned_messages(), 0);
+    }
+
+    #[test]
+    fn send_helpers_set_content_type() {
+        assert_eq!(
+            encode_to_string(&send_text("/queue/a", "hi")),
+            "SEND\\ndestination:/queue/a\\ncontent-type:text/plain;charset=UTF-8\\n\
+             content-length:2\\n\\nhi\\x00"
+        );
+        assert_eq!(
+            encode_to_string(&send_bytes("/queue/a", &b"hi"[..])),
+            "SEND\\ndestination:/queue/a\\ncontent-type:application/octet-stream\\n\
+             content-length:2\\n\\nhi\\x00"
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn send_json_serializes_body() {
+        let msg = send_json("/queue/a", &serde_json::json!({ "code": 42 })).unwrap();
+        assert_eq!(
+            encode_to_string(&msg),
+            "SEND\\ndestination:/queue/a\\ncontent-type:application/json\\n\
+             content-length:11\\n\\n{\\\"code\\\":42}\\x00"
+        );
+    }
+
+    #[test]
+    fn ack_decorators_apply_per_subscription() {
+        use futures::executor::block_on;
+
+        let mut transport = mock_transport(
+            b"MESSAGE\ndestination:/queue/a\nmessage-id:m-1\nsubscription:1\n\n\x00\
+              MESSAGE\ndestination:/queue/b\nmessage-id:m-2\nsubscription:2\n\n\x00",
+        );
+        for (dest, id) in &[("/queue/a", "1"), ("/queue/b", "2")] {
+            let subscribe = subscribe_with_ack(*dest, *id, AckMode::Client);
+            block_on(transport.send(subscribe)).unwrap();
+        }
+        transport.decorate_acks("1", |msg| match &msg.content {
+            FromServer::Message { message_id, .. } => vec![("x-audit".into(), message_id.clone())],
+            _ => vec![],
+        });
+
+        let first = block_on(transport.next()).unwrap().unwrap();
+        let second = block_on(transport.next()).unwrap().unwrap();
+        let ack = encode_to_string(&transport.ack_message(&first).unwrap());
+        assert_eq!(
+            ack,
+            "ACK\\nid:m-1\\nsubscription:1\\nx-audit:m-1\\n\\n\\x00"
+        );
+        let nack = encode_to_string(&transport.nack_message(&first).unwrap());
+        assert_eq!(
+            nack,
+            "NACK\\nid:m-1\\nsubscription:1\\nx-audit:m-1\\n\\n\\x00"
+        );
+        let ack = encode_to_string(&transport.ack_message(&second).unwrap());
+        assert_eq!(ack, "ACK\\nid:m-2\\nsubscription:2\\n\\n\\x00");
+
+        block_on(transport.send(ToServer::Unsubscribe { id: "1".into() }.into())).unwrap();
+        let ack = encode_to_string(&transport.ack_message(&first).unwrap());
+        assert_eq!(ack, "ACK\\nid:m-1\\nsubscription:1\\n\\n\\x00");
+    }
+
+    #[test]
+    fn ack_checks_protocol_version() {
+        let (transport, msg) =
+            received_message("1.1", Some(AckMode::Client), ConnectOptions::new());
+        let ack = encode_to_string(&transport.ack_message(&msg).unwrap());
+        assert_eq!(ack, "ACK\\nid:m-1\\nsubscription:1\\n\\n\\x00");
+        transport.nack_message(&msg).unwrap();
+
+        let (transport, msg) =
+            received_message("1.0", Some(AckMode::Client), ConnectOptions::new());
+        transport.ack_message(&msg).unwrap();
+        let err = transport.nack_message(&msg).unwrap_err();
+        assert!(is_ack_not_applicable(&err));
+
+        let options = ConnectOptions::new().allow_inapplicable_acks(true);
+        let (transport, msg) = received_message("1.0", Some(AckMode::Client), options);
+        transport.nack_message(&msg).unwrap();
+    }
+
+    #[test]
+    fn subscribe_ack_headers() {
+        let serialize = |msg: Message<ToServer>| {
+            let mut buffer = BytesMut::new();
+            msg.to_frame().serialize(&mut buffer);
+            String::from_utf8(buffer.to_vec()).unwrap()
+        };
+        let modes = [
+            (AckMode::Auto, "auto"),
+            (AckMode::Client, "client"),
+            (AckMode::ClientIndividual, "client-individual"),
+        ];
+        for &(mode, header) in &modes {
+            let msg = subscribe_with_ack("/queue/a", "1", mode).header("x-extra", "1");
+            assert_eq!(
+                serialize(msg),
+                format!(
+                    "SUBSCRIBE\ndestination:/queue/a\nid:1\nack:{}\nx-extra:1\n\n\x00",
+                    header
+                )
+            );
+        }
+        assert_eq!(serialize(ack("msg-1")), "ACK\nid:msg-1\n\n\x00");
+    }
+
+    #[test]
+    fn send_ref_matches_send() {
+        use futures::executor::block_on;
+
+        let message = || {
+            SendBuilder::new("/queue/a")
+                .body_bytes(vec![7; 1024])
+                .build()
+        };
+        let mut msg = message();
+        let expected = {
+            let io = MockIo::default();
+            let written = io.written.clone();
+            let options = ConnectOptions::new();
+            let mut transport = StompTransport::new(options.framed(io), &options);
+            block_on(transport.send(subscribe("/queue/replies", "1"))).unwrap();
+            block_on(transport.send(message())).unwrap();
+            let written = written.lock().unwrap().clone();
+            written
+        };
+
+        let io = MockIo::default();
+        let written = io.written.clone();
+        let options = ConnectOptions::new();
+        let mut transport = StompTransport::new(options.framed(io), &options);
+        // frames queued via the sink are written first
+        block_on(transport.feed(subscribe("/queue/replies", "1"))).unwrap();
+        block_on(transport.send_ref(&msg)).unwrap();
+        assert_eq!(*written.lock().unwrap(), expected);
+
+        if let ToServer::Send { destination, .. } = &mut msg.content {
+            *destination = "/queue/b".into();
+        }
+        block_on(transport.send_ref(&msg)).unwrap();
+        let written = written.lock().unwrap();
+        let second = String::from_utf8_lossy(&written[expected.len()..]);
+        assert!(second.starts_with("SEND\ndestination:/queue/b\n"));
+    }
+
+    fn dialect_after_handshake(connected: &[u8], options: ConnectOptions) -> String {
+        let transport = futures::executor::block_on(connect_stream_with_options(
+            MockIo::new(connected),
+            "localhost".into(),
+            options,
+        ))
+        .unwrap();
+        format!("{:?}", transport.dialect())
+    }
+
+    #[test]
+    fn detect_flavor_from_connected() {
+        let frames: &[(&[u8], &str)] = &[
+            (
+                b"CONNECTED\nserver:ActiveMQ/5.17.0\nheart-beat:0,0\n\
+                  session:ID:broker-40591-1650000000000-3:1\nversion:1.2\n\n\x00",
+                "ActiveMQ",
+            ),
+            (
+                b"CONNECTED\nversion:1.2\nsession:5a2e4d3b\n\
+                  server:ActiveMQ-Artemis/2.31.2 ActiveMQ Artemis Messaging Engine\n\
+                  heart-beat:0,0\n\n\x00",
+                "Artemis",
+            ),
+            (
+                b"CONNECTED\nserver:RabbitMQ/3.12.0\nsession:session-Xo7hJ4JqOi6F0KSb5d4l5g\n\
+                  heart-beat:0,0\nversion:1.2\n\n\x00",
+                "RabbitMQ",
+            ),
+            (
+                b"CONNECTED\nsession:session-Xo7hJ4JqOi6F0KSb5d4l5g\nversion:1.2\n\n\x00",
+                "RabbitMQ",
+            ),
+            (
+                b"CONNECTED\nversion:1.2\nserver:Acme/1.0\n\n\x00",
+                "Generic",
+            ),
+            (b"CONNECTED\nversion:1.2\n\n\x00", "Generic"),
+        ];
+        for &(connected, flavor) in frames {
+            assert_eq!(
+                dialect_after_handshake(connected, ConnectOptions::new()),
+                flavor
+            );
+        }
+
+        // an explicit flavor is never overridden
+        let options = ConnectOptions::new().flavor(BrokerFlavor::Generic);
+        assert_eq!(dialect_after_handshake(frames[0].0, options), "Generic");
+    }
+
+    #[test]
+    fn capabilities_from_connected() {
+        let connected = b"CONNECTED\nversion:1.2\nserver:RabbitMQ/3.12.0\n\
+                          cluster-name:rabbit@node-1\n\n\x00";
+        let transport = futures::executor::block_on(connect_stream_with_options(
+            MockIo::new(connected),
+            "localhost".into(),
+            ConnectOptions::new(),
+        ))
+        .unwrap();
+        let capabilities = transport.connection_details().capabilities();
+        assert_eq!(capabilities.name.as_deref(), Some("RabbitMQ"));
+        assert_eq!(capabilities.version.as_deref(), Some("3.12.0"));
+        assert_eq!(capabilities.comment, None);
+        assert_eq!(
+            capabilities.extra("cluster-name"),
+            Some(&b"rabbit@node-1"[..])
+        );
+        assert_eq!(capabilities.extra("server"), None);
+    }
+
+    #[test]
+    fn frames_sent_with_connected_are_kept() {
+        use futures::executor::block_on;
+
+        let input = b"CONNECTED\nversion:1.2\n\n\x00\
+                      MESSAGE\ndestination:motd\nmessage-id:1\nsubscription:0\n\nhello\x00";
+        let mut transport = block_on(connect_stream_with_options(
+            MockIo::new(input),
+            "localhost".into(),
+            ConnectOptions::new(),
+        ))
+        .unwrap();
+        let msg = block_on(transport.next()).unwrap().unwrap();
+        match msg.content {
+            FromServer::Message { destination, .. } => assert_eq!(destination, "motd"),
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn connection_details_keep_extra_headers() {
+        let connected = b"CONNECTED\nversion:1.2\nserver:Acme/1.0\nx-cluster:eu-1\n\n\x00";
+        let transport = futures::executor::block_on(connect_stream_with_options(
+            MockIo::new(connected),
+            "localhost".into(),
+            ConnectOptions::new(),
+        ))
+        .unwrap();
+        let details = transport.connection_details();
+        assert_eq!(details.version, "1.2");
+        assert_eq!(details.server.as_deref(), Some("Acme/1.0"));
+        assert_eq!(
+            details.extra_headers,
+            vec![(b"x-cluster".to_vec(), b"eu-1".to_vec())]
+        );
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn stats_count_bytes() {
+        use futures::executor::block_on;
+
+        let connected = b"CONNECTED\nversion:1.2\n\n\x00";
+        let mut transport = mock_transport(connected);
+        block_on(transport.next()).unwrap().unwrap();
+        assert_eq!(transport.stats().bytes_received, connected.len() as u64);
+
+        let msg = subscribe("/queue/a", "1");
+        let mut expected = BytesMut::new();
+        msg.to_frame().serialize(&mut expected);
+        block_on(transport.send(msg)).unwrap();
+        let stats = transport.stats();
+        assert_eq!(stats.bytes_sent, expected.len() as u64);
+        assert!(stats.sent_commands().contains(&("SUBSCRIBE", 1)));
+        assert!(stats.received_commands().contains(&("CONNECTED", 1)));
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn stats_use_configured_buckets() {
+        use futures::executor::block_on;
+
+        let options = ConnectOptions::new().frame_size_buckets(vec![40, 1000]);
+        let mut transport = mock_transport_with_options(b"", options);
+        block_on(transport.send(subscribe("/queue/a", "1"))).unwrap();
+        let body = SendBuilder::new("/queue/a").body_bytes(vec![0; 2000]);
+        block_on(transport.send(body.build())).unwrap();
+        block_on(transport.send(subscribe("/queue/b", "2"))).unwrap();
+
+        let sizes = transport.stats().sent_sizes();
+        assert_eq!(sizes.buckets, vec![40, 1000]);
+        assert_eq!(sizes.counts, vec![2, 0, 1]);
+        transport.reset_stats();
+        assert_eq!(transport.stats().sent_sizes().counts, vec![0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn split_halves_work_in_separate_tasks() {
+        use tokio::io::AsyncReadExt;
+        use tokio::net::TcpListener;
+
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let read = socket.read(&mut buf).await.unwrap();
+            assert!(buf[..read].starts_with(b"CONNECT\n"));
+            socket
+                .write_all(b"CONNECTED\nversion:1.2\n\n\x00")
+                .await
+                .unwrap();
+            let read = socket.read(&mut buf).await.unwrap();
+            assert!(buf[..read].starts_with(b"SUBSCRIBE\n"));
+            socket
+                .write_all(b"MESSAGE\ndestination:a\nmessage-id:1\nsubscription:1\n\n\x00")
+                .await
+                .unwrap();
+        });
+
+        let (mut stream, mut sink) = connect_split(address, ConnectOptions::new()).await.unwrap();
+        let receiver = tokio::spawn(async move { stream.next().await.unwrap().unwrap() });
+        tokio::spawn(async move { sink.send(subscribe("a", "1")).await.unwrap() })
+            .await
+            .unwrap();
+        let msg = receiver.await.unwrap();
+        assert!(matches!(msg.content, FromServer::Message { .. }));
+        server.await.unwrap();
+    }
+
+    /// A transport connected to a server which answers each frame requesting a receipt
+    /// after `delay`, preceded by a MESSAGE. Returns the frames the server received.
+    async fn delayed_receipt_server(
+        options: ConnectOptions,
+        delay: Duration,
+    ) -> (
+        StompTransport<TcpStream>,
+        tokio::task::JoinHandle<Vec<String>>,
+    ) {
+        use tokio::io::AsyncReadExt;
+        use tokio::net::TcpListener;
+
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut received = BytesMut::new();
+            let mut frames = vec![];
+            let mut buf = [0; 1024];
+            loop {
+                let n = socket.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    return frames;
+                }
+                received.extend_from_slice(&buf[..n]);
+                while let Ok((remain, frame)) = frame::parse_frame(&received) {
+                    let offset = remain.as_ptr() as usize - received.as_ptr() as usize;
+                    let msg = frame.to_client_msg().unwrap();
+                    frames.push(encode_to_string(&msg));
+                    let receipt = msg.extra_headers.iter().find(|(k, _)| k == b"receipt");
+                    if let Some((_, receipt)) = receipt {
+                        let receipt = String::from_utf8_lossy(receipt);
+                        time::delay_for(delay).await;
+                        let reply = format!(
+                            "MESSAGE\ndestination:a\nmessage-id:1\nsubscription:1\n\n\x00\
+                             RECEIPT\nreceipt-id:{}\n\n\x00",
+                            receipt
+                        );
+                        socket.write_all(reply.as_bytes()).await.unwrap();
+                    }
+                    received.advance(offset);
+                }
+            }
+        });
+        let tcp = TcpStream::connect(&address).await.unwrap();
+        let transport = StompTransport::new(options.framed(tcp), &options);
+        (transport, server)
+    }
+
+    #[tokio::test]
+    async fn ping_measures_round_trip() {
+        let delay = Duration::from_millis(50);
+        let (mut transport, server) = delayed_receipt_server(ConnectOptions::new(), delay).await;
+        let rtt = transport.ping(Duration::from_secs(5)).await.unwrap();
+        assert!(rtt >= delay);
+        // the MESSAGE which arrived before the receipt is still delivered
+        let msg = transport.next().await.unwrap().unwrap();
+        assert!(matches!(msg.content, FromServer::Message { .. }));
+        drop(transport);
+        let frames = server.await.unwrap();
+        assert_eq!(frames.len(), 2);
+        assert!(frames[0].starts_with("BEGIN\\ntransaction:tx-"));
+        assert!(frames[1].starts_with("ABORT\\ntransaction:tx-"));
+        assert!(frames[1].contains("\\nreceipt:rcpt-"));
+
+        let options = ConnectOptions::new().ping_strategy(PingStrategy::Send {
+            destination: "/queue/ping".into(),
+        });
+        let (mut transport, server) = delayed_receipt_server(options, delay).await;
+        assert!(transport.ping(Duration::from_secs(5)).await.unwrap() >= delay);
+        drop(transport);
+        let frames = server.await.unwrap();
+        assert_eq!(frames.len(), 1);
+        assert!(frames[0].starts_with("SEND\\ndestination:/queue/ping\\nreceipt:rcpt-"));
+    }
+
+    #[tokio::test]
+    async fn ping_times_out() {
+        let delay = Duration::from_millis(500);
+        let (mut transport, _) = delayed_receipt_server(ConnectOptions::new(), delay).await;
+        let err = transport.ping(Duration::from_millis(50)).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StompError>(),
+            Some(StompError::RecvElapsed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn drain_and_disconnect_settles_remaining_messages() {
+        use crate::test_utils::StompRouter;
+
+        let router = StompRouter::start().await.unwrap();
+        let mut transport = router.connect().await.unwrap();
+        transport
+            .send(subscribe_with_ack("/queue/a", "1", AckMode::ClientIndividual).receipt("r"))
+            .await
+            .unwrap();
+        transport.next().await.unwrap().unwrap();
+
+        // messages still in flight when draining starts
+        let mut publisher = router.connect().await.unwrap();
+        for body in &["one", "two"] {
+            publisher
+                .send(send_text("/queue/a", *body).receipt("sent"))
+                .await
+                .unwrap();
+            publisher.next().await.unwrap().unwrap();
+        }
+
+        let mut handled = vec![];
+        transport
+            .drain_and_disconnect(Duration::from_secs(5), |msg| {
+                let body = msg.body_as_str().unwrap().to_string();
+                handled.push(body.clone());
+                async move {
+                    if body == "two" {
+                        failure::bail!("cannot handle {}", body);
+                    }
+                    Ok(())
+                }
+            })
+            .await
+            .unwrap();
+        assert_eq!(handled, ["one", "two"]);
+        assert_eq!(transport.orphaned_messages(), 0);
+
+        // the subscription is closed before the messages are settled and the broker is left
+        let received = router.received(0);
+        assert_eq!(received.len(), 6);
+        assert!(matches!(received[2].content, ToServer::Unsubscribe { .. }));
+        assert!(matches!(received[3].content, ToServer::Ack { .. }));
+        assert!(matches!(received[4].content, ToServer::Nack { .. }));
+        assert!(matches!(received[5].content, ToServer::Disconnect { .. }));
+
+        // without the receipts, draining stops at the timeout, before disconnecting
+        router.withhold_receipts(true);
+        let mut transport = router.connect().await.unwrap();
+        let subscribe = subscribe_with_ack("/queue/a", "2", AckMode::ClientIndividual);
+        transport.send(subscribe).await.unwrap();
+        let err = transport
+            .drain_and_disconnect(Duration::from_millis(100), |_| async { Ok(()) })
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StompError>(),
+            Some(StompError::RecvElapsed)
+        ));
+        let last = router.received(2).pop().unwrap();
+        assert!(matches!(last.content, ToServer::Unsubscribe { .. }));
+    }
+
+    /// The SEND frames received by the router from its `connection`th client, once the
+    /// last of them is `marker`
+    async fn sent_before(
+        router: &crate::test_utils::StompRouter,
+        connection: usize,
+        marker: &str,
+    ) -> Vec<Message<ToServer>> {
+        loop {
+            let sent: Vec<_> = router
+                .received(connection)
+                .into_iter()
+                .filter(|msg| matches!(msg.content, ToServer::Send { .. }))
+                .collect();
+            let ended = match sent.last().map(|msg| &msg.content) {
+                Some(ToServer::Send {
+                    body: Some(body), ..
+                }) => body[..] == *marker.as_bytes(),
+                _ => false,
+            };
+            if ended {
+                return sent;
+            }
+            time::delay_for(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn pending_receipts_are_capped() {
+        use crate::test_utils::StompRouter;
+
+        let router = StompRouter::start().await.unwrap();
+        router.withhold_receipts(true);
+        let timeout = Duration::from_millis(50);
+        let options = ConnectOptions::new()
+            .max_pending_receipts(2)
+            .receipt_limit_policy(ReceiptLimitPolicy::Fail);
+        let mut transport = connect_with_options(router.address(), options)
+            .await
+            .unwrap();
+        for receipt in &["r-1", "r-2"] {
+            transport
+                .send(send_text("/queue/a", "x").receipt(*receipt))
+                .await
+                .unwrap();
+        }
+        assert_eq!(transport.pending_receipts(), 2);
+        let err = transport
+            .send_with_receipt(send_text("/queue/a", "x"), Duration::from_secs(5))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StompError>(),
+            Some(StompError::TooManyPendingReceipts { limit: 2 })
+        ));
+        #[cfg(feature = "metrics")]
+        {
+            let stats = transport.stats();
+            assert_eq!(stats.pending_receipts, 2);
+            assert_eq!(stats.pending_receipts_high_water, 2);
+            assert_eq!(stats.max_pending_receipts, Some(2));
+        }
+        // the third SEND was never written
+        transport.send(send_text("/queue/a", "end")).await.unwrap();
+        assert_eq!(sent_before(&router, 0, "end").await.len(), 3);
+
+        // by default, sending waits for a receipt to arrive
+        let options = ConnectOptions::new().max_pending_receipts(1);
+        let mut transport = connect_with_options(router.address(), options.clone())
+            .await
+            .unwrap();
+        transport
+            .send(send_text("/queue/a", "x").receipt("r-1"))
+            .await
+            .unwrap();
+        let started = Instant::now();
+        let err = transport
+            .send_with_receipt(send_text("/queue/a", "y"), timeout)
+            .await
+            .unwrap_err();
+        assert!(started.elapsed() >= timeout);
+        assert!(matches!(
+            err.downcast_ref::<StompError>(),
+            Some(StompError::RecvElapsed)
+        ));
+        assert_eq!(transport.pending_receipts(), 1);
+        transport.send(send_text("/queue/a", "end")).await.unwrap();
+        assert_eq!(sent_before(&router, 1, "end").await.len(), 2);
+
+        // a receipt which timed out no longer holds its place
+        let mut transport = connect_with_options(router.address(), options)
+            .await
+            .unwrap();
+        let err = transport
+            .send_with_receipt(send_text("/queue/a", "x"), timeout)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StompError>(),
+            Some(StompError::RecvElapsed)
+        ));
+        assert_eq!(transport.pending_receipts(), 0);
+        router.withhold_receipts(false);
+        transport
+            .send_with_receipt(send_text("/queue/a", "y"), Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(transport.pending_receipts(), 0);
+    }
+
+    #[tokio::test]
+    async fn subscribe_confirmed_waits_for_receipt() {
+        let delay = Duration::from_millis(10);
+        let (mut transport, server) = delayed_receipt_server(ConnectOptions::new(), delay).await;
+        let subscription = subscribe("a", "1").receipt("mine");
+        let id = transport
+            .subscribe_confirmed(subscription, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(id, "1");
+        // the message which arrived before the receipt is still delivered
+        assert!(matches!(
+            transport.next().await.unwrap().unwrap().content,
+            FromServer::Message { .. }
+        ));
+        drop(transport);
+        let frames = server.await.unwrap();
+        assert_eq!(frames.len(), 1);
+        assert!(frames[0].starts_with("SUBSCRIBE\\n"));
+        assert!(!frames[0].contains("receipt:mine"));
+
+        let delay = Duration::from_millis(500);
+        let (mut transport, _) = delayed_receipt_server(ConnectOptions::new(), delay).await;
+        let err = transport
+            .subscribe_confirmed(subscribe("a", "2"), Duration::from_millis(50))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StompError>(),
+            Some(StompError::SubscribeConfirmTimeout(id)) if id == "2"
+        ));
+        assert!(transport
+            .subscribe_confirmed(send_text("a", "b"), Duration::from_secs(5))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn unconfirmed_subscriptions_can_be_retried() {
+        use crate::test_utils::StompRouter;
+
+        let router = StompRouter::start().await.unwrap();
+        let mut transport = router.connect().await.unwrap();
+        router.withhold_receipts(true);
+        let timeout = Duration::from_millis(50);
+        let err = transport
+            .subscribe_confirmed(subscribe("/queue/a", "1"), timeout)
+            .await
+            .unwrap_err();
+        assert!(StompError::is_retriable_error(&err));
+        assert_eq!(transport.iter_subscriptions().count(), 0);
+
+        router.withhold_receipts(false);
+        let id = transport
+            .subscribe_confirmed(subscribe("/queue/a", "1"), Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(id, "1");
+        // the unconfirmed subscription was undone before it was tried again
+        let received = router.received(0);
+        assert_eq!(received.len(), 4);
+        assert!(matches!(received[1].content, ToServer::Subscribe { .. }));
+        assert!(matches!(received[2].content, ToServer::Unsubscribe { .. }));
+        assert!(matches!(received[3].content, ToServer::Subscribe { .. }));
+    }
+
+    /// A connected transport whose peer never reads nor writes
+    async fn stalled_transport(options: ConnectOptions) -> StompTransport<TcpStream> {
+        use tokio::net::TcpListener;
+
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            time::delay_for(Duration::from_secs(60)).await;
+            drop(socket);
+        });
+        let tcp = TcpStream::connect(address).await.unwrap();
+        tcp.set_send_buffer_size(1024).unwrap();
+        StompTransport::new(options.framed(tcp), &options)
+    }
+
+    fn is_send_elapsed(err: &failure::Error) -> Option<bool> {
+        match err.downcast_ref::<StompError>() {
+            Some(StompError::SendElapsed { handed_to_writer }) => Some(*handed_to_writer),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn send_timeout_reports_handoff() {
+        let mut transport = stalled_transport(ConnectOptions::new()).await;
+        let large = || -> Message<ToServer> {
+            ToServer::Send {
+                destination: "stalled".into(),
+                transaction: None,
+                body: Some(vec![b'x'; 16 * 1024 * 1024].into()),
+            }
+            .into()
+        };
+        let timeout = Duration::from_millis(50);
+
+        // the frame is encoded, but can't be flushed
+        let err = transport.send_timeout(large(), timeout).await.unwrap_err();
+        assert_eq!(is_send_elapsed(&err), Some(true));
+        // the write buffer is still full, so the next frame isn't accepted at all
+        let err = transport.send_timeout(large(), timeout).await.unwrap_err();
+        assert_eq!(is_send_elapsed(&err), Some(false));
+    }
+
+    #[tokio::test]
+    async fn recv_timeout_elapses() {
+        let options = ConnectOptions::new().recv_timeout(Duration::from_millis(50));
+        let mut transport = stalled_transport(options).await;
+        let err = transport.timed_recv().await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StompError>(),
+            Some(StompError::RecvElapsed)
+        ));
+    }
+
+    #[test]
+    fn heartbeat_negotiation() {
+        use futures::executor::block_on;
+
+        let negotiate = |heartbeat: &str, options: ConnectOptions| {
+            let connected = format!("CONNECTED\nversion:1.2\n{}\n\x00", heartbeat);
+            let io = MockIo::new(connected.as_bytes());
+            let written = io.written.clone();
+            let transport =
+                block_on(connect_stream_with_options(io, "localhost".into(), options)).unwrap();
+            let written = String::from_utf8(written.lock().unwrap().clone()).unwrap();
+            (transport.heartbeat_negotiation(), written)
+        };
+        let requested = Duration::from_secs(1);
+        let options = ConnectOptions::new().heartbeat(requested);
+
+        let (negotiation, connect) = negotiate("heart-beat:0,0\n", options.clone());
+        assert!(connect.contains("\nheart-beat:0,1000\n"));
+        assert_eq!(negotiation, HeartbeatNegotiation::Declined);
+        assert!(negotiation.is_degraded());
+        let (negotiation, _) = negotiate("", options.clone());
+        assert_eq!(negotiation, HeartbeatNegotiation::Declined);
+        let (negotiation, _) = negotiate("heart-beat:60000,0\n", options.clone());
+        assert_eq!(
+            negotiation,
+            HeartbeatNegotiation::Inflated {
+                requested,
+                agreed: Duration::from_secs(60)
+            }
+        );
+        assert!(negotiation.is_degraded());
+        let (negotiation, _) = negotiate("heart-beat:1500,0\n", options.clone());
+        assert_eq!(
+            negotiation,
+            HeartbeatNegotiation::Agreed(Duration::from_millis(1500))
+        );
+        assert!(!negotiation.is_degraded());
+        let (negotiation, _) = negotiate("heart-beat:500,0\n", options);
+        assert_eq!(negotiation, HeartbeatNegotiation::Agreed(requested));
+
+        let (negotiation, connect) = negotiate("heart-beat:0,0\n", ConnectOptions::new());
+        assert!(!connect.contains("heart-beat"));
+        assert_eq!(negotiation, HeartbeatNegotiation::NotRequested);
+
+        // intervals which don't fit the header are capped, and ones which round to zero
+        // request nothing
+        let options = ConnectOptions::new().heartbeat(Duration::from_secs(u64::MAX));
+        let (_, connect) = negotiate("heart-beat:0,0\n", options);
+        assert!(connect.contains("\nheart-beat:0,4294967295\n"));
+        let options = ConnectOptions::new().heartbeat(Duration::from_micros(500));
+        let (negotiation, connect) = negotiate("heart-beat:0,0\n", options);
+        assert!(!connect.contains("heart-beat"));
+        assert_eq!(negotiation, HeartbeatNegotiation::NotRequested);
+    }
+
+    #[tokio::test]
+    async fn liveness_fallback() {
+        use crate::test_utils::StompRouter;
+
+        let router = StompRouter::start().await.unwrap();
+        // the server sends a heart-beat after 100ms, then falls silent
+        let connect = |heartbeat: &'static str| {
+            router.connected_heartbeat(heartbeat);
+            let options = ConnectOptions::new()
+                .heartbeat(Duration::from_millis(20))
+                .liveness_fallback(Duration::from_millis(150));
+            let router = &router;
+            async move {
+                let transport = connect_with_options(router.address(), options)
+                    .await
+                    .unwrap();
+                time::delay_for(Duration::from_millis(100)).await;
+                router.send_heartbeat();
+                transport
+            }
+        };
+
+        for heartbeat in &["0,0", "60000,0"] {
+            let mut transport = connect(heartbeat).await;
+            assert!(transport.heartbeat_negotiation().is_degraded());
+            transport.send(subscribe("/queue/a", "1")).await.unwrap();
+            let err = transport.next().await.unwrap().unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<StompError>(),
+                Some(StompError::ServerSilent(_))
+            ));
+            // the heart-beat put the deadline back
+            let silence = transport.last_received().elapsed();
+            assert!(silence >= Duration::from_millis(150));
+            assert!(
+                transport.last_received() - transport.connected_at() >= Duration::from_millis(90)
+            );
+            assert!(matches!(
+                transport.closed(),
+                Some(CloseReason::HeartbeatTimeout)
+            ));
+            // the stream has ended, and the server sees the connection close
+            assert!(transport.next().await.is_none());
+            while router.subscribers("/queue/a") > 0 {
+                time::delay_for(Duration::from_millis(10)).await;
+            }
+        }
+
+        // with the heart-beats agreed, it's up to them to reveal a dead connection
+        let mut transport = connect("20,0").await;
+        let err = transport
+            .recv_timeout(Duration::from_millis(400))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StompError>(),
+            Some(StompError::RecvElapsed)
+        ));
+    }
+
+    #[derive(Debug)]
+    struct XorLayer(u8);
+
+    impl CodecLayer for XorLayer {
+        fn encode(&mut self, frame: &mut BytesMut) -> Result<()> {
+            frame.iter_mut().for_each(|b| *b ^= self.0);
+            Ok(())
+        }
+
+        fn decode(&mut self, src: &mut BytesMut, dst: &mut BytesMut) -> Result<()> {
+            dst.extend(src.split().iter().map(|b| b ^ self.0));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn codec_layer_applies_both_ways() {
+        use futures::executor::block_on;
+
+        let xor = |data: &[u8]| data.iter().map(|b| b ^ 0x5a).collect::<Vec<_>>();
+        let input = xor(
+            b"RECEIPT\nreceipt-id:1\n\n\x00MESSAGE\ndestination:/queue/a\n\
+            message-id:2\nsubscription:3\n\nhi\x00",
+        );
+        let io = MockIo::new(&input);
+        let written = io.written.clone();
+        let options = ConnectOptions::new()
+            .codec_layer(|| XorLayer(0x5a))
+            .allow_resubscribe(true);
+        let mut transport = StompTransport::new(options.framed(io), &options);
+
+        let msg = || subscribe("/queue/a", "3");
+        block_on(transport.send(msg())).unwrap();
+        block_on(transport.send_ref(&msg())).unwrap();
+        let mut expected = BytesMut::new();
+        msg().to_frame().serialize(&mut expected);
+        expected.extend_from_slice(&expected.clone());
+        assert_eq!(xor(&written.lock().unwrap()), expected);
+
+        let received = block_on(transport.try_next()).unwrap().unwrap();
+        assert!(matches!(received.content, FromServer::Receipt { .. }));
+        let received = block_on(transport.try_next()).unwrap().unwrap();
+        assert_eq!(received.body_as_str(), Some("hi"));
+    }
+
+    #[test]
+    fn codec_layer_sees_partial_reads() {
+        let frame = b"RECEIPT\nreceipt-id:1\n\n\x00";
+        let mut codec = ClientCodec::default().with_layer(XorLayer(0xff));
+        let mut buffer = BytesMut::new();
+        for (i, b) in frame.iter().enumerate() {
+            buffer.extend_from_slice(&[b ^ 0xff]);
+            let item = codec.decode(&mut buffer).unwrap();
+            assert_eq!(item.is_some(), i == frame.len() - 1);
+            assert!(buffer.is_empty());
+        }
+    }
+
+    #[test]
+    fn line_ending_applies_to_all_sends() {
+        use futures::executor::block_on;
+
+        let io = MockIo::default();
+        let written = io.written.clone();
+        let options = ConnectOptions::new().line_ending(LineEnding::Crlf);
+        let mut transport = StompTransport::new(options.framed(io), &options);
+        block_on(transport.send(ack("1"))).unwrap();
+        block_on(transport.send_ref(&ack("2"))).unwrap();
+        assert_eq!(
+            &written.lock().unwrap()[..],
+            &b"ACK\r\nid:1\r\n\r\n\x00ACK\r\nid:2\r\n\r\n\x00"[..]
+        );
+    }
+
+    #[test]
+    fn send_batch_writes_once() {
+        let io = MockIo::default();
+        let (written, writes) = (io.written.clone(), io.writes.clone());
+        let options = ConnectOptions::new();
+        let mut transport = StompTransport::new(options.framed(io), &options);
+        let messages: Vec<_> = (0..50)
+            .map(|n| send_text("/queue/a", n.to_string()))
+            .collect();
+        let expected: String = messages.iter().map(encode_to_string).collect();
+        futures::executor::block_on(send_batch(&mut transport, messages)).unwrap();
+        assert_eq!(writes.load(Ordering::SeqCst), 1);
+        let written = written.lock().unwrap();
+        let written: String = written
+            .iter()
+            .flat_map(|&b| std::ascii::escape_default(b))
+            .map(char::from)
+            .collect();
+        assert_eq!(written, expected);
+        assert_eq!(transport.queue_depth(), 0);
+    }
+
+    #[test]
+    fn header_order_applies_to_all_sends() {
+        use futures::executor::block_on;
+
+        let io = MockIo::default();
+        let written = io.written.clone();
+        let options = ConnectOptions::new().header_order(HeaderOrder::ContentFirst);
+        let mut transport = StompTransport::new(options.framed(io), &options);
+        let msg = || SendBuilder::new("a").body_bytes("hi").build();
+        block_on(transport.send(msg())).unwrap();
+        block_on(transport.send_ref(&msg())).unwrap();
+        let frame = b"SEND\ncontent-type:text/plain;charset=UTF-8\ncontent-length:2\n\
+                      destination:a\n\nhi\x00";
+        assert_eq!(
+            &written.lock().unwrap()[..],
+            &[&frame[..], frame].concat()[..]
+        );
+    }
+
+    #[test]
+    fn buffer_shrinks_after_large_frame() {
+        let mut codec = ClientCodec::with_buffer_config(BufferConfig {
+            initial_capacity: 1024,
+            growth_increment: 512,
+            shrink_threshold: Some(4096),
+        });
+        let mut buffer = BytesMut::from(&b"ERROR\n\n"[..]);
+        assert!(codec.decode(&mut buffer).unwrap().is_none());
+        assert!(codec.buffer_capacity() - codec.buffer_len() >= 512);
+
+        buffer.extend_from_slice(&vec![b'x'; 64 * 1024]);
+        assert!(codec.decode(&mut buffer).unwrap().is_none());
+        assert!(codec.buffer_capacity() > 64 * 1024);
+
+        buffer.extend_from_slice(b"\x00");
+        assert!(codec.decode(&mut buffer).unwrap().is_some());
+        assert_eq!(codec.buffer_len(), 0);
+        assert_eq!(codec.buffer_capacity(), buffer.capacity());
+        assert!(buffer.capacity() < 4096);
+    }
+
+    #[test]
+    fn unterminated_frame_hits_size_limit() {
+        let limit = 512 * 1024;
+        let mut codec = ClientCodec {
+            max_frame_size: Some(limit),
+            ..Default::default()
+        };
+        let mut buffer = BytesMut::from(&b"MESSAGE\ndestination:/queue/a\n\n"[..]);
+        let chunk = [b'x'; 4096];
+        let start = std::time::Instant::now();
+        let err = loop {
+            buffer.extend_from_slice(&chunk);
+            match codec.decode(&mut buffer) {
+                Ok(item) => assert!(item.is_none()),
+                Err(err) => break err,
+            }
+            // only the new data is searched for a NUL
+            assert_eq!(codec.scanned, buffer.len());
+            assert!(buffer.len() <= 1024 * 1024);
+        };
+        assert!(buffer.len() > limit && buffer.len() - chunk.len() <= limit);
+        assert!(matches!(
+            err.downcast_ref::<StompError>(),
+            Some(StompError::FrameTooLarge { limit: 524_288 })
+        ));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn partial_frames_resume_nul_search() {
+        let mut codec = ClientCodec::default();
+        let mut buffer = BytesMut::from(&b"\n\nMESSAGE\ndestination:a\nmessage-id:1\n"[..]);
+        buffer.extend_from_slice(b"subscription:1\ncontent-length:4\n\n\x00\x00");
+        assert!(codec.decode(&mut buffer).unwrap().is_none());
+        // leading heart-beats are dropped
+        assert!(buffer.starts_with(b"MESSAGE"));
+        // the search resumes after the first NUL
+        assert_eq!(codec.scanned, buffer.len() - 1);
+
+        buffer.extend_from_slice(b"\x00\x00\x00");
+        let msg = codec.decode(&mut buffer).unwrap().unwrap();
+        assert_eq!(msg.body_as_str(), Some("\0\0\0\0"));
+        assert_eq!(codec.scanned, 0);
+        assert!(buffer.is_empty());
+
+        buffer.extend_from_slice(b"\n\n\r\n");
+        assert!(codec.decode(&mut buffer).unwrap().is_none());
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn codec_debug_shows_counts() {
+        let mut codec = ClientCodec {
+            max_frame_size: Some(1024),
+            ..Default::default()
+        };
+        let mut buffer = BytesMut::from(&b"RECEIPT\nreceipt-id:1\n\n\x00\n"[..]);
+        assert!(codec.decode(&mut buffer).unwrap().is_some());
+        assert!(codec.decode(&mut buffer).unwrap().is_none());
+        codec.encode(disconnect(), &mut buffer).unwrap();
+        assert_eq!((codec.frames_encoded(), codec.frames_decoded()), (1, 1));
+
+        let debug = format!("{:?}", codec);
+        assert!(debug.starts_with("ClientCodec {"));
+        assert!(debug.contains("max_frame_size: Some(1024)"));
+        assert!(debug.contains("frames_encoded: 1, frames_decoded: 1"));
+    }
+
+    #[test]
+    fn buffer_len_tracks_partial_frames() {
+        let mut codec = ClientCodec::default();
+        let mut buffer = BytesMut::from(&b"RECEIPT\nreceipt-id:1\n\n\x00MESSAGE\n"[..]);
+        assert_eq!(codec.buffer_len(), 0);
+
+        let item = codec.decode(&mut buffer).unwrap();
+        assert!(matches!(
+            item,
+            Some(Message {
+                content: FromServer::Receipt { .. },
+                ..
+            })
+        ));
+        assert_eq!(codec.buffer_len(), b"MESSAGE\n".len());
+
+        assert!(codec.decode(&mut buffer).unwrap().is_none());
+        assert_eq!(codec.buffer_len(), b"MESSAGE\n".len());
+
+        buffer.extend_from_slice(b"destination:a\nmessage-id:2\nsubscription:3\n\n\x00");
+        assert!(codec.decode(&mut buffer).unwrap().is_some());
+        assert_eq!(codec.buffer_len(), 0);
+    }
+
+    /// An IO object which never produces input, and whose writes wait while `stalled` is set
+    struct StalledIo {
+        stalled: Arc<std::sync::atomic::AtomicBool>,
+    }
+
+    impl AsyncRead for StalledIo {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Pending
+        }
+    }
+
+    impl AsyncWrite for StalledIo {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            if self.stalled.load(Ordering::SeqCst) {
+                Poll::Pending
+            } else {
+                Poll::Ready(Ok(buf.len()))
+            }
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn frames_never_follow_an_abandoned_write() {
+        use futures::executor::block_on;
+
+        let stalled = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let io = StalledIo { stalled };
+        let options = ConnectOptions::default();
+        let mut transport = StompTransport::new(options.framed(io), &options);
+        let msg = send_text("/queue/a", "first");
+        {
+            let send = transport.send_ref(&msg);
+            futures::pin_mut!(send);
+            assert!(send.now_or_never().is_none());
+        }
+        let is_torn = |err: failure::Error| {
+            matches!(
+                err.downcast_ref::<StompError>(),
+                Some(StompError::TornFrame)
+            )
+        };
+        let second = send_text("/queue/a", "second");
+        assert!(is_torn(
+            block_on(transport.send(second.clone())).unwrap_err()
+        ));
+        assert!(is_torn(transport.start_send(second.clone()).unwrap_err()));
+        assert!(is_torn(block_on(transport.send_ref(&second)).unwrap_err()));
+    }
+
+    #[test]
+    fn queue_watermarks() {
+        let stalled = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let io = StalledIo {
+            stalled: stalled.clone(),
+        };
+        let options = ConnectOptions::default();
+        let mut transport = StompTransport::new(options.framed(io), &options);
+        let depth = transport.queue_depth_handle();
+        let level = transport.watch_queue(2, 4);
+        assert_eq!(*level.borrow(), QueueLevel::LowWater);
+        assert!(transport.oldest_queued().is_none());
+
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        for n in 1..=5 {
+            assert!(transport.poll_send_ready(&mut cx).is_ready());
+            transport.start_send(send_text("/queue/a", "hi")).unwrap();
+            assert_eq!(depth.get(), n);
+            let expected = if n < 4 {
+                QueueLevel::LowWater
+            } else {
+                QueueLevel::HighWater
+            };
+            assert_eq!(*level.borrow(), expected);
+        }
+        assert!(transport.poll_flush(&mut cx).is_pending());
+        assert_eq!(transport.queue_depth(), 5);
+        assert!(transport.oldest_queued().is_some());
+
+        stalled.store(false, Ordering::SeqCst);
+        assert!(matches!(transport.poll_flush(&mut cx), Poll::Ready(Ok(()))));
+        assert_eq!(transport.queue_depth(), 0);
+        assert!(transport.oldest_queued().is_none());
+        assert_eq!(*level.borrow(), QueueLevel::LowWater);
+    }
+
+    #[test]
+    fn iter_subscriptions_follows_sent_frames() {
+        use futures::executor::block_on;
+
+        let mut transport = mock_transport(b"");
+        assert_eq!(transport.iter_subscriptions().count(), 0);
+        let subscription = SubscriptionBuilder::new("/queue/a", "1")
+            .ack(AckMode::Client)
+            .header("selector", "priority > 4")
+            .build()
+            .receipt("r");
+        block_on(transport.send(subscription)).unwrap();
+        block_on(transport.send(subscribe("/topic/b", "2"))).unwrap();
+
+        let mut subscriptions: Vec<_> = transport.iter_subscriptions().collect();
+        subscriptions.sort_by(|a, b| a.id.cmp(&b.id));
+        assert_eq!(
+            subscriptions,
+            [
+                &SubscriptionSpec {
+                    destination: "/queue/a".into(),
+                    id: "1".into(),
+                    ack: AckMode::Client,
+                    headers: vec![("selector".into(), "priority > 4".into())],
+                },
+                &SubscriptionSpec {
+                    destination: "/topic/b".into(),
+                    id: "2".into(),
+                    ack: AckMode::Auto,
+                    headers: vec![],
+                },
+            ]
+        );
+
+        block_on(transport.send(ToServer::Unsubscribe { id: "1".into() }.into())).unwrap();
+        let ids: Vec<_> = transport.iter_subscriptions().map(|s| &s.id[..]).collect();
+        assert_eq!(ids, ["2"]);
+    }
+
+    #[tokio::test]
+    async fn restore_exported_subscriptions() {
+        use crate::test_utils::StompRouter;
+
+        let router = StompRouter::start().await.unwrap();
+        let mut old = router.connect().await.unwrap();
+        let subscription = SubscriptionBuilder::new("/queue/a", "durable-1")
+            .ack(AckMode::ClientIndividual)
+            .header("x", "y")
+            .build();
+        old.send(subscription.receipt("r")).await.unwrap();
+        old.send(subscribe("/queue/b", "durable-0")).await.unwrap();
+        let exported = old.export_subscriptions();
+        assert_eq!(
+            exported,
+            [
+                SubscriptionSpec {
+                    destination: "/queue/b".into(),
+                    id: "durable-0".into(),
+                    ack: AckMode::Auto,
+                    headers: vec![],
+                },
+                SubscriptionSpec {
+                    destination: "/queue/a".into(),
+                    id: "durable-1".into(),
+                    ack: AckMode::ClientIndividual,
+                    headers: vec![("x".into(), "y".into())],
+                },
+            ]
+        );
+        drop(old);
+
+        let options = ConnectOptions::new().restore_subscriptions(exported.clone());
+        let mut new = connect_with_options(router.address(), options)
+            .await
+            .unwrap();
+        assert_eq!(new.export_subscriptions(), exported);
+        // the receipt follows the restored SUBSCRIBEs
+        new.ping(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(router.subscribers("/queue/a"), 1);
+
+        let mut publisher = router.connect().await.unwrap();
+        publisher
+            .send(send_text("/queue/a", "hello"))
+            .await
+            .unwrap();
+        let msg = new.next().await.unwrap().unwrap();
+        assert!(matches!(
+            msg.content,
+            FromServer::Message { ref subscription, .. } if subscription == "durable-1"
+        ));
+        // restored subscriptions are tracked like any other
+        let ack = new.ack_message(&msg).unwrap();
+        new.send(ack).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn migrate_between_brokers() {
+        use crate::test_utils::StompRouter;
+
+        let old = StompRouter::start().await.unwrap();
+        let new = StompRouter::start().await.unwrap();
+        let mut transport = old.connect().await.unwrap();
+        let subscription = SubscriptionBuilder::new("/queue/a", "1")
+            .ack(AckMode::ClientIndividual)
+            .header("selector", "x > 1");
+        transport
+            .send(subscription.build().receipt("r"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            transport.next().await.unwrap().unwrap().content,
+            FromServer::Receipt { .. }
+        ));
+
+        let mut publisher = old.connect().await.unwrap();
+        publisher
+            .send(send_text("/queue/a", "before").receipt("sent"))
+            .await
+            .unwrap();
+        publisher.next().await.unwrap().unwrap();
+
+        let nonce = |id: String| id.split('-').nth(1).unwrap().to_string();
+        let before = nonce(transport.next_id(IdKind::Receipt));
+        transport
+            .migrate(new.connect().await.unwrap(), Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert!(transport.closed().is_none());
+        // ids on the new connection can't collide with ones the old broker saw
+        assert_ne!(nonce(transport.next_id(IdKind::Receipt)), before);
+        // the receipt follows the repeated SUBSCRIBE
+        transport.ping(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(new.subscribers("/queue/a"), 1);
+        // with its headers
+        let subscribe = new
+            .received(0)
+            .into_iter()
+            .find(|msg| matches!(msg.content, ToServer::Subscribe { .. }))
+            .unwrap();
+        assert_eq!(
+            subscribe.extra_headers,
+            [(b"selector".to_vec(), b"x > 1".to_vec())]
+        );
+
+        let mut publisher = new.connect().await.unwrap();
+        publisher
+            .send(send_text("/queue/a", "after"))
+            .await
+            .unwrap();
+        for expected in &["before", "after"] {
+            let msg = transport.next().await.unwrap().unwrap();
+            assert_eq!(msg.body_as_str(), Some(*expected));
+            assert!(matches!(
+                msg.content,
+                FromServer::Message { ref subscription, .. } if subscription == "1"
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn suppress_echoes() {
+        use crate::test_utils::StompRouter;
+
+        let router = StompRouter::start().await.unwrap();
+        let options = ConnectOptions::new().suppress_echoes("x-sender");
+        let mut transport = connect_with_options(router.address().to_string(), options.clone())
+            .await
+            .unwrap();
+        transport.allow_echoes("2");
+        for (id, ack) in &[("1", AckMode::ClientIndividual), ("2", AckMode::Auto)] {
+            transport
+                .send(subscribe_with_ack("/topic/a", *id, *ack))
+                .await
+                .unwrap();
+        }
+        transport
+            .send(send_text("/topic/a", "mine").receipt("sent"))
+            .await
+            .unwrap();
+        // the MESSAGEs are routed before the RECEIPT, and only the exempt one is yielded
+        let msg = transport.next().await.unwrap().unwrap();
+        assert_eq!(msg.body_as_str(), Some("mine"));
+        assert!(matches!(
+            msg.content,
+            FromServer::Message { ref subscription, .. } if subscription == "2"
+        ));
+        assert!(msg.extra_headers.iter().any(|(key, _)| key == b"x-sender"));
+        assert!(matches!(
+            transport.next().await.unwrap().unwrap().content,
+            FromServer::Receipt { .. }
+        ));
+        assert_eq!(transport.echoes_dropped(), 1);
+
+        let mut publisher = connect_with_options(router.address().to_string(), options)
+            .await
+            .unwrap();
+        publisher
+            .send(send_text("/topic/a", "theirs"))
+            .await
+            .unwrap();
+        for expected in &["1", "2"] {
+            let msg = transport.next().await.unwrap().unwrap();
+            assert_eq!(msg.body_as_str(), Some("theirs"));
+            assert!(matches!(
+                msg.content,
+                FromServer::Message { ref subscription, .. } if subscription == expected
+            ));
+        }
+        assert_eq!(transport.echoes_dropped(), 1);
+    }
+
+    #[tokio::test]
+    async fn write_stall_closes_connection() {
+        use crate::test_utils::StompRouter;
+
+        let timeout = Duration::from_millis(200);
+        let options = ConnectOptions::new().write_stall_timeout(timeout);
+        let router = StompRouter::start().await.unwrap();
+        let mut transport = connect_with_options(router.address().to_string(), options)
+            .await
+            .unwrap();
+        // the broker completed the handshake, and now stops reading
+        router.stop_reading();
+        let (reasons, reasons_rx) = std::sync::mpsc::channel();
+        transport.on_disconnect(move |reason| reasons.send(reason).unwrap());
+        let body = vec![b'x'; 64 * 1024];
+        let sending = async {
+            loop {
+                let msg = Message::new(ToServer::Send {
+                    destination: "/queue/a".into(),
+                    transaction: None,
+                    body: Some(body.clone().into()),
+                });
+                if let Err(e) = transport.send(msg).await {
+                    return e;
+                }
+            }
+        };
+        let err = time::timeout(Duration::from_secs(10), sending)
+            .await
+            .expect("the stalled writer was not detected");
+        assert!(matches!(
+            err.downcast_ref::<StompError>(),
+            Some(StompError::WriteStalled(t)) if *t == timeout
+        ));
+        assert!(StompError::is_retriable_error(&err));
+        assert!(matches!(
+            transport.closed(),
+            Some(CloseReason::WriteStalled)
+        ));
+        assert!(matches!(
+            reasons_rx.try_recv(),
+            Ok(DisconnectReason::Timeout)
+        ));
+        assert!(transport.next().await.is_none());
+    }
+
+    /// An IO object which never produces input, and accepts `chunk` bytes every `interval`
+    struct SlowIo {
+        chunk: usize,
+        interval: Duration,
+        next_write: Option<time::Delay>,
+        written: Arc<AtomicUsize>,
+    }
+
+    impl AsyncRead for SlowIo {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Pending
+        }
+    }
+
+    impl AsyncWrite for SlowIo {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let this = self.get_mut();
+            if let Some(next_write) = &mut this.next_write {
+                futures::ready!(Pin::new(next_write).poll(cx));
+            }
+            this.next_write = Some(time::delay_for(this.interval));
+            let written = buf.len().min(this.chunk);
+            this.written.fetch_add(written, Ordering::SeqCst);
+            Poll::Ready(Ok(written))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_writer_is_not_stalled() {
+        // the frame takes about 400ms to write, twice the timeout, but bytes keep moving
+        let options = ConnectOptions::new().write_stall_timeout(Duration::from_millis(200));
+        let io = SlowIo {
+            chunk: 8 * 1024,
+            interval: Duration::from_millis(50),
+            next_write: None,
+            written: Arc::default(),
+        };
+        let written = io.written.clone();
+        let mut transport = StompTransport::new(options.framed(io), &options);
+        let body = vec![b'x'; 64 * 1024];
+        let msg = Message::new(ToServer::Send {
+            destination: "/queue/a".into(),
+            transaction: None,
+            body: Some(body.into()),
+        });
+        transport.send(msg).await.unwrap();
+        assert!(transport.closed().is_none());
+        assert!(written.load(Ordering::SeqCst) > 64 * 1024);
+    }
+
+    #[tokio::test]
+    async fn stream_outlives_closed_sink() {
+        use crate::test_utils::StompRouter;
+
+        let router = StompRouter::start().await.unwrap();
+        let mut transport = router.connect().await.unwrap();
+        transport
+            .send(subscribe("/queue/a", "1").receipt("subscribed"))
+            .await
+            .unwrap();
+        let receipt = transport.next().await.unwrap().unwrap();
+        assert!(matches!(receipt.content, FromServer::Receipt { .. }));
+
+        // once the publisher has its receipts, the messages are on their way to the subscriber
+        let mut publisher = router.connect().await.unwrap();
+        for i in 0..3 {
+            let msg = send_text("/queue/a", i.to_string()).receipt(format!("sent-{}", i));
+            publisher.send(msg).await.unwrap();
+            let receipt = publisher.next().await.unwrap().unwrap();
+            assert!(matches!(receipt.content, FromServer::Receipt { .. }));
+        }
+
+        // the router closes the connection once the client has
+        transport.close().await.unwrap();
+        let err = transport
+            .send(send_text("/queue/a", "too late"))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StompError>(),
+            Some(StompError::Closing)
+        ));
+
+        let mut bodies = vec![];
+        while let Some(msg) = transport.next().await {
+            bodies.push(msg.unwrap().body_as_str().unwrap().to_string());
+        }
+        assert_eq!(bodies, ["0", "1", "2"]);
+        assert!(matches!(transport.closed(), Some(CloseReason::RemoteEof)));
     }
 }
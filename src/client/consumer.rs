@@ -0,0 +1,407 @@
+//! Starting to consume, with control over when the subscriptions are made
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use futures::channel::oneshot;
+use futures::future::{self, Either};
+use futures::prelude::*;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::task::JoinHandle;
+
+use super::StompTransport;
+use crate::{FromServer, Message, Result, StompError, ToServer};
+
+/// The number of frames `ConsumerBuilder` buffers by default
+pub const DEFAULT_STARTUP_BUFFER: usize = 1024;
+
+/// When a `Consumer`'s subscriptions are made
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SubscriptionActivation {
+    /// Subscribe in `ConsumerBuilder::start`, and buffer what is received until
+    /// `Consumer::run`, so that messages start flowing as early as possible
+    Immediate,
+    /// Subscribe in `Consumer::run`, so that nothing can be received before the
+    /// application is ready for it
+    #[default]
+    OnRun,
+}
+
+/// What a `Consumer` does with a frame received while its startup buffer is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SpillPolicy {
+    /// Stop reading from the connection until `Consumer::run`, leaving the server to be
+    /// held back by TCP flow control
+    #[default]
+    Backpressure,
+    /// Discard the oldest buffered MESSAGE frame to make room; RECEIPT and ERROR frames are
+    /// always kept. A discarded message isn't acknowledged, but it isn't safe either: under
+    /// `AckMode::Client` a later cumulative ACK acknowledges it too, so it is lost, and only
+    /// under `AckMode::ClientIndividual` is it redelivered once the connection closes.
+    DropOldest,
+    /// Stop reading, and fail `Consumer::run` with `StompError::StartupBufferFull`
+    Fail,
+}
+
+/// Configures a `Consumer`: its subscriptions, when they are made, and how what arrives
+/// before `Consumer::run` is buffered
+#[derive(Debug, Clone)]
+pub struct ConsumerBuilder {
+    subscriptions: Vec<Message<ToServer>>,
+    activation: SubscriptionActivation,
+    buffer_limit: usize,
+    spill_policy: SpillPolicy,
+}
+
+impl Default for ConsumerBuilder {
+    fn default() -> Self {
+        ConsumerBuilder {
+            subscriptions: vec![],
+            activation: SubscriptionActivation::default(),
+            buffer_limit: DEFAULT_STARTUP_BUFFER,
+            spill_policy: SpillPolicy::default(),
+        }
+    }
+}
+
+impl ConsumerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make a subscription, e.g. one built by `subscribe` or `SubscriptionBuilder`
+    pub fn subscribe(mut self, subscription: impl Into<Message<ToServer>>) -> Self {
+        self.subscriptions.push(subscription.into());
+        self
+    }
+
+    /// When the subscriptions are made, `SubscriptionActivation::OnRun` by default
+    pub fn activation(mut self, activation: SubscriptionActivation) -> Self {
+        self.activation = activation;
+        self
+    }
+
+    /// The number of MESSAGE frames buffered before `Consumer::run` with
+    /// `SubscriptionActivation::Immediate`, `DEFAULT_STARTUP_BUFFER` by default.
+    /// RECEIPT and ERROR frames don't count towards the limit.
+    pub fn buffer_limit(mut self, messages: usize) -> Self {
+        self.buffer_limit = messages;
+        self
+    }
+
+    /// What happens once the startup buffer is full, `SpillPolicy::Backpressure` by default
+    pub fn spill_policy(mut self, policy: SpillPolicy) -> Self {
+        self.spill_policy = policy;
+        self
+    }
+
+    /// Take over `transport`. With `SubscriptionActivation::Immediate` the subscriptions are
+    /// made now, and a task is spawned to read from the connection until `Consumer::run`,
+    /// so this must be called within a Tokio runtime.
+    pub async fn start<S>(self, mut transport: StompTransport<S>) -> Result<Consumer<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let state = match self.activation {
+            SubscriptionActivation::OnRun => State::Deferred {
+                transport: Box::new(transport),
+                subscriptions: self.subscriptions,
+            },
+            SubscriptionActivation::Immediate => {
+                for subscription in self.subscriptions {
+                    transport.feed(subscription).await?;
+                }
+                transport.flush().await?;
+                let (stop, stopped) = oneshot::channel();
+                let buffered = Arc::new(AtomicUsize::new(0));
+                let task = tokio::spawn(buffer_until_run(
+                    transport,
+                    stopped,
+                    buffered.clone(),
+                    self.buffer_limit,
+                    self.spill_policy,
+                ));
+                State::Buffering {
+                    stop,
+                    task,
+                    buffered,
+                }
+            }
+        };
+        Ok(Consumer { state })
+    }
+}
+
+/// A connection whose subscriptions are made, or whose received frames are released,
+/// once the application is ready to handle them. Created by `ConsumerBuilder::start`.
+pub struct Consumer<S> {
+    state: State<S>,
+}
+
+enum State<S> {
+    Deferred {
+        transport: Box<StompTransport<S>>,
+        subscriptions: Vec<Message<ToServer>>,
+    },
+    Buffering {
+        stop: oneshot::Sender<()>,
+        task: JoinHandle<Buffered<S>>,
+        buffered: Arc<AtomicUsize>,
+    },
+}
+
+struct Buffered<S> {
+    transport: StompTransport<S>,
+    frames: VecDeque<Message<FromServer>>,
+    error: Option<failure::Error>,
+}
+
+impl<S> Consumer<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    /// The number of frames received and waiting for `run`
+    pub fn buffered(&self) -> usize {
+        match &self.state {
+            State::Deferred { .. } => 0,
+            State::Buffering { buffered, .. } => buffered.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Start handling messages, returning the connection to receive them from. Deferred
+    /// subscriptions are made first; buffered frames are yielded from the stream first,
+    /// in the order they were received.
+    ///
+    /// Fails if a subscription can't be sent, or if reading into the buffer failed,
+    /// in which case the buffered frames are lost.
+    pub async fn run(self) -> Result<StompTransport<S>> {
+        match self.state {
+            State::Deferred {
+                mut transport,
+                subscriptions,
+            } => {
+                for subscription in subscriptions {
+                    transport.feed(subscription).await?;
+                }
+                transport.flush().await?;
+                Ok(*transport)
+            }
+            State::Buffering { stop, task, .. } => {
+                // the task may have stopped already, e.g. at the end of the stream
+                let _ = stop.send(());
+                let Buffered {
+                    mut transport,
+                    mut frames,
+                    error,
+                } = task.await?;
+                if let Some(error) = error {
+                    return Err(error);
+                }
+                // anything still held was received after the buffered frames
+                frames.extend(transport.held.drain(..));
+                transport.held = frames;
+                Ok(transport)
+            }
+        }
+    }
+}
+
+/// Read frames from `transport` into a buffer until `stop` fires
+async fn buffer_until_run<S>(
+    mut transport: StompTransport<S>,
+    mut stop: oneshot::Receiver<()>,
+    count: Arc<AtomicUsize>,
+    limit: usize,
+    policy: SpillPolicy,
+) -> Buffered<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let is_message = |msg: &Message<FromServer>| matches!(msg.content, FromServer::Message { .. });
+    let mut frames = VecDeque::new();
+    let mut messages = 0;
+    let mut error = None;
+    loop {
+        if messages >= limit && policy == SpillPolicy::Backpressure {
+            let _ = stop.await;
+            break;
+        }
+        let msg = match future::select(&mut stop, transport.next()).await {
+            Either::Left(_) => break,
+            Either::Right((Some(Ok(msg)), _)) => msg,
+            Either::Right((Some(Err(e)), _)) => {
+                error = Some(e);
+                break;
+            }
+            Either::Right((None, _)) => break,
+        };
+        if is_message(&msg) {
+            if messages < limit {
+                messages += 1;
+            } else {
+                match policy {
+                    SpillPolicy::DropOldest => match frames.iter().position(is_message) {
+                        Some(oldest) => {
+                            frames.remove(oldest);
+                        }
+                        // a limit of 0 leaves no room even for this one
+                        None => continue,
+                    },
+                    SpillPolicy::Fail => {
+                        error = Some(StompError::StartupBufferFull { limit }.into());
+                        break;
+                    }
+                    SpillPolicy::Backpressure => unreachable!(),
+                }
+            }
+        }
+        frames.push_back(msg);
+        count.store(frames.len(), Ordering::SeqCst);
+    }
+    Buffered {
+        transport,
+        frames,
+        error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::client::{send_text, subscribe};
+    use crate::test_utils::StompRouter;
+
+    /// Wait until `condition` holds, failing the test after a few seconds
+    async fn eventually(condition: impl Fn() -> bool) {
+        for _ in 0..500 {
+            if condition() {
+                return;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        panic!("condition never held");
+    }
+
+    async fn publish(router: &StompRouter, bodies: &[&str]) {
+        let mut publisher = router.connect().await.unwrap();
+        for body in bodies {
+            publisher.send(send_text("/queue/a", *body)).await.unwrap();
+        }
+    }
+
+    async fn next_body<S>(transport: &mut StompTransport<S>) -> String
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let msg = transport.next().await.unwrap().unwrap();
+        msg.body_as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn immediate_activation_buffers_until_run() {
+        let router = StompRouter::start().await.unwrap();
+        let consumer = ConsumerBuilder::new()
+            .subscribe(subscribe("/queue/a", "1"))
+            .activation(SubscriptionActivation::Immediate)
+            .start(router.connect().await.unwrap())
+            .await
+            .unwrap();
+        eventually(|| router.subscribers("/queue/a") == 1).await;
+
+        publish(&router, &["1", "2", "3"]).await;
+        eventually(|| consumer.buffered() == 3).await;
+
+        let mut transport = consumer.run().await.unwrap();
+        publish(&router, &["4"]).await;
+        for expected in &["1", "2", "3", "4"] {
+            assert_eq!(next_body(&mut transport).await, *expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn on_run_activation_defers_subscribing() {
+        let router = StompRouter::start().await.unwrap();
+        let consumer = ConsumerBuilder::new()
+            .subscribe(subscribe("/queue/a", "1"))
+            .start(router.connect().await.unwrap())
+            .await
+            .unwrap();
+        // nothing is subscribed to receive this
+        publish(&router, &["early"]).await;
+        assert_eq!(router.subscribers("/queue/a"), 0);
+
+        let mut transport = consumer.run().await.unwrap();
+        transport.ping(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(router.subscribers("/queue/a"), 1);
+        publish(&router, &["late"]).await;
+        assert_eq!(next_body(&mut transport).await, "late");
+    }
+
+    #[tokio::test]
+    async fn spill_policies() {
+        let router = &StompRouter::start().await.unwrap();
+        let start = |policy| async move {
+            let consumer = ConsumerBuilder::new()
+                .subscribe(subscribe("/queue/a", "1"))
+                .activation(SubscriptionActivation::Immediate)
+                .buffer_limit(2)
+                .spill_policy(policy)
+                .start(router.connect().await.unwrap())
+                .await
+                .unwrap();
+            eventually(|| router.subscribers("/queue/a") > 0).await;
+            consumer
+        };
+
+        let dropping = start(SpillPolicy::DropOldest).await;
+        let failing = start(SpillPolicy::Fail).await;
+        let waiting = start(SpillPolicy::Backpressure).await;
+        eventually(|| router.subscribers("/queue/a") == 3).await;
+        publish(router, &["1", "2", "3"]).await;
+        eventually(|| dropping.buffered() == 2 && waiting.buffered() == 2).await;
+
+        let mut transport = dropping.run().await.unwrap();
+        assert_eq!(next_body(&mut transport).await, "2");
+        assert_eq!(next_body(&mut transport).await, "3");
+
+        let err = failing.run().await.err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<StompError>(),
+            Some(StompError::StartupBufferFull { limit: 2 })
+        ));
+
+        // the third message was left on the connection
+        let mut transport = waiting.run().await.unwrap();
+        for expected in &["1", "2", "3"] {
+            assert_eq!(next_body(&mut transport).await, *expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn dropping_keeps_receipts() {
+        let router = StompRouter::start().await.unwrap();
+        let consumer = ConsumerBuilder::new()
+            .subscribe(subscribe("/queue/a", "1").receipt("subscribed"))
+            .activation(SubscriptionActivation::Immediate)
+            .buffer_limit(1)
+            .spill_policy(SpillPolicy::DropOldest)
+            .start(router.connect().await.unwrap())
+            .await
+            .unwrap();
+        eventually(|| consumer.buffered() == 1).await;
+        // the message fills the buffer, but the receipt before it stays
+        publish(&router, &["1"]).await;
+        eventually(|| consumer.buffered() == 2).await;
+
+        let mut transport = consumer.run().await.unwrap();
+        let receipt = transport.next().await.unwrap().unwrap();
+        assert!(matches!(
+            receipt.content,
+            FromServer::Receipt { ref receipt_id } if receipt_id == "subscribed"
+        ));
+        assert_eq!(next_body(&mut transport).await, "1");
+    }
+}
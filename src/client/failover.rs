@@ -0,0 +1,546 @@
+//! Connections which fail over between several brokers, like ActiveMQ's `failover:` transport
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use tokio::net::TcpStream;
+
+use super::{connect_with_options, CloseReason, ConnectOptions, StompTransport, SubscriptionSpec};
+use crate::id::IdGenerator;
+use crate::{FromServer, Message, Result, StompError, ToServer};
+
+/// Connect to the first of `addresses` which accepts the connection, trying them in order.
+/// See `FailoverTransport`.
+pub async fn connect(addresses: Vec<String>, options: ConnectOptions) -> Result<FailoverTransport> {
+    connect_with_config(addresses, options, ReconnectConfig::new()).await
+}
+
+/// Like `connect`, with `config` limiting the attempts to connect
+pub async fn connect_with_config(
+    addresses: Vec<String>,
+    options: ConnectOptions,
+    config: ReconnectConfig,
+) -> Result<FailoverTransport> {
+    let max_attempts = config.max_attempts.unwrap_or(addresses.len() as u32);
+    let (current, transport) = reconnect(
+        addresses.clone(),
+        options.clone(),
+        0,
+        vec![],
+        None,
+        max_attempts,
+    )
+    .await?;
+    Ok(FailoverTransport {
+        addresses,
+        options,
+        current,
+        failovers: 0,
+        max_attempts,
+        on_give_up: config.on_give_up,
+        state: State::Connected(Box::new(transport)),
+    })
+}
+
+type GiveUpCallback = Box<dyn FnOnce(StompError) + Send>;
+
+/// Limits how long a `FailoverTransport` keeps trying to reconnect
+#[derive(Default)]
+pub struct ReconnectConfig {
+    max_attempts: Option<u32>,
+    on_give_up: Option<GiveUpCallback>,
+}
+
+impl ReconnectConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Give up after this many consecutive attempts to connect have failed, trying the
+    /// addresses in turn and wrapping around to the start of the list as often as needed.
+    /// By default each address is tried once.
+    pub fn max_attempts(mut self, attempts: u32) -> Self {
+        assert!(attempts > 0, "at least one attempt is needed to connect");
+        self.max_attempts = Some(attempts);
+        self
+    }
+
+    /// Register a callback to be invoked with `StompError::MaxReconnectsExceeded` once the
+    /// transport gives up reconnecting, e.g. to alert or shut down the application
+    pub fn on_give_up(mut self, callback: impl FnOnce(StompError) + Send + 'static) -> Self {
+        self.on_give_up = Some(Box::new(callback));
+        self
+    }
+}
+
+/// Whether a `FailoverTransport` is connected to a broker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    /// Moving to another broker
+    Reconnecting,
+    /// A DISCONNECT was sent, and the connection ended
+    Closed,
+    /// Every attempt to reconnect failed, and no more will be made
+    PermanentlyFailed,
+}
+
+/// A connection to one of several brokers, which moves to the next broker when it fails.
+///
+/// When the connection is lost or yields an error, the following addresses are tried in
+/// order, wrapping around to the start of the list, until one accepts the connection.
+/// Every open subscription is then made again on the new connection, with the same id,
+/// acknowledgment mode and headers. The stream carries on with frames from the new connection,
+/// and sending waits until it has been made. Sends which fail when the connection is lost
+/// return the error, as the frames may or may not have reached the broker.
+///
+/// If no broker accepts the connection within `ReconnectConfig::max_attempts` attempts, the
+/// stream yields `StompError::MaxReconnectsExceeded` and ends, and sending fails with
+/// `StompError::NoConnections`. Nothing is retried once a DISCONNECT
+/// has been sent. Messages received before a failover can no longer be acknowledged, and
+/// open transactions are lost.
+pub struct FailoverTransport {
+    addresses: Vec<String>,
+    options: ConnectOptions,
+    /// The index of the address connected to, or last connected to
+    current: usize,
+    failovers: u64,
+    max_attempts: u32,
+    on_give_up: Option<GiveUpCallback>,
+    state: State,
+}
+
+enum State {
+    Connected(Box<StompTransport<TcpStream>>),
+    Reconnecting(BoxFuture<'static, Result<(usize, StompTransport<TcpStream>)>>),
+    Closed,
+    Failed,
+}
+
+async fn reconnect(
+    addresses: Vec<String>,
+    options: ConnectOptions,
+    start: usize,
+    subscriptions: Vec<SubscriptionSpec>,
+    ids: Option<IdGenerator>,
+    max_attempts: u32,
+) -> Result<(usize, StompTransport<TcpStream>)> {
+    if addresses.is_empty() {
+        return Err(StompError::NoConnections.into());
+    }
+    let mut error = None;
+    for attempt in 0..max_attempts as usize {
+        let index = (start + attempt) % addresses.len();
+        let address = &addresses[index];
+        let resubscribed = async {
+            let mut transport = connect_with_options(address.clone(), options.clone()).await?;
+            for subscription in &subscriptions {
+                transport.feed(subscription.clone().into()).await?;
+            }
+            transport.flush().await?;
+            Ok::<_, failure::Error>(transport)
+        };
+        match resubscribed.await {
+            Ok(mut transport) => {
+                // keep issuing ids from the previous connection's generator, under a new nonce
+                if let Some(mut ids) = ids {
+                    ids.rotate();
+                    transport.ids = ids;
+                }
+                return Ok((index, transport));
+            }
+            Err(e) => {
+                log::warn!("failed to connect to {}: {}", address, e);
+                error = Some(e);
+            }
+        }
+    }
+    Err(error.unwrap_or_else(|| StompError::NoConnections.into()))
+}
+
+impl FailoverTransport {
+    /// The address of the broker connected to, unless a failover is in progress
+    /// or every broker has failed
+    pub fn current_address(&self) -> Option<&str> {
+        match self.state {
+            State::Connected(_) => Some(&self.addresses[self.current]),
+            _ => None,
+        }
+    }
+
+    /// The number of times the connection has moved to another broker
+    pub fn failovers(&self) -> u64 {
+        self.failovers
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        match self.state {
+            State::Connected(_) => ConnectionState::Connected,
+            State::Reconnecting(_) => ConnectionState::Reconnecting,
+            State::Closed => ConnectionState::Closed,
+            State::Failed => ConnectionState::PermanentlyFailed,
+        }
+    }
+
+    /// The current connection, unless a failover is in progress or every broker has failed
+    pub fn get_ref(&self) -> Option<&StompTransport<TcpStream>> {
+        match &self.state {
+            State::Connected(transport) => Some(&**transport),
+            _ => None,
+        }
+    }
+
+    /// Start moving to the next broker, unless the connection was closed deliberately
+    fn fail_over(&mut self) {
+        let transport = match std::mem::replace(&mut self.state, State::Closed) {
+            State::Connected(transport) => transport,
+            state => {
+                self.state = state;
+                return;
+            }
+        };
+        if transport.disconnect_sent
+            || matches!(transport.closed(), Some(CloseReason::LocalDisconnect))
+        {
+            return;
+        }
+        let subscriptions = transport.export_subscriptions();
+        let ids = Some(transport.ids);
+        let next = (self.current + 1) % self.addresses.len();
+        let addresses = self.addresses.clone();
+        // the live subscriptions are repeated below, including any which were restored
+        let options = self.options.clone().restore_subscriptions(vec![]);
+        let max_attempts = self.max_attempts;
+        let reconnecting = reconnect(addresses, options, next, subscriptions, ids, max_attempts);
+        self.state = State::Reconnecting(Box::pin(reconnecting));
+    }
+
+    /// Poll until connected, returning the connection
+    /// or the error which ended the last attempt to reconnect
+    fn poll_connected(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<&mut StompTransport<TcpStream>>> {
+        if let State::Reconnecting(reconnecting) = &mut self.state {
+            match futures::ready!(reconnecting.as_mut().poll(cx)) {
+                Ok((current, transport)) => {
+                    log::info!("failed over to {}", self.addresses[current]);
+                    self.current = current;
+                    self.failovers += 1;
+                    self.state = State::Connected(Box::new(transport));
+                }
+                Err(e) => {
+                    log::warn!("giving up reconnecting: {}", e);
+                    self.state = State::Failed;
+                    let attempts = self.max_attempts;
+                    let last_error: Arc<dyn std::error::Error + Send + Sync> = Arc::new(e.compat());
+                    let give_up = || StompError::MaxReconnectsExceeded {
+                        attempts,
+                        last_error: last_error.clone(),
+                    };
+                    if let Some(callback) = self.on_give_up.take() {
+                        callback(give_up());
+                    }
+                    return Poll::Ready(Err(give_up().into()));
+                }
+            }
+        }
+        match &mut self.state {
+            State::Connected(transport) => Poll::Ready(Ok(transport)),
+            _ => Poll::Ready(Err(StompError::NoConnections.into())),
+        }
+    }
+
+    /// Poll a sending operation, failing over if it fails
+    fn poll_send<F>(&mut self, cx: &mut Context<'_>, op: F) -> Poll<Result<()>>
+    where
+        F: FnOnce(&mut StompTransport<TcpStream>, &mut Context<'_>) -> Poll<Result<()>>,
+    {
+        let result = futures::ready!(self.poll_connected(cx)).map(|transport| op(transport, cx));
+        match result {
+            Ok(Poll::Ready(Err(e))) => {
+                self.fail_over();
+                Poll::Ready(Err(e))
+            }
+            Ok(poll) => poll,
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+}
+
+impl Stream for FailoverTransport {
+    type Item = Result<Message<FromServer>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let State::Closed | State::Failed = this.state {
+                return Poll::Ready(None);
+            }
+            let transport = match futures::ready!(this.poll_connected(cx)) {
+                Ok(transport) => transport,
+                Err(e) => return Poll::Ready(Some(Err(e))),
+            };
+            match futures::ready!(transport.poll_next_unpin(cx)) {
+                Some(Ok(msg)) => return Poll::Ready(Some(Ok(msg))),
+                Some(Err(e)) => {
+                    log::warn!(
+                        "connection to {} failed: {}",
+                        this.addresses[this.current],
+                        e
+                    );
+                    this.fail_over();
+                }
+                None => this.fail_over(),
+            }
+        }
+    }
+}
+
+impl Sink<Message<ToServer>> for FailoverTransport {
+    type Error = failure::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut()
+            .poll_send(cx, |transport, cx| transport.poll_ready_unpin(cx))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message<ToServer>) -> Result<()> {
+        let this = self.get_mut();
+        let result = match &mut this.state {
+            State::Connected(transport) => transport.start_send_unpin(item),
+            _ => return Err(StompError::NoConnections.into()),
+        };
+        if result.is_err() {
+            this.fail_over();
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut()
+            .poll_send(cx, |transport, cx| transport.poll_flush_unpin(cx))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        match &mut self.get_mut().state {
+            State::Connected(transport) => transport.poll_close_unpin(cx),
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{send_text, subscribe, SubscriptionBuilder};
+    use crate::test_utils::StompRouter;
+    use crate::AckMode;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// A broker which accepts one connection, confirms the first SUBSCRIBE
+    /// with a receipt for `r` and then closes the connection
+    async fn short_lived_broker() -> String {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut received = vec![];
+            let mut buf = [0; 1024];
+            let mut connected = false;
+            loop {
+                let n = socket.read(&mut buf).await.unwrap();
+                received.extend_from_slice(&buf[..n]);
+                if !connected && received.contains(&0) {
+                    socket
+                        .write_all(b"CONNECTED\nversion:1.2\n\n\x00")
+                        .await
+                        .unwrap();
+                    received.clear();
+                    connected = true;
+                } else if connected && received.contains(&0) {
+                    assert!(received.starts_with(b"SUBSCRIBE\n"));
+                    socket
+                        .write_all(b"RECEIPT\nreceipt-id:r\n\n\x00")
+                        .await
+                        .unwrap();
+                    return;
+                }
+            }
+        });
+        address
+    }
+
+    /// An address which refuses connections
+    async fn closed_address() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn fails_over_and_resubscribes() {
+        let first = short_lived_broker().await;
+        let router = StompRouter::start().await.unwrap();
+        let addresses = vec![
+            first.clone(),
+            closed_address().await,
+            router.address().into(),
+        ];
+        let mut transport = connect(addresses, ConnectOptions::new()).await.unwrap();
+        assert_eq!(transport.current_address(), Some(&first[..]));
+
+        let subscription = SubscriptionBuilder::new("/queue/a", "1").header("selector", "x > 1");
+        transport
+            .send(subscription.build().receipt("r"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            transport.next().await.unwrap().unwrap().content,
+            FromServer::Receipt { .. }
+        ));
+
+        let mut publisher = router.connect().await.unwrap();
+        let publish = tokio::spawn(async move {
+            while router.subscribers("/queue/a") == 0 {
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+            publisher
+                .send(send_text("/queue/a", "hello"))
+                .await
+                .unwrap();
+            router
+        });
+        let msg = transport.next().await.unwrap().unwrap();
+        assert_eq!(msg.body_as_str(), Some("hello"));
+        assert!(matches!(
+            msg.content,
+            FromServer::Message { ref subscription, .. } if subscription == "1"
+        ));
+        let router = publish.await.unwrap();
+        assert_eq!(transport.current_address(), Some(router.address()));
+        assert_eq!(transport.failovers(), 1);
+        // the SUBSCRIBE was repeated with its headers
+        let subscribe = (0..2)
+            .flat_map(|connection| router.received(connection))
+            .find(|msg| matches!(msg.content, ToServer::Subscribe { .. }))
+            .unwrap();
+        assert_eq!(
+            subscribe.extra_headers,
+            [(b"selector".to_vec(), b"x > 1".to_vec())]
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_over_with_restored_subscriptions() {
+        let first = short_lived_broker().await;
+        let router = StompRouter::start().await.unwrap();
+        let addresses = vec![first, router.address().into()];
+        let restored = SubscriptionSpec {
+            destination: "/queue/a".into(),
+            id: "1".into(),
+            ack: AckMode::Auto,
+            headers: vec![],
+        };
+        let options = ConnectOptions::new().restore_subscriptions(vec![restored]);
+        let mut transport = connect(addresses, options).await.unwrap();
+        assert!(matches!(
+            transport.next().await.unwrap().unwrap().content,
+            FromServer::Receipt { .. }
+        ));
+
+        let mut publisher = router.connect().await.unwrap();
+        let publish = tokio::spawn(async move {
+            while router.subscribers("/queue/a") == 0 {
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+            publisher
+                .send(send_text("/queue/a", "hello"))
+                .await
+                .unwrap();
+            router
+        });
+        let msg = transport.next().await.unwrap().unwrap();
+        assert_eq!(msg.body_as_str(), Some("hello"));
+        let router = publish.await.unwrap();
+        assert_eq!(transport.state(), ConnectionState::Connected);
+        // the restored subscription was only made once on the new broker
+        let subscribes = (0..2)
+            .flat_map(|connection| router.received(connection))
+            .filter(|msg| matches!(msg.content, ToServer::Subscribe { .. }))
+            .count();
+        assert_eq!(subscribes, 1);
+    }
+
+    #[tokio::test]
+    async fn every_broker_failing() {
+        let addresses = vec![closed_address().await, closed_address().await];
+        assert!(connect(addresses, ConnectOptions::new()).await.is_err());
+
+        let addresses = vec![short_lived_broker().await, closed_address().await];
+        let mut transport = connect(addresses, ConnectOptions::new()).await.unwrap();
+        transport
+            .send(subscribe("/queue/a", "1").receipt("r"))
+            .await
+            .unwrap();
+        transport.next().await.unwrap().unwrap();
+        // the first broker is tried again, but has stopped listening
+        assert!(transport.next().await.unwrap().is_err());
+        assert!(transport.next().await.is_none());
+        let err = transport
+            .send(send_text("/queue/a", "hi"))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StompError>(),
+            Some(StompError::NoConnections)
+        ));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        use std::sync::{Arc, Mutex};
+
+        let given_up = Arc::new(Mutex::new(vec![]));
+        let config = {
+            let given_up = given_up.clone();
+            ReconnectConfig::new()
+                .max_attempts(3)
+                .on_give_up(move |err| given_up.lock().unwrap().push(err))
+        };
+        let addresses = vec![short_lived_broker().await, closed_address().await];
+        let mut transport = connect_with_config(addresses, ConnectOptions::new(), config)
+            .await
+            .unwrap();
+        assert_eq!(transport.state(), ConnectionState::Connected);
+        transport
+            .send(subscribe("/queue/a", "1").receipt("r"))
+            .await
+            .unwrap();
+        transport.next().await.unwrap().unwrap();
+        assert!(given_up.lock().unwrap().is_empty());
+
+        // both addresses are tried, then the first one again
+        let err = transport.next().await.unwrap().unwrap_err();
+        let err = err.downcast_ref::<StompError>().unwrap();
+        assert!(matches!(
+            err,
+            StompError::MaxReconnectsExceeded { attempts: 3, .. }
+        ));
+        // the last attempt's error is kept as the cause
+        let cause = std::error::Error::source(err).unwrap();
+        let refused = cause.to_string();
+        assert!(refused.to_lowercase().contains("refused"), "{}", refused);
+        assert_eq!(transport.state(), ConnectionState::PermanentlyFailed);
+        assert!(matches!(
+            given_up.lock().unwrap()[..],
+            [StompError::MaxReconnectsExceeded { attempts: 3, .. }]
+        ));
+        assert!(transport.next().await.is_none());
+        assert_eq!(given_up.lock().unwrap().len(), 1);
+    }
+}
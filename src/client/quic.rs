@@ -0,0 +1,79 @@
+//! STOMP over QUIC, using `quinn` (experimental).
+//! Frames are carried on a single bidirectional stream; the framing is unchanged.
+
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use quinn::{ClientConfig, Connection, Endpoint, NewConnection, RecvStream, SendStream};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::{connect_stream_with_options, ConnectOptions, StompTransport};
+use crate::Result;
+
+/// A bidirectional QUIC stream, usable wherever an `AsyncRead + AsyncWrite` is expected.
+/// Keeps the connection and its endpoint open for as long as the stream is in use.
+#[derive(Debug)]
+pub struct QuicStream {
+    send: SendStream,
+    recv: RecvStream,
+    _connection: Connection,
+    _endpoint: Endpoint,
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().send).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().send).poll_shutdown(cx)
+    }
+}
+
+/// Connect to a STOMP server over QUIC, including the connection handshake.
+/// `server_name` is checked against the server's certificate, and sent as the `host` header
+/// unless `ConnectOptions::host_header` says otherwise.
+pub async fn connect(
+    addr: SocketAddr,
+    server_name: &str,
+    crypto: ClientConfig,
+    options: ConnectOptions,
+) -> Result<StompTransport<QuicStream>> {
+    let local: SocketAddr = if addr.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let mut builder = Endpoint::builder();
+    builder.default_client_config(crypto);
+    let (endpoint, _incoming) = builder.bind(&local)?;
+    let NewConnection { connection, .. } = endpoint.connect(&addr, server_name)?.await?;
+    let (send, recv) = connection.open_bi().await?;
+    let stream = QuicStream {
+        send,
+        recv,
+        _connection: connection,
+        _endpoint: endpoint,
+    };
+    connect_stream_with_options(stream, server_name.to_string(), options).await
+}
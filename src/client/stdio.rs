@@ -0,0 +1,213 @@
+//! STOMP over a pair of one-way streams, such as the stdin and stdout of a child process
+//! which tunnels to the broker, e.g. `ssh -W broker:61613 gateway`
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(feature = "process")]
+use tokio::process::{ChildStdin, ChildStdout};
+
+#[cfg(feature = "process")]
+use super::{connect_stream_with_options, ConnectOptions, StompTransport};
+#[cfg(feature = "process")]
+use crate::Result;
+
+/// A writer and a reader joined into one stream, usable wherever an
+/// `AsyncRead + AsyncWrite` is expected. Shutting it down shuts down the writer and then
+/// drops it, so that e.g. a child process's stdin is closed, which shutting down
+/// `ChildStdin` alone doesn't do. Nothing more can be written after that.
+#[derive(Debug)]
+pub struct StdioStream<W, R> {
+    /// `None` once the stream has been shut down
+    writer: Option<W>,
+    reader: R,
+}
+
+impl<W, R> StdioStream<W, R> {
+    pub fn new(writer: W, reader: R) -> Self {
+        StdioStream {
+            writer: Some(writer),
+            reader,
+        }
+    }
+
+    /// The writer, unless the stream has been shut down, and the reader
+    pub fn into_inner(self) -> (Option<W>, R) {
+        (self.writer, self.reader)
+    }
+}
+
+fn shut_down() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "the writer has been shut down")
+}
+
+impl<W: Unpin, R: AsyncRead + Unpin> AsyncRead for StdioStream<W, R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().reader).poll_read(cx, buf)
+    }
+}
+
+impl<W: AsyncWrite + Unpin, R: Unpin> AsyncWrite for StdioStream<W, R> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.get_mut().writer {
+            Some(writer) => Pin::new(writer).poll_write(cx, buf),
+            None => Poll::Ready(Err(shut_down())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().writer {
+            Some(writer) => Pin::new(writer).poll_flush(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(writer) = &mut this.writer {
+            futures::ready!(Pin::new(writer).poll_shutdown(cx))?;
+            this.writer = None;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Connect to a STOMP server through a child process, including the connection handshake.
+/// Frames are written to the process's `stdin` and read from its `stdout`; `host` is sent
+/// as the `host` header unless `ConnectOptions::host_header` says otherwise.
+///
+/// The process should be spawned with `kill_on_drop`, so that it ends with the connection.
+#[cfg(feature = "process")]
+pub async fn connect_stdio(
+    stdin: ChildStdin,
+    stdout: ChildStdout,
+    host: &str,
+    options: ConnectOptions,
+) -> Result<StompTransport<StdioStream<ChildStdin, ChildStdout>>> {
+    let stream = StdioStream::new(stdin, stdout);
+    connect_stream_with_options(stream, host.to_string(), options).await
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use futures::prelude::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::client::{connect_stream_with_options, send_text, ConnectOptions};
+    use crate::{FromServer, ToServer};
+
+    /// Read the next client frame from `socket`
+    async fn read_frame(socket: &mut TcpStream, received: &mut BytesMut) -> ToServer {
+        loop {
+            if let Ok((remain, frame)) = crate::frame::parse_frame(received) {
+                let offset = remain.as_ptr() as usize - received.as_ptr() as usize;
+                let msg = frame.to_client_msg().unwrap();
+                let _ = received.split_to(offset);
+                return msg.content;
+            }
+            let mut buf = [0; 1024];
+            let n = socket.read(&mut buf).await.unwrap();
+            assert!(n > 0, "connection closed mid-frame");
+            received.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_cross_separate_streams() {
+        // two connections stand in for a child process's stdin and stdout
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let stdin = TcpStream::connect(&address).await.unwrap();
+        let (mut server_in, _) = listener.accept().await.unwrap();
+        let stdout = TcpStream::connect(&address).await.unwrap();
+        let (mut server_out, _) = listener.accept().await.unwrap();
+
+        let server = tokio::spawn(async move {
+            let mut received = BytesMut::new();
+            let connect = read_frame(&mut server_in, &mut received).await;
+            assert!(
+                matches!(connect, ToServer::Connect { host: Some(ref host), .. } if host == "broker")
+            );
+            server_out
+                .write_all(b"CONNECTED\nversion:1.2\n\n\x00")
+                .await
+                .unwrap();
+            match read_frame(&mut server_in, &mut received).await {
+                ToServer::Send {
+                    destination, body, ..
+                } => {
+                    let reply = format!(
+                        "MESSAGE\ndestination:{}\nmessage-id:1\nsubscription:1\n\n{}\x00",
+                        destination,
+                        String::from_utf8_lossy(&body.unwrap())
+                    );
+                    server_out.write_all(reply.as_bytes()).await.unwrap();
+                }
+                other => panic!("unexpected frame: {:?}", other),
+            }
+            // shutting down the transport closes "stdin"
+            let mut rest = vec![];
+            server_in.read_to_end(&mut rest).await.unwrap();
+        });
+
+        let stream = StdioStream::new(stdin, stdout);
+        let mut transport =
+            connect_stream_with_options(stream, "broker".into(), ConnectOptions::new())
+                .await
+                .unwrap();
+        assert_eq!(transport.connection_details().version, "1.2");
+        transport
+            .send(send_text("/queue/a", "hello"))
+            .await
+            .unwrap();
+        let msg = transport.next().await.unwrap().unwrap();
+        assert!(matches!(msg.content, FromServer::Message { .. }));
+        assert_eq!(msg.body_as_str(), Some("hello"));
+        transport.close().await.unwrap();
+        server.await.unwrap();
+    }
+
+    #[cfg(feature = "process")]
+    #[tokio::test]
+    async fn connect_through_child_process() {
+        use std::process::Stdio;
+        use tokio::process::Command;
+
+        // a broker which answers the CONNECT, then discards everything until stdin closes
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg("printf 'CONNECTED\\nversion:1.2\\n\\n\\000'; cat >/dev/null")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .unwrap();
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let mut transport = connect_stdio(stdin, stdout, "broker", ConnectOptions::new())
+            .await
+            .unwrap();
+        assert_eq!(transport.connection_details().version, "1.2");
+        transport
+            .send(send_text("/queue/a", "hello"))
+            .await
+            .unwrap();
+        // closing stdin ends the child, and with it the stream
+        transport.close().await.unwrap();
+        assert!(transport.next().await.is_none());
+        assert!(child.await.unwrap().success());
+    }
+}
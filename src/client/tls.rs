@@ -0,0 +1,107 @@
+//! STOMP over TLS, using `rustls`. Configured with `ConnectOptions::tls`,
+//! `tls_with_verifier` or `tls_config`.
+
+use std::fmt::{self, Debug};
+use std::sync::Arc;
+
+use rustls::{ClientConfig, ServerCertVerifier};
+use tokio::net::TcpStream;
+use tokio_rustls::{client::TlsStream, TlsConnector};
+use webpki::DNSNameRef;
+
+use super::{connect_stream_with_options, strip_port, ConnectOptions, StompTransport};
+use crate::Result;
+
+/// How the server's certificate is verified, and any other TLS settings
+#[derive(Clone, Default)]
+pub enum TlsConfig {
+    /// Verify the server against the Mozilla root certificates bundled by `webpki-roots`
+    #[default]
+    Default,
+    /// A configuration built by hand, e.g. with a custom CA bundle or a client certificate
+    Custom(Arc<ClientConfig>),
+    /// Accept any certificate. This defeats the purpose of TLS, and is only meant for
+    /// testing against brokers with self-signed certificates.
+    #[cfg(feature = "tls-insecure")]
+    InsecureNoVerify,
+}
+
+impl Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsConfig::Default => f.write_str("Default"),
+            TlsConfig::Custom(_) => f.write_str("Custom(..)"),
+            #[cfg(feature = "tls-insecure")]
+            TlsConfig::InsecureNoVerify => f.write_str("InsecureNoVerify"),
+        }
+    }
+}
+
+impl TlsConfig {
+    /// Verify the server with `verifier`, rather than against the root certificates
+    pub fn with_verifier(verifier: Arc<dyn ServerCertVerifier>) -> Self {
+        let mut config = default_config();
+        config.dangerous().set_certificate_verifier(verifier);
+        TlsConfig::Custom(Arc::new(config))
+    }
+
+    fn client_config(&self) -> Arc<ClientConfig> {
+        match self {
+            TlsConfig::Default => Arc::new(default_config()),
+            TlsConfig::Custom(config) => config.clone(),
+            #[cfg(feature = "tls-insecure")]
+            TlsConfig::InsecureNoVerify => {
+                let mut config = default_config();
+                config
+                    .dangerous()
+                    .set_certificate_verifier(Arc::new(insecure::NoVerification));
+                Arc::new(config)
+            }
+        }
+    }
+}
+
+fn default_config() -> ClientConfig {
+    let mut config = ClientConfig::new();
+    config
+        .root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    config
+}
+
+#[cfg(feature = "tls-insecure")]
+mod insecure {
+    use rustls::{Certificate, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError};
+    use webpki::DNSNameRef;
+
+    pub(super) struct NoVerification;
+
+    impl ServerCertVerifier for NoVerification {
+        fn verify_server_cert(
+            &self,
+            _roots: &RootCertStore,
+            _presented_certs: &[Certificate],
+            _dns_name: DNSNameRef<'_>,
+            _ocsp_response: &[u8],
+        ) -> Result<ServerCertVerified, TLSError> {
+            Ok(ServerCertVerified::assertion())
+        }
+    }
+}
+
+/// Connect to a STOMP server over TLS, including the connection handshake.
+/// The host part of `address` (without its port) is checked against the server's
+/// certificate, which must name it as a DNS name, as configured by `ConnectOptions::tls`.
+pub async fn connect(
+    address: impl Into<String>,
+    options: ConnectOptions,
+) -> Result<StompTransport<TlsStream<TcpStream>>> {
+    let address = address.into();
+    let host = strip_port(&address);
+    let server_name = DNSNameRef::try_from_ascii_str(host)
+        .map_err(|_| failure::format_err!("'{}' is not a valid DNS name", host))?;
+    let tcp = TcpStream::connect(&address).await?;
+    let connector = TlsConnector::from(options.tls.client_config());
+    let stream = connector.connect(server_name, tcp).await?;
+    connect_stream_with_options(stream, address, options).await
+}
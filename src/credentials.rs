@@ -0,0 +1,137 @@
+use std::fmt::{self, Debug};
+
+use futures::future::{self, BoxFuture};
+
+use crate::{Result, StompError};
+
+/// Supplies the login and passcode for each CONNECT frame, e.g. by minting a short-lived
+/// token, so that every connection made with the same options authenticates afresh.
+/// See `ConnectOptions::credentials_provider`.
+pub trait CredentialsProvider: Debug + Send + Sync {
+    /// The login and passcode to send in the next CONNECT frame.
+    /// An error fails the attempt to connect.
+    fn credentials(&self) -> BoxFuture<'_, Result<(Option<String>, Option<String>)>>;
+}
+
+/// A login and passcode which never change, as set by `ConnectOptions::login` and `passcode`
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct StaticCredentials {
+    pub login: Option<String>,
+    pub passcode: Option<String>,
+}
+
+impl Debug for StaticCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StaticCredentials")
+            .field("login", &self.login)
+            .field("passcode", &self.passcode.as_ref().map(|_| "***"))
+            .finish()
+    }
+}
+
+impl CredentialsProvider for StaticCredentials {
+    fn credentials(&self) -> BoxFuture<'_, Result<(Option<String>, Option<String>)>> {
+        Box::pin(future::ok((self.login.clone(), self.passcode.clone())))
+    }
+}
+
+/// How the login and passcode are written into the CONNECT frame's headers.
+/// See `ConnectOptions::credential_encoding`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CredentialEncoding {
+    /// As plain header values, as the STOMP specification says
+    #[default]
+    Plain,
+    /// Percent-encoded as in a URL, as expected by some brokers which tunnel STOMP over HTTP.
+    /// Every byte of the UTF-8 value other than an ASCII letter, digit, `-`, `.`, `_` or `~`
+    /// is written as `%XX`.
+    PercentEncoded,
+}
+
+impl CredentialEncoding {
+    /// Encode a login or passcode for sending
+    pub fn encode(self, value: &str) -> String {
+        match self {
+            CredentialEncoding::Plain => value.to_string(),
+            CredentialEncoding::PercentEncoded => {
+                let mut encoded = String::with_capacity(value.len());
+                for &byte in value.as_bytes() {
+                    if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+                        encoded.push(byte as char);
+                    } else {
+                        encoded.push_str(&format!("%{:02X}", byte));
+                    }
+                }
+                encoded
+            }
+        }
+    }
+
+    /// Decode a login or passcode received in a CONNECT frame, e.g. by a server.
+    /// Fails if a percent-encoded value has a malformed escape or isn't UTF-8 once decoded,
+    /// without quoting the value.
+    pub fn decode(self, value: &str) -> std::result::Result<String, StompError> {
+        match self {
+            CredentialEncoding::Plain => Ok(value.to_string()),
+            CredentialEncoding::PercentEncoded => {
+                let invalid = || StompError::InvalidMessage("invalid percent-encoding".into());
+                let mut decoded = Vec::with_capacity(value.len());
+                let mut bytes = value.bytes();
+                while let Some(byte) = bytes.next() {
+                    if byte != b'%' {
+                        decoded.push(byte);
+                        continue;
+                    }
+                    let mut digit = || {
+                        bytes
+                            .next()
+                            .and_then(|digit| (digit as char).to_digit(16))
+                            .ok_or_else(invalid)
+                    };
+                    let high = digit()?;
+                    let low = digit()?;
+                    decoded.push((high * 16 + low) as u8);
+                }
+                String::from_utf8(decoded).map_err(|_| invalid())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_encoding_round_trip() {
+        let encoding = CredentialEncoding::PercentEncoded;
+        assert_eq!(encoding.encode("guest"), "guest");
+        assert_eq!(encoding.encode("a b:c%d"), "a%20b%3Ac%25d");
+        assert_eq!(encoding.encode("pässwörd~1"), "p%C3%A4ssw%C3%B6rd~1");
+        for value in &["guest", "a b:c%d", "pässwörd~1", "line\nbreak", ""] {
+            assert_eq!(encoding.decode(&encoding.encode(value)).unwrap(), *value);
+        }
+        assert_eq!(encoding.decode("a%3ab").unwrap(), "a:b");
+
+        for bad in &["%", "%4", "%zz", "%C3"] {
+            assert!(encoding.decode(bad).is_err(), "{:?} should not decode", bad);
+        }
+
+        let plain = CredentialEncoding::Plain;
+        assert_eq!(plain.encode("a b%20"), "a b%20");
+        assert_eq!(plain.decode("a b%20").unwrap(), "a b%20");
+    }
+
+    #[test]
+    fn static_credentials_debug_masks_passcode() {
+        let credentials = StaticCredentials {
+            login: Some("user".into()),
+            passcode: Some("secret".into()),
+        };
+        let debug = format!("{:?}", credentials);
+        assert_eq!(
+            debug,
+            "StaticCredentials { login: Some(\"user\"), passcode: Some(\"***\") }"
+        );
+    }
+}
@@ -0,0 +1,219 @@
+use std::fmt::Debug;
+use std::time::Duration;
+
+/// Maps broker-independent options onto the headers and destinations a particular broker expects.
+/// Implement this to support an in-house broker; `BrokerFlavor` covers the common ones.
+/// Methods return `None` when the broker has no equivalent.
+pub trait BrokerDialect: Debug + Send + Sync {
+    /// Header marking a sent message as persistent
+    fn persistent(&self) -> Option<(String, String)>;
+    /// Subscribe header limiting the number of unacknowledged messages in flight
+    fn prefetch(&self, count: u32) -> Option<(String, String)>;
+    /// Send header delaying delivery of a message
+    fn delay(&self, delay: Duration) -> Option<(String, String)>;
+    /// Destination of a temporary queue suitable for receiving replies
+    fn temp_reply_destination(&self, name: &str) -> Option<String>;
+}
+
+/// Dialects of well known brokers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BrokerFlavor {
+    /// ActiveMQ "Classic"
+    ActiveMQ,
+    /// ActiveMQ Artemis
+    Artemis,
+    RabbitMQ,
+    /// Only what the STOMP specification guarantees
+    Generic,
+}
+
+fn header(key: &str, value: impl ToString) -> Option<(String, String)> {
+    Some((key.into(), value.to_string()))
+}
+
+impl BrokerDialect for BrokerFlavor {
+    fn persistent(&self) -> Option<(String, String)> {
+        match self {
+            BrokerFlavor::ActiveMQ | BrokerFlavor::Artemis | BrokerFlavor::RabbitMQ => {
+                header("persistent", "true")
+            }
+            BrokerFlavor::Generic => None,
+        }
+    }
+
+    fn prefetch(&self, count: u32) -> Option<(String, String)> {
+        match self {
+            BrokerFlavor::ActiveMQ => header("activemq.prefetchSize", count),
+            BrokerFlavor::Artemis => header("consumer-window-size", count),
+            BrokerFlavor::RabbitMQ => header("prefetch-count", count),
+            BrokerFlavor::Generic => None,
+        }
+    }
+
+    fn delay(&self, delay: Duration) -> Option<(String, String)> {
+        match self {
+            BrokerFlavor::ActiveMQ | BrokerFlavor::Artemis => {
+                header("AMQ_SCHEDULED_DELAY", delay.as_millis())
+            }
+            // requires the rabbitmq_delayed_message_exchange plugin
+            BrokerFlavor::RabbitMQ => header("x-delay", delay.as_millis()),
+            BrokerFlavor::Generic => None,
+        }
+    }
+
+    fn temp_reply_destination(&self, name: &str) -> Option<String> {
+        match self {
+            BrokerFlavor::ActiveMQ | BrokerFlavor::Artemis | BrokerFlavor::RabbitMQ => {
+                Some(format!("/temp-queue/{}", name))
+            }
+            BrokerFlavor::Generic => None,
+        }
+    }
+}
+
+/// What a broker advertised about itself in its CONNECTED frame,
+/// see `ConnectionDetails::capabilities`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerCapabilities {
+    /// The product name from the `server` header, e.g. `RabbitMQ`
+    pub name: Option<String>,
+    /// The version from the `server` header, e.g. `3.12.0`
+    pub version: Option<String>,
+    /// Whatever follows the name and version in the `server` header
+    pub comment: Option<String>,
+    /// Headers of the CONNECTED frame which STOMP doesn't define, e.g. vendor specific ones
+    pub extras: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl ServerCapabilities {
+    /// Parse a `server` header, which the spec gives as `name ["/" version] *(comment)`,
+    /// e.g. `ActiveMQ-Artemis/2.31.2 ActiveMQ Artemis Messaging Engine`
+    pub fn from_server_header(server: &str) -> ServerCapabilities {
+        let server = server.trim();
+        let (product, comment) = match server.find(char::is_whitespace) {
+            Some(end) => (&server[..end], Some(server[end..].trim_start())),
+            None => (server, None),
+        };
+        let (name, version) = match product.find('/') {
+            Some(slash) => (&product[..slash], Some(&product[slash + 1..])),
+            None => (product, None),
+        };
+        let non_empty = |s: &str| Some(s.to_string()).filter(|s| !s.is_empty());
+        ServerCapabilities {
+            name: non_empty(name),
+            version: version.and_then(non_empty),
+            comment: comment.and_then(non_empty),
+            extras: vec![],
+        }
+    }
+
+    /// The value of an extra CONNECTED header
+    pub fn extra(&self, key: &str) -> Option<&[u8]> {
+        self.extras
+            .iter()
+            .find(|(k, _)| k == key.as_bytes())
+            .map(|(_, v)| &v[..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(
+        name: Option<&str>,
+        version: Option<&str>,
+        comment: Option<&str>,
+    ) -> ServerCapabilities {
+        ServerCapabilities {
+            name: name.map(Into::into),
+            version: version.map(Into::into),
+            comment: comment.map(Into::into),
+            extras: vec![],
+        }
+    }
+
+    #[test]
+    fn parse_server_name_only() {
+        assert_eq!(
+            ServerCapabilities::from_server_header("Acme"),
+            server(Some("Acme"), None, None)
+        );
+        assert_eq!(
+            ServerCapabilities::from_server_header(""),
+            server(None, None, None)
+        );
+    }
+
+    #[test]
+    fn parse_server_name_and_version() {
+        assert_eq!(
+            ServerCapabilities::from_server_header("RabbitMQ/3.12.0"),
+            server(Some("RabbitMQ"), Some("3.12.0"), None)
+        );
+        assert_eq!(
+            ServerCapabilities::from_server_header("Acme/"),
+            server(Some("Acme"), None, None)
+        );
+    }
+
+    #[test]
+    fn parse_server_with_comments() {
+        assert_eq!(
+            ServerCapabilities::from_server_header(
+                "ActiveMQ-Artemis/2.31.2 ActiveMQ Artemis Messaging Engine"
+            ),
+            server(
+                Some("ActiveMQ-Artemis"),
+                Some("2.31.2"),
+                Some("ActiveMQ Artemis Messaging Engine")
+            )
+        );
+        assert_eq!(
+            ServerCapabilities::from_server_header("Acme  (beta)"),
+            server(Some("Acme"), None, Some("(beta)"))
+        );
+    }
+
+    fn header_of(key: Option<&str>, value: &str) -> Option<(String, String)> {
+        key.map(|k| (k.to_string(), value.to_string()))
+    }
+
+    #[test]
+    fn flavor_headers() {
+        use BrokerFlavor::*;
+        let table = [
+            (
+                ActiveMQ,
+                Some("persistent"),
+                Some("activemq.prefetchSize"),
+                Some("AMQ_SCHEDULED_DELAY"),
+                Some("/temp-queue/replies"),
+            ),
+            (
+                Artemis,
+                Some("persistent"),
+                Some("consumer-window-size"),
+                Some("AMQ_SCHEDULED_DELAY"),
+                Some("/temp-queue/replies"),
+            ),
+            (
+                RabbitMQ,
+                Some("persistent"),
+                Some("prefetch-count"),
+                Some("x-delay"),
+                Some("/temp-queue/replies"),
+            ),
+            (Generic, None, None, None, None),
+        ];
+        for &(flavor, persistent, prefetch, delay, temp) in &table {
+            assert_eq!(flavor.persistent(), header_of(persistent, "true"));
+            assert_eq!(flavor.prefetch(10), header_of(prefetch, "10"));
+            assert_eq!(
+                flavor.delay(Duration::from_secs(2)),
+                header_of(delay, "2000")
+            );
+            assert_eq!(flavor.temp_reply_destination("replies").as_deref(), temp);
+        }
+    }
+}
@@ -0,0 +1,239 @@
+use std::fmt;
+use std::sync::Arc;
+
+/// Errors raised by this crate, as opposed to ones from IO or the server.
+/// These are returned wrapped in a `failure::Error`, and can be recovered with `downcast_ref`.
+#[derive(Debug)]
+pub enum StompError {
+    /// A frame referenced a transaction which was never begun, or has already finished
+    UnknownTransaction(String),
+    /// A BEGIN used the id of a transaction which is in progress,
+    /// or which was recently committed or aborted on the same connection
+    TransactionIdInUse(String),
+    /// An ACK or NACK would be rejected by the server, e.g. because the subscription
+    /// acknowledges messages automatically
+    AckNotApplicable(String),
+    /// Every connection of a `LoadBalancedSink` has failed
+    NoConnections,
+    /// A send did not complete in time. If `handed_to_writer` is set the frame had
+    /// already been encoded for writing, and may still reach the server.
+    SendElapsed { handed_to_writer: bool },
+    /// No frame was received in time
+    RecvElapsed,
+    /// A received frame's content-length header disagrees with where its body ends.
+    /// `found_nul_at` is the offset into the body of the first NUL, if any has been received.
+    ContentLengthMismatch {
+        declared: usize,
+        found_nul_at: Option<usize>,
+    },
+    /// A frame's command is not one defined by STOMP
+    UnknownCommand(String),
+    /// A frame at `offset` into the input could not be decoded
+    InvalidFrame { offset: usize, reason: String },
+    /// A frame being received grew larger than `ConnectOptions::max_frame_size`,
+    /// e.g. because the peer never terminated it
+    FrameTooLarge { limit: usize },
+    /// A MESSAGE frame arrived for a subscription which is not open,
+    /// e.g. because it was delivered just before an UNSUBSCRIBE reached the server
+    UnknownSubscription(String),
+    /// A SUBSCRIBE used the id of a subscription which is already open.
+    /// See `ConnectOptions::allow_resubscribe`.
+    SubscriptionIdInUse(String),
+    /// The receipt for the SUBSCRIBE with this subscription id did not arrive in time,
+    /// see `StompTransport::subscribe_confirmed`
+    SubscribeConfirmTimeout(String),
+    /// Frames could not be flushed to the connection for longer than
+    /// `ConnectOptions::write_stall_timeout`, e.g. because the server stopped reading
+    WriteStalled(std::time::Duration),
+    /// A frame's body could not be serialized or deserialized
+    InvalidBody(String),
+    /// A frame was sent after the transport's sink was closed
+    Closing,
+    /// A frame parsed, but could not be converted into a message, e.g. because a required
+    /// header is missing or malformed. The reason names the header at fault.
+    InvalidMessage(String),
+    /// More frames arrived before `Consumer::run` than its startup buffer holds, with
+    /// `SpillPolicy::Fail`
+    StartupBufferFull { limit: usize },
+    /// `ConnectOptions::max_pending_receipts` receipts were outstanding, with
+    /// `ReceiptLimitPolicy::Fail`
+    TooManyPendingReceipts { limit: usize },
+    /// Nothing was received for longer than `ConnectOptions::liveness_fallback`, while the
+    /// server declined the requested heart-beats
+    ServerSilent(std::time::Duration),
+    /// A `FailoverTransport` gave up after this many consecutive attempts to reconnect failed.
+    /// `last_error` is why the last attempt failed, and is also this error's `source`.
+    MaxReconnectsExceeded {
+        attempts: u32,
+        last_error: Arc<dyn std::error::Error + Send + Sync>,
+    },
+    /// A SEND was dropped rather than sent, because its `expires` header had passed.
+    /// See `SendBuilder::deadline`.
+    MessageExpired,
+    /// A frame was only partly written to the connection, e.g. because a `send_ref`
+    /// future was dropped, so nothing more can be sent on it
+    TornFrame,
+}
+
+impl fmt::Display for StompError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StompError::UnknownTransaction(id) => {
+                write!(f, "transaction '{}' is not in progress", id)
+            }
+            StompError::TransactionIdInUse(id) => {
+                write!(f, "transaction id '{}' is already in use", id)
+            }
+            StompError::AckNotApplicable(reason) => {
+                write!(f, "acknowledgment not applicable: {}", reason)
+            }
+            StompError::NoConnections => f.write_str("no connections available"),
+            StompError::SendElapsed { .. } => f.write_str("timed out sending frame"),
+            StompError::RecvElapsed => f.write_str("timed out waiting for a frame"),
+            StompError::ContentLengthMismatch { declared, .. } => write!(
+                f,
+                "content-length {} does not match the frame's NUL terminator",
+                declared
+            ),
+            StompError::UnknownCommand(command) => write!(f, "unknown command '{}'", command),
+            StompError::InvalidFrame { offset, reason } => {
+                write!(f, "invalid frame at offset {}: {}", offset, reason)
+            }
+            StompError::FrameTooLarge { limit } => {
+                write!(f, "frame exceeds the maximum size of {} bytes", limit)
+            }
+            StompError::UnknownSubscription(id) => {
+                write!(f, "message for unknown subscription '{}'", id)
+            }
+            StompError::SubscriptionIdInUse(id) => {
+                write!(f, "subscription id '{}' is already in use", id)
+            }
+            StompError::SubscribeConfirmTimeout(id) => {
+                write!(f, "subscription '{}' was not confirmed in time", id)
+            }
+            StompError::WriteStalled(timeout) => {
+                write!(f, "no frames could be written for {:?}", timeout)
+            }
+            StompError::InvalidBody(reason) => write!(f, "invalid body: {}", reason),
+            StompError::Closing => f.write_str("the connection is closing"),
+            StompError::InvalidMessage(reason) => write!(f, "invalid message: {}", reason),
+            StompError::StartupBufferFull { limit } => {
+                write!(
+                    f,
+                    "more than {} frames arrived before the consumer ran",
+                    limit
+                )
+            }
+            StompError::TooManyPendingReceipts { limit } => {
+                write!(f, "{} receipts are already pending", limit)
+            }
+            StompError::ServerSilent(max_silence) => {
+                write!(f, "nothing was received for {:?}", max_silence)
+            }
+            StompError::MaxReconnectsExceeded { attempts, .. } => {
+                write!(f, "gave up after {} attempts to reconnect", attempts)
+            }
+            StompError::MessageExpired => f.write_str("the message expired before it was sent"),
+            StompError::TornFrame => {
+                f.write_str("a frame was only partly written, so the connection can't be used")
+            }
+        }
+    }
+}
+
+/// `StompError` is a standard error, so it is also a `failure::Fail`
+/// and can be wrapped in a `failure::Error`
+impl std::error::Error for StompError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StompError::MaxReconnectsExceeded { last_error, .. } => Some(&**last_error),
+            _ => None,
+        }
+    }
+}
+
+impl StompError {
+    /// Whether the operation which failed may succeed if tried again, e.g. on a new connection.
+    /// Timeouts and the loss of every connection are transient; errors in frames,
+    /// acknowledgments, transactions or subscriptions will recur.
+    pub fn is_retriable(&self) -> bool {
+        match self {
+            StompError::SendElapsed { .. }
+            | StompError::RecvElapsed
+            | StompError::NoConnections
+            | StompError::SubscribeConfirmTimeout(_)
+            | StompError::WriteStalled(_)
+            | StompError::TooManyPendingReceipts { .. }
+            | StompError::ServerSilent(_)
+            | StompError::TornFrame => true,
+            StompError::UnknownTransaction(_)
+            | StompError::TransactionIdInUse(_)
+            | StompError::AckNotApplicable(_)
+            | StompError::ContentLengthMismatch { .. }
+            | StompError::UnknownCommand(_)
+            | StompError::InvalidFrame { .. }
+            | StompError::FrameTooLarge { .. }
+            | StompError::UnknownSubscription(_)
+            | StompError::SubscriptionIdInUse(_)
+            | StompError::InvalidBody(_)
+            | StompError::Closing
+            | StompError::InvalidMessage(_)
+            | StompError::StartupBufferFull { .. }
+            | StompError::MaxReconnectsExceeded { .. }
+            | StompError::MessageExpired => false,
+        }
+    }
+
+    /// Like `is_retriable`, for any error returned by this crate. IO errors caused by
+    /// the network, such as a reset connection or a timeout, are retriable; errors from
+    /// parsing or from the server are not.
+    pub fn is_retriable_error(error: &failure::Error) -> bool {
+        use std::io::ErrorKind::*;
+        if let Some(error) = error.downcast_ref::<StompError>() {
+            return error.is_retriable();
+        }
+        match error.downcast_ref::<std::io::Error>() {
+            Some(error) => matches!(
+                error.kind(),
+                ConnectionRefused
+                    | ConnectionReset
+                    | ConnectionAborted
+                    | NotConnected
+                    | BrokenPipe
+                    | TimedOut
+                    | Interrupted
+                    | UnexpectedEof
+            ),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn retriable_errors() {
+        assert!(StompError::RecvElapsed.is_retriable());
+        assert!(StompError::SendElapsed {
+            handed_to_writer: true
+        }
+        .is_retriable());
+        assert!(StompError::NoConnections.is_retriable());
+        assert!(!StompError::UnknownCommand("FOO".into()).is_retriable());
+        assert!(!StompError::FrameTooLarge { limit: 10 }.is_retriable());
+
+        let reset = io::Error::new(io::ErrorKind::ConnectionReset, "reset");
+        assert!(StompError::is_retriable_error(&reset.into()));
+        let denied = io::Error::new(io::ErrorKind::PermissionDenied, "denied");
+        assert!(!StompError::is_retriable_error(&denied.into()));
+        assert!(StompError::is_retriable_error(
+            &StompError::RecvElapsed.into()
+        ));
+        assert!(!StompError::is_retriable_error(&failure::err_msg(
+            "ERROR frame from server"
+        )));
+    }
+}
@@ -0,0 +1,483 @@
+use std::collections::HashSet;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::prelude::*;
+
+use crate::{FromServer, Message, Result};
+
+/// A predicate on messages received from the server
+pub trait StompFilter {
+    fn accepts(&self, msg: &Message<FromServer>) -> bool;
+}
+
+/// Accepts MESSAGE frames sent to a destination
+#[derive(Debug, Clone)]
+pub struct DestinationFilter(String);
+
+impl DestinationFilter {
+    pub fn new(destination: &str) -> Self {
+        DestinationFilter(destination.into())
+    }
+}
+
+impl StompFilter for DestinationFilter {
+    fn accepts(&self, msg: &Message<FromServer>) -> bool {
+        match &msg.content {
+            FromServer::Message { destination, .. } => *destination == self.0,
+            _ => false,
+        }
+    }
+}
+
+/// Accepts MESSAGE frames delivered to a subscription
+#[derive(Debug, Clone)]
+pub struct SubscriptionFilter(String);
+
+impl SubscriptionFilter {
+    pub fn new(subscription_id: &str) -> Self {
+        SubscriptionFilter(subscription_id.into())
+    }
+}
+
+impl StompFilter for SubscriptionFilter {
+    fn accepts(&self, msg: &Message<FromServer>) -> bool {
+        match &msg.content {
+            FromServer::Message { subscription, .. } => *subscription == self.0,
+            _ => false,
+        }
+    }
+}
+
+/// Accepts messages accepted by both filters
+#[derive(Debug, Clone)]
+pub struct AndFilter<A, B>(pub A, pub B);
+
+impl<A: StompFilter, B: StompFilter> StompFilter for AndFilter<A, B> {
+    fn accepts(&self, msg: &Message<FromServer>) -> bool {
+        self.0.accepts(msg) && self.1.accepts(msg)
+    }
+}
+
+/// Accepts messages accepted by either filter
+#[derive(Debug, Clone)]
+pub struct OrFilter<A, B>(pub A, pub B);
+
+impl<A: StompFilter, B: StompFilter> StompFilter for OrFilter<A, B> {
+    fn accepts(&self, msg: &Message<FromServer>) -> bool {
+        self.0.accepts(msg) || self.1.accepts(msg)
+    }
+}
+
+/// Accepts messages rejected by the inner filter
+#[derive(Debug, Clone)]
+pub struct NotFilter<F>(pub F);
+
+impl<F: StompFilter> StompFilter for NotFilter<F> {
+    fn accepts(&self, msg: &Message<FromServer>) -> bool {
+        !self.0.accepts(msg)
+    }
+}
+
+/// A filter built from simple conditions by `MessageFilter::builder`, without writing
+/// a closure. Conditions on a MESSAGE frame's destination, subscription or message id
+/// reject every other frame.
+///
+/// ```
+/// use tokio_stomp::client::{MessageFilter, StompFilter};
+///
+/// // orders for the EU region, or anything which has been redelivered
+/// let filter = MessageFilter::builder()
+///     .destination_glob("/queue/orders.*")
+///     .header_eq("region", "eu")
+///     .or()
+///     .header_eq("redelivered", "true")
+///     .build();
+/// # let _ = filter;
+/// ```
+#[derive(Debug, Clone)]
+pub struct MessageFilter {
+    /// Each branch's conditions must all hold, and any one branch must hold
+    branches: Vec<Vec<Condition>>,
+}
+
+impl MessageFilter {
+    pub fn builder() -> MessageFilterBuilder {
+        MessageFilterBuilder::default()
+    }
+}
+
+impl StompFilter for MessageFilter {
+    fn accepts(&self, msg: &Message<FromServer>) -> bool {
+        self.branches
+            .iter()
+            .any(|branch| branch.iter().all(|condition| condition.accepts(msg)))
+    }
+}
+
+/// Builds a `MessageFilter`. Conditions are combined with AND, until `or` starts another
+/// branch of conditions. A filter with no conditions accepts every message.
+#[derive(Debug, Clone, Default)]
+pub struct MessageFilterBuilder {
+    branches: Vec<Vec<Condition>>,
+    current: Vec<Condition>,
+}
+
+impl MessageFilterBuilder {
+    /// The destination is `destination`
+    pub fn destination_eq(self, destination: impl Into<String>) -> Self {
+        self.with(Condition::DestinationEq(destination.into()))
+    }
+
+    /// The destination matches `pattern`, in which `*` stands for any run of characters,
+    /// including `/`, and `?` for any one character
+    pub fn destination_glob(self, pattern: impl Into<String>) -> Self {
+        self.with(Condition::DestinationGlob(pattern.into()))
+    }
+
+    /// The message was delivered to the subscription with id `subscription`
+    pub fn subscription_eq(self, subscription: impl Into<String>) -> Self {
+        self.with(Condition::SubscriptionEq(subscription.into()))
+    }
+
+    /// The message id is one of `ids`
+    pub fn message_id_in(self, ids: HashSet<String>) -> Self {
+        self.with(Condition::MessageIdIn(ids))
+    }
+
+    /// The body of a MESSAGE or ERROR frame contains `pattern`
+    pub fn body_contains(self, pattern: &[u8]) -> Self {
+        self.with(Condition::BodyContains(pattern.to_vec()))
+    }
+
+    /// The frame has an extra header named `name`
+    pub fn has_header(self, name: impl Into<Vec<u8>>) -> Self {
+        self.with(Condition::HasHeader(name.into()))
+    }
+
+    /// The frame has an extra header named `name` with the value `value`
+    pub fn header_eq(self, name: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Self {
+        self.with(Condition::HeaderEq(name.into(), value.into()))
+    }
+
+    /// Start another branch: the filter accepts messages meeting every condition before
+    /// this call, or every condition after it. Branches without conditions are ignored.
+    pub fn or(mut self) -> Self {
+        let branch = std::mem::take(&mut self.current);
+        if !branch.is_empty() {
+            self.branches.push(branch);
+        }
+        self
+    }
+
+    pub fn build(self) -> MessageFilter {
+        let mut builder = self.or();
+        if builder.branches.is_empty() {
+            builder.branches.push(vec![]);
+        }
+        MessageFilter {
+            branches: builder.branches,
+        }
+    }
+
+    fn with(mut self, condition: Condition) -> Self {
+        self.current.push(condition);
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Condition {
+    DestinationEq(String),
+    DestinationGlob(String),
+    SubscriptionEq(String),
+    MessageIdIn(HashSet<String>),
+    BodyContains(Vec<u8>),
+    HasHeader(Vec<u8>),
+    HeaderEq(Vec<u8>, Vec<u8>),
+}
+
+impl Condition {
+    fn accepts(&self, msg: &Message<FromServer>) -> bool {
+        let message = match &msg.content {
+            FromServer::Message {
+                destination,
+                message_id,
+                subscription,
+                ..
+            } => Some((destination, message_id, subscription)),
+            _ => None,
+        };
+        match self {
+            Condition::DestinationEq(expected) => {
+                matches!(message, Some((destination, _, _)) if destination == expected)
+            }
+            Condition::DestinationGlob(pattern) => {
+                matches!(message, Some((destination, _, _)) if glob_matches(pattern, destination))
+            }
+            Condition::SubscriptionEq(expected) => {
+                matches!(message, Some((_, _, subscription)) if subscription == expected)
+            }
+            Condition::MessageIdIn(ids) => {
+                matches!(message, Some((_, message_id, _)) if ids.contains(message_id))
+            }
+            Condition::BodyContains(pattern) => match msg.body() {
+                Some(_) if pattern.is_empty() => true,
+                Some(body) => body
+                    .windows(pattern.len())
+                    .any(|window| window == &pattern[..]),
+                None => false,
+            },
+            Condition::HasHeader(name) => msg.extra_headers.iter().any(|(key, _)| key == name),
+            Condition::HeaderEq(name, value) => msg
+                .extra_headers
+                .iter()
+                .any(|(key, found)| key == name && found == value),
+        }
+    }
+}
+
+/// Whether `text` matches `pattern`, where `*` matches any run of characters and `?` any one
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // where to resume after the last `*`: the pattern after it, and the text it matched up to
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p + 1, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                // let the `*` match one more character
+                Some((after_star, matched)) => {
+                    p = after_star;
+                    t = matched + 1;
+                    backtrack = Some((after_star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// A stream which only yields the messages accepted by a filter.
+/// Errors, and ERROR frames from the server, are always passed through.
+#[derive(Debug)]
+pub struct FilteredStream<S, F> {
+    stream: S,
+    filter: F,
+}
+
+impl<S, F> FilteredStream<S, F> {
+    pub fn new(stream: S, filter: F) -> Self {
+        FilteredStream { stream, filter }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+/// ERROR frames pass like errors, so that no filter can hide them
+fn passes(filter: &impl StompFilter, msg: &Message<FromServer>) -> bool {
+    matches!(msg.content, FromServer::Error { .. }) || filter.accepts(msg)
+}
+
+impl<S, F> Stream for FilteredStream<S, F>
+where
+    S: Stream<Item = Result<Message<FromServer>>> + Unpin,
+    F: StompFilter + Unpin,
+{
+    type Item = Result<Message<FromServer>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            match futures::ready!(this.stream.poll_next_unpin(cx)) {
+                Some(Ok(msg)) if !passes(&this.filter, &msg) => continue,
+                item => return Poll::Ready(item),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    fn message(destination: &str, subscription: &str) -> Message<FromServer> {
+        Message {
+            content: FromServer::Message {
+                destination: destination.into(),
+                message_id: "1".into(),
+                subscription: subscription.into(),
+                body: None,
+            },
+            extra_headers: vec![],
+        }
+    }
+
+    fn filtered(filter: impl StompFilter + Unpin) -> Vec<(String, String)> {
+        let receipt = Message {
+            content: FromServer::Receipt {
+                receipt_id: "r".into(),
+            },
+            extra_headers: vec![],
+        };
+        let input = vec![
+            message("/queue/a", "1"),
+            message("/queue/b", "1"),
+            receipt,
+            message("/queue/a", "2"),
+        ];
+        let stream = FilteredStream::new(stream::iter(input).map(Ok), filter);
+        block_on(stream.collect::<Vec<_>>())
+            .into_iter()
+            .map(|msg| match msg.unwrap().content {
+                FromServer::Message {
+                    destination,
+                    subscription,
+                    ..
+                } => (destination, subscription),
+                other => panic!("unexpected message: {:?}", other),
+            })
+            .collect()
+    }
+
+    fn pairs(expected: &[(&str, &str)]) -> Vec<(String, String)> {
+        expected
+            .iter()
+            .map(|&(d, s)| (d.to_string(), s.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn builtin_filters() {
+        assert_eq!(
+            filtered(DestinationFilter::new("/queue/a")),
+            pairs(&[("/queue/a", "1"), ("/queue/a", "2")])
+        );
+        assert_eq!(
+            filtered(SubscriptionFilter::new("1")),
+            pairs(&[("/queue/a", "1"), ("/queue/b", "1")])
+        );
+    }
+
+    #[test]
+    fn combinators() {
+        let dest = DestinationFilter::new;
+        let sub = SubscriptionFilter::new;
+        assert_eq!(
+            filtered(AndFilter(dest("/queue/a"), sub("1"))),
+            pairs(&[("/queue/a", "1")])
+        );
+        assert_eq!(
+            filtered(OrFilter(dest("/queue/b"), sub("2"))),
+            pairs(&[("/queue/b", "1"), ("/queue/a", "2")])
+        );
+        assert_eq!(
+            filtered(AndFilter(dest("/queue/a"), NotFilter(sub("1")))),
+            pairs(&[("/queue/a", "2")])
+        );
+    }
+
+    #[test]
+    fn errors_pass_through() {
+        let input = vec![
+            Err(failure::format_err!("boom")),
+            Ok(message("/queue/b", "1")),
+        ];
+        let stream = FilteredStream::new(stream::iter(input), DestinationFilter::new("/queue/a"));
+        let items = block_on(stream.collect::<Vec<_>>());
+        assert_eq!(items.len(), 1);
+        assert!(items[0].is_err());
+
+        let error = Message {
+            content: FromServer::Error {
+                message: Some("boom".into()),
+                body: None,
+            },
+            extra_headers: vec![],
+        };
+        let input = vec![Ok(message("/queue/b", "1")), Ok(error)];
+        let stream = FilteredStream::new(stream::iter(input), DestinationFilter::new("/queue/a"));
+        let items = block_on(stream.collect::<Vec<_>>());
+        assert_eq!(items.len(), 1);
+        assert!(matches!(
+            items[0].as_ref().unwrap().content,
+            FromServer::Error { .. }
+        ));
+    }
+
+    #[test]
+    fn message_filter_branches() {
+        let filter = MessageFilter::builder()
+            .destination_eq("/queue/a")
+            .subscription_eq("1")
+            .or()
+            .subscription_eq("2")
+            .build();
+        assert_eq!(
+            filtered(filter),
+            pairs(&[("/queue/a", "1"), ("/queue/a", "2")])
+        );
+        // an empty filter accepts everything, and an empty branch is ignored
+        let everything = MessageFilter::builder().build();
+        assert!(everything.accepts(&message("/queue/c", "3")));
+        let filter = MessageFilter::builder()
+            .or()
+            .destination_eq("/queue/b")
+            .or()
+            .build();
+        assert_eq!(filtered(filter), pairs(&[("/queue/b", "1")]));
+    }
+
+    #[test]
+    fn message_filter_conditions() {
+        let mut msg = message("/topic/prices.eu", "1");
+        msg.extra_headers = vec![(b"priority".to_vec(), b"high".to_vec())];
+        if let FromServer::Message { body, .. } = &mut msg.content {
+            *body = Some(b"{\"symbol\":\"ACME\"}".to_vec());
+        }
+        let accepts = |builder: MessageFilterBuilder| builder.build().accepts(&msg);
+        let builder = MessageFilter::builder;
+
+        assert!(accepts(builder().destination_glob("/topic/prices.*")));
+        assert!(accepts(builder().destination_glob("/topic/prices.e?")));
+        assert!(accepts(builder().destination_glob("*.eu")));
+        assert!(!accepts(builder().destination_glob("/topic/prices.?")));
+        assert!(!accepts(builder().destination_glob("/queue/*")));
+
+        let ids = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect();
+        assert!(accepts(builder().message_id_in(ids(&["1", "2"]))));
+        assert!(!accepts(builder().message_id_in(ids(&["2"]))));
+
+        assert!(accepts(builder().body_contains(b"ACME")));
+        assert!(accepts(builder().body_contains(b"")));
+        assert!(!accepts(builder().body_contains(b"INITECH")));
+
+        assert!(accepts(builder().has_header("priority")));
+        assert!(accepts(builder().header_eq("priority", "high")));
+        assert!(!accepts(builder().header_eq("priority", "low")));
+        assert!(!accepts(builder().has_header("expires")));
+
+        // conditions on a MESSAGE's headers never hold for other frames
+        let receipt = Message {
+            content: FromServer::Receipt {
+                receipt_id: "r".into(),
+            },
+            extra_headers: vec![],
+        };
+        assert!(!builder().destination_glob("*").build().accepts(&receipt));
+        assert!(!builder().body_contains(b"").build().accepts(&receipt));
+    }
+}
@@ -1,9 +1,15 @@
 use bytes::{BufMut, BytesMut};
-use failure::{bail, format_err};
+use nom::IResult;
 
 use std::borrow::Cow;
+use std::fmt::{self, Write};
+use std::io;
 
-use crate::{AckMode, FromServer, Message, Result, ToServer, ToServerType};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    AckMode, AsciiCaseIgnore, FromServer, Message, Result, StompError, ToServer, ToServerType,
+};
 
 type OptionalCowBytes<'a> = Option<Cow<'a, [u8]>>;
 
@@ -35,58 +41,361 @@ impl<'a> Frame<'a> {
         }
     }
 
+    /// Render the frame for people to read, see `pretty_frame`
+    pub(crate) fn pretty<H: AsRef<str>>(&self, redacted: &[H]) -> String {
+        let headers = self.headers.iter().map(|(k, v)| (*k, &v[..]));
+        crate::pretty::render(self.command, headers, self.body, redacted)
+    }
+
+    pub(crate) fn extend_headers(&mut self, headers: &'a [(Vec<u8>, Vec<u8>)]) {
+        self.headers
+            .extend(headers.iter().map(|(k, v)| (&k[..], Cow::Borrowed(&v[..]))));
+    }
+
     pub(crate) fn serialize(&self, buffer: &mut BytesMut) {
-        fn write_escaped(b: u8, buffer: &mut BytesMut) {
-            match b {
-                b'\r' => {
-                    buffer.put_u8(b'\\');
-                    buffer.put_u8(b'r')
-                }
-                b'\n' => {
-                    buffer.put_u8(b'\\');
-                    buffer.put_u8(b'n')
-                }
-                b':' => {
-                    buffer.put_u8(b'\\');
-                    buffer.put_u8(b'c')
-                }
-                b'\\' => {
-                    buffer.put_u8(b'\\');
-                    buffer.put_u8(b'\\')
-                }
-                b => buffer.put_u8(b),
-            }
-        }
+        self.serialize_with(buffer, LineEnding::Lf, HeaderOrder::Declared);
+    }
+
+    pub(crate) fn serialize_with(
+        &self,
+        buffer: &mut BytesMut,
+        line_ending: LineEnding,
+        order: HeaderOrder,
+    ) {
+        let mut sink = BytesMutSink(std::mem::take(buffer));
+        self.write_to(&mut sink, line_ending, order);
+        *buffer = sink.0;
+    }
+
+    pub(crate) fn serialize_to<W: SerializeSink>(&self, buffer: &mut W) {
+        self.write_to(buffer, LineEnding::Lf, HeaderOrder::Declared);
+    }
+
+    fn write_to<W: SerializeSink>(
+        &self,
+        buffer: &mut W,
+        line_ending: LineEnding,
+        order: HeaderOrder,
+    ) {
+        let eol = line_ending.as_bytes();
         let requires = self.command.len()
             + self.body.map(|b| b.len() + 20).unwrap_or(0)
             + self
                 .headers
                 .iter()
                 .fold(0, |acc, &(ref k, ref v)| acc + k.len() + v.len())
+            + (self.headers.len() + 3) * eol.len()
             + 30;
-        if buffer.remaining_mut() < requires {
-            buffer.reserve(requires);
+        buffer.reserve(requires);
+        self.write_head(buffer, line_ending, order);
+        if let Some(body) = self.body {
+            buffer.put_slice(body);
         }
-        buffer.put_slice(self.command);
-        buffer.put_u8(b'\n');
-        self.headers.iter().for_each(|&(key, ref val)| {
+        buffer.put_u8(b'\x00');
+    }
+
+    /// Write the command and headers, up to and including the blank line before the body
+    fn write_head<W: SerializeSink>(
+        &self,
+        buffer: &mut W,
+        line_ending: LineEnding,
+        order: HeaderOrder,
+    ) {
+        let eol = line_ending.as_bytes();
+        fn write_escaped<W: SerializeSink>(b: u8, buffer: &mut W) {
+            match escape(b) {
+                Some(escaped) => {
+                    buffer.put_u8(b'\\');
+                    buffer.put_u8(escaped)
+                }
+                None => buffer.put_u8(b),
+            }
+        }
+        let write_header = |buffer: &mut W, key: &[u8], val: &[u8]| {
             for byte in key {
                 write_escaped(*byte, buffer);
             }
             buffer.put_u8(b':');
-            for byte in val.iter() {
+            for byte in val {
                 write_escaped(*byte, buffer);
             }
-            buffer.put_u8(b'\n');
-        });
+            buffer.put_slice(eol);
+        };
+        let write_content_length = |buffer: &mut W| {
+            if let Some(body) = self.body {
+                write!(SinkWriter(buffer), "content-length:{}", body.len())
+                    .expect("formatting into a SerializeSink can't fail");
+                buffer.put_slice(eol);
+            }
+        };
+        buffer.put_slice(self.command);
+        buffer.put_slice(eol);
+        // the content-type header moved to the front, if any
+        let mut leading = None;
+        if order == HeaderOrder::ContentFirst {
+            leading = self
+                .headers
+                .iter()
+                .position(|(key, _)| *key == b"content-type");
+            if let Some(index) = leading {
+                write_header(buffer, self.headers[index].0, &self.headers[index].1);
+            }
+            write_content_length(buffer);
+        }
+        for (index, (key, val)) in self.headers.iter().enumerate() {
+            if Some(index) != leading {
+                write_header(buffer, key, val);
+            }
+        }
+        if order == HeaderOrder::Declared {
+            write_content_length(buffer);
+        }
+        buffer.put_slice(eol);
+    }
+
+    /// Write the frame to `writer` without copying the body into a buffer first
+    async fn write_async<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> io::Result<()> {
+        let mut head = BytesMutSink(BytesMut::new());
+        self.write_head(&mut head, LineEnding::Lf, HeaderOrder::Declared);
+        writer.write_all(&head.0).await?;
         if let Some(body) = self.body {
-            buffer.put_slice(&get_content_length_header(&body));
-            buffer.put_u8(b'\n');
-            buffer.put_slice(body);
-        } else {
-            buffer.put_u8(b'\n');
+            writer.write_all(body).await?;
+        }
+        writer.write_all(b"\x00").await
+    }
+}
+
+/// Serialize a message, escaping the output so that it can be pasted into a Rust string literal,
+/// e.g. `SEND\ndestination:a\n\n\x00`. Useful for comparing frames in tests.
+pub fn encode_to_string(msg: &Message<ToServer>) -> String {
+    let mut buffer = BytesMut::new();
+    msg.to_frame().serialize(&mut buffer);
+    buffer
+        .iter()
+        .flat_map(|&b| std::ascii::escape_default(b))
+        .map(char::from)
+        .collect()
+}
+
+/// The line ending written after the command and each header of a frame.
+/// STOMP 1.2 accepts either, but some older brokers only understand `\r\n`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    Crlf,
+}
+
+/// The order in which the headers of a frame are written. With either order, the
+/// frame's own headers (e.g. `destination`) come in the order they are defined by the
+/// frame's variant of `ToServer`, and extra headers in the order they were added.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HeaderOrder {
+    /// The frame's own headers, then extra headers, then `content-length`.
+    /// `SendBuilder` adds `content-type` as the first extra header.
+    #[default]
+    Declared,
+    /// The first `content-type` header and then `content-length` straight after the command,
+    /// followed by the other headers as with `Declared`. For brokers and plugins which only
+    /// look at the start of a frame's headers.
+    ContentFirst,
+}
+
+/// How the names of the headers of received frames are matched
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HeaderCase {
+    /// Names must match exactly, as the STOMP spec requires
+    #[default]
+    Sensitive,
+    /// Names match regardless of ASCII case, e.g. `Message-Id` is taken to be the
+    /// `message-id` header, for gateways which change the case of headers in transit.
+    /// The names of the standard headers which the client reads from `extra_headers`,
+    /// such as `ack` and `content-type`, are normalized too. Other extra headers keep the
+    /// names they were received with.
+    Insensitive,
+}
+
+impl HeaderCase {
+    fn matches(self, name: &[u8], expected: &[u8]) -> bool {
+        match self {
+            HeaderCase::Sensitive => name == expected,
+            HeaderCase::Insensitive => name.eq_ignore_ascii_case(expected),
         }
-        buffer.put_u8(b'\x00');
+    }
+}
+
+/// The headers of received frames which are parsed into the fields of `FromServer`,
+/// or looked up in `extra_headers` by the client
+const SERVER_HEADERS: &[&[u8]] = &[
+    b"version",
+    b"session",
+    b"server",
+    b"heart-beat",
+    b"destination",
+    b"message-id",
+    b"subscription",
+    b"receipt-id",
+    b"message",
+    b"ack",
+    b"content-type",
+    b"content-length",
+    b"redelivered",
+    b"timestamp",
+];
+
+impl LineEnding {
+    fn as_bytes(self) -> &'static [u8] {
+        match self {
+            LineEnding::Lf => b"\n",
+            LineEnding::Crlf => b"\r\n",
+        }
+    }
+}
+
+/// The command of a STOMP frame, sent by either a client or a server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FrameCommand {
+    Connect,
+    Stomp,
+    Send,
+    Subscribe,
+    Unsubscribe,
+    Ack,
+    Nack,
+    Begin,
+    Commit,
+    Abort,
+    Disconnect,
+    Connected,
+    Message,
+    Receipt,
+    Error,
+}
+
+impl FrameCommand {
+    /// Parse a command, e.g. `b"SEND"`, ignoring ASCII case.
+    /// Fails with `StompError::UnknownCommand` if it isn't one.
+    pub fn from_bytes(command: &[u8]) -> std::result::Result<FrameCommand, StompError> {
+        let known = match ToServerType::parse_from_bytes(command) {
+            Some(ToServerType::Connect) if command.eq_ignore_ascii_case(b"STOMP") => {
+                FrameCommand::Stomp
+            }
+            Some(ToServerType::Connect) => FrameCommand::Connect,
+            Some(ToServerType::Send) => FrameCommand::Send,
+            Some(ToServerType::Subscribe) => FrameCommand::Subscribe,
+            Some(ToServerType::Unsubscribe) => FrameCommand::Unsubscribe,
+            Some(ToServerType::Ack) => FrameCommand::Ack,
+            Some(ToServerType::Nack) => FrameCommand::Nack,
+            Some(ToServerType::Begin) => FrameCommand::Begin,
+            Some(ToServerType::Commit) => FrameCommand::Commit,
+            Some(ToServerType::Abort) => FrameCommand::Abort,
+            Some(ToServerType::Disconnect) => FrameCommand::Disconnect,
+            None => match AsciiCaseIgnore(command) {
+                v if v == b"connected" => FrameCommand::Connected,
+                v if v == b"message" => FrameCommand::Message,
+                v if v == b"receipt" => FrameCommand::Receipt,
+                v if v == b"error" => FrameCommand::Error,
+                _ => {
+                    let command = String::from_utf8_lossy(command).into_owned();
+                    return Err(StompError::UnknownCommand(command));
+                }
+            },
+        };
+        Ok(known)
+    }
+
+    /// The command of the serialized frame at the start of `frame`, skipping any heart-beats
+    pub fn of_frame(frame: &[u8]) -> std::result::Result<FrameCommand, StompError> {
+        let start = frame
+            .iter()
+            .take_while(|&&b| b == b'\n' || b == b'\r')
+            .count();
+        let line = frame[start..]
+            .split(|&b| b == b'\n')
+            .next()
+            .unwrap_or_default();
+        Self::from_bytes(line.strip_suffix(b"\r").unwrap_or(line))
+    }
+
+    /// The command as it appears in a frame, e.g. `b"SEND"`
+    pub fn as_bytes(self) -> &'static [u8] {
+        match self {
+            FrameCommand::Connect => b"CONNECT",
+            FrameCommand::Stomp => b"STOMP",
+            FrameCommand::Send => b"SEND",
+            FrameCommand::Subscribe => b"SUBSCRIBE",
+            FrameCommand::Unsubscribe => b"UNSUBSCRIBE",
+            FrameCommand::Ack => b"ACK",
+            FrameCommand::Nack => b"NACK",
+            FrameCommand::Begin => b"BEGIN",
+            FrameCommand::Commit => b"COMMIT",
+            FrameCommand::Abort => b"ABORT",
+            FrameCommand::Disconnect => b"DISCONNECT",
+            FrameCommand::Connected => b"CONNECTED",
+            FrameCommand::Message => b"MESSAGE",
+            FrameCommand::Receipt => b"RECEIPT",
+            FrameCommand::Error => b"ERROR",
+        }
+    }
+
+    /// Whether frames with this command are sent by clients, rather than servers
+    pub fn is_client_command(self) -> bool {
+        !matches!(
+            self,
+            FrameCommand::Connected
+                | FrameCommand::Message
+                | FrameCommand::Receipt
+                | FrameCommand::Error
+        )
+    }
+}
+
+/// Somewhere serialized frames can be written to, e.g. a buffer or a list of slices
+/// to be written with vectored IO
+pub trait SerializeSink {
+    fn put_slice(&mut self, data: &[u8]);
+    fn put_u8(&mut self, b: u8);
+    /// Called before a frame is written with the number of bytes it is likely to take up
+    fn reserve(&mut self, _additional: usize) {}
+}
+
+/// Serializes frames into a `BytesMut`, growing it as needed
+#[derive(Debug, Default)]
+pub struct BytesMutSink(pub BytesMut);
+
+impl SerializeSink for BytesMutSink {
+    fn put_slice(&mut self, data: &[u8]) {
+        self.0.put_slice(data);
+    }
+
+    fn put_u8(&mut self, b: u8) {
+        self.0.put_u8(b);
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.0.reserve(additional);
+    }
+}
+
+/// Formats straight into a `SerializeSink`
+/// (unlike `BytesMut`'s own `fmt::Write`, which fails once its capacity is exhausted)
+struct SinkWriter<'a, W>(&'a mut W);
+
+impl<W: SerializeSink> fmt::Write for SinkWriter<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.put_slice(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// The character following a backslash when `b` is escaped in a header, if it needs escaping
+fn escape(b: u8) -> Option<u8> {
+    match b {
+        b'\r' => Some(b'r'),
+        b'\n' => Some(b'n'),
+        b':' => Some(b'c'),
+        b'\\' => Some(b'\\'),
+        _ => None,
     }
 }
 
@@ -107,10 +416,10 @@ named!(
     )
 );
 
-fn get_content_length(headers: &[(&[u8], Cow<[u8]>)]) -> Option<u32> {
+fn get_content_length(headers: &[(&[u8], Cow<[u8]>)], case: HeaderCase) -> Option<u32> {
     headers
         .iter()
-        .find(|(name, _)| name == b"content-length")
+        .find(|(name, _)| case.matches(name, b"content-length"))
         .and_then(|(_, value)| std::str::from_utf8(value).ok())
         .and_then(|value| value.parse().ok())
 }
@@ -124,26 +433,130 @@ fn is_empty_slice(s: &[u8]) -> Option<&[u8]> {
 }
 
 named!(
-    pub(crate) parse_frame<Frame>,
+    frame_head<(&[u8], Vec<(&[u8], Cow<[u8]>)>)>,
     do_parse!(
         many0!(eol)
             >> command: map!(take_until_and_consume!("\n"), strip_cr)
             >> headers: many0!(parse_header)
             >> eol
-            >> body: switch!(value!(get_content_length(&*headers)),
+            >> (command, headers)
+    )
+);
+
+pub(crate) fn parse_frame(input: &[u8]) -> IResult<&[u8], Frame<'_>> {
+    parse_frame_with(input, HeaderCase::Sensitive)
+}
+
+// Like `parse_frame`, matching the content-length header's name as `case` says
+named_args!(
+    pub(crate) parse_frame_with(case: HeaderCase)<Frame>,
+    do_parse!(
+        head: frame_head
+            >> body: switch!(value!(get_content_length(&head.1, case)),
                 Some(v) => map!(take!(v), Some) |
                 None => map!(take_until!("\x00"), is_empty_slice)
             )
             >> tag!("\x00")
             >> many0!(complete!(eol))
             >> (Frame {
-                command,
-                headers,
+                command: head.0,
+                headers: head.1,
                 body,
             })
     )
 );
 
+// Like `parse_frame`, but ignoring any content-length header
+named!(
+    pub(crate) parse_frame_nul_terminated<Frame>,
+    do_parse!(
+        head: frame_head
+            >> body: map!(take_until!("\x00"), is_empty_slice)
+            >> tag!("\x00")
+            >> many0!(complete!(eol))
+            >> (Frame {
+                command: head.0,
+                headers: head.1,
+                body,
+            })
+    )
+);
+
+/// Join header values continued onto the next line, which starts with whitespace, as some
+/// STOMP 1.0 brokers send them (borrowing HTTP/1.1's folding). The continuation is appended
+/// to the value with its leading whitespace stripped. Only the head of the frame at the
+/// start of `buffer` is changed, and only if it ends before the first NUL and within the
+/// first `limit` bytes. Returns the number of bytes removed.
+pub(crate) fn unfold_headers(buffer: &mut BytesMut, limit: usize) -> usize {
+    let limit = buffer[..limit]
+        .iter()
+        .position(|&b| b == 0)
+        .unwrap_or(limit);
+    let start = buffer[..limit]
+        .iter()
+        .take_while(|&&b| b == b'\n' || b == b'\r')
+        .count();
+    let mut head = Vec::new();
+    let mut pos = start;
+    // the number of lines of the head read so far, starting with the command
+    let mut lines = 0;
+    let mut folded = false;
+    let end = loop {
+        let eol = match buffer[pos..limit].iter().position(|&b| b == b'\n') {
+            Some(eol) => pos + eol,
+            // the head is incomplete
+            None => return 0,
+        };
+        let line = &buffer[pos..eol];
+        if strip_cr(line).is_empty() {
+            head.extend_from_slice(&buffer[pos..=eol]);
+            break eol + 1;
+        }
+        // the line after the command starts the headers, and continues nothing
+        let continues = lines > 1 && (line[0] == b' ' || line[0] == b'\t');
+        lines += 1;
+        if continues {
+            // drop the previous line's EOL
+            head.pop();
+            if head.last() == Some(&b'\r') {
+                head.pop();
+            }
+            let indent = line
+                .iter()
+                .take_while(|&&b| b == b' ' || b == b'\t')
+                .count();
+            head.extend_from_slice(&buffer[pos + indent..=eol]);
+            folded = true;
+        } else {
+            head.extend_from_slice(&buffer[pos..=eol]);
+        }
+        pos = eol + 1;
+    };
+    if !folded {
+        return 0;
+    }
+    let removed = end - start - head.len();
+    let rest = buffer.split_off(end);
+    buffer.truncate(start);
+    buffer.extend_from_slice(&head);
+    buffer.unsplit(rest);
+    removed
+}
+
+/// Diagnose a frame which failed to parse because the byte its content-length header
+/// points to is not the terminating NUL
+pub(crate) fn content_length_mismatch(input: &[u8], case: HeaderCase) -> Option<StompError> {
+    let (body, (_, headers)) = frame_head(input).ok()?;
+    let declared = get_content_length(&headers, case)? as usize;
+    match body.get(declared) {
+        Some(0) | None => None,
+        Some(_) => Some(StompError::ContentLengthMismatch {
+            declared,
+            found_nul_at: body.iter().position(|&b| b == 0),
+        }),
+    }
+}
+
 fn strip_cr(buf: &[u8]) -> &[u8] {
     if let Some(&b'\r') = buf.last() {
         &buf[..buf.len() - 1]
@@ -162,13 +575,38 @@ fn fetch_header<'a>(headers: &'a [(&'a [u8], Cow<'a, [u8]>)], key: &'a str) -> O
     None
 }
 
-fn expect_header<'a>(headers: &'a [(&'a [u8], Cow<'a, [u8]>)], key: &'a str) -> Result<String> {
-    fetch_header(headers, key).ok_or_else(|| format_err!("Expected header '{}' missing", key))
+/// The result of converting a parsed frame into a message
+type Conversion<T> = std::result::Result<T, StompError>;
+
+/// A frame which can't be converted because of its header `key`, e.g. "'id' header: missing"
+fn invalid_header(key: &str, reason: impl fmt::Display) -> StompError {
+    StompError::InvalidMessage(format!("'{}' header: {}", key, reason))
+}
+
+fn unrecognized_frame(command: &[u8]) -> StompError {
+    StompError::InvalidMessage(format!(
+        "Frame not recognized: {:?}",
+        String::from_utf8_lossy(command)
+    ))
+}
+
+fn expect_header<'a>(headers: &'a [(&'a [u8], Cow<'a, [u8]>)], key: &'a str) -> Conversion<String> {
+    fetch_header(headers, key).ok_or_else(|| invalid_header(key, "missing"))
+}
+
+fn parse_ack_mode(ack: Option<String>) -> Conversion<Option<AckMode>> {
+    match ack.as_deref() {
+        Some("auto") => Ok(Some(AckMode::Auto)),
+        Some("client") => Ok(Some(AckMode::Client)),
+        Some("client-individual") => Ok(Some(AckMode::ClientIndividual)),
+        Some(other) => Err(invalid_header("ack", format!("invalid value '{}'", other))),
+        None => Ok(None),
+    }
 }
 
 impl<'a> Frame<'a> {
     #[allow(dead_code)]
-    pub(crate) fn to_client_msg(&'a self) -> Result<Message<ToServer>> {
+    pub(crate) fn to_client_msg(&'a self) -> Conversion<Message<ToServer>> {
         use self::expect_header as eh;
         use self::fetch_header as fh;
         use ToServer::*;
@@ -176,21 +614,21 @@ impl<'a> Frame<'a> {
 
         let command_type = match ToServerType::parse_from_bytes(self.command) {
             Some(command_type) => command_type,
-            None => bail!(
-                "Frame not recognized: {:?}",
-                String::from_utf8_lossy(self.command)
-            ),
+            None => return Err(unrecognized_frame(self.command)),
         };
 
         let expected_headers = command_type.expected_headers();
 
         let content = match command_type {
             ToServerType::Connect => {
-                let heartbeat = fh(h, "heart-beat").map(parse_heartbeat).transpose()?;
+                let heartbeat = fh(h, "heart-beat")
+                    .map(heartbeat_intervals)
+                    .transpose()
+                    .map_err(|reason| invalid_header("heart-beat", reason))?;
 
                 Connect {
                     accept_version: eh(h, "accept-version")?,
-                    host: eh(h, "host")?,
+                    host: fh(h, "host"),
                     login: fh(h, "login"),
                     passcode: fh(h, "passcode"),
                     heartbeat,
@@ -205,20 +643,14 @@ impl<'a> Frame<'a> {
                 Send {
                     destination: eh(h, "destination")?,
                     transaction: fh(h, "transaction"),
-                    body: self.body.map(|v| v.to_vec()),
+                    body: self.body.map(|v| Cow::Owned(v.to_vec())),
                 }
             }
             ToServerType::Subscribe => {
                 Subscribe {
                     destination: eh(h, "destination")?,
                     id: eh(h, "id")?,
-                    ack: match fh(h, "ack").as_deref() {
-                        Some("auto") => Some(AckMode::Auto),
-                        Some("client") => Some(AckMode::Client),
-                        Some("client-individual") => Some(AckMode::ClientIndividual),
-                        Some(other) => bail!("Invalid ack mode: {}", other),
-                        None => None,
-                    },
+                    ack: parse_ack_mode(fh(h, "ack"))?,
                 }
             }
             ToServerType::Unsubscribe => {
@@ -269,7 +701,21 @@ impl<'a> Frame<'a> {
         })
     }
 
-    pub(crate) fn to_server_msg(&'a self) -> Result<Message<FromServer>> {
+    /// Spell the names of the headers parsed into the fields of `FromServer`, or read from
+    /// `extra_headers`, as they are looked up, if they match as `case` says. Other headers
+    /// keep their names.
+    pub(crate) fn normalize_header_names(&mut self, case: HeaderCase) {
+        for (name, _) in &mut self.headers {
+            if let Some(known) = SERVER_HEADERS
+                .iter()
+                .find(|known| case.matches(name, known))
+            {
+                *name = known;
+            }
+        }
+    }
+
+    pub(crate) fn to_server_msg(&'a self) -> Conversion<Message<FromServer>> {
         use self::expect_header as eh;
         use self::fetch_header as fh;
         use FromServer::{Connected, Error, Message as Msg, Receipt};
@@ -307,7 +753,7 @@ impl<'a> Frame<'a> {
                     body: self.body.map(|v| v.to_vec()),
                 }
             }
-            other => bail!("Frame not recognized: {:?}", String::from_utf8_lossy(other)),
+            other => return Err(unrecognized_frame(other)),
         };
         let extra_headers = h
             .iter()
@@ -326,19 +772,206 @@ impl<'a> Frame<'a> {
     }
 }
 
+impl FromServer {
+    pub(crate) fn to_frame(&self) -> Frame<'_> {
+        use self::opt_str_to_bytes as sb;
+        use Cow::*;
+        use FromServer::*;
+        match self {
+            Connected {
+                version,
+                session,
+                server,
+                heartbeat,
+            } => Frame::new(
+                b"CONNECTED",
+                &[
+                    (b"version", Some(Borrowed(version.as_bytes()))),
+                    (b"session", sb(session)),
+                    (b"server", sb(server)),
+                    (b"heart-beat", sb(heartbeat)),
+                ],
+                None,
+            ),
+            Message {
+                destination,
+                message_id,
+                subscription,
+                body,
+            } => Frame::new(
+                b"MESSAGE",
+                &[
+                    (b"destination", Some(Borrowed(destination.as_bytes()))),
+                    (b"message-id", Some(Borrowed(message_id.as_bytes()))),
+                    (b"subscription", Some(Borrowed(subscription.as_bytes()))),
+                ],
+                body.as_deref(),
+            ),
+            Receipt { receipt_id } => Frame::new(
+                b"RECEIPT",
+                &[(b"receipt-id", Some(Borrowed(receipt_id.as_bytes())))],
+                None,
+            ),
+            Error { message, body } => {
+                Frame::new(b"ERROR", &[(b"message", sb(message))], body.as_deref())
+            }
+        }
+    }
+}
+
 fn opt_str_to_bytes(s: &Option<String>) -> Option<Cow<'_, [u8]>> {
     s.as_ref().map(|v| Cow::Borrowed(v.as_bytes()))
 }
 
-fn get_content_length_header(body: &[u8]) -> Vec<u8> {
-    format!("content-length:{}\n", body.len()).into()
+/// Decode a buffer holding any number of complete frames sent by a server, e.g. from a log.
+/// Fails with `StompError::InvalidFrame` at the first frame which is incomplete or invalid;
+/// the frames before its `offset` can be decoded on their own.
+pub fn decode_all(input: &[u8]) -> std::result::Result<Vec<Message<FromServer>>, StompError> {
+    let mut messages = vec![];
+    let mut rest = input;
+    while rest.iter().any(|&b| b != b'\n' && b != b'\r') {
+        let offset = input.len() - rest.len();
+        let invalid = |reason: String| StompError::InvalidFrame { offset, reason };
+        let (remain, frame) = match parse_frame(rest) {
+            Ok(parsed) => parsed,
+            Err(nom::Err::Incomplete(_)) => return Err(invalid("incomplete frame".into())),
+            Err(e) => {
+                return Err(match content_length_mismatch(rest, HeaderCase::Sensitive) {
+                    Some(mismatch) => invalid(mismatch.to_string()),
+                    None => invalid(format!("{:?}", e)),
+                })
+            }
+        };
+        messages.push(frame.to_server_msg().map_err(|e| invalid(e.to_string()))?);
+        rest = remain;
+    }
+    Ok(messages)
+}
+
+/// Check that `frame` holds a single frame which could be sent by a client: thoroughly by
+/// parsing it in debug builds, and in release builds only by checking that it starts with a
+/// known command and ends with a NUL (optionally followed by EOLs)
+pub(crate) fn check_raw_frame(frame: &[u8]) -> std::result::Result<(), StompError> {
+    if cfg!(debug_assertions) {
+        parse_client_frame(frame)
+    } else {
+        scan_client_frame(frame)
+    }
+}
+
+/// Describe a parse error without quoting the input, which may hold credentials
+pub(crate) fn describe_parse_error(e: nom::Err<&[u8]>) -> String {
+    format!("parse error: {:?}", e.into_error_kind())
+}
+
+fn invalid_raw_frame(offset: usize, reason: impl Into<String>) -> StompError {
+    StompError::InvalidFrame {
+        offset,
+        reason: reason.into(),
+    }
+}
+
+fn parse_client_frame(frame: &[u8]) -> std::result::Result<(), StompError> {
+    let (rest, parsed) = match parse_frame(frame) {
+        Ok(parsed) => parsed,
+        Err(nom::Err::Incomplete(_)) => return Err(invalid_raw_frame(0, "incomplete frame")),
+        Err(e) => return Err(invalid_raw_frame(0, describe_parse_error(e))),
+    };
+    parsed
+        .to_client_msg()
+        .map_err(|e| invalid_raw_frame(0, e.to_string()))?;
+    if rest.iter().any(|&b| b != b'\n' && b != b'\r') {
+        let offset = frame.len() - rest.len();
+        return Err(invalid_raw_frame(offset, "trailing data after frame"));
+    }
+    Ok(())
+}
+
+fn scan_client_frame(frame: &[u8]) -> std::result::Result<(), StompError> {
+    match FrameCommand::of_frame(frame) {
+        Ok(command) if command.is_client_command() => {}
+        _ => return Err(invalid_raw_frame(0, "unknown command")),
+    }
+    let eols = frame
+        .iter()
+        .rev()
+        .take_while(|&&b| b == b'\n' || b == b'\r')
+        .count();
+    let end = frame.len() - eols;
+    if end == 0 || frame[end - 1] != b'\x00' {
+        return Err(invalid_raw_frame(end, "frame is not terminated by NUL"));
+    }
+    Ok(())
+}
+
+/// Write a SEND frame whose body of `content_length` bytes is streamed from `body`,
+/// rather than held in memory. Fails with `io::ErrorKind::UnexpectedEof` if `body`
+/// ends early, in which case the frame is left incomplete and the connection is unusable.
+pub async fn write_send_streaming<W, R>(
+    mut writer: W,
+    destination: &str,
+    content_length: u64,
+    body: R,
+) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+    R: AsyncRead + Unpin,
+{
+    let length = content_length.to_string();
+    let frame = Frame::new(
+        b"SEND",
+        &[
+            (b"destination", Some(Cow::Borrowed(destination.as_bytes()))),
+            (b"content-length", Some(Cow::Borrowed(length.as_bytes()))),
+        ],
+        None,
+    );
+    let mut header = BytesMut::new();
+    frame.serialize(&mut header);
+    // drop the NUL, which goes after the body
+    header.truncate(header.len() - 1);
+    writer.write_all(&header).await?;
+    let copied = tokio::io::copy(&mut body.take(content_length), &mut writer).await?;
+    if copied < content_length {
+        let reason = format!("body ended after {} of {} bytes", copied, content_length);
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, reason).into());
+    }
+    writer.write_all(b"\x00").await?;
+    writer.flush().await?;
+    Ok(())
 }
 
-fn parse_heartbeat<S: AsRef<str>>(hb: S) -> Result<(u32, u32)> {
-    let mut split = hb.as_ref().splitn(1, ',');
-    let left = split.next().ok_or_else(|| format_err!("Bad heartbeat"))?;
-    let right = split.next().ok_or_else(|| format_err!("Bad heartbeat"))?;
-    Ok((left.parse()?, right.parse()?))
+/// Write `msg` to `writer` as a STOMP frame, for code which manages its own writes rather
+/// than going through `ClientCodec`. The command and headers are written first, then the
+/// body straight from `msg`, so large bodies are not copied into an intermediate buffer.
+/// The writer is flushed once the frame has been written.
+pub async fn write_frame<W>(writer: &mut W, msg: &Message<ToServer>) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    msg.to_frame().write_async(writer).await?;
+    writer.flush().await
+}
+
+/// Parse the value of a `heart-beat` header, e.g. `"500,1000"`, into its two intervals
+/// in milliseconds. Anything following the second interval is ignored.
+pub fn parse_heartbeat<S: AsRef<str>>(hb: S) -> Result<(u32, u32)> {
+    Ok(heartbeat_intervals(hb).map_err(StompError::InvalidMessage)?)
+}
+
+/// The two intervals of a `heart-beat` header, or the reason they can't be parsed
+fn heartbeat_intervals<S: AsRef<str>>(hb: S) -> std::result::Result<(u32, u32), String> {
+    let mut split = hb.as_ref().split(',');
+    // the sender's outgoing interval comes first, then the incoming one it would like
+    let mut interval = |name: &str| {
+        let value = split
+            .next()
+            .ok_or_else(|| format!("{} interval: missing", name))?;
+        value
+            .parse()
+            .map_err(|e| format!("{} interval '{}': {}", name, value, e))
+    };
+    Ok((interval("outgoing")?, interval("incoming")?))
 }
 
 impl ToServer {
@@ -357,7 +990,7 @@ impl ToServer {
                 b"CONNECT",
                 &[
                     (b"accept-version", Some(Borrowed(accept_version.as_bytes()))),
-                    (b"host", Some(Borrowed(host.as_bytes()))),
+                    (b"host", sb(host)),
                     (b"login", sb(login)),
                     (b"passcode", sb(passcode)),
                     (
@@ -368,7 +1001,7 @@ impl ToServer {
                 None,
             ),
             Disconnect { ref receipt } => {
-                Frame::new(b"DISCONNECT", &[(b"receipt", sb(&receipt))], None)
+                Frame::new(b"DISCONNECT", &[(b"receipt", sb(receipt))], None)
             }
             Subscribe {
                 ref destination,
@@ -403,7 +1036,7 @@ impl ToServer {
                 b"SEND",
                 &[
                     (b"destination", Some(Borrowed(destination.as_bytes()))),
-                    (b"id", sb(transaction)),
+                    (b"transaction", sb(transaction)),
                 ],
                 body.as_ref().map(|v| v.as_ref()),
             ),
@@ -506,4 +1139,632 @@ empty-header:
         // let roundtrip = stomp.to_frame().serialize();
         // assert_eq!(roundtrip, data);
     }
+
+    #[test]
+    fn display_matches_wire_format() {
+        let msg = Message::new(ToServer::Send {
+            destination: "/queue/a:b".into(),
+            transaction: None,
+            body: Some(b"hello\nworld".to_vec().into()),
+        })
+        .header("x-note", "line\nbreak");
+        let mut buffer = BytesMut::new();
+        msg.to_frame().serialize(&mut buffer);
+        assert_eq!(msg.to_string().as_bytes(), &*buffer);
+        assert_eq!(
+            msg.to_string(),
+            "SEND\ndestination:/queue/a\\cb\nx-note:line\\nbreak\n\
+             content-length:11\n\nhello\nworld\x00"
+        );
+    }
+
+    #[test]
+    fn frame_commands() {
+        let all = [
+            FrameCommand::Connect,
+            FrameCommand::Stomp,
+            FrameCommand::Send,
+            FrameCommand::Subscribe,
+            FrameCommand::Unsubscribe,
+            FrameCommand::Ack,
+            FrameCommand::Nack,
+            FrameCommand::Begin,
+            FrameCommand::Commit,
+            FrameCommand::Abort,
+            FrameCommand::Disconnect,
+            FrameCommand::Connected,
+            FrameCommand::Message,
+            FrameCommand::Receipt,
+            FrameCommand::Error,
+        ];
+        for &command in &all {
+            assert_eq!(
+                FrameCommand::from_bytes(command.as_bytes()).unwrap(),
+                command
+            );
+        }
+        assert_eq!(
+            FrameCommand::from_bytes(b"nack").unwrap(),
+            FrameCommand::Nack
+        );
+        assert!(matches!(
+            FrameCommand::from_bytes(b"SNED"),
+            Err(StompError::UnknownCommand(command)) if command == "SNED"
+        ));
+
+        let frame = b"\r\n\nMESSAGE\r\ndestination:/queue/a\r\n\r\n\x00";
+        let command = FrameCommand::of_frame(frame).unwrap();
+        assert_eq!(command, FrameCommand::Message);
+        assert!(!command.is_client_command());
+        assert!(FrameCommand::of_frame(b"STOMP\n")
+            .unwrap()
+            .is_client_command());
+        assert!(FrameCommand::of_frame(b"").is_err());
+    }
+
+    #[test]
+    fn check_raw_frames() {
+        let send = b"SEND\ndestination:/queue/a\ncontent-length:2\n\nhi\x00\n";
+        let truncated = &send[..send.len() - 2];
+        let unknown = b"SNED\ndestination:/queue/a\n\n\x00";
+        let server_frame = b"RECEIPT\nreceipt-id:1\n\n\x00";
+        let two_frames = b"BEGIN\ntransaction:1\n\n\x00COMMIT\ntransaction:1\n\n\x00";
+        let missing_header = b"SEND\n\n\x00";
+
+        for check in &[parse_client_frame, scan_client_frame] {
+            assert!(check(send).is_ok());
+            assert!(check(&send[..send.len() - 1]).is_ok());
+            assert!(check(b"begin\r\ntransaction:1\r\n\r\n\x00").is_ok());
+            assert!(check(truncated).is_err());
+            assert!(check(unknown).is_err());
+            assert!(check(server_frame).is_err());
+            assert!(check(b"").is_err());
+        }
+
+        // only parsing catches these
+        assert!(scan_client_frame(two_frames).is_ok());
+        assert!(matches!(
+            parse_client_frame(two_frames),
+            Err(StompError::InvalidFrame { offset: 22, .. })
+        ));
+        assert!(scan_client_frame(missing_header).is_ok());
+        assert!(parse_client_frame(missing_header).is_err());
+
+        // errors don't quote the frame
+        let malformed = b"CONNECT\npasscode:secret\nno colon\n\n\x00";
+        let err = parse_client_frame(malformed).unwrap_err();
+        assert!(!err.to_string().contains("secret"), "{}", err);
+    }
+
+    #[test]
+    fn unfold_continued_headers() {
+        let unfold = |input: &[u8]| {
+            let mut buffer = BytesMut::from(input);
+            let removed = unfold_headers(&mut buffer, input.len());
+            assert_eq!(removed, input.len() - buffer.len());
+            buffer
+        };
+        assert_eq!(
+            unfold(b"MESSAGE\nx-note:first\n  second\n\tthird\ny:1\n\n body\n x\x00"),
+            &b"MESSAGE\nx-note:firstsecondthird\ny:1\n\n body\n x\x00"[..]
+        );
+        assert_eq!(
+            unfold(b"\nMESSAGE\r\nx-note:a\r\n b\r\n\r\n\x00"),
+            &b"\nMESSAGE\r\nx-note:ab\r\n\r\n\x00"[..]
+        );
+        // the line after the command is not a continuation, and incomplete heads, or heads
+        // cut short by a NUL, are left alone
+        let unchanged: &[&[u8]] = &[
+            b"MESSAGE\n x:1\n\n\x00",
+            b"MESSAGE\nx:1\n y",
+            b"\x00MESSAGE\nx:1\n      a\n\n",
+            b"MESSAGE\nx:1\x00\n a\n\n",
+        ];
+        for input in unchanged {
+            assert_eq!(unfold(input), input);
+        }
+        // nothing past the limit is looked at
+        let mut buffer = BytesMut::from(&b"MESSAGE\nx:1\n a\n\n"[..]);
+        assert_eq!(unfold_headers(&mut buffer, 12), 0);
+    }
+
+    #[test]
+    fn write_streamed_send() {
+        use futures::executor::block_on;
+
+        let mut written = vec![];
+        let body = &b"hello\x00world, and more"[..];
+        block_on(write_send_streaming(&mut written, "/queue/a:b", 11, body)).unwrap();
+        assert_eq!(
+            written,
+            &b"SEND\ndestination:/queue/a\\cb\ncontent-length:11\n\nhello\x00world\x00"[..]
+        );
+        let (_, frame) = parse_frame(&written).unwrap();
+        assert_eq!(frame.body, Some(&b"hello\x00world"[..]));
+
+        let mut written = vec![];
+        let err = block_on(write_send_streaming(&mut written, "a", 12, &b"short"[..])).unwrap_err();
+        let err = err.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn write_frame_matches_serialize() {
+        use futures::executor::block_on;
+        use std::pin::Pin;
+        use std::task::{Context, Poll};
+
+        /// Records each write separately
+        #[derive(Default)]
+        struct Writes(Vec<Vec<u8>>);
+
+        impl AsyncWrite for Writes {
+            fn poll_write(
+                self: Pin<&mut Self>,
+                _cx: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<io::Result<usize>> {
+                self.get_mut().0.push(buf.to_vec());
+                Poll::Ready(Ok(buf.len()))
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        let body = vec![b'x'; 100_000];
+        let send = Message::new(ToServer::Send {
+            destination: "/queue/a:b".into(),
+            transaction: None,
+            body: Some(body.clone().into()),
+        })
+        .header("x-custom", "value");
+        let subscribe = Message::new(ToServer::Subscribe {
+            destination: "/queue/a".into(),
+            id: "1".into(),
+            ack: None,
+        });
+        for msg in &[send, subscribe] {
+            let mut writes = Writes::default();
+            block_on(write_frame(&mut writes, msg)).unwrap();
+            let mut expected = BytesMut::new();
+            msg.to_frame().serialize(&mut expected);
+            assert_eq!(writes.0.concat(), &expected[..]);
+        }
+
+        let send = Message::new(ToServer::Send {
+            destination: "/queue/a".into(),
+            transaction: None,
+            body: Some(body.clone().into()),
+        });
+        let mut writes = Writes::default();
+        block_on(write_frame(&mut writes, &send)).unwrap();
+        assert_eq!(writes.0.len(), 3);
+        assert_eq!(writes.0[1], body);
+    }
+
+    #[test]
+    fn serialize_extra_headers() {
+        let msg = Message::new(ToServer::Begin {
+            transaction: "tx-1".into(),
+        })
+        .header("x-custom", "value")
+        .receipt("77");
+        let mut buffer = BytesMut::new();
+        msg.to_frame().serialize(&mut buffer);
+        assert_eq!(
+            &*buffer,
+            &b"BEGIN\ntransaction:tx-1\nx-custom:value\nreceipt:77\n\n\x00"[..]
+        );
+    }
+
+    #[test]
+    fn receipt_replaces_earlier_receipt() {
+        let serialize = |msg: Message<ToServer>| {
+            let mut buffer = BytesMut::new();
+            msg.to_frame().serialize(&mut buffer);
+            buffer
+        };
+
+        let msg = Message::new(ToServer::Unsubscribe { id: "1".into() })
+            .receipt("a")
+            .receipt("b");
+        assert_eq!(
+            &*serialize(msg),
+            &b"UNSUBSCRIBE\nid:1\nreceipt:b\n\n\x00"[..]
+        );
+
+        let msg = Message::new(ToServer::Disconnect {
+            receipt: Some("a".into()),
+        })
+        .receipt("b");
+        assert_eq!(&*serialize(msg), &b"DISCONNECT\nreceipt:b\n\n\x00"[..]);
+    }
+
+    #[test]
+    fn disconnect_auto_receipt() {
+        let receipt = |msg: Message<ToServer>| {
+            let mut buffer = BytesMut::new();
+            msg.to_frame().serialize(&mut buffer);
+            let (_, frame) = parse_frame(&buffer).unwrap();
+            assert_eq!(frame.headers.len(), 1);
+            match frame.to_client_msg().unwrap().content {
+                ToServer::Disconnect { receipt } => receipt,
+                _ => panic!("expected a DISCONNECT frame"),
+            }
+        };
+
+        let generated = receipt(crate::client::disconnect()).unwrap();
+        assert_eq!(generated.len(), 36);
+        let disconnect = Message::new(ToServer::Disconnect {
+            receipt: Some("bye".into()),
+        });
+        assert_eq!(receipt(disconnect.auto_receipt()), Some("bye".into()));
+        let send = || {
+            Message::new(ToServer::Send {
+                destination: "a".into(),
+                transaction: None,
+                body: None,
+            })
+        };
+        let explicit = send().receipt("a").auto_receipt();
+        assert_eq!(
+            explicit.extra_headers,
+            vec![(b"receipt".to_vec(), b"a".to_vec())]
+        );
+        let generated = send().auto_receipt();
+        assert_eq!(generated.extra_headers.len(), 1);
+        assert_eq!(generated.extra_headers[0].1.len(), 36);
+    }
+
+    #[test]
+    fn body_delimitation() {
+        use crate::BodyDelimitation;
+
+        let decode = |data: &[u8]| parse_frame(data).unwrap().1.to_server_msg().unwrap();
+        let length = decode(
+            b"MESSAGE\ndestination:a\nmessage-id:1\nsubscription:1\ncontent-length:2\n\nhi\x00",
+        );
+        assert_eq!(
+            length.body_delimitation(),
+            Some(BodyDelimitation::ContentLength)
+        );
+        let nul = decode(b"MESSAGE\ndestination:a\nmessage-id:1\nsubscription:1\n\nhi\x00");
+        assert_eq!(nul.body_delimitation(), Some(BodyDelimitation::Nul));
+        let error = decode(b"ERROR\nmessage:oops\n\n\x00");
+        assert_eq!(error.body_delimitation(), Some(BodyDelimitation::Nul));
+        let receipt = decode(b"RECEIPT\nreceipt-id:1\n\n\x00");
+        assert_eq!(receipt.body_delimitation(), None);
+    }
+
+    #[test]
+    fn audit_records() {
+        use crate::AuditRecord;
+
+        let decode = |data: &[u8]| parse_frame(data).unwrap().1.to_server_msg().unwrap();
+        let full = decode(
+            b"MESSAGE\ndestination:/queue/a\nmessage-id:m-1\nsubscription:s\nack:a-1\n\
+              redelivered:true\ntimestamp:1600000000000\n\nhello\x00",
+        );
+        assert_eq!(
+            full.audit_record(),
+            Some(AuditRecord {
+                destination: "/queue/a".into(),
+                message_id: "m-1".into(),
+                ack_id: Some("a-1".into()),
+                subscription: "s".into(),
+                redelivered: true,
+                timestamp: Some("1600000000000".into()),
+                body_len: 5,
+            })
+        );
+
+        let bare = decode(b"MESSAGE\ndestination:/queue/a\nmessage-id:m-2\nsubscription:s\n\n\x00");
+        assert_eq!(
+            bare.audit_record(),
+            Some(AuditRecord {
+                destination: "/queue/a".into(),
+                message_id: "m-2".into(),
+                ack_id: None,
+                subscription: "s".into(),
+                redelivered: false,
+                timestamp: None,
+                body_len: 0,
+            })
+        );
+
+        let receipt = decode(b"RECEIPT\nreceipt-id:1\n\n\x00");
+        assert_eq!(receipt.audit_record(), None);
+    }
+
+    #[test]
+    fn decode_all_frames() {
+        let receipt = b"RECEIPT\nreceipt-id:1\n\n\x00";
+        let message = b"\nMESSAGE\ndestination:a\nmessage-id:2\nsubscription:3\n\nhi\x00\n";
+        let input = [&receipt[..], message, b"\r\n"].concat();
+        let messages = decode_all(&input).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert!(matches!(messages[0].content, FromServer::Receipt { .. }));
+        assert_eq!(messages[1].body_as_str(), Some("hi"));
+        assert!(decode_all(b"").unwrap().is_empty());
+
+        let invalid = |input: &[u8]| match decode_all(input) {
+            Err(StompError::InvalidFrame { offset, .. }) => offset,
+            other => panic!("expected an invalid frame, got {:?}", other),
+        };
+        // the EOL following a frame is consumed along with it
+        let truncated = [&receipt[..], &message[..10]].concat();
+        assert_eq!(invalid(&truncated), receipt.len() + 1);
+        let unknown = [&receipt[..], b"NOPE\n\n\x00"].concat();
+        assert_eq!(invalid(&unknown), receipt.len());
+        let mismatch = [&receipt[..], b"RECEIPT\ncontent-length:5\n\n\x00\x00"].concat();
+        assert_eq!(invalid(&mismatch), receipt.len());
+    }
+
+    #[test]
+    fn parse_heartbeats() {
+        assert_eq!(parse_heartbeat("0,0").unwrap(), (0, 0));
+        assert_eq!(parse_heartbeat("500,1000").unwrap(), (500, 1000));
+        assert_eq!(parse_heartbeat("1000,500").unwrap(), (1000, 500));
+        assert_eq!(parse_heartbeat("500,1000,extra").unwrap(), (500, 1000));
+        for bad in &["0,", ",", "", "abc,def", "500"] {
+            assert!(parse_heartbeat(bad).is_err(), "{:?} should not parse", bad);
+        }
+    }
+
+    #[test]
+    fn conversion_errors_name_the_header() {
+        let describe = |data: &[u8]| {
+            let err = parse_frame(data).unwrap().1.to_client_msg().unwrap_err();
+            match err {
+                StompError::InvalidMessage(reason) => reason,
+                other => panic!("expected an invalid message, not {:?}", other),
+            }
+        };
+        assert_eq!(
+            describe(b"SUBSCRIBE\nid:1\n\n\x00"),
+            "'destination' header: missing"
+        );
+        assert_eq!(
+            describe(b"SUBSCRIBE\ndestination:/q\nid:1\nack:sometimes\n\n\x00"),
+            "'ack' header: invalid value 'sometimes'"
+        );
+        assert!(
+            describe(b"CONNECT\naccept-version:1.2\nheart-beat:500\n\n\x00")
+                .starts_with("'heart-beat' header: incoming interval")
+        );
+
+        let err = parse_heartbeat("500,x").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StompError>(),
+            Some(StompError::InvalidMessage(reason)) if reason.starts_with("incoming interval 'x'")
+        ));
+    }
+
+    #[test]
+    fn header_order() {
+        let msg = Message::new(ToServer::Send {
+            destination: "a".into(),
+            transaction: Some("tx".into()),
+            body: Some(Cow::Borrowed(b"hi")),
+        })
+        .header("x", "1")
+        .header("content-type", "text/plain")
+        .receipt("r");
+        let serialize = |msg: &Message<ToServer>, order| {
+            let mut buffer = BytesMut::new();
+            msg.to_frame()
+                .serialize_with(&mut buffer, LineEnding::Lf, order);
+            buffer
+        };
+        assert_eq!(
+            &serialize(&msg, HeaderOrder::Declared)[..],
+            &b"SEND\ndestination:a\ntransaction:tx\nx:1\ncontent-type:text/plain\n\
+               receipt:r\ncontent-length:2\n\nhi\x00"[..]
+        );
+        let content_first = serialize(&msg, HeaderOrder::ContentFirst);
+        assert_eq!(
+            &content_first[..],
+            &b"SEND\ncontent-type:text/plain\ncontent-length:2\ndestination:a\n\
+               transaction:tx\nx:1\nreceipt:r\n\nhi\x00"[..]
+        );
+        let (_, frame) = parse_frame(&content_first).unwrap();
+        assert!(matches!(
+            frame.to_client_msg().unwrap().content,
+            ToServer::Send { body: Some(ref body), .. } if &body[..] == b"hi"
+        ));
+
+        // without a body or content-type, both orders are the same
+        let msg = Message::new(ToServer::Unsubscribe { id: "1".into() }).header("x", "1");
+        assert_eq!(
+            serialize(&msg, HeaderOrder::ContentFirst),
+            serialize(&msg, HeaderOrder::Declared)
+        );
+    }
+
+    #[test]
+    fn serialize_crlf_line_endings() {
+        let msg = Message::new(ToServer::Send {
+            destination: "a".into(),
+            transaction: None,
+            body: Some(b"hi\n".to_vec().into()),
+        })
+        .header("x", "1");
+        let mut buffer = BytesMut::new();
+        msg.to_frame()
+            .serialize_with(&mut buffer, LineEnding::Crlf, HeaderOrder::Declared);
+        let expected = b"SEND\r\ndestination:a\r\nx:1\r\ncontent-length:3\r\n\r\nhi\n\x00";
+        assert_eq!(&buffer[..], &expected[..]);
+
+        let (_, frame) = parse_frame(&buffer).unwrap();
+        let parsed = frame.to_client_msg().unwrap();
+        assert_eq!(parsed.extra_headers[0], (b"x".to_vec(), b"1".to_vec()));
+        match parsed.content {
+            ToServer::Send { body, .. } => assert_eq!(body.as_deref(), Some(&b"hi\n"[..])),
+            _ => panic!("expected a SEND frame"),
+        }
+
+        let mut buffer = BytesMut::new();
+        ToServer::Disconnect { receipt: None }
+            .to_frame()
+            .serialize_with(&mut buffer, LineEnding::Crlf, HeaderOrder::Declared);
+        assert_eq!(&buffer[..], b"DISCONNECT\r\n\r\n\x00");
+    }
+
+    #[test]
+    fn serialize_to_custom_sink() {
+        /// Collects each write separately, like a list of slices for vectored IO
+        #[derive(Default)]
+        struct Slices(Vec<Vec<u8>>);
+
+        impl SerializeSink for Slices {
+            fn put_slice(&mut self, data: &[u8]) {
+                self.0.push(data.to_vec());
+            }
+
+            fn put_u8(&mut self, b: u8) {
+                self.0.push(vec![b]);
+            }
+        }
+
+        let msg = Message::new(ToServer::Send {
+            destination: "a:b".into(),
+            transaction: None,
+            body: Some(b"body".to_vec().into()),
+        });
+        let mut slices = Slices::default();
+        msg.serialize_to(&mut slices);
+        assert!(slices.0.contains(&b"body".to_vec()));
+        let mut buffer = BytesMut::new();
+        msg.to_frame().serialize(&mut buffer);
+        assert_eq!(slices.0.concat(), &buffer[..]);
+        assert_eq!(
+            &buffer[..],
+            &b"SEND\ndestination:a\\cb\ncontent-length:4\n\nbody\x00"[..]
+        );
+    }
+
+    #[test]
+    fn sink_writer_grows() {
+        let mut sink = BytesMutSink::default();
+        write!(SinkWriter(&mut sink), "content-length:{}", 1234567).unwrap();
+        assert_eq!(&sink.0[..], b"content-length:1234567");
+    }
+
+    #[test]
+    fn error_body_accessors() {
+        let data = b"ERROR\nmessage:bad\ncontent-type:application/json\n\n{\"code\":42}\x00";
+        let (_, frame) = parse_frame(data).unwrap();
+        let msg = frame.to_server_msg().unwrap();
+        assert_eq!(msg.content_type(), Some(&b"application/json"[..]));
+        assert_eq!(msg.body_as_str(), Some("{\"code\":42}"));
+
+        let data = b"ERROR\nmessage:bad\n\n\xff\xfe\x00";
+        let (_, frame) = parse_frame(data).unwrap();
+        let msg = frame.to_server_msg().unwrap();
+        assert_eq!(msg.content_type(), None);
+        assert_eq!(msg.body_as_str(), None);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn error_body_json() {
+        let data = b"ERROR\ncontent-type:application/json;charset=utf-8\n\n{\"code\":42}\x00";
+        let (_, frame) = parse_frame(data).unwrap();
+        let msg = frame.to_server_msg().unwrap();
+        let value: serde_json::Value = msg.body_json().unwrap().unwrap();
+        assert_eq!(value["code"], 42);
+
+        let data = b"ERROR\ncontent-type:application/json\n\nnot json\x00";
+        let (_, frame) = parse_frame(data).unwrap();
+        let msg = frame.to_server_msg().unwrap();
+        assert!(msg.body_json::<serde_json::Value>().unwrap().is_err());
+
+        let data = b"ERROR\ncontent-type:text/plain\n\n{}\x00";
+        let (_, frame) = parse_frame(data).unwrap();
+        let msg = frame.to_server_msg().unwrap();
+        assert!(msg.body_json::<serde_json::Value>().is_none());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn audit_record_json() {
+        let data = b"MESSAGE\ndestination:a\nmessage-id:1\nsubscription:s\nack:2\n\nhi\x00";
+        let (_, frame) = parse_frame(data).unwrap();
+        let record = frame.to_server_msg().unwrap().audit_record().unwrap();
+        assert_eq!(
+            serde_json::to_value(&record).unwrap(),
+            serde_json::json!({
+                "destination": "a",
+                "message_id": "1",
+                "ack_id": "2",
+                "subscription": "s",
+                "redelivered": false,
+                "timestamp": null,
+                "body_len": 2,
+            })
+        );
+    }
+
+    #[test]
+    fn content_length_must_match_nul() {
+        let mismatch = |data: &[u8]| match content_length_mismatch(data, HeaderCase::Sensitive) {
+            Some(StompError::ContentLengthMismatch {
+                declared,
+                found_nul_at,
+            }) => Some((declared, found_nul_at)),
+            _ => None,
+        };
+
+        // declared longer than the body
+        let long = b"MESSAGE\ncontent-length:10\n\n12345678\x00\nRECEIPT\n";
+        assert!(parse_frame(long).is_err());
+        assert_eq!(mismatch(long), Some((10, Some(8))));
+
+        // declared shorter than the body
+        let short = b"MESSAGE\ncontent-length:4\n\n12345678\x00";
+        assert!(parse_frame(short).is_err());
+        assert_eq!(mismatch(short), Some((4, Some(8))));
+        let (_, frame) = parse_frame_nul_terminated(short).unwrap();
+        assert_eq!(frame.body, Some(&b"12345678"[..]));
+
+        let exact = b"MESSAGE\ncontent-length:8\n\n12345678\x00";
+        assert_eq!(parse_frame(exact).unwrap().1.body, Some(&b"12345678"[..]));
+        assert_eq!(mismatch(exact), None);
+
+        // NULs are allowed in a body of the declared length
+        let nuls = b"MESSAGE\ncontent-length:5\n\n12\x0045\x00";
+        assert_eq!(parse_frame(nuls).unwrap().1.body, Some(&b"12\x0045"[..]));
+        assert_eq!(mismatch(nuls), None);
+    }
+
+    #[test]
+    fn encode_to_string_escapes() {
+        let msg = Message::new(ToServer::Send {
+            destination: "a".into(),
+            transaction: None,
+            body: Some(b"\"hi\"\r\n\\\xff".to_vec().into()),
+        });
+        assert_eq!(
+            encode_to_string(&msg),
+            r#"SEND\ndestination:a\ncontent-length:8\n\n\"hi\"\r\n\\\xff\x00"#
+        );
+        let connect = Message::new(ToServer::Connect {
+            accept_version: "1.2".into(),
+            host: Some("datafeeds.here.co.uk".into()),
+            login: Some("user".into()),
+            passcode: Some("password".into()),
+            heartbeat: None,
+        });
+        assert_eq!(
+            encode_to_string(&connect),
+            "CONNECT\\naccept-version:1.2\\nhost:datafeeds.here.co.uk\\n\
+             login:user\\npasscode:password\\n\\n\\x00"
+        );
+    }
 }
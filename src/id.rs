@@ -0,0 +1,224 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+/// The kind of identifier to generate, which determines its prefix
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IdKind {
+    Subscription,
+    Transaction,
+    Receipt,
+}
+
+impl IdKind {
+    fn prefix(self) -> &'static str {
+        match self {
+            IdKind::Subscription => "sub",
+            IdKind::Transaction => "tx",
+            IdKind::Receipt => "rcpt",
+        }
+    }
+}
+
+/// Returns a random number without pulling in an RNG dependency.
+/// `RandomState` is randomly seeded per thread and its keys change on every call.
+pub(crate) fn random_u64() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    if let Ok(elapsed) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        hasher.write_u128(elapsed.as_nanos());
+    }
+    hasher.finish()
+}
+
+/// Generates a random (version 4) UUID
+pub(crate) fn uuid_v4() -> String {
+    let high = (random_u64() & 0xffff_ffff_ffff_0fff) | 0x4000;
+    let low = (random_u64() & 0x3fff_ffff_ffff_ffff) | 0x8000_0000_0000_0000;
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        high >> 32,
+        (high >> 16) & 0xffff,
+        high & 0xffff,
+        low >> 48,
+        low & 0xffff_ffff_ffff
+    )
+}
+
+/// A source of subscription ids
+pub trait SubscriptionIdGenerator {
+    fn next(&mut self) -> String;
+}
+
+/// Generates sequential subscription ids: `sub-0`, `sub-1`, ...
+///
+/// Ids can be taken through a shared reference, so one counter can be kept in a `static`
+/// or an `Arc` and used from several tasks; `&AtomicCounter` is a generator too.
+#[derive(Debug, Default)]
+pub struct AtomicCounter(AtomicU64);
+
+impl AtomicCounter {
+    pub const fn new() -> Self {
+        AtomicCounter(AtomicU64::new(0))
+    }
+
+    /// Take the next id
+    pub fn next(&self) -> String {
+        format!("sub-{}", self.0.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl SubscriptionIdGenerator for AtomicCounter {
+    fn next(&mut self) -> String {
+        AtomicCounter::next(self)
+    }
+}
+
+impl SubscriptionIdGenerator for &AtomicCounter {
+    fn next(&mut self) -> String {
+        AtomicCounter::next(self)
+    }
+}
+
+/// Generates random UUIDs as subscription ids
+#[derive(Debug, Default)]
+pub struct UuidGenerator;
+
+impl SubscriptionIdGenerator for UuidGenerator {
+    fn next(&mut self) -> String {
+        uuid_v4()
+    }
+}
+
+/// Generates identifiers which are unique within a connection.
+/// Each physical connection gets its own random nonce, so ids don't collide
+/// with ones issued on a previous connection whose state the broker may still hold.
+#[derive(Debug)]
+pub(crate) struct IdGenerator {
+    nonce: u32,
+    counter: u64,
+}
+
+impl IdGenerator {
+    pub(crate) fn new() -> Self {
+        IdGenerator {
+            nonce: random_u64() as u32,
+            counter: 0,
+        }
+    }
+
+    /// Pick a new nonce for a new physical connection
+    pub(crate) fn rotate(&mut self) {
+        let previous = self.nonce;
+        while self.nonce == previous {
+            self.nonce = random_u64() as u32;
+        }
+        self.counter = 0;
+    }
+
+    /// Make sure `id` is never generated, if it could have been
+    pub(crate) fn skip_past(&mut self, id: &str) {
+        let nonce = format!("-{:08x}-", self.nonce);
+        let counter = match id.find(&nonce) {
+            Some(at) => id[at + nonce.len()..].parse::<u64>(),
+            None => return,
+        };
+        if let Ok(counter) = counter {
+            self.counter = self.counter.max(counter.saturating_add(1));
+        }
+    }
+
+    pub(crate) fn next_id(&mut self, kind: IdKind) -> String {
+        let id = format!("{}-{:08x}-{}", kind.prefix(), self.nonce, self.counter);
+        self.counter += 1;
+        id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn id_format() {
+        let mut gen = IdGenerator::new();
+        let nonce = format!("{:08x}", gen.nonce);
+        assert_eq!(
+            gen.next_id(IdKind::Subscription),
+            format!("sub-{}-0", nonce)
+        );
+        assert_eq!(gen.next_id(IdKind::Transaction), format!("tx-{}-1", nonce));
+        assert_eq!(gen.next_id(IdKind::Receipt), format!("rcpt-{}-2", nonce));
+    }
+
+    #[test]
+    fn skip_past_restored_ids() {
+        let mut gen = IdGenerator::new();
+        let nonce = format!("{:08x}", gen.nonce);
+        gen.skip_past(&format!("sub-{}-41", nonce));
+        gen.skip_past(&format!("tx-{}-7", nonce));
+        gen.skip_past("sub-ffffffff-ffffffff-100");
+        gen.skip_past("my-subscription");
+        assert_eq!(gen.next_id(IdKind::Receipt), format!("rcpt-{}-42", nonce));
+    }
+
+    #[test]
+    fn ids_are_unique() {
+        let mut gen = IdGenerator::new();
+        let mut seen = HashSet::new();
+        for _ in 0..1000 {
+            assert!(seen.insert(gen.next_id(IdKind::Receipt)));
+        }
+    }
+
+    #[test]
+    fn subscription_id_generators() {
+        let counter = AtomicCounter::default();
+        assert_eq!(counter.next(), "sub-0");
+        assert_eq!(SubscriptionIdGenerator::next(&mut &counter), "sub-1");
+
+        let mut uuids = UuidGenerator;
+        let uuid = uuids.next();
+        let groups: Vec<_> = uuid.split('-').map(str::len).collect();
+        assert_eq!(groups, [8, 4, 4, 4, 12]);
+        assert_eq!(&uuid[14..15], "4");
+        assert!("89ab".contains(&uuid[19..20]));
+        assert_ne!(uuid, uuids.next());
+    }
+
+    #[test]
+    fn shared_counter() {
+        use std::sync::Arc;
+
+        static COUNTER: AtomicCounter = AtomicCounter::new();
+        let counter = Arc::new(AtomicCounter::new());
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let counter = counter.clone();
+                std::thread::spawn(move || {
+                    COUNTER.next();
+                    (0..100).map(|_| counter.next()).collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let mut seen = HashSet::new();
+        for thread in threads {
+            for id in thread.join().unwrap() {
+                assert!(seen.insert(id));
+            }
+        }
+        assert_eq!(seen.len(), 400);
+        assert_eq!(COUNTER.next(), "sub-4");
+    }
+
+    #[test]
+    fn rotation_changes_nonce() {
+        let mut gen = IdGenerator::new();
+        let before = gen.next_id(IdKind::Subscription);
+        gen.rotate();
+        let after = gen.next_id(IdKind::Subscription);
+        assert_ne!(before, after);
+        assert!(after.ends_with("-0"));
+    }
+}
@@ -0,0 +1,40 @@
+use std::fmt::{self, Debug};
+use std::sync::Arc;
+
+use bytes::BytesMut;
+
+use crate::Result;
+
+/// Transforms frames on their way to and from the connection, e.g. to compress or encrypt them.
+/// Installed with `ConnectOptions::codec_layer` or `ClientCodec::with_layer`.
+pub trait CodecLayer: Debug + Send {
+    /// Transform a frame before it is written. `frame` holds exactly one encoded STOMP frame,
+    /// and may be modified in place or replaced entirely.
+    fn encode(&mut self, frame: &mut BytesMut) -> Result<()>;
+    /// Transform data read from the connection, removing whatever can be recovered from `src`
+    /// and appending the result to `dst`. Anything left in `src` is offered again, together
+    /// with the next data to arrive.
+    fn decode(&mut self, src: &mut BytesMut, dst: &mut BytesMut) -> Result<()>;
+}
+
+/// Creates the layer for each new connection
+#[derive(Clone)]
+pub(crate) struct LayerFactory(Arc<dyn Fn() -> Box<dyn CodecLayer> + Send + Sync>);
+
+impl LayerFactory {
+    pub(crate) fn new<L: CodecLayer + 'static>(
+        layer: impl Fn() -> L + Send + Sync + 'static,
+    ) -> Self {
+        LayerFactory(Arc::new(move || Box::new(layer())))
+    }
+
+    pub(crate) fn create(&self) -> Box<dyn CodecLayer> {
+        (self.0)()
+    }
+}
+
+impl Debug for LayerFactory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("LayerFactory")
+    }
+}
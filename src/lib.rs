@@ -3,16 +3,46 @@
 #[macro_use]
 extern crate nom;
 
+use std::borrow::Cow;
+use std::fmt;
+
+use bytes::BytesMut;
+
 use custom_debug_derive::CustomDebug;
 use frame::Frame;
 
+mod accumulator;
+mod balance;
+mod builder;
 pub mod client;
+mod credentials;
+mod dialect;
+mod error;
+mod filter;
 mod frame;
+mod id;
+mod layer;
+mod merge;
+#[cfg(feature = "metrics")]
+mod monitor;
+mod partition;
+mod pool;
+mod pretty;
+mod progress;
+mod queue;
+mod redact;
+mod replay;
+mod shared;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
+mod writer;
+
+pub use error::StompError;
 
 pub(crate) type Result<T> = std::result::Result<T, failure::Error>;
 
 /// A representation of a STOMP frame
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Message<T> {
     /// The message content
     pub content: T,
@@ -28,6 +58,14 @@ fn pretty_bytes(b: &Option<Vec<u8>>, f: &mut std::fmt::Formatter) -> std::fmt::R
     }
 }
 
+/// Formats a credential without revealing it
+fn redacted(value: &Option<String>, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match value {
+        Some(_) => write!(f, "Some(\"***\")"),
+        None => write!(f, "None"),
+    }
+}
+
 /// A STOMP message sent from the server
 /// See the [Spec](https://stomp.github.io/stomp-specification-1.2.html) for more information
 #[derive(CustomDebug, Clone)]
@@ -58,11 +96,158 @@ pub enum FromServer {
     },
 }
 
+/// How the end of a frame's body was marked
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BodyDelimitation {
+    /// By a `content-length` header
+    ContentLength,
+    /// Only by the NUL terminating the frame
+    Nul,
+}
+
+/// What identifies a received MESSAGE, for audit logs, from `Message::audit_record`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde_crate::Serialize),
+    serde(crate = "serde_crate")
+)]
+pub struct AuditRecord {
+    pub destination: String,
+    pub message_id: String,
+    /// The `ack` header, which STOMP 1.2 brokers send even for subscriptions
+    /// using `AckMode::Auto`
+    pub ack_id: Option<String>,
+    pub subscription: String,
+    /// Whether the broker marked the message as delivered before, by a `redelivered`
+    /// header of `true`
+    pub redelivered: bool,
+    /// The `timestamp` header, e.g. milliseconds since the epoch for ActiveMQ
+    pub timestamp: Option<String>,
+    pub body_len: usize,
+}
+
+impl<T> Message<T> {
+    /// Add extra headers to the message, keeping any it already has
+    ///
+    /// ```
+    /// use tokio_stomp::{Message, ToServer};
+    ///
+    /// let send = ToServer::Send {
+    ///     destination: "/queue/a".into(),
+    ///     transaction: None,
+    ///     body: None,
+    /// };
+    /// let msg = Message::from(send)
+    ///     .with_extra_headers([("reply-to", "/temp/abc"), ("priority", "9")]);
+    /// assert_eq!(msg.extra_headers[1], (b"priority".to_vec(), b"9".to_vec()));
+    /// ```
+    pub fn with_extra_headers<K, V>(mut self, headers: impl IntoIterator<Item = (K, V)>) -> Self
+    where
+        K: Into<Vec<u8>>,
+        V: Into<Vec<u8>>,
+    {
+        self.extra_headers
+            .extend(headers.into_iter().map(|(k, v)| (k.into(), v.into())));
+        self
+    }
+}
+
 // TODO tidy this lot up with traits?
 impl Message<FromServer> {
     // TODO make this undead
     fn from_frame(frame: Frame) -> Result<Message<FromServer>> {
-        frame.to_server_msg()
+        Ok(frame.to_server_msg()?)
+    }
+
+    fn body(&self) -> Option<&[u8]> {
+        match &self.content {
+            FromServer::Message { body, .. } | FromServer::Error { body, .. } => body.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// The value of the `content-type` header, if present
+    pub fn content_type(&self) -> Option<&[u8]> {
+        self.extra_headers
+            .iter()
+            .find(|(key, _)| key == b"content-type")
+            .map(|(_, value)| &value[..])
+    }
+
+    /// Whether the server gave the length of a MESSAGE or ERROR frame's body, or relied on
+    /// the NUL terminator. Some brokers treat the two differently, e.g. ActiveMQ sends text
+    /// messages without a `content-length` header. Returns `None` for other frames.
+    pub fn body_delimitation(&self) -> Option<BodyDelimitation> {
+        match self.content {
+            FromServer::Message { .. } | FromServer::Error { .. } => {
+                let has_length = self
+                    .extra_headers
+                    .iter()
+                    .any(|(key, _)| key == b"content-length");
+                Some(if has_length {
+                    BodyDelimitation::ContentLength
+                } else {
+                    BodyDelimitation::Nul
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// The identifying details of a MESSAGE frame, for logging every message which is
+    /// processed. Returns `None` for other frames.
+    pub fn audit_record(&self) -> Option<AuditRecord> {
+        let (destination, message_id, subscription, body) = match &self.content {
+            FromServer::Message {
+                destination,
+                message_id,
+                subscription,
+                body,
+            } => (destination, message_id, subscription, body),
+            _ => return None,
+        };
+        let header = |name: &[u8]| {
+            self.extra_headers
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| String::from_utf8_lossy(value).into_owned())
+        };
+        Some(AuditRecord {
+            destination: destination.clone(),
+            message_id: message_id.clone(),
+            ack_id: header(b"ack"),
+            subscription: subscription.clone(),
+            redelivered: header(b"redelivered").as_deref() == Some("true"),
+            timestamp: header(b"timestamp"),
+            body_len: body.as_ref().map_or(0, Vec::len),
+        })
+    }
+
+    /// The body of a MESSAGE or ERROR frame as text, if it is valid UTF-8
+    pub fn body_as_str(&self) -> Option<&str> {
+        self.body().and_then(|body| std::str::from_utf8(body).ok())
+    }
+
+    /// A rendering of the frame for people to read, as `client::pretty_frame` would give
+    pub fn pretty(&self) -> String {
+        let mut frame = self.content.to_frame();
+        frame.extend_headers(&self.extra_headers);
+        frame.pretty(redact::DEFAULT_REDACTED_HEADERS)
+    }
+
+    /// Deserialize the body of a MESSAGE or ERROR frame whose `content-type` is
+    /// `application/json`, e.g. the structured details some brokers attach to errors.
+    /// Returns `None` if there is no body or it is not JSON.
+    #[cfg(feature = "serde")]
+    pub fn body_json<T: serde_crate::de::DeserializeOwned>(
+        &self,
+    ) -> Option<std::result::Result<T, StompError>> {
+        self.content_type()
+            .filter(|content_type| content_type.starts_with(b"application/json"))?;
+        self.body().map(|body| {
+            serde_json::from_slice(body).map_err(|e| StompError::InvalidBody(e.to_string()))
+        })
     }
 }
 
@@ -126,21 +311,23 @@ impl ToServerType {
 
 /// A STOMP message sent by the client.
 /// See the [Spec](https://stomp.github.io/stomp-specification-1.2.html) for more information
-#[derive(Debug, Clone)]
+#[derive(CustomDebug, Clone)]
 pub enum ToServer {
     #[doc(hidden)] // The user shouldn't need to know about this one
     Connect {
         accept_version: String,
-        host: String,
+        host: Option<String>,
         login: Option<String>,
+        #[debug(with = "redacted")]
         passcode: Option<String>,
         heartbeat: Option<(u32, u32)>,
     },
-    /// Send a message to a destination in the messaging system
+    /// Send a message to a destination in the messaging system. A static body, such as
+    /// `Cow::Borrowed(b"ping")`, is sent without being copied.
     Send {
         destination: String,
         transaction: Option<String>,
-        body: Option<Vec<u8>>,
+        body: Option<Cow<'static, [u8]>>,
     },
     /// Register to listen to a given destination
     Subscribe {
@@ -173,7 +360,12 @@ pub enum ToServer {
     Disconnect { receipt: Option<String> },
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde_crate::Serialize, serde_crate::Deserialize),
+    serde(crate = "serde_crate", rename_all = "kebab-case")
+)]
 pub enum AckMode {
     Auto,
     Client,
@@ -181,13 +373,124 @@ pub enum AckMode {
 }
 
 impl Message<ToServer> {
+    /// Create a message with no extra headers
+    pub fn new(content: ToServer) -> Message<ToServer> {
+        content.into()
+    }
+
+    /// Create a message with the given extra headers
+    pub fn with_headers<K, V>(
+        content: ToServer,
+        headers: impl IntoIterator<Item = (K, V)>,
+    ) -> Message<ToServer>
+    where
+        K: Into<String>,
+        V: Into<Vec<u8>>,
+    {
+        Message::new(content).headers(headers)
+    }
+
+    /// Add an extra header to the message
+    ///
+    /// ```
+    /// use std::borrow::Cow;
+    /// use tokio_stomp::{Message, ToServer};
+    ///
+    /// let msg = Message::new(ToServer::Send {
+    ///     destination: "/queue/a".into(),
+    ///     transaction: None,
+    ///     body: Some(Cow::Borrowed(b"hello")),
+    /// })
+    /// .header("priority", "9")
+    /// .header("reply-to", "/temp-queue/b")
+    /// .receipt("send-1");
+    /// assert_eq!(msg.extra_headers.len(), 3);
+    /// ```
+    pub fn header(
+        mut self,
+        key: impl Into<String>,
+        value: impl Into<Vec<u8>>,
+    ) -> Message<ToServer> {
+        self.extra_headers
+            .push((key.into().into_bytes(), value.into()));
+        self
+    }
+
+    /// Add several extra headers to the message
+    ///
+    /// ```
+    /// use tokio_stomp::{Message, ToServer};
+    ///
+    /// let msg = Message::new(ToServer::Unsubscribe { id: "sub-1".into() })
+    ///     .headers(vec![("a", "1"), ("b", "2")]);
+    /// assert_eq!(msg.extra_headers[1], (b"b".to_vec(), b"2".to_vec()));
+    /// ```
+    pub fn headers<K, V>(self, headers: impl IntoIterator<Item = (K, V)>) -> Message<ToServer>
+    where
+        K: Into<String>,
+        V: Into<Vec<u8>>,
+    {
+        self.with_extra_headers(headers.into_iter().map(|(k, v)| (k.into(), v)))
+    }
+
+    /// Request a receipt from the server for this message, replacing any receipt
+    /// requested before, so that the frame carries a single `receipt` header
+    pub fn receipt(mut self, id: impl Into<String>) -> Message<ToServer> {
+        let id = id.into();
+        self.extra_headers.retain(|(key, _)| key != b"receipt");
+        match &mut self.content {
+            ToServer::Disconnect { receipt, .. } => {
+                *receipt = Some(id);
+                self
+            }
+            _ => self.header("receipt", id),
+        }
+    }
+
+    /// Request a receipt from the server with a randomly generated id, unless the message
+    /// already requests one, in which case the receipt id set before is kept
+    pub fn auto_receipt(self) -> Message<ToServer> {
+        let has_receipt = match &self.content {
+            ToServer::Disconnect { receipt, .. } => receipt.is_some(),
+            _ => self.extra_headers.iter().any(|(key, _)| key == b"receipt"),
+        };
+        if has_receipt {
+            self
+        } else {
+            self.receipt(id::uuid_v4())
+        }
+    }
+
+    /// Serialize the message as a STOMP frame, e.g. to write it out without `ClientCodec`
+    pub fn serialize_to<W: client::SerializeSink>(&self, sink: &mut W) {
+        self.to_frame().serialize_to(sink);
+    }
+
+    /// A rendering of the frame for people to read, e.g. to report what was sent,
+    /// as `client::pretty_frame` would give. The login and passcode are masked.
+    pub fn pretty(&self) -> String {
+        self.to_frame().pretty(redact::DEFAULT_REDACTED_HEADERS)
+    }
+
     fn to_frame(&self) -> Frame<'_> {
-        self.content.to_frame()
+        let mut frame = self.content.to_frame();
+        frame.extend_headers(&self.extra_headers);
+        frame
     }
 
     #[allow(dead_code)]
     fn from_frame(frame: Frame) -> Result<Message<ToServer>> {
-        frame.to_client_msg()
+        Ok(frame.to_client_msg()?)
+    }
+}
+
+/// Writes the frame as it would appear on the wire (with any invalid UTF-8 replaced), e.g. to
+/// paste into `nc` when testing by hand. Unlike `Debug` and `pretty`, nothing is masked.
+impl fmt::Display for Message<ToServer> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut buffer = BytesMut::new();
+        self.to_frame().serialize(&mut buffer);
+        f.write_str(&String::from_utf8_lossy(&buffer))
     }
 }
 
@@ -199,3 +502,9 @@ impl From<ToServer> for Message<ToServer> {
         }
     }
 }
+
+impl From<(ToServer, Vec<(String, String)>)> for Message<ToServer> {
+    fn from((content, headers): (ToServer, Vec<(String, String)>)) -> Message<ToServer> {
+        Message::with_headers(content, headers)
+    }
+}
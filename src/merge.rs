@@ -0,0 +1,235 @@
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::prelude::*;
+
+use crate::{FromServer, Message, Result};
+
+/// How many messages `FairMerge` buffers for each subscription by default
+pub const FAIR_MERGE_CAPACITY: usize = 64;
+
+/// A stream of the messages from every subscription on a connection, taking turns between
+/// the subscriptions so that a busy one can't starve a quiet one.
+///
+/// Messages are read ahead into a bounded queue per subscription, and yielded from the
+/// queues round-robin. Reading stops while any queue is full, leaving the broker to be held
+/// back by TCP flow control, so at most `capacity` messages are buffered per subscription.
+///
+/// Each subscription's messages are yielded in the order they were received, but there is
+/// no order between subscriptions: a message may overtake up to `capacity` messages from a
+/// busier subscription which were received before it. Frames other than MESSAGE, such as
+/// receipts, take their turn as though they were one more subscription. If the stream
+/// fails, the buffered messages are yielded before the error.
+pub struct FairMerge<S> {
+    inner: S,
+    capacity: usize,
+    /// A queue per subscription, or `None` for other frames, in the order first seen
+    sources: Vec<(Option<String>, VecDeque<Message<FromServer>>)>,
+    /// The index of the source whose turn is next
+    turn: usize,
+    done: bool,
+    error: Option<failure::Error>,
+}
+
+impl<S> FairMerge<S>
+where
+    S: Stream<Item = Result<Message<FromServer>>> + Unpin,
+{
+    /// Merge the subscriptions of `inner`, e.g. a `StompTransport`, buffering up to
+    /// `capacity` messages for each
+    pub fn new(inner: S, capacity: usize) -> Self {
+        assert!(
+            capacity > 0,
+            "a merge needs room for a message per subscription"
+        );
+        FairMerge {
+            inner,
+            capacity,
+            sources: vec![],
+            turn: 0,
+            done: false,
+            error: None,
+        }
+    }
+
+    /// The underlying stream, e.g. to build acknowledgments with
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// The underlying stream, e.g. to send acknowledgments with. Reading from it directly
+    /// bypasses the queues.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// The number of messages buffered for `subscription`
+    pub fn queued(&self, subscription: &str) -> usize {
+        self.sources
+            .iter()
+            .find(|(id, _)| id.as_deref() == Some(subscription))
+            .map_or(0, |(_, queue)| queue.len())
+    }
+
+    fn enqueue(&mut self, msg: Message<FromServer>) {
+        let source = match &msg.content {
+            FromServer::Message { subscription, .. } => Some(subscription),
+            _ => None,
+        };
+        let position = self
+            .sources
+            .iter()
+            .position(|(id, _)| id.as_ref() == source);
+        let index = position.unwrap_or_else(|| {
+            self.sources.push((source.cloned(), VecDeque::new()));
+            self.sources.len() - 1
+        });
+        self.sources[index].1.push_back(msg);
+    }
+
+    /// Take the next message, in turn, from the queues
+    fn dequeue(&mut self) -> Option<Message<FromServer>> {
+        let count = self.sources.len();
+        for offset in 0..count {
+            let index = (self.turn + offset) % count;
+            if let Some(msg) = self.sources[index].1.pop_front() {
+                self.turn = (index + 1) % count;
+                return Some(msg);
+            }
+        }
+        None
+    }
+}
+
+impl<S> Stream for FairMerge<S>
+where
+    S: Stream<Item = Result<Message<FromServer>>> + Unpin,
+{
+    type Item = Result<Message<FromServer>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        while !this.done {
+            let capacity = this.capacity;
+            if this
+                .sources
+                .iter()
+                .any(|(_, queue)| queue.len() >= capacity)
+            {
+                break;
+            }
+            match this.inner.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(msg))) => this.enqueue(msg),
+                Poll::Ready(Some(Err(e))) => {
+                    this.error = Some(e);
+                    this.done = true;
+                }
+                Poll::Ready(None) => this.done = true,
+                Poll::Pending => break,
+            }
+        }
+        match this.dequeue() {
+            Some(msg) => Poll::Ready(Some(Ok(msg))),
+            None if this.done => Poll::Ready(this.error.take().map(Err)),
+            // reading stopped at `Pending`, as no queue is full while all are empty
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    fn message(subscription: &str, seq: usize) -> Message<FromServer> {
+        Message {
+            content: FromServer::Message {
+                destination: "/queue/a".into(),
+                message_id: format!("{}-{}", subscription, seq),
+                subscription: subscription.into(),
+                body: None,
+            },
+            extra_headers: vec![],
+        }
+    }
+
+    fn message_id(msg: &Message<FromServer>) -> String {
+        match &msg.content {
+            FromServer::Message { message_id, .. } => message_id.clone(),
+            _ => panic!("not a MESSAGE"),
+        }
+    }
+
+    #[test]
+    fn quiet_subscription_is_not_starved() {
+        // a flood on "busy", with a message on "quiet" after every 30th
+        let mut input = vec![];
+        for seq in 0..120 {
+            input.push(message("busy", seq));
+            if seq % 30 == 29 {
+                input.push(message("quiet", seq / 30));
+            }
+        }
+        let arrived: Vec<_> = input.iter().map(message_id).collect();
+
+        let capacity = 8;
+        let merged = FairMerge::new(stream::iter(input.into_iter().map(Ok)), capacity);
+        let yielded: Vec<_> = block_on(merged.map(|msg| message_id(&msg.unwrap())).collect());
+        assert_eq!(yielded.len(), arrived.len());
+
+        for subscription in &["busy", "quiet"] {
+            let of = |ids: &[String]| -> Vec<String> {
+                let prefix = format!("{}-", subscription);
+                ids.iter()
+                    .filter(|id| id.starts_with(&prefix))
+                    .cloned()
+                    .collect()
+            };
+            assert_eq!(of(&yielded), of(&arrived));
+        }
+
+        // each quiet message overtakes the busy messages buffered ahead of it
+        for seq in 0..4 {
+            let id = format!("quiet-{}", seq);
+            let busy_before = |ids: &[String]| {
+                let position = ids.iter().position(|other| *other == id).unwrap();
+                position - seq
+            };
+            assert!(
+                busy_before(&yielded) + capacity / 2 <= busy_before(&arrived),
+                "{} was yielded after {} busy messages",
+                id,
+                busy_before(&yielded)
+            );
+        }
+    }
+
+    #[test]
+    fn buffering_is_bounded() {
+        let input = (0..20).map(|seq| Ok(message("busy", seq)));
+        let mut merged = FairMerge::new(stream::iter(input), 4);
+        block_on(async {
+            assert_eq!(message_id(&merged.next().await.unwrap().unwrap()), "busy-0");
+            assert_eq!(merged.queued("busy"), 3);
+            assert_eq!(merged.queued("other"), 0);
+        });
+    }
+
+    #[test]
+    fn error_follows_buffered_messages() {
+        let input = vec![
+            Ok(message("a", 0)),
+            Ok(message("b", 0)),
+            Err(failure::format_err!("connection reset")),
+            Ok(message("a", 1)),
+        ];
+        let merged = FairMerge::new(stream::iter(input), 8);
+        let results: Vec<_> = block_on(merged.collect());
+        assert_eq!(results.len(), 3);
+        assert_eq!(message_id(results[0].as_ref().unwrap()), "a-0");
+        assert_eq!(message_id(results[1].as_ref().unwrap()), "b-0");
+        assert!(results[2].is_err());
+    }
+}
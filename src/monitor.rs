@@ -0,0 +1,465 @@
+use std::collections::{HashSet, VecDeque};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use futures::prelude::*;
+use metrics::{counter, gauge, histogram};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::client::StompTransport;
+use crate::{FromServer, Message, Result, ToServer};
+
+/// Upper bounds (inclusive, in bytes) of the frame size buckets used unless configured otherwise
+pub const DEFAULT_SIZE_BUCKETS: [usize; 8] =
+    [64, 256, 1024, 4096, 16384, 65536, 262_144, 1_048_576];
+
+const SENT_COMMANDS: [&str; 10] = [
+    "CONNECT",
+    "SEND",
+    "SUBSCRIBE",
+    "UNSUBSCRIBE",
+    "ACK",
+    "NACK",
+    "BEGIN",
+    "COMMIT",
+    "ABORT",
+    "DISCONNECT",
+];
+const RECEIVED_COMMANDS: [&str; 4] = ["CONNECTED", "MESSAGE", "RECEIPT", "ERROR"];
+
+fn sent_index(content: &ToServer) -> usize {
+    match content {
+        ToServer::Connect { .. } => 0,
+        ToServer::Send { .. } => 1,
+        ToServer::Subscribe { .. } => 2,
+        ToServer::Unsubscribe { .. } => 3,
+        ToServer::Ack { .. } => 4,
+        ToServer::Nack { .. } => 5,
+        ToServer::Begin { .. } => 6,
+        ToServer::Commit { .. } => 7,
+        ToServer::Abort { .. } => 8,
+        ToServer::Disconnect { .. } => 9,
+    }
+}
+
+fn received_index(content: &FromServer) -> usize {
+    match content {
+        FromServer::Connected { .. } => 0,
+        FromServer::Message { .. } => 1,
+        FromServer::Receipt { .. } => 2,
+        FromServer::Error { .. } => 3,
+    }
+}
+
+/// The distribution of frame sizes in one direction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    /// Inclusive upper bound of each bucket, in bytes
+    pub buckets: Vec<usize>,
+    /// The number of frames in each bucket, followed by the number larger than the last bound
+    pub counts: Vec<u64>,
+}
+
+#[derive(Debug, Clone)]
+struct SizeHistogram {
+    bounds: Vec<usize>,
+    counts: Vec<u64>,
+}
+
+impl SizeHistogram {
+    fn new(mut bounds: Vec<usize>) -> Self {
+        bounds.sort_unstable();
+        bounds.dedup();
+        let counts = vec![0; bounds.len() + 1];
+        SizeHistogram { bounds, counts }
+    }
+
+    fn record(&mut self, size: usize) {
+        let bucket = self
+            .bounds
+            .iter()
+            .take_while(|&&bound| bound < size)
+            .count();
+        self.counts[bucket] += 1;
+    }
+
+    fn snapshot(&self) -> HistogramSnapshot {
+        HistogramSnapshot {
+            buckets: self.bounds.clone(),
+            counts: self.counts.clone(),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.counts.iter_mut().for_each(|count| *count = 0);
+    }
+}
+
+/// Running totals kept by the codec of every connection while the `metrics` feature is enabled.
+/// Recording a frame never allocates.
+#[derive(Debug, Clone)]
+pub struct FrameStats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// The receipts requested but not yet received
+    pub pending_receipts: usize,
+    /// The most receipts which have been pending at once
+    pub pending_receipts_high_water: usize,
+    /// `ConnectOptions::max_pending_receipts`
+    pub max_pending_receipts: Option<usize>,
+    sent_sizes: SizeHistogram,
+    received_sizes: SizeHistogram,
+    sent_commands: [u64; SENT_COMMANDS.len()],
+    received_commands: [u64; RECEIVED_COMMANDS.len()],
+}
+
+impl FrameStats {
+    /// Track frame sizes using buckets with the given upper bounds
+    pub fn with_size_buckets(bounds: Vec<usize>) -> Self {
+        FrameStats {
+            bytes_sent: 0,
+            bytes_received: 0,
+            pending_receipts: 0,
+            pending_receipts_high_water: 0,
+            max_pending_receipts: None,
+            sent_sizes: SizeHistogram::new(bounds.clone()),
+            received_sizes: SizeHistogram::new(bounds),
+            sent_commands: Default::default(),
+            received_commands: Default::default(),
+        }
+    }
+
+    pub(crate) fn record_sent(&mut self, content: &ToServer, size: usize) {
+        self.bytes_sent += size as u64;
+        self.sent_sizes.record(size);
+        self.sent_commands[sent_index(content)] += 1;
+    }
+
+    pub(crate) fn record_received(&mut self, content: &FromServer, size: usize) {
+        self.bytes_received += size as u64;
+        self.received_sizes.record(size);
+        self.received_commands[received_index(content)] += 1;
+    }
+
+    pub(crate) fn record_receipts(
+        &mut self,
+        pending: usize,
+        high_water: usize,
+        max: Option<usize>,
+    ) {
+        self.pending_receipts = pending;
+        self.pending_receipts_high_water = high_water;
+        self.max_pending_receipts = max;
+    }
+
+    /// The sizes of the frames sent
+    pub fn sent_sizes(&self) -> HistogramSnapshot {
+        self.sent_sizes.snapshot()
+    }
+
+    /// The sizes of the frames received
+    pub fn received_sizes(&self) -> HistogramSnapshot {
+        self.received_sizes.snapshot()
+    }
+
+    /// The number of frames sent with each command
+    pub fn sent_commands(&self) -> Vec<(&'static str, u64)> {
+        SENT_COMMANDS
+            .iter()
+            .copied()
+            .zip(self.sent_commands.iter().copied())
+            .collect()
+    }
+
+    /// The number of frames received with each command
+    pub fn received_commands(&self) -> Vec<(&'static str, u64)> {
+        RECEIVED_COMMANDS
+            .iter()
+            .copied()
+            .zip(self.received_commands.iter().copied())
+            .collect()
+    }
+
+    /// Clear the size histograms and command counts.
+    /// The byte totals keep running, as `StompMonitor` reports their increase.
+    pub fn reset(&mut self) {
+        self.sent_sizes.reset();
+        self.received_sizes.reset();
+        self.sent_commands = Default::default();
+        self.received_commands = Default::default();
+    }
+}
+
+impl Default for FrameStats {
+    fn default() -> Self {
+        Self::with_size_buckets(DEFAULT_SIZE_BUCKETS.to_vec())
+    }
+}
+
+/// Wraps a `StompTransport`, reporting its traffic through the `metrics` crate.
+/// Every metric is labelled with the broker's `address`:
+///
+/// - `stomp_frames_sent_total`, `stomp_frames_received_total`
+/// - `stomp_bytes_sent_total`, `stomp_bytes_received_total`
+/// - `stomp_errors_total`, counting both failed operations and ERROR frames
+/// - `stomp_reconnects_total`, counting calls to `record_reconnect`
+/// - `stomp_subscriptions_active`, the subscriptions opened but not yet closed
+/// - `stomp_frame_processing_duration_seconds`, labelled with its `direction`: for a frame
+///   sent, the time from `start_send` until a flush completes with the frame written; for a
+///   frame received, the time from when the stream was first polled for it until it was
+///   yielded, including any wait for it to arrive
+pub struct StompMonitor<S> {
+    inner: StompTransport<S>,
+    address: String,
+    subscriptions: HashSet<String>,
+    /// When each frame handed to `start_send` but not yet flushed was sent
+    sends_started: VecDeque<Instant>,
+    /// When the stream was first polled for the next frame
+    receive_started: Option<Instant>,
+    /// The byte totals as of the last report
+    reported_sent: u64,
+    reported_received: u64,
+}
+
+impl<S> StompMonitor<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(inner: StompTransport<S>, address: impl Into<String>) -> Self {
+        StompMonitor {
+            reported_sent: inner.stats().bytes_sent,
+            reported_received: inner.stats().bytes_received,
+            inner,
+            address: address.into(),
+            subscriptions: HashSet::new(),
+            sends_started: VecDeque::new(),
+            receive_started: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &StompTransport<S> {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut StompTransport<S> {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> StompTransport<S> {
+        self.inner
+    }
+
+    /// Count a reconnection, for applications which replace a failed connection
+    /// with a new one monitored under the same address
+    pub fn record_reconnect(&self) {
+        counter!("stomp_reconnects_total", 1, "address" => self.address.clone());
+    }
+
+    /// Report the bytes the codec has handled since the last call
+    fn report_bytes(&mut self) {
+        let stats = self.inner.stats();
+        let sent = stats.bytes_sent - self.reported_sent;
+        let received = stats.bytes_received - self.reported_received;
+        self.reported_sent = stats.bytes_sent;
+        self.reported_received = stats.bytes_received;
+        if sent > 0 {
+            counter!("stomp_bytes_sent_total", sent, "address" => self.address.clone());
+        }
+        if received > 0 {
+            counter!("stomp_bytes_received_total", received, "address" => self.address.clone());
+        }
+    }
+
+    fn record_error(&self) {
+        counter!("stomp_errors_total", 1, "address" => self.address.clone());
+    }
+
+    fn record_duration(&self, direction: &'static str, start: Instant) {
+        histogram!(
+            "stomp_frame_processing_duration_seconds",
+            start.elapsed().as_secs_f64(),
+            "address" => self.address.clone(),
+            "direction" => direction
+        );
+    }
+
+    /// Record how long the frames sent since the last flush took to be written,
+    /// once a flush has completed
+    fn flushed(&mut self, result: &Result<()>) {
+        let started = std::mem::take(&mut self.sends_started);
+        match result {
+            Ok(()) => {
+                for start in started {
+                    self.record_duration("sent", start);
+                }
+            }
+            Err(_) => self.record_error(),
+        }
+    }
+
+    fn track_subscriptions(&mut self, msg: &Message<ToServer>) {
+        let changed = match &msg.content {
+            ToServer::Subscribe { id, .. } => self.subscriptions.insert(id.clone()),
+            ToServer::Unsubscribe { id } => self.subscriptions.remove(id),
+            _ => false,
+        };
+        if changed {
+            gauge!(
+                "stomp_subscriptions_active",
+                self.subscriptions.len() as f64,
+                "address" => self.address.clone()
+            );
+        }
+    }
+}
+
+impl<S> Stream for StompMonitor<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    type Item = Result<Message<FromServer>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let start = *this.receive_started.get_or_insert_with(Instant::now);
+        let item = futures::ready!(this.inner.poll_next_unpin(cx));
+        this.receive_started = None;
+        this.report_bytes();
+        match &item {
+            Some(Ok(msg)) => {
+                this.record_duration("received", start);
+                counter!("stomp_frames_received_total", 1, "address" => this.address.clone());
+                if let FromServer::Error { .. } = msg.content {
+                    this.record_error();
+                }
+            }
+            Some(Err(_)) => this.record_error(),
+            None => {}
+        }
+        Poll::Ready(item)
+    }
+}
+
+impl<S> Sink<Message<ToServer>> for StompMonitor<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    type Error = failure::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        let result = futures::ready!(this.inner.poll_ready_unpin(cx));
+        this.report_bytes();
+        if result.is_err() {
+            this.record_error();
+        }
+        Poll::Ready(result)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message<ToServer>) -> Result<()> {
+        let this = self.get_mut();
+        let start = Instant::now();
+        this.track_subscriptions(&item);
+        let result = this.inner.start_send_unpin(item);
+        this.report_bytes();
+        match result {
+            Ok(()) => {
+                this.sends_started.push_back(start);
+                counter!("stomp_frames_sent_total", 1, "address" => this.address.clone());
+            }
+            Err(_) => this.record_error(),
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        let result = futures::ready!(this.inner.poll_flush_unpin(cx));
+        this.flushed(&result);
+        Poll::Ready(result)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        let result = futures::ready!(this.inner.poll_close_unpin(cx));
+        this.flushed(&result);
+        Poll::Ready(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{send_text, subscribe};
+    use crate::test_utils::StompRouter;
+
+    #[tokio::test]
+    async fn monitor_times_whole_operations() {
+        let router = StompRouter::start().await.unwrap();
+        let transport = router.connect().await.unwrap();
+        let mut monitor = StompMonitor::new(transport, router.address());
+
+        // a frame sent is timed until it has been flushed
+        monitor.feed(subscribe("/queue/a", "1")).await.unwrap();
+        assert_eq!(monitor.sends_started.len(), 1);
+        assert!(monitor.subscriptions.contains("1"));
+        monitor.flush().await.unwrap();
+        assert!(monitor.sends_started.is_empty());
+
+        // and a frame received from when the stream was first polled for it
+        assert!(monitor.next().now_or_never().is_none());
+        let started = monitor.receive_started.unwrap();
+        assert!(monitor.next().now_or_never().is_none());
+        assert_eq!(monitor.receive_started, Some(started));
+        let mut publisher = router.connect().await.unwrap();
+        publisher
+            .send(send_text("/queue/a", "hello"))
+            .await
+            .unwrap();
+        let msg = monitor.next().await.unwrap().unwrap();
+        assert_eq!(msg.body_as_str(), Some("hello"));
+        assert!(monitor.receive_started.is_none());
+
+        monitor
+            .send(ToServer::Unsubscribe { id: "1".into() }.into())
+            .await
+            .unwrap();
+        assert!(monitor.subscriptions.is_empty());
+        assert!(monitor.sends_started.is_empty());
+    }
+
+    #[test]
+    fn size_histogram_and_command_counts() {
+        let mut stats = FrameStats::with_size_buckets(vec![100, 10]);
+        let send = ToServer::Send {
+            destination: "a".into(),
+            transaction: None,
+            body: None,
+        };
+        let receipt = FromServer::Receipt {
+            receipt_id: "r".into(),
+        };
+        for &size in &[1, 10, 11, 100, 101, 5000] {
+            stats.record_sent(&send, size);
+        }
+        stats.record_received(&receipt, 50);
+
+        assert_eq!(
+            stats.sent_sizes(),
+            HistogramSnapshot {
+                buckets: vec![10, 100],
+                counts: vec![2, 2, 2],
+            }
+        );
+        assert_eq!(stats.received_sizes().counts, vec![0, 1, 0]);
+        assert!(stats.sent_commands().contains(&("SEND", 6)));
+        assert!(stats.received_commands().contains(&("RECEIPT", 1)));
+        assert_eq!(stats.bytes_sent, 5223);
+
+        stats.reset();
+        assert_eq!(stats.sent_sizes().counts, vec![0, 0, 0]);
+        assert!(stats.sent_commands().iter().all(|&(_, count)| count == 0));
+        assert_eq!(stats.bytes_sent, 5223);
+    }
+}
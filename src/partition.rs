@@ -0,0 +1,231 @@
+use std::hash::{Hash, Hasher};
+
+use futures::channel::mpsc;
+use futures::prelude::*;
+
+use crate::{FromServer, Message, Result};
+
+/// How many messages may wait in each worker's channel before routing waits for the worker
+pub const PARTITION_CHANNEL_CAPACITY: usize = 64;
+
+/// The receiving end of a worker's channel, yielding the messages routed to it in the order
+/// they were received
+pub type PartitionReceiver = mpsc::Receiver<Message<FromServer>>;
+
+/// Routes messages to a fixed set of workers by a key extracted from each message, so that
+/// every message with the same key is handled by the same worker, in the order received.
+///
+/// This is for consuming one queue with several connections, where the broker shares the
+/// messages out between them with no regard for their keys: feed the connections' merged
+/// streams (e.g. with `futures::stream::select_all`) to `run`. Ordering is only kept for
+/// messages which arrive on the same connection, as the broker gives no ordering between
+/// competing consumers.
+///
+/// Keys are hashed with FNV-1a rather than a randomly seeded hasher, so within one build of
+/// a program a key is always routed to the same worker for the same worker weights. Other
+/// builds may disagree, as the bytes a key feeds to the hasher are up to its `Hash` impl,
+/// which can differ between platforms and Rust releases. When a worker's channel is full,
+/// routing waits for it, holding back the messages for every other worker.
+pub struct Partitioner<F> {
+    key_fn: F,
+    workers: Vec<mpsc::Sender<Message<FromServer>>>,
+    /// The running total of the workers' weights, in worker order
+    cumulative_weights: Vec<u64>,
+}
+
+impl<F, K> Partitioner<F>
+where
+    F: Fn(&Message<FromServer>) -> K,
+    K: Hash,
+{
+    /// Route messages evenly between `num_workers` workers, returning the partitioner
+    /// and each worker's receiver
+    pub fn new(num_workers: usize, key_fn: F) -> (Self, Vec<PartitionReceiver>) {
+        Partitioner::weighted(vec![1; num_workers], key_fn)
+    }
+
+    /// Route messages between one worker per weight, each taking a share of the keys
+    /// in proportion to its weight. Workers with a weight of zero receive nothing.
+    pub fn weighted(weights: Vec<u32>, key_fn: F) -> (Self, Vec<PartitionReceiver>) {
+        let cumulative_weights: Vec<u64> = weights
+            .iter()
+            .scan(0, |total, &weight| {
+                *total += u64::from(weight);
+                Some(*total)
+            })
+            .collect();
+        assert!(
+            cumulative_weights.last().copied().unwrap_or(0) > 0,
+            "a partitioner needs a worker with a non-zero weight"
+        );
+        let (workers, receivers) = weights
+            .iter()
+            .map(|_| mpsc::channel(PARTITION_CHANNEL_CAPACITY))
+            .unzip();
+        let partitioner = Partitioner {
+            key_fn,
+            workers,
+            cumulative_weights,
+        };
+        (partitioner, receivers)
+    }
+
+    /// The number of workers
+    pub fn workers(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// The index of the worker which handles messages with `key`
+    pub fn worker_for(&self, key: &K) -> usize {
+        let mut hasher = Fnv1a::default();
+        key.hash(&mut hasher);
+        let total = *self.cumulative_weights.last().unwrap();
+        let point = hasher.finish() % total;
+        self.cumulative_weights
+            .iter()
+            .position(|&bound| point < bound)
+            .unwrap()
+    }
+
+    /// Send `msg` to the worker for its key, waiting if that worker's channel is full.
+    /// Fails if the worker's receiver has been dropped.
+    pub async fn route(&mut self, msg: Message<FromServer>) -> Result<()> {
+        let worker = self.worker_for(&(self.key_fn)(&msg));
+        self.workers[worker]
+            .send(msg)
+            .await
+            .map_err(|_| failure::format_err!("partition worker {} has stopped", worker))
+    }
+
+    /// Route every message from `stream` until it ends, then close the workers' channels.
+    /// Stops at the first error from the stream or from `route`.
+    pub async fn run<S>(mut self, mut stream: S) -> Result<()>
+    where
+        S: Stream<Item = Result<Message<FromServer>>> + Unpin,
+    {
+        while let Some(msg) = stream.next().await {
+            self.route(msg?).await?;
+        }
+        Ok(())
+    }
+}
+
+/// The 64-bit FNV-1a hash, which unlike `DefaultHasher` isn't seeded or changed between
+/// Rust releases
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    fn message(key: &str, seq: usize) -> Message<FromServer> {
+        Message {
+            content: FromServer::Message {
+                destination: "/queue/orders".into(),
+                message_id: format!("{}-{}", key, seq),
+                subscription: "1".into(),
+                body: Some(seq.to_string().into_bytes()),
+            },
+            extra_headers: vec![(b"order-id".to_vec(), key.as_bytes().to_vec())],
+        }
+    }
+
+    fn order_id(msg: &Message<FromServer>) -> Vec<u8> {
+        msg.extra_headers
+            .iter()
+            .find(|(key, _)| key == b"order-id")
+            .map(|(_, value)| value.clone())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn keys_map_to_the_same_worker() {
+        let (a, _) = Partitioner::new(4, order_id);
+        let (b, _) = Partitioner::new(4, order_id);
+        let mut used = [false; 4];
+        for i in 0..100 {
+            let key = format!("order-{}", i).into_bytes();
+            let worker = a.worker_for(&key);
+            assert_eq!(worker, b.worker_for(&key));
+            used[worker] = true;
+        }
+        assert_eq!(used, [true; 4]);
+
+        // the hash must not change between releases
+        let mut hasher = Fnv1a::default();
+        hasher.write(b"a");
+        assert_eq!(hasher.finish(), 0xaf63_dc4c_8601_ec8c);
+
+        let (weighted, _) = Partitioner::weighted(vec![0, 1, 3], order_id);
+        let mut counts = [0; 3];
+        for i in 0..1000 {
+            counts[weighted.worker_for(&format!("order-{}", i).into_bytes())] += 1;
+        }
+        assert_eq!(counts[0], 0);
+        assert!(counts[2] > 2 * counts[1], "{:?}", counts);
+    }
+
+    #[test]
+    fn per_key_order_is_kept() {
+        let (partitioner, receivers) = Partitioner::new(3, order_id);
+        let keys = ["a", "b", "c", "d", "e", "f", "g"];
+        // round-robin across the keys, as messages from several producers interleave
+        let messages: Vec<_> = (0..20)
+            .flat_map(|seq| keys.iter().map(move |key| Ok(message(key, seq))))
+            .collect();
+        let expected_workers: Vec<_> = keys
+            .iter()
+            .map(|key| partitioner.worker_for(&key.as_bytes().to_vec()))
+            .collect();
+
+        let routing = partitioner.run(stream::iter(messages));
+        let collecting = future::join_all(receivers.into_iter().map(|rx| rx.collect::<Vec<_>>()));
+        let (routed, received) = block_on(future::join(routing, collecting));
+        routed.unwrap();
+
+        let mut total = 0;
+        for (worker, messages) in received.iter().enumerate() {
+            for (key, &expected) in keys.iter().zip(&expected_workers) {
+                let seqs: Vec<_> = messages
+                    .iter()
+                    .filter(|msg| order_id(msg) == key.as_bytes())
+                    .map(|msg| msg.body_as_str().unwrap().parse::<usize>().unwrap())
+                    .collect();
+                if worker == expected {
+                    assert_eq!(seqs, (0..20).collect::<Vec<_>>());
+                } else {
+                    assert!(seqs.is_empty());
+                }
+            }
+            total += messages.len();
+        }
+        assert_eq!(total, keys.len() * 20);
+    }
+
+    #[test]
+    fn stopped_worker_fails_routing() {
+        let (mut partitioner, mut receivers) = Partitioner::new(1, order_id);
+        receivers.clear();
+        assert!(block_on(partitioner.route(message("a", 0))).is_err());
+    }
+}
@@ -0,0 +1,427 @@
+use std::collections::VecDeque;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use futures::channel::oneshot;
+use futures::prelude::*;
+use tokio::net::TcpStream;
+
+use crate::client::{connect_with_options, ConnectOptions, StompTransport};
+use crate::{FromServer, Message, Result};
+
+/// Sizing and maintenance settings for a `Pool`
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// The number of connections kept open, even when idle
+    pub min_connections: usize,
+    /// The maximum number of connections open at once, including leased ones
+    pub max_connections: usize,
+    /// How often idle connections are checked and sent a heartbeat
+    pub health_check_interval: Duration,
+    /// Idle connections in excess of `min_connections` are closed after this long
+    pub idle_timeout: Duration,
+}
+
+struct Idle {
+    transport: StompTransport<TcpStream>,
+    since: Instant,
+}
+
+/// The idle connections, and the callers of `acquire` waiting for one to be released.
+/// A waiter is sent either a connection, or `None` to open one itself in the slot
+/// given up by a closed connection.
+/// Only ever locked briefly, never across an `.await`.
+#[derive(Default)]
+struct Slots {
+    idle: VecDeque<Idle>,
+    waiters: VecDeque<oneshot::Sender<Option<StompTransport<TcpStream>>>>,
+}
+
+struct Shared {
+    address: String,
+    options: ConnectOptions,
+    config: PoolConfig,
+    /// Number of open connections, both idle and leased
+    open: AtomicUsize,
+    slots: Mutex<Slots>,
+}
+
+/// A pool of connections to a single STOMP server.
+///
+/// A background task periodically sends heartbeats on idle connections,
+/// replacing any which have failed, and closes connections which have been idle
+/// for longer than `idle_timeout` while more than `min_connections` are open.
+/// Frames received on a connection while it sits idle in the pool are discarded.
+/// The task stops once the `Pool` is dropped.
+pub struct Pool {
+    shared: Arc<Shared>,
+}
+
+impl Pool {
+    /// Open `min_connections` connections to the server, and start maintaining them
+    pub async fn connect(
+        address: impl Into<String>,
+        options: ConnectOptions,
+        config: PoolConfig,
+    ) -> Result<Pool> {
+        let shared = Arc::new(Shared {
+            address: address.into(),
+            options,
+            config,
+            open: AtomicUsize::new(0),
+            slots: Mutex::default(),
+        });
+        for _ in 0..shared.config.min_connections {
+            let transport = shared.open_connection().await?;
+            shared.release(transport);
+        }
+        tokio::spawn(maintain(
+            Arc::downgrade(&shared),
+            shared.config.health_check_interval,
+        ));
+        Ok(Pool { shared })
+    }
+
+    /// Lease a connection from the pool, opening a new one if none are idle.
+    /// If `max_connections` are already open, waits for one to be returned.
+    pub async fn acquire(&self) -> Result<PooledConnection> {
+        let shared = &self.shared;
+        let released = {
+            let mut slots = shared.slots.lock().unwrap();
+            if let Some(idle) = slots.idle.pop_front() {
+                return Ok(PooledConnection::new(idle.transport, shared));
+            }
+            if shared.reserve() {
+                None
+            } else {
+                let (tx, rx) = oneshot::channel();
+                slots.waiters.push_back(tx);
+                Some(rx)
+            }
+        };
+        let transport = match released {
+            None => shared.connect_reserved().await?,
+            // waiters are only dropped after being sent a connection or a slot
+            Some(rx) => match rx.await.expect("pool waiter dropped") {
+                Some(transport) => transport,
+                None => shared.connect_reserved().await?,
+            },
+        };
+        Ok(PooledConnection::new(transport, shared))
+    }
+
+    /// The number of open connections, both idle and leased
+    pub fn open_connections(&self) -> usize {
+        self.shared.open.load(Ordering::SeqCst)
+    }
+}
+
+impl Shared {
+    /// Reserve a slot for a new connection, if fewer than `max_connections` are open
+    fn reserve(&self) -> bool {
+        let mut open = self.open.load(Ordering::SeqCst);
+        while open < self.config.max_connections {
+            match self
+                .open
+                .compare_exchange(open, open + 1, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => return true,
+                Err(current) => open = current,
+            }
+        }
+        false
+    }
+
+    async fn connect_reserved(&self) -> Result<StompTransport<TcpStream>> {
+        let result = connect_with_options(self.address.as_str(), self.options.clone()).await;
+        if result.is_err() {
+            self.release_slot();
+        }
+        result
+    }
+
+    async fn open_connection(&self) -> Result<StompTransport<TcpStream>> {
+        self.open.fetch_add(1, Ordering::SeqCst);
+        self.connect_reserved().await
+    }
+
+    fn release(&self, transport: StompTransport<TcpStream>) {
+        self.release_idle(Idle {
+            transport,
+            since: Instant::now(),
+        });
+    }
+
+    /// Hand the connection to the longest waiting caller of `acquire`, or else keep it idle
+    fn release_idle(&self, mut idle: Idle) {
+        let mut slots = self.slots.lock().unwrap();
+        while let Some(waiter) = slots.waiters.pop_front() {
+            // a waiter whose `acquire` was dropped hands the connection back
+            match waiter.send(Some(idle.transport)) {
+                Ok(()) => return,
+                Err(returned) => idle.transport = returned.unwrap(),
+            }
+        }
+        slots.idle.push_back(idle);
+    }
+
+    /// Give up the slot of a connection which has been closed, passing it on to the longest
+    /// waiting caller of `acquire` to open a new connection in, or else freeing it
+    fn release_slot(&self) {
+        let mut slots = self.slots.lock().unwrap();
+        while let Some(waiter) = slots.waiters.pop_front() {
+            if waiter.send(None).is_ok() {
+                return;
+            }
+        }
+        // decremented under the lock, so that `acquire` can't miss it and wait regardless
+        self.open.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    async fn check_idle(&self) {
+        let idle: Vec<_> = self.slots.lock().unwrap().idle.drain(..).collect();
+
+        let mut healthy = vec![];
+        for mut conn in idle {
+            let open = self.open.load(Ordering::SeqCst);
+            if conn.since.elapsed() >= self.config.idle_timeout
+                && open > self.config.min_connections
+            {
+                drop(conn);
+                self.release_slot();
+            } else if is_healthy(&mut conn.transport).await {
+                healthy.push(conn);
+            } else {
+                drop(conn);
+                self.release_slot();
+            }
+        }
+        for conn in healthy {
+            self.release_idle(conn);
+        }
+
+        while self.open.load(Ordering::SeqCst) < self.config.min_connections {
+            match self.open_connection().await {
+                Ok(transport) => self.release(transport),
+                // try again on the next check
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+async fn is_healthy(transport: &mut StompTransport<TcpStream>) -> bool {
+    // drain anything received while idle, looking for signs the connection is dead
+    loop {
+        match transport.next().now_or_never() {
+            Some(None) | Some(Some(Err(_))) => return false,
+            Some(Some(Ok(Message {
+                content: FromServer::Error { .. },
+                ..
+            }))) => return false,
+            Some(Some(Ok(_))) => continue,
+            None => break,
+        }
+    }
+    transport.send_heartbeat().await.is_ok()
+}
+
+async fn maintain(shared: Weak<Shared>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    // the first tick completes immediately
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        match shared.upgrade() {
+            Some(shared) => shared.check_idle().await,
+            None => return,
+        }
+    }
+}
+
+/// A connection leased from a `Pool`, which is returned to the pool when dropped.
+/// A connection which has ended, or has been closed or sent a DISCONNECT, is dropped
+/// instead, freeing its slot for a new connection.
+pub struct PooledConnection {
+    transport: Option<StompTransport<TcpStream>>,
+    shared: Arc<Shared>,
+}
+
+impl PooledConnection {
+    fn new(transport: StompTransport<TcpStream>, shared: &Arc<Shared>) -> Self {
+        PooledConnection {
+            transport: Some(transport),
+            shared: shared.clone(),
+        }
+    }
+}
+
+impl Deref for PooledConnection {
+    type Target = StompTransport<TcpStream>;
+
+    fn deref(&self) -> &Self::Target {
+        self.transport.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledConnection {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.transport.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Some(transport) = self.transport.take() {
+            if transport.is_finished() {
+                drop(transport);
+                self.shared.release_slot();
+            } else {
+                self.shared.release(transport);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::mpsc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Accept connections, completing the handshake and then reading until EOF.
+    /// The first `close` connections are closed right after the handshake instead.
+    /// Yields the number of connections accepted so far as each is accepted.
+    async fn mock_server(close: usize) -> (String, mpsc::UnboundedReceiver<usize>) {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (accepted_tx, accepted) = mpsc::unbounded();
+        tokio::spawn(async move {
+            for n in 0.. {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let _ = accepted_tx.unbounded_send(n + 1);
+                tokio::spawn(async move {
+                    let mut buf = [0; 1024];
+                    let read = socket.read(&mut buf).await.unwrap();
+                    assert!(buf[..read].starts_with(b"CONNECT\n"));
+                    socket
+                        .write_all(b"CONNECTED\nversion:1.2\n\n\x00")
+                        .await
+                        .unwrap();
+                    if n >= close {
+                        while socket.read(&mut buf).await.unwrap_or(0) > 0 {}
+                    }
+                });
+            }
+        });
+        (address, accepted)
+    }
+
+    fn config(min_connections: usize, max_connections: usize) -> PoolConfig {
+        PoolConfig {
+            min_connections,
+            max_connections,
+            health_check_interval: Duration::from_millis(50),
+            idle_timeout: Duration::from_secs(60),
+        }
+    }
+
+    #[tokio::test]
+    async fn acquire_respects_max_connections() {
+        let (address, mut accepted) = mock_server(0).await;
+        let mut config = config(1, 2);
+        // health checks are run by hand below
+        config.health_check_interval = Duration::from_secs(3600);
+        let pool = Pool::connect(address, ConnectOptions::new(), config)
+            .await
+            .unwrap();
+        assert_eq!(accepted.next().await, Some(1));
+
+        let first = pool.acquire().await.unwrap();
+        let second = pool.acquire().await.unwrap();
+        assert_eq!(pool.open_connections(), 2);
+        assert_eq!(accepted.next().await, Some(2));
+
+        let third = pool.acquire();
+        futures::pin_mut!(third);
+        assert!(futures::poll!(third.as_mut()).is_pending());
+        // a waiting `acquire` doesn't hold up the health checks
+        pool.shared.check_idle().await;
+        drop(first);
+        third.await.unwrap();
+        drop(second);
+        assert_eq!(pool.open_connections(), 2);
+        assert!(accepted.next().now_or_never().is_none());
+    }
+
+    #[tokio::test]
+    async fn closed_connections_free_their_slot() {
+        let (address, mut accepted) = mock_server(0).await;
+        let pool = Pool::connect(address, ConnectOptions::new(), config(0, 1))
+            .await
+            .unwrap();
+
+        let mut first = pool.acquire().await.unwrap();
+        assert_eq!(accepted.next().await, Some(1));
+        let second = pool.acquire();
+        futures::pin_mut!(second);
+        assert!(futures::poll!(second.as_mut()).is_pending());
+        first.close().await.unwrap();
+        // the closed connection isn't handed on, but the waiter opens a new one
+        drop(first);
+        second.await.unwrap();
+        assert_eq!(accepted.next().await, Some(2));
+        assert_eq!(pool.open_connections(), 1);
+    }
+
+    #[tokio::test]
+    async fn failed_connect_wakes_a_waiter() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (fail_tx, fail_rx) = oneshot::channel::<()>();
+        tokio::spawn(async move {
+            // the first handshake is held until told to fail
+            let (socket, _) = listener.accept().await.unwrap();
+            let _ = fail_rx.await;
+            drop(socket);
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let read = socket.read(&mut buf).await.unwrap();
+            assert!(buf[..read].starts_with(b"CONNECT\n"));
+            socket
+                .write_all(b"CONNECTED\nversion:1.2\n\n\x00")
+                .await
+                .unwrap();
+            while socket.read(&mut buf).await.unwrap_or(0) > 0 {}
+        });
+        let pool = Pool::connect(address, ConnectOptions::new(), config(0, 1))
+            .await
+            .unwrap();
+
+        let first = pool.acquire();
+        futures::pin_mut!(first);
+        assert!(futures::poll!(first.as_mut()).is_pending());
+        let second = pool.acquire();
+        futures::pin_mut!(second);
+        assert!(futures::poll!(second.as_mut()).is_pending());
+        fail_tx.send(()).unwrap();
+        assert!(first.await.is_err());
+        second.await.unwrap();
+        assert_eq!(pool.open_connections(), 1);
+    }
+
+    #[tokio::test]
+    async fn failed_connections_are_replaced() {
+        let (address, mut accepted) = mock_server(1).await;
+        let pool = Pool::connect(address, ConnectOptions::new(), config(1, 1))
+            .await
+            .unwrap();
+        assert_eq!(accepted.next().await, Some(1));
+        // the first connection is closed by the server, and the health checks replace it
+        assert_eq!(accepted.next().await, Some(2));
+        assert_eq!(pool.open_connections(), 1);
+    }
+}
@@ -0,0 +1,242 @@
+use std::fmt::Write;
+
+use crate::frame::parse_frame;
+use crate::redact::DEFAULT_REDACTED_HEADERS;
+
+/// The number of bytes of a body which `pretty_frame` and `Message::pretty` show.
+/// The rest is left out, with a note of how much was cut.
+pub const PRETTY_BODY_LIMIT: usize = 1024;
+
+/// A rendering of `frame`, a single serialized frame, for people to read, e.g. in an error
+/// report: the command, then the headers with their values aligned, then the body. The
+/// values of `DEFAULT_REDACTED_HEADERS` are masked. A body which is printable UTF-8 is
+/// shown as text, and any other body as a hexdump, cut off after `PRETTY_BODY_LIMIT` bytes.
+///
+/// ```
+/// use tokio_stomp::client::pretty_frame;
+///
+/// let frame = b"SEND\ndestination:/queue/a\npasscode:secret\n\nhello\x00";
+/// assert_eq!(
+///     pretty_frame(frame),
+///     "SEND\n  destination : /queue/a\n  passcode    : ***\nbody (5 bytes):\n  hello"
+/// );
+/// ```
+pub fn pretty_frame(frame: &[u8]) -> String {
+    pretty_frame_redacting(frame, DEFAULT_REDACTED_HEADERS)
+}
+
+/// Like `pretty_frame`, masking the values of `redacted` headers instead
+pub(crate) fn pretty_frame_redacting<H: AsRef<str>>(frame: &[u8], redacted: &[H]) -> String {
+    match parse_frame(frame) {
+        Ok((_, parsed)) => parsed.pretty(redacted),
+        // the frame isn't shown, as credentials can't be masked in it
+        Err(_) => format!("({} bytes which are not a frame)", frame.len()),
+    }
+}
+
+/// Render a frame from its parts, as described by `pretty_frame`
+pub(crate) fn render<'a, H: AsRef<str>>(
+    command: &[u8],
+    headers: impl IntoIterator<Item = (&'a [u8], &'a [u8])>,
+    body: Option<&[u8]>,
+    redacted: &[H],
+) -> String {
+    let headers: Vec<(String, String)> = headers
+        .into_iter()
+        .map(|(key, value)| {
+            let key = escape(key);
+            let masked = redacted
+                .iter()
+                .any(|name| name.as_ref().eq_ignore_ascii_case(&key));
+            let value = if masked { "***".into() } else { escape(value) };
+            (key, value)
+        })
+        .collect();
+    let width = headers
+        .iter()
+        .map(|(key, _)| key.chars().count())
+        .max()
+        .unwrap_or(0);
+
+    let mut out = escape(command);
+    for (key, value) in &headers {
+        write!(out, "\n  {:width$} : {}", key, value, width = width).unwrap();
+    }
+    if let Some(body) = body.filter(|body| !body.is_empty()) {
+        write!(out, "\nbody ({} bytes):\n", body.len()).unwrap();
+        out.push_str(&format_body(body, PRETTY_BODY_LIMIT));
+    }
+    out
+}
+
+/// A command or header as text, with control characters escaped
+fn escape(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len());
+    for c in String::from_utf8_lossy(bytes).chars() {
+        if c.is_control() {
+            out.extend(c.escape_default());
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// The first `limit` bytes of `body`, indented, as text if it is printable UTF-8 and as a
+/// hexdump otherwise. Text is cut at the last character boundary within the limit.
+fn format_body(body: &[u8], limit: usize) -> String {
+    let (mut out, shown) = match std::str::from_utf8(body) {
+        Ok(text) if is_printable(text) => {
+            let mut end = limit.min(text.len());
+            while !text.is_char_boundary(end) {
+                end -= 1;
+            }
+            let lines: Vec<_> = text[..end]
+                .lines()
+                .map(|line| format!("  {}", line))
+                .collect();
+            (lines.join("\n"), end)
+        }
+        _ => {
+            let end = limit.min(body.len());
+            (hexdump(&body[..end]), end)
+        }
+    };
+    if shown < body.len() {
+        write!(out, "\n  ... ({} more bytes)", body.len() - shown).unwrap();
+    }
+    out
+}
+
+fn is_printable(text: &str) -> bool {
+    text.chars()
+        .all(|c| !c.is_control() || c == '\n' || c == '\r' || c == '\t')
+}
+
+/// 16 bytes per line: their offset, their values in hex and the printable ASCII ones
+fn hexdump(bytes: &[u8]) -> String {
+    let lines: Vec<_> = bytes
+        .chunks(16)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let mut line = format!("  {:08x} ", i * 16);
+            for (j, byte) in chunk.iter().enumerate() {
+                if j == 8 {
+                    line.push(' ');
+                }
+                write!(line, " {:02x}", byte).unwrap();
+            }
+            // pad short lines so the ASCII column lines up
+            let missing = 16 - chunk.len();
+            line.push_str(&" ".repeat(missing * 3 + if chunk.len() <= 8 { 1 } else { 0 }));
+            let ascii: String = chunk
+                .iter()
+                .map(|&b| {
+                    if b.is_ascii_graphic() || b == b' ' {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            write!(line, "  |{}|", ascii).unwrap();
+            line
+        })
+        .collect();
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aligns_headers_and_masks_credentials() {
+        let frame = b"CONNECT\naccept-version:1.2\nlogin:user\npasscode:secret\n\n\x00";
+        assert_eq!(
+            pretty_frame(frame),
+            "CONNECT\n  accept-version : 1.2\n  login          : ***\n  passcode       : ***"
+        );
+        assert_eq!(
+            pretty_frame_redacting(frame, &["passcode"]),
+            "CONNECT\n  accept-version : 1.2\n  login          : user\n  passcode       : ***"
+        );
+        assert_eq!(
+            pretty_frame(b"not a frame"),
+            "(11 bytes which are not a frame)"
+        );
+    }
+
+    #[test]
+    fn messages() {
+        use crate::{FromServer, Message, ToServer};
+
+        let connect = Message::new(ToServer::Connect {
+            accept_version: "1.2".into(),
+            host: Some("/".into()),
+            login: Some("user".into()),
+            passcode: Some("secret".into()),
+            heartbeat: None,
+        });
+        assert_eq!(
+            connect.pretty(),
+            "CONNECT\n  accept-version : 1.2\n  host           : /\n  \
+             login          : ***\n  passcode       : ***"
+        );
+
+        let message = Message {
+            content: FromServer::Message {
+                destination: "/queue/a".into(),
+                message_id: "1".into(),
+                subscription: "s".into(),
+                body: Some(vec![0xff, 0x00]),
+            },
+            extra_headers: vec![(b"token".to_vec(), b"abc".to_vec())],
+        };
+        let pretty = message.pretty();
+        let (head, body) = pretty.split_at(pretty.find("\nbody").unwrap());
+        assert_eq!(
+            head,
+            "MESSAGE\n  destination  : /queue/a\n  message-id   : 1\n  subscription : s\n  \
+             token        : ***"
+        );
+        let padding = " ".repeat(14 * 3 + 1);
+        assert_eq!(
+            body,
+            format!("\nbody (2 bytes):\n  00000000  ff 00{}  |..|", padding)
+        );
+    }
+
+    #[test]
+    fn text_bodies() {
+        assert_eq!(format_body(b"one\ntwo", 100), "  one\n  two");
+        assert_eq!(
+            format_body(b"hello world", 5),
+            "  hello\n  ... (6 more bytes)"
+        );
+
+        // "é" is two bytes, the first of which is the 5th byte of the body
+        let body = "abcdéf".as_bytes();
+        assert_eq!(format_body(body, 5), "  abcd\n  ... (3 more bytes)");
+        assert_eq!(format_body(body, 6), "  abcdé\n  ... (1 more bytes)");
+        // a limit which lands inside the first character shows no text
+        assert_eq!(format_body("€".as_bytes(), 2), "\n  ... (3 more bytes)");
+    }
+
+    #[test]
+    fn binary_bodies() {
+        let body: Vec<u8> = (0..20).collect();
+        assert_eq!(
+            format_body(&body, 100),
+            "  00000000  00 01 02 03 04 05 06 07  08 09 0a 0b 0c 0d 0e 0f  |................|\n  \
+             00000010  10 11 12 13                                       |....|"
+        );
+        assert_eq!(
+            format_body(b"ab\x00cd", 3),
+            "  00000000  61 62 00                                          |ab.|\n  \
+             ... (2 more bytes)"
+        );
+        // invalid UTF-8 is shown as hex, even if it is mostly text
+        assert!(format_body(b"caf\xe9", 100).contains("63 61 66 e9"));
+    }
+}
@@ -0,0 +1,110 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+/// Whether the outgoing queue is above its high watermark,
+/// as reported by `StompTransport::watch_queue`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueLevel {
+    /// The queue has drained to the low watermark or below, or has not yet reached the high one
+    LowWater,
+    /// The queue has reached the high watermark, and not since drained to the low one
+    HighWater,
+}
+
+/// A handle to the depth of a transport's outgoing queue,
+/// which can be read from other tasks. See `StompTransport::queue_depth_handle`.
+#[derive(Debug, Clone, Default)]
+pub struct QueueDepth(Arc<AtomicUsize>);
+
+impl QueueDepth {
+    /// The number of frames handed to the transport which are yet to be flushed
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug)]
+struct Watermarks {
+    low: usize,
+    high: usize,
+    level: QueueLevel,
+    sender: watch::Sender<QueueLevel>,
+}
+
+/// Frames handed to the writer which are not yet known to have been flushed.
+/// The writer's buffer can't be inspected, so frames only leave the queue,
+/// all at once, when a flush completes.
+#[derive(Debug, Default)]
+pub(crate) struct OutgoingQueue {
+    depth: QueueDepth,
+    oldest: Option<Instant>,
+    watermarks: Option<Watermarks>,
+}
+
+impl OutgoingQueue {
+    pub(crate) fn depth(&self) -> &QueueDepth {
+        &self.depth
+    }
+
+    /// When the oldest frame still waiting to be flushed was queued
+    pub(crate) fn oldest(&self) -> Option<Instant> {
+        self.oldest
+    }
+
+    pub(crate) fn oldest_age(&self) -> Option<Duration> {
+        self.oldest.map(|queued| queued.elapsed())
+    }
+
+    pub(crate) fn push(&mut self) {
+        let depth = self.depth.0.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.oldest.is_none() {
+            self.oldest = Some(Instant::now());
+        }
+        self.update(depth);
+    }
+
+    pub(crate) fn flushed(&mut self) {
+        if self.oldest.take().is_some() {
+            self.depth.0.store(0, Ordering::Relaxed);
+            self.update(0);
+        }
+    }
+
+    pub(crate) fn watch(&mut self, low: usize, high: usize) -> watch::Receiver<QueueLevel> {
+        assert!(low < high, "the low watermark must be below the high one");
+        let level = if self.depth.get() >= high {
+            QueueLevel::HighWater
+        } else {
+            QueueLevel::LowWater
+        };
+        let (sender, receiver) = watch::channel(level);
+        self.watermarks = Some(Watermarks {
+            low,
+            high,
+            level,
+            sender,
+        });
+        receiver
+    }
+
+    fn update(&mut self, depth: usize) {
+        let watermarks = match &mut self.watermarks {
+            Some(watermarks) => watermarks,
+            None => return,
+        };
+        let level = match watermarks.level {
+            QueueLevel::LowWater if depth >= watermarks.high => QueueLevel::HighWater,
+            QueueLevel::HighWater if depth <= watermarks.low => QueueLevel::LowWater,
+            _ => return,
+        };
+        watermarks.level = level;
+        if watermarks.sender.broadcast(level).is_err() {
+            // every receiver has been dropped
+            self.watermarks = None;
+        }
+    }
+}
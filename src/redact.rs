@@ -0,0 +1,57 @@
+use std::fmt::{self, Debug};
+use std::sync::Arc;
+
+use crate::pretty::pretty_frame_redacting;
+
+/// The headers whose values are masked by default in frames passed to
+/// a `ConnectOptions::log_frames` logger. Names are compared case-insensitively.
+pub const DEFAULT_REDACTED_HEADERS: &[&str] = &[
+    "login",
+    "passcode",
+    "authorization",
+    "proxy-authorization",
+    "token",
+];
+
+/// Which way a frame passed to a `ConnectOptions::log_frames` logger was travelling
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameDirection {
+    Sent,
+    Received,
+}
+
+type LogCallback = Arc<dyn Fn(FrameDirection, &str) + Send + Sync>;
+
+/// Describes frames to a logging callback, masking credentials
+#[derive(Clone)]
+pub(crate) struct FrameLogger {
+    callback: LogCallback,
+    redacted: Arc<Vec<String>>,
+}
+
+impl FrameLogger {
+    pub(crate) fn new(callback: impl Fn(FrameDirection, &str) + Send + Sync + 'static) -> Self {
+        let redacted = DEFAULT_REDACTED_HEADERS.iter().map(|h| h.to_string());
+        FrameLogger {
+            callback: Arc::new(callback),
+            redacted: Arc::new(redacted.collect()),
+        }
+    }
+
+    pub(crate) fn redact(&mut self, headers: Vec<String>) {
+        self.redacted = Arc::new(headers);
+    }
+
+    /// Pass a description of `frame`, a single serialized frame, to the callback
+    pub(crate) fn log(&self, direction: FrameDirection, frame: &[u8]) {
+        (self.callback)(direction, &pretty_frame_redacting(frame, &self.redacted));
+    }
+}
+
+impl Debug for FrameLogger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FrameLogger")
+            .field("redacted", &self.redacted)
+            .finish()
+    }
+}
@@ -0,0 +1,316 @@
+use std::convert::TryInto;
+use std::io;
+use std::path::Path;
+
+use bytes::{BufMut, BytesMut};
+use failure::bail;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+use crate::{FromServer, Message, Result};
+
+const RECEIVED: u8 = b'M';
+const PROCESSED: u8 = b'P';
+
+/// Persists received messages until they have been processed, so that messages which were
+/// acknowledged but not yet processed when the application crashed can be replayed
+/// after it restarts.
+///
+/// `record` a message before acknowledging it, and `mark_processed` once done with it.
+/// On startup, handle the messages returned by `replay` before subscribing again.
+/// Entries are appended to the file, which is never rewritten.
+#[derive(Debug)]
+pub struct SelectiveReplay {
+    file: File,
+    unprocessed: Vec<Message<FromServer>>,
+}
+
+enum Entry {
+    Received(Message<FromServer>),
+    Processed(String),
+}
+
+impl SelectiveReplay {
+    /// Open the log at `path`, creating it if it does not exist, and load
+    /// the messages which were recorded but never marked processed
+    pub async fn open(path: impl AsRef<Path>) -> Result<SelectiveReplay> {
+        let path = path.as_ref();
+        let data = match fs::read(path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e.into()),
+        };
+        let mut unprocessed = vec![];
+        let mut input = &data[..];
+        while !input.is_empty() {
+            let offset = data.len() - input.len();
+            match decode_entry(&mut input) {
+                Ok(Some(Entry::Received(msg))) => unprocessed.push(msg),
+                Ok(Some(Entry::Processed(id))) => unprocessed.retain(|msg| message_id(msg) != id),
+                Ok(None) => break,
+                Err(tag) => bail!(
+                    "unknown entry tag {:#04x} at offset {} of {}",
+                    tag,
+                    offset,
+                    path.display()
+                ),
+            }
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        if !input.is_empty() {
+            // the last entry was cut short, e.g. by a crash while it was being written
+            log::warn!(
+                "discarding {} bytes of incomplete entry from {}",
+                input.len(),
+                path.display()
+            );
+            file.set_len((data.len() - input.len()) as u64).await?;
+        }
+        Ok(SelectiveReplay { file, unprocessed })
+    }
+
+    /// Take the messages which were recorded but not marked processed when the log
+    /// was opened, oldest first. They stay in the log until marked processed.
+    pub fn replay(&mut self) -> Vec<Message<FromServer>> {
+        std::mem::take(&mut self.unprocessed)
+    }
+
+    /// Durably record `msg`, which must be a MESSAGE frame, before it is acknowledged
+    pub async fn record(&mut self, msg: &Message<FromServer>) -> Result<()> {
+        let mut buf = BytesMut::new();
+        match &msg.content {
+            FromServer::Message {
+                destination,
+                message_id,
+                subscription,
+                body,
+            } => {
+                buf.put_u8(RECEIVED);
+                put_field(&mut buf, destination.as_bytes());
+                put_field(&mut buf, message_id.as_bytes());
+                put_field(&mut buf, subscription.as_bytes());
+                match body {
+                    Some(body) => {
+                        buf.put_u8(1);
+                        put_field(&mut buf, body);
+                    }
+                    None => buf.put_u8(0),
+                }
+                buf.put_u32(msg.extra_headers.len() as u32);
+                for (key, value) in &msg.extra_headers {
+                    put_field(&mut buf, key);
+                    put_field(&mut buf, value);
+                }
+            }
+            other => bail!("only MESSAGE frames can be recorded, not {:?}", other),
+        }
+        self.append(&buf).await
+    }
+
+    /// Remove the message with the given `message-id` from the log
+    pub async fn mark_processed(&mut self, message_id: &str) -> Result<()> {
+        let mut buf = BytesMut::new();
+        buf.put_u8(PROCESSED);
+        put_field(&mut buf, message_id.as_bytes());
+        self.append(&buf).await
+    }
+
+    async fn append(&mut self, entry: &[u8]) -> Result<()> {
+        self.file.write_all(entry).await?;
+        self.file.sync_data().await?;
+        Ok(())
+    }
+}
+
+fn message_id(msg: &Message<FromServer>) -> &str {
+    match &msg.content {
+        FromServer::Message { message_id, .. } => message_id,
+        _ => "",
+    }
+}
+
+fn put_field(buf: &mut BytesMut, field: &[u8]) {
+    buf.put_u32(field.len() as u32);
+    buf.put_slice(field);
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if input.len() < len {
+        return None;
+    }
+    let (taken, rest) = input.split_at(len);
+    *input = rest;
+    Some(taken)
+}
+
+fn take_u32(input: &mut &[u8]) -> Option<u32> {
+    Some(u32::from_be_bytes(take(input, 4)?.try_into().ok()?))
+}
+
+fn take_field(input: &mut &[u8]) -> Option<Vec<u8>> {
+    let len = take_u32(input)? as usize;
+    take(input, len).map(<[u8]>::to_vec)
+}
+
+fn take_string(input: &mut &[u8]) -> Option<String> {
+    String::from_utf8(take_field(input)?).ok()
+}
+
+/// Decode the entry at the start of `input`, advancing past it.
+/// Leaves `input` untouched if it does not start with a whole entry,
+/// and fails with the tag if the entry starts with an unknown one.
+fn decode_entry(input: &mut &[u8]) -> std::result::Result<Option<Entry>, u8> {
+    let mut rest = *input;
+    let entry = match take(&mut rest, 1).map(|tag| tag[0]) {
+        Some(RECEIVED) => decode_received(&mut rest).map(Entry::Received),
+        Some(PROCESSED) => take_string(&mut rest).map(Entry::Processed),
+        Some(tag) => return Err(tag),
+        None => None,
+    };
+    if entry.is_some() {
+        *input = rest;
+    }
+    Ok(entry)
+}
+
+fn decode_received(rest: &mut &[u8]) -> Option<Message<FromServer>> {
+    let destination = take_string(rest)?;
+    let message_id = take_string(rest)?;
+    let subscription = take_string(rest)?;
+    let body = match take(rest, 1)?[0] {
+        0 => None,
+        _ => Some(take_field(rest)?),
+    };
+    let extra_headers = (0..take_u32(rest)?)
+        .map(|_| Some((take_field(rest)?, take_field(rest)?)))
+        .collect::<Option<_>>()?;
+    Some(Message {
+        content: FromServer::Message {
+            destination,
+            message_id,
+            subscription,
+            body,
+        },
+        extra_headers,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: &str, body: Option<&[u8]>) -> Message<FromServer> {
+        Message {
+            content: FromServer::Message {
+                destination: "/queue/a".into(),
+                message_id: id.into(),
+                subscription: "1".into(),
+                body: body.map(<[u8]>::to_vec),
+            },
+            extra_headers: vec![(b"ack".to_vec(), format!("ack-{}", id).into_bytes())],
+        }
+    }
+
+    fn ids(messages: &[Message<FromServer>]) -> Vec<&str> {
+        messages.iter().map(message_id).collect()
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        let name = format!("{}-{}", name, crate::id::uuid_v4());
+        std::env::temp_dir().join(name)
+    }
+
+    #[tokio::test]
+    async fn replays_unprocessed_messages() {
+        let path = temp_path("replay");
+        let mut replay = SelectiveReplay::open(&path).await.unwrap();
+        assert!(replay.replay().is_empty());
+        replay
+            .record(&message("ID:a-1", Some(b"one")))
+            .await
+            .unwrap();
+        replay.record(&message("ID:a-2", None)).await.unwrap();
+        replay
+            .record(&message("ID:a-3", Some(b"\0\n")))
+            .await
+            .unwrap();
+        replay.mark_processed("ID:a-2").await.unwrap();
+        drop(replay);
+
+        let mut replay = SelectiveReplay::open(&path).await.unwrap();
+        let messages = replay.replay();
+        assert_eq!(ids(&messages), ["ID:a-1", "ID:a-3"]);
+        match &messages[1].content {
+            FromServer::Message { body, .. } => assert_eq!(body.as_deref(), Some(&b"\0\n"[..])),
+            _ => unreachable!(),
+        }
+        assert_eq!(
+            messages[1].extra_headers,
+            message("ID:a-3", None).extra_headers
+        );
+        assert!(replay.replay().is_empty());
+
+        replay.mark_processed("ID:a-1").await.unwrap();
+        drop(replay);
+        let mut replay = SelectiveReplay::open(&path).await.unwrap();
+        assert_eq!(ids(&replay.replay()), ["ID:a-3"]);
+        fs::remove_file(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn incomplete_entry_is_discarded() {
+        let path = temp_path("replay-truncated");
+        let mut replay = SelectiveReplay::open(&path).await.unwrap();
+        replay.record(&message("1", Some(b"one"))).await.unwrap();
+        replay.record(&message("2", Some(b"two"))).await.unwrap();
+        drop(replay);
+        let data = fs::read(&path).await.unwrap();
+        fs::write(&path, &data[..data.len() - 2]).await.unwrap();
+
+        let mut replay = SelectiveReplay::open(&path).await.unwrap();
+        assert_eq!(ids(&replay.replay()), ["1"]);
+        replay.record(&message("3", None)).await.unwrap();
+        drop(replay);
+
+        let mut replay = SelectiveReplay::open(&path).await.unwrap();
+        assert_eq!(ids(&replay.replay()), ["1", "3"]);
+        fs::remove_file(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn unknown_entry_is_an_error() {
+        let path = temp_path("replay-unknown");
+        let mut replay = SelectiveReplay::open(&path).await.unwrap();
+        replay.record(&message("1", Some(b"one"))).await.unwrap();
+        drop(replay);
+        let mut data = fs::read(&path).await.unwrap();
+        let offset = data.len();
+        data.extend_from_slice(b"X\0\0\0\0");
+        fs::write(&path, &data).await.unwrap();
+
+        let err = SelectiveReplay::open(&path).await.unwrap_err().to_string();
+        assert!(err.contains("0x58"), "{}", err);
+        assert!(err.contains(&format!("offset {}", offset)), "{}", err);
+        // the log is left as it was, rather than truncated
+        assert_eq!(fs::read(&path).await.unwrap(), data);
+        fs::remove_file(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn only_messages_are_recorded() {
+        let path = temp_path("replay-receipt");
+        let mut replay = SelectiveReplay::open(&path).await.unwrap();
+        let receipt = Message {
+            content: FromServer::Receipt {
+                receipt_id: "1".into(),
+            },
+            extra_headers: vec![],
+        };
+        assert!(replay.record(&receipt).await.is_err());
+        fs::remove_file(&path).await.unwrap();
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use futures::prelude::*;
+use futures::task::{waker_ref, ArcWake};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::client::StompTransport;
+use crate::{FromServer, Message, Result, ToServer};
+
+/// A handle to a `StompTransport` shared between several tasks, created with
+/// `ArcTransport::new` and duplicated with `clone`.
+///
+/// Every handle is a `Sink`, sending through the one connection, and a `Stream` which
+/// yields every frame received after the handle was created, including those read through
+/// other handles. Frames are queued for each handle until it reads them, so a handle whose
+/// stream is not polled should be dropped, or the frames will pile up. Errors received
+/// through another handle are yielded as an error with the same message.
+pub struct ArcTransport<S> {
+    shared: Arc<Shared<S>>,
+    id: usize,
+}
+
+struct Shared<S> {
+    state: Mutex<State<S>>,
+    /// Wakes every handle waiting to read when the connection can be read from
+    readers: Arc<WakeAll>,
+    /// Wakes every handle waiting to send when the connection can be written to
+    writers: Arc<WakeAll>,
+}
+
+struct State<S> {
+    transport: StompTransport<S>,
+    /// The frames read through other handles, yet to be yielded from each handle
+    queues: HashMap<usize, VecDeque<Result<Message<FromServer>>>>,
+    next_id: usize,
+    ended: bool,
+}
+
+/// Wakes every task registered since the last wake-up
+#[derive(Default)]
+struct WakeAll {
+    wakers: Mutex<HashMap<usize, Waker>>,
+}
+
+impl WakeAll {
+    fn register(&self, id: usize, waker: &Waker) {
+        self.wakers.lock().unwrap().insert(id, waker.clone());
+    }
+}
+
+impl ArcWake for WakeAll {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        let wakers: Vec<_> = arc_self.wakers.lock().unwrap().drain().collect();
+        for (_, waker) in wakers {
+            waker.wake();
+        }
+    }
+}
+
+impl<S> ArcTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Share `transport` between two handles. More can be made with `clone`.
+    pub fn new(transport: StompTransport<S>) -> (ArcTransport<S>, ArcTransport<S>) {
+        let mut queues = HashMap::new();
+        queues.insert(0, VecDeque::new());
+        let state = State {
+            transport,
+            queues,
+            next_id: 1,
+            ended: false,
+        };
+        let shared = Arc::new(Shared {
+            state: Mutex::new(state),
+            readers: Arc::new(WakeAll::default()),
+            writers: Arc::new(WakeAll::default()),
+        });
+        let first = ArcTransport { shared, id: 0 };
+        let second = first.clone();
+        (first, second)
+    }
+
+    /// Call `f` with the shared transport, e.g. to build an ACK with `ack_message`.
+    /// Other handles wait until `f` returns, so it must not block.
+    pub fn with_transport<T>(&self, f: impl FnOnce(&mut StompTransport<S>) -> T) -> T {
+        f(&mut self.shared.state.lock().unwrap().transport)
+    }
+
+    /// Poll a sending operation on the transport
+    fn poll_send(
+        &self,
+        cx: &mut Context<'_>,
+        op: impl FnOnce(&mut StompTransport<S>, &mut Context<'_>) -> Poll<Result<()>>,
+    ) -> Poll<Result<()>> {
+        self.shared.writers.register(self.id, cx.waker());
+        let waker = waker_ref(&self.shared.writers);
+        let mut state = self.shared.state.lock().unwrap();
+        op(&mut state.transport, &mut Context::from_waker(&waker))
+    }
+}
+
+impl<S> Clone for ArcTransport<S> {
+    /// Make another handle, which yields the frames received from now on
+    fn clone(&self) -> Self {
+        let mut state = self.shared.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.queues.insert(id, VecDeque::new());
+        ArcTransport {
+            shared: self.shared.clone(),
+            id,
+        }
+    }
+}
+
+impl<S> Drop for ArcTransport<S> {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.shared.state.lock() {
+            state.queues.remove(&self.id);
+        }
+        self.shared.readers.wakers.lock().unwrap().remove(&self.id);
+        self.shared.writers.wakers.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl<S> Stream for ArcTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    type Item = Result<Message<FromServer>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        this.shared.readers.register(this.id, cx.waker());
+        let mut state = this.shared.state.lock().unwrap();
+        if let Some(item) = state.queues.get_mut(&this.id).and_then(VecDeque::pop_front) {
+            return Poll::Ready(Some(item));
+        }
+        if state.ended {
+            return Poll::Ready(None);
+        }
+        let waker = waker_ref(&this.shared.readers);
+        let item = futures::ready!(state
+            .transport
+            .poll_next_unpin(&mut Context::from_waker(&waker)));
+        let id = this.id;
+        for (_, queue) in state.queues.iter_mut().filter(|(other, _)| **other != id) {
+            match &item {
+                Some(Ok(msg)) => queue.push_back(Ok(msg.clone())),
+                Some(Err(e)) => queue.push_back(Err(failure::format_err!("{}", e))),
+                None => {}
+            }
+        }
+        if item.is_none() {
+            state.ended = true;
+        }
+        drop(state);
+        ArcWake::wake_by_ref(&this.shared.readers);
+        Poll::Ready(item)
+    }
+}
+
+impl<S> Sink<Message<ToServer>> for ArcTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    type Error = failure::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.poll_send(cx, |transport, cx| transport.poll_ready_unpin(cx))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message<ToServer>) -> Result<()> {
+        self.with_transport(|transport| transport.start_send_unpin(item))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.poll_send(cx, |transport, cx| transport.poll_flush_unpin(cx))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.poll_send(cx, |transport, cx| transport.poll_close_unpin(cx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{send_text, subscribe};
+    use crate::test_utils::StompRouter;
+
+    async fn next_body<S>(handle: &mut ArcTransport<S>) -> String
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let msg = handle.next().await.unwrap().unwrap();
+        msg.body_as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn every_handle_receives_every_frame() {
+        let router = StompRouter::start().await.unwrap();
+        let (mut a, mut b) = ArcTransport::new(router.connect().await.unwrap());
+        a.send(subscribe("/queue/a", "1").receipt("r"))
+            .await
+            .unwrap();
+        for handle in &mut [&mut a, &mut b] {
+            assert!(matches!(
+                handle.next().await.unwrap().unwrap().content,
+                FromServer::Receipt { .. }
+            ));
+        }
+
+        // b waits for a message while a, on another task, sends one
+        let sending = tokio::spawn(async move {
+            a.send(send_text("/queue/a", "from a")).await.unwrap();
+            a
+        });
+        assert_eq!(next_body(&mut b).await, "from a");
+        let mut a = sending.await.unwrap();
+        assert_eq!(next_body(&mut a).await, "from a");
+
+        let mut c = b.clone();
+        b.send(send_text("/queue/a", "from b")).await.unwrap();
+        for handle in &mut [&mut a, &mut b, &mut c] {
+            assert_eq!(next_body(handle).await, "from b");
+        }
+
+        drop(b);
+        let mut publisher = router.connect().await.unwrap();
+        publisher
+            .send(send_text("/queue/a", "from publisher"))
+            .await
+            .unwrap();
+        assert_eq!(next_body(&mut c).await, "from publisher");
+        assert_eq!(next_body(&mut a).await, "from publisher");
+        assert_eq!(a.with_transport(|transport| transport.queue_depth()), 0);
+    }
+}
@@ -0,0 +1,408 @@
+//! Helpers for testing code which uses this crate without a real broker
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use bytes::{Buf, BytesMut};
+use failure::bail;
+use futures::channel::mpsc;
+use futures::future::{self, AbortHandle};
+use futures::prelude::*;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::client::{self, StompTransport};
+use crate::frame::{parse_frame, Frame};
+use crate::{Message, Result, ToServer};
+
+/// A minimal STOMP broker listening on localhost, for testing clients.
+///
+/// Replies to CONNECT with CONNECTED, routes each SEND to every subscriber of its destination,
+/// and sends a RECEIPT for every frame which requests one. Acknowledgments and transactions
+/// are accepted but have no effect. Each connection is served by its own task, so `start`
+/// must be called within a Tokio runtime. The router stops accepting connections when dropped.
+#[derive(Debug)]
+pub struct StompRouter {
+    address: String,
+    routes: Arc<Mutex<Routes>>,
+    accepting: AbortHandle,
+}
+
+#[derive(Debug, Default)]
+struct Routes {
+    /// The frames to be written to each connection
+    connections: HashMap<usize, mpsc::UnboundedSender<BytesMut>>,
+    /// The connection and subscription id of every subscriber to each destination
+    subscribers: HashMap<String, Vec<(usize, String)>>,
+    /// The frames received from each connection, including ones since closed
+    received: Vec<Vec<Message<ToServer>>>,
+    next_connection: usize,
+    next_message: u64,
+    /// Set by `StompRouter::stop_reading`
+    reading_stopped: bool,
+    /// Set by `StompRouter::withhold_receipts`
+    receipts_withheld: bool,
+    /// Set by `StompRouter::connected_heartbeat`
+    connected_heartbeat: Option<String>,
+}
+
+impl StompRouter {
+    /// Start listening on a free port
+    pub async fn start() -> Result<StompRouter> {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?.to_string();
+        let routes = Arc::new(Mutex::new(Routes::default()));
+        let accept_routes = routes.clone();
+        let (accept, accepting) = future::abortable(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        log::warn!("router failed to accept a connection: {}", e);
+                        continue;
+                    }
+                };
+                let routes = accept_routes.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serve(stream, routes).await {
+                        log::warn!("router connection failed: {}", e);
+                    }
+                });
+            }
+        });
+        tokio::spawn(accept);
+        Ok(StompRouter {
+            address,
+            routes,
+            accepting,
+        })
+    }
+
+    /// The address to connect to, e.g. `127.0.0.1:61613`
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Connect a new client to the router
+    pub async fn connect(&self) -> Result<StompTransport<TcpStream>> {
+        client::connect(self.address.clone(), None, None).await
+    }
+
+    /// The number of open subscriptions to `destination`
+    pub fn subscribers(&self, destination: &str) -> usize {
+        let routes = self.routes.lock().unwrap();
+        routes.subscribers.get(destination).map_or(0, Vec::len)
+    }
+
+    /// The frames received so far from the `connection`th client to connect, counting from 0,
+    /// starting with its CONNECT
+    pub fn received(&self, connection: usize) -> Vec<Message<ToServer>> {
+        let routes = self.routes.lock().unwrap();
+        routes.received.get(connection).cloned().unwrap_or_default()
+    }
+
+    /// Stop reading from every connection, while keeping them open, e.g. to test how a client
+    /// copes with a broker which stalls. Frames already on their way may still be handled.
+    pub fn stop_reading(&self) {
+        self.routes.lock().unwrap().reading_stopped = true;
+    }
+
+    /// Stop sending, or resume sending, the RECEIPTs which frames request, e.g. to test how a
+    /// client copes with a broker which is slow to confirm them
+    pub fn withhold_receipts(&self, withhold: bool) {
+        self.routes.lock().unwrap().receipts_withheld = withhold;
+    }
+
+    /// The `heart-beat` header of the CONNECTED frames sent from now on, e.g. `0,0` to
+    /// decline heart-beats. By default there is none.
+    pub fn connected_heartbeat(&self, header: impl Into<String>) {
+        self.routes.lock().unwrap().connected_heartbeat = Some(header.into());
+    }
+
+    /// Send a heart-beat (a lone EOL) to every connected client
+    pub fn send_heartbeat(&self) {
+        let routes = self.routes.lock().unwrap();
+        for &connection in routes.connections.keys() {
+            routes.send(connection, BytesMut::from(&b"\n"[..]));
+        }
+    }
+}
+
+impl Drop for StompRouter {
+    fn drop(&mut self) {
+        self.accepting.abort();
+    }
+}
+
+impl Routes {
+    fn add_connection(&mut self, frames: mpsc::UnboundedSender<BytesMut>) -> usize {
+        let connection = self.next_connection;
+        self.next_connection += 1;
+        self.connections.insert(connection, frames);
+        self.received.push(vec![]);
+        connection
+    }
+
+    fn remove_connection(&mut self, connection: usize) {
+        self.connections.remove(&connection);
+        for subscribers in self.subscribers.values_mut() {
+            subscribers.retain(|(other, _)| *other != connection);
+        }
+    }
+
+    fn send(&self, connection: usize, frame: BytesMut) {
+        if let Some(frames) = self.connections.get(&connection) {
+            // the connection's writer may already have stopped, in which case it is closing
+            let _ = frames.unbounded_send(frame);
+        }
+    }
+
+    /// Act on a frame received from `connection`.
+    /// Returns `false` if the connection should be closed.
+    fn handle(&mut self, connection: usize, msg: Message<ToServer>) -> bool {
+        self.received[connection].push(msg.clone());
+        let mut receipt = header(&msg, b"receipt").map(<[u8]>::to_vec);
+        let mut open = true;
+        match &msg.content {
+            ToServer::Connect { .. } => {
+                let mut headers: Vec<(&[u8], &[u8])> = vec![(b"version", b"1.2")];
+                if let Some(heartbeat) = &self.connected_heartbeat {
+                    headers.push((b"heart-beat", heartbeat.as_bytes()));
+                }
+                self.send(connection, encode(b"CONNECTED", &headers, &[], None))
+            }
+            ToServer::Send {
+                destination, body, ..
+            } => self.route(destination, body.as_deref(), &msg.extra_headers),
+            ToServer::Subscribe {
+                destination, id, ..
+            } => self
+                .subscribers
+                .entry(destination.clone())
+                .or_default()
+                .push((connection, id.clone())),
+            ToServer::Unsubscribe { id } => {
+                for subscribers in self.subscribers.values_mut() {
+                    subscribers.retain(|(other, other_id)| *other != connection || other_id != id);
+                }
+            }
+            ToServer::Disconnect { receipt: id, .. } => {
+                receipt = id.as_ref().map(|id| id.clone().into_bytes());
+                open = false;
+            }
+            _ => {}
+        }
+        if let Some(receipt) = receipt.filter(|_| !self.receipts_withheld) {
+            let frame = encode(b"RECEIPT", &[(b"receipt-id", &receipt)], &[], None);
+            self.send(connection, frame);
+        }
+        open
+    }
+
+    /// Deliver a MESSAGE to every subscriber to `destination`,
+    /// passing on the SEND's extra headers
+    fn route(&mut self, destination: &str, body: Option<&[u8]>, headers: &[(Vec<u8>, Vec<u8>)]) {
+        let headers: Vec<_> = headers
+            .iter()
+            .filter(|(key, _)| key != b"receipt" && key != b"content-length")
+            .cloned()
+            .collect();
+        let subscribers = match self.subscribers.get(destination) {
+            Some(subscribers) => subscribers,
+            None => return,
+        };
+        for (connection, subscription) in subscribers {
+            let message_id = self.next_message.to_string();
+            self.next_message += 1;
+            let frame = encode(
+                b"MESSAGE",
+                &[
+                    (b"destination", destination.as_bytes()),
+                    (b"message-id", message_id.as_bytes()),
+                    (b"subscription", subscription.as_bytes()),
+                ],
+                &headers,
+                body,
+            );
+            self.send(*connection, frame);
+        }
+    }
+}
+
+fn header<'a>(msg: &'a Message<ToServer>, key: &[u8]) -> Option<&'a [u8]> {
+    msg.extra_headers
+        .iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| &v[..])
+}
+
+fn encode(
+    command: &[u8],
+    headers: &[(&[u8], &[u8])],
+    extra_headers: &[(Vec<u8>, Vec<u8>)],
+    body: Option<&[u8]>,
+) -> BytesMut {
+    let headers: Vec<_> = headers
+        .iter()
+        .map(|&(key, value)| (key, Some(Cow::Borrowed(value))))
+        .collect();
+    let mut frame = Frame::new(command, &headers, body);
+    frame.extend_headers(extra_headers);
+    let mut buffer = BytesMut::new();
+    frame.serialize(&mut buffer);
+    buffer
+}
+
+/// Serve one client until it disconnects
+async fn serve(stream: TcpStream, routes: Arc<Mutex<Routes>>) -> Result<()> {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let (frames, mut outgoing) = mpsc::unbounded::<BytesMut>();
+    let connection = routes.lock().unwrap().add_connection(frames);
+    tokio::spawn(async move {
+        while let Some(frame) = outgoing.next().await {
+            if writer.write_all(&frame).await.is_err() {
+                break;
+            }
+        }
+    });
+    let result = read_frames(&mut reader, connection, &routes).await;
+    // dropping the connection's sender lets the writer finish, closing the connection
+    routes.lock().unwrap().remove_connection(connection);
+    result
+}
+
+async fn read_frames<R>(reader: &mut R, connection: usize, routes: &Mutex<Routes>) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buffer = BytesMut::with_capacity(4096);
+    loop {
+        while let Some(msg) = next_frame(&mut buffer)? {
+            if !routes.lock().unwrap().handle(connection, msg) {
+                return Ok(());
+            }
+        }
+        if routes.lock().unwrap().reading_stopped {
+            return future::pending().await;
+        }
+        buffer.reserve(4096);
+        if reader.read_buf(&mut buffer).await? == 0 {
+            return Ok(());
+        }
+    }
+}
+
+/// Remove the next complete frame from `buffer`, skipping heart-beats
+fn next_frame(buffer: &mut BytesMut) -> Result<Option<Message<ToServer>>> {
+    let eols = buffer
+        .iter()
+        .take_while(|&&b| b == b'\n' || b == b'\r')
+        .count();
+    buffer.advance(eols);
+    let (msg, len) = match parse_frame(buffer) {
+        Ok((rest, frame)) => (frame.to_client_msg()?, buffer.len() - rest.len()),
+        Err(nom::Err::Incomplete(_)) => return Ok(None),
+        Err(e) => bail!("Parse failed: {:?}", e),
+    };
+    buffer.advance(len);
+    Ok(Some(msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{disconnect, send_text, subscribe};
+    use crate::FromServer;
+
+    async fn next(transport: &mut StompTransport<TcpStream>) -> FromServer {
+        transport.next().await.unwrap().unwrap().content
+    }
+
+    async fn expect_receipt(transport: &mut StompTransport<TcpStream>, id: &str) {
+        match next(transport).await {
+            FromServer::Receipt { receipt_id } => assert_eq!(receipt_id, id),
+            other => panic!("expected a RECEIPT, got {:?}", other),
+        }
+    }
+
+    async fn expect_message(transport: &mut StompTransport<TcpStream>, subscription: &str) {
+        match next(transport).await {
+            FromServer::Message {
+                destination,
+                subscription: id,
+                body,
+                ..
+            } => {
+                assert_eq!(destination, "/queue/a");
+                assert_eq!(id, subscription);
+                assert_eq!(body.as_deref(), Some(&b"hello"[..]));
+            }
+            other => panic!("expected a MESSAGE, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn routes_messages_to_subscribers() {
+        let router = StompRouter::start().await.unwrap();
+        let mut first = router.connect().await.unwrap();
+        let mut second = router.connect().await.unwrap();
+        first
+            .send(subscribe("/queue/a", "1").receipt("r-1"))
+            .await
+            .unwrap();
+        expect_receipt(&mut first, "r-1").await;
+        second
+            .send(subscribe("/queue/a", "2").receipt("r-2"))
+            .await
+            .unwrap();
+        expect_receipt(&mut second, "r-2").await;
+        assert_eq!(router.subscribers("/queue/a"), 2);
+
+        let sender = router.connect().await.unwrap();
+        let send = tokio::spawn(async move {
+            let mut sender = sender;
+            sender
+                .send(send_text("/queue/a", "hello").receipt("sent"))
+                .await
+                .unwrap();
+            expect_receipt(&mut sender, "sent").await;
+            sender.send(send_text("/queue/b", "unheard")).await.unwrap();
+        });
+        expect_message(&mut first, "1").await;
+        expect_message(&mut second, "2").await;
+        send.await.unwrap();
+
+        let unsubscribe = Message::new(ToServer::Unsubscribe { id: "2".into() });
+        second.send(unsubscribe.receipt("r-3")).await.unwrap();
+        expect_receipt(&mut second, "r-3").await;
+        assert_eq!(router.subscribers("/queue/a"), 1);
+    }
+
+    #[tokio::test]
+    async fn disconnect_closes_connection() {
+        let router = StompRouter::start().await.unwrap();
+        let mut transport = router.connect().await.unwrap();
+        transport
+            .send(subscribe("/queue/a", "1").receipt("r-1"))
+            .await
+            .unwrap();
+        expect_receipt(&mut transport, "r-1").await;
+        transport
+            .send(Message::new(ToServer::Disconnect {
+                receipt: Some("bye".into()),
+            }))
+            .await
+            .unwrap();
+        expect_receipt(&mut transport, "bye").await;
+        assert!(transport.next().await.is_none());
+        assert_eq!(router.subscribers("/queue/a"), 0);
+
+        let mut transport = router.connect().await.unwrap();
+        transport.send(disconnect()).await.unwrap();
+        assert!(matches!(
+            next(&mut transport).await,
+            FromServer::Receipt { .. }
+        ));
+    }
+}
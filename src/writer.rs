@@ -0,0 +1,111 @@
+use std::io::{self, Write};
+
+use futures::executor::block_on;
+use futures::prelude::*;
+
+use crate::{Message, ToServer};
+
+/// A synchronous `std::io::Write` adapter, which buffers written bytes and
+/// sends them as the body of a single SEND frame on every `flush`.
+///
+/// # Blocking
+///
+/// `flush` blocks the current thread until the frame has been sent. This is intended
+/// only for migrating synchronous code or for FFI, and must never be called from within
+/// an async context: on a single-threaded runtime it will deadlock if `sink` depends
+/// on that runtime to make progress.
+/// Unflushed bytes are discarded when the writer is dropped.
+///
+/// Hidden from the documentation, as a use of last resort.
+#[doc(hidden)]
+pub struct SyncStompWriter<S> {
+    sink: S,
+    destination: String,
+    buffer: Vec<u8>,
+}
+
+impl<S> SyncStompWriter<S>
+where
+    S: Sink<Message<ToServer>, Error = failure::Error> + Unpin,
+{
+    /// Write messages to `destination` via `sink`
+    pub fn new(sink: S, destination: impl Into<String>) -> Self {
+        SyncStompWriter {
+            sink,
+            destination: destination.into(),
+            buffer: vec![],
+        }
+    }
+
+    /// Recover the underlying sink, discarding any unflushed bytes
+    pub fn into_inner(self) -> S {
+        self.sink
+    }
+}
+
+impl<S> Write for SyncStompWriter<S>
+where
+    S: Sink<Message<ToServer>, Error = failure::Error> + Unpin,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let msg = ToServer::Send {
+            destination: self.destination.clone(),
+            transaction: None,
+            body: Some(std::mem::take(&mut self.buffer).into()),
+        };
+        block_on(self.sink.send(msg.into())).map_err(|err| {
+            err.downcast::<io::Error>()
+                .unwrap_or_else(|err| io::Error::other(err.to_string()))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::mpsc;
+
+    #[test]
+    fn flush_sends_buffered_bytes() {
+        let (tx, mut rx) = mpsc::unbounded();
+        let sink = tx.sink_map_err(failure::Error::from);
+        let mut writer = SyncStompWriter::new(sink, "/queue/sync");
+        write!(writer, "hello, ").unwrap();
+        write!(writer, "world").unwrap();
+        assert!(rx.next().now_or_never().is_none());
+
+        writer.flush().unwrap();
+        // nothing was written since the last flush
+        writer.flush().unwrap();
+        drop(writer);
+
+        let msgs: Vec<_> = block_on(rx.collect());
+        assert_eq!(msgs.len(), 1);
+        match &msgs[0].content {
+            ToServer::Send {
+                destination, body, ..
+            } => {
+                assert_eq!(destination, "/queue/sync");
+                assert_eq!(body.as_deref(), Some(&b"hello, world"[..]));
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn flush_reports_send_errors() {
+        let (tx, rx) = mpsc::unbounded();
+        drop(rx);
+        let mut writer = SyncStompWriter::new(tx.sink_map_err(failure::Error::from), "a");
+        writer.write_all(b"lost").unwrap();
+        assert_eq!(writer.flush().unwrap_err().kind(), io::ErrorKind::Other);
+    }
+}
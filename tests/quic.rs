@@ -0,0 +1,67 @@
+#![cfg(feature = "quic")]
+
+use futures::prelude::*;
+use quinn::{
+    Certificate, CertificateChain, ClientConfigBuilder, Endpoint, NewConnection, PrivateKey,
+    ServerConfigBuilder,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_stomp::client::{self, quic, ConnectOptions};
+
+/// Read one NUL-terminated frame
+async fn read_frame<R: tokio::io::AsyncRead + Unpin>(reader: &mut R) -> Vec<u8> {
+    let mut frame = Vec::new();
+    loop {
+        let byte = reader.read_u8().await.unwrap();
+        if byte == 0 {
+            return frame;
+        }
+        frame.push(byte);
+    }
+}
+
+#[tokio::test]
+async fn connect_over_loopback() {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let key = PrivateKey::from_der(&cert.serialize_private_key_der()).unwrap();
+    let cert = Certificate::from_der(&cert.serialize_der().unwrap()).unwrap();
+
+    let mut server_config = ServerConfigBuilder::default();
+    server_config
+        .certificate(CertificateChain::from_certs(vec![cert.clone()]), key)
+        .unwrap();
+    let mut builder = Endpoint::builder();
+    builder.listen(server_config.build());
+    let (endpoint, mut incoming) = builder.bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = endpoint.local_addr().unwrap();
+
+    let server = tokio::spawn(async move {
+        let NewConnection { mut bi_streams, .. } = incoming.next().await.unwrap().await.unwrap();
+        let (mut send, mut recv) = bi_streams.next().await.unwrap().unwrap();
+        let connect = read_frame(&mut recv).await;
+        assert!(connect.starts_with(b"CONNECT\n"));
+        assert!(connect.windows(15).any(|h| h == b"host:localhost\n"));
+        send.write_all(b"CONNECTED\nversion:1.2\n\n\0")
+            .await
+            .unwrap();
+        let frame = read_frame(&mut recv).await;
+        assert!(frame.starts_with(b"SEND\n"));
+        assert!(frame.ends_with(b"\n\nhello"));
+    });
+
+    let mut client_config = ClientConfigBuilder::default();
+    client_config.add_certificate_authority(cert).unwrap();
+    let mut transport = quic::connect(
+        addr,
+        "localhost",
+        client_config.build(),
+        ConnectOptions::new(),
+    )
+    .await
+    .unwrap();
+    transport
+        .send(client::send_text("/queue/a", "hello"))
+        .await
+        .unwrap();
+    server.await.unwrap();
+}
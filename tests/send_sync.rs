@@ -0,0 +1,29 @@
+//! Checks that public types can be stored by async frameworks which require `Send + Sync`
+
+use static_assertions::assert_impl_all;
+use tokio::net::TcpStream;
+use tokio_stomp::client::failover::FailoverTransport;
+use tokio_stomp::client::{
+    ArcTransport, CloseReason, ConnectOptions, ConnectionDetails, DisconnectReason, SendBuilder,
+    StompTransport, SubscriptionBuilder,
+};
+use tokio_stomp::{AckMode, BodyDelimitation, FromServer, Message, StompError, ToServer};
+
+assert_impl_all!(Message<FromServer>: Send, Sync);
+assert_impl_all!(Message<ToServer>: Send, Sync);
+assert_impl_all!(StompError: Send, Sync);
+assert_impl_all!(AckMode: Send, Sync);
+assert_impl_all!(BodyDelimitation: Send, Sync);
+assert_impl_all!(ConnectOptions: Send, Sync);
+assert_impl_all!(ConnectionDetails: Send, Sync);
+assert_impl_all!(DisconnectReason: Send, Sync);
+assert_impl_all!(CloseReason: Send, Sync);
+assert_impl_all!(SendBuilder: Send, Sync);
+assert_impl_all!(SubscriptionBuilder: Send, Sync);
+
+// The connection returned by `connect` can be moved between tasks. It is only ever
+// used through `&mut`, so the callbacks it stores need only be `Send`
+assert_impl_all!(StompTransport<TcpStream>: Send);
+assert_impl_all!(FailoverTransport: Send);
+// shared handles lock the connection, so they can be used from several tasks at once
+assert_impl_all!(ArcTransport<TcpStream>: Send, Sync);
@@ -0,0 +1,119 @@
+#![cfg(feature = "tls")]
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use futures::prelude::*;
+use rustls::{
+    Certificate, ClientConfig, NoClientAuth, PrivateKey, RootCertStore, ServerCertVerified,
+    ServerCertVerifier, ServerConfig, TLSError,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tokio_stomp::client::{self, tls, ConnectOptions};
+use webpki::DNSNameRef;
+
+/// Read one NUL-terminated frame
+async fn read_frame<R: tokio::io::AsyncRead + Unpin>(reader: &mut R) -> Vec<u8> {
+    let mut frame = Vec::new();
+    loop {
+        let byte = reader.read_u8().await.unwrap();
+        if byte == 0 {
+            return frame;
+        }
+        frame.push(byte);
+    }
+}
+
+/// Start a server with a self-signed certificate for "localhost", which accepts one
+/// connection and one SEND frame. Returns its port and its certificate.
+async fn start_server() -> (u16, Certificate, tokio::task::JoinHandle<()>) {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let key = PrivateKey(cert.serialize_private_key_der());
+    let cert = Certificate(cert.serialize_der().unwrap());
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(vec![cert.clone()], key).unwrap();
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let server = tokio::spawn(async move {
+        let (tcp, _) = listener.accept().await.unwrap();
+        let mut stream = acceptor.accept(tcp).await.unwrap();
+        let connect = read_frame(&mut stream).await;
+        assert!(connect.starts_with(b"CONNECT\n"));
+        stream
+            .write_all(b"CONNECTED\nversion:1.2\n\n\0")
+            .await
+            .unwrap();
+        let frame = read_frame(&mut stream).await;
+        assert!(frame.starts_with(b"SEND\n"));
+        assert!(frame.ends_with(b"\n\nhello"));
+    });
+    (port, cert, server)
+}
+
+#[tokio::test]
+async fn connect_with_custom_roots() {
+    let (port, cert, server) = start_server().await;
+
+    let mut config = ClientConfig::new();
+    config.root_store.add(&cert).unwrap();
+    let options = ConnectOptions::new().tls(Arc::new(config));
+    let mut transport = tls::connect(format!("localhost:{}", port), options)
+        .await
+        .unwrap();
+    transport
+        .send(client::send_text("/queue/a", "hello"))
+        .await
+        .unwrap();
+    server.await.unwrap();
+}
+
+#[tokio::test]
+async fn untrusted_certificate_is_rejected() {
+    let (port, _, _server) = start_server().await;
+
+    let result = tls::connect(format!("localhost:{}", port), ConnectOptions::new()).await;
+    assert!(result.is_err());
+}
+
+/// Accepts every certificate, counting how often it was asked
+#[derive(Default)]
+struct CountingVerifier {
+    calls: AtomicUsize,
+}
+
+impl ServerCertVerifier for CountingVerifier {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        dns_name: DNSNameRef<'_>,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        assert_eq!(presented_certs.len(), 1);
+        assert_eq!(AsRef::<str>::as_ref(&dns_name.to_owned()), "localhost");
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+#[tokio::test]
+async fn connect_with_custom_verifier() {
+    let (port, _, server) = start_server().await;
+
+    let verifier = Arc::new(CountingVerifier::default());
+    let options = ConnectOptions::new().tls_with_verifier(verifier.clone());
+    let mut transport = tls::connect(format!("localhost:{}", port), options)
+        .await
+        .unwrap();
+    transport
+        .send(client::send_text("/queue/a", "hello"))
+        .await
+        .unwrap();
+    server.await.unwrap();
+    assert_eq!(verifier.calls.load(Ordering::SeqCst), 1);
+}